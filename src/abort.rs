@@ -0,0 +1,113 @@
+//! Cancellation of connections in progress.
+
+use crate::Error;
+use futures::{task, Future, Poll};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, Mutex,
+};
+
+/// Wraps `future` so that it can be aborted with the returned `AbortHandle`.
+///
+/// Once aborted, the future fails with `Error::Cancelled` the next time it's polled,
+/// dropping the wrapped future and thereby closing the connection to the proxy server
+/// along with the handshake in progress. The task polling the future is woken up by the
+/// handle.
+pub fn abortable<F>(future: F) -> (Abortable<F>, AbortHandle)
+where
+    F: Future<Error = Error>,
+{
+    let handle = AbortHandle::new();
+    let future = Abortable {
+        inner: Some(future),
+        handle: handle.clone(),
+    };
+    (future, handle)
+}
+
+/// A handle to abort an `Abortable` future, which can be cloned and sent to other tasks.
+#[derive(Debug, Clone)]
+pub struct AbortHandle {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    aborted: AtomicBool,
+    task: Mutex<Option<task::Task>>,
+}
+
+impl AbortHandle {
+    pub(crate) fn new() -> AbortHandle {
+        AbortHandle {
+            inner: Arc::new(Inner {
+                aborted: AtomicBool::new(false),
+                task: Mutex::new(None),
+            }),
+        }
+    }
+
+    /// Aborts the future. It has no effect if the future already resolved.
+    pub fn abort(&self) {
+        self.inner.aborted.store(true, Ordering::SeqCst);
+        if let Some(task) = self.inner.task.lock().unwrap().take() {
+            task.notify();
+        }
+    }
+
+    /// Returns `true` if `abort` was called.
+    pub fn is_aborted(&self) -> bool {
+        self.inner.aborted.load(Ordering::SeqCst)
+    }
+
+    /// Returns `true` if `abort` was called, or arranges for the current task to be woken
+    /// up when it is.
+    pub(crate) fn poll_aborted(&self) -> bool {
+        // The task is registered before checking the flag, so that an abort in between
+        // isn't missed.
+        *self.inner.task.lock().unwrap() = Some(task::current());
+        self.is_aborted()
+    }
+}
+
+/// A `Future` which can be aborted with an `AbortHandle`.
+#[derive(Debug)]
+pub struct Abortable<F> {
+    inner: Option<F>,
+    handle: AbortHandle,
+}
+
+impl<F> Future for Abortable<F>
+where
+    F: Future<Error = Error>,
+{
+    type Item = F::Item;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<F::Item, Error> {
+        if self.handle.poll_aborted() {
+            self.inner = None;
+            return Err(Error::Cancelled);
+        }
+        match &mut self.inner {
+            Some(inner) => inner.poll(),
+            None => Err(Error::Cancelled),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future;
+
+    #[test]
+    fn fails_once_aborted() {
+        let (future, handle) = abortable(future::empty::<(), Error>());
+        handle.abort();
+        match future.wait() {
+            Err(Error::Cancelled) => {}
+            res => panic!("unexpected result: {:?}", res),
+        }
+    }
+}
@@ -0,0 +1,165 @@
+//! A synchronous API for the programs which don't run an event loop, like command-line
+//! tools and build scripts.
+//!
+//! Each stream drives a small single-threaded runtime of its own, with the same proxy
+//! logic and error types as the asynchronous API.
+
+use crate::{
+    connector::Socks5Connector, tcp, Error, IntoTargetAddr, Result, TargetAddr, ToProxyAddrs,
+};
+use futures::{future, Future};
+use std::{
+    fmt,
+    io::{self, Read, Write},
+    net::{Shutdown, SocketAddr},
+};
+use tokio::runtime::current_thread::Runtime;
+use tokio_io::{AsyncRead, AsyncWrite};
+
+/// A blocking stream connected to a target server through a SOCKS5 proxy.
+pub struct Socks5Stream {
+    inner: tcp::Socks5Stream,
+    runtime: Runtime,
+}
+
+impl Socks5Stream {
+    /// Connects to a target server through a SOCKS5 proxy, blocking until the handshake
+    /// completes.
+    ///
+    /// # Error
+    ///
+    /// It fails if the runtime can't be started, and propagates the error of the
+    /// handshake.
+    pub fn connect<'t, P, T>(proxy: P, target: T) -> Result<Socks5Stream>
+    where
+        P: ToProxyAddrs,
+        T: IntoTargetAddr<'t>,
+    {
+        Self::block_on(tcp::Socks5Stream::connect(proxy, target)?)
+    }
+
+    /// Connects to a target server through a SOCKS5 proxy using given username and
+    /// password, blocking until the handshake completes.
+    ///
+    /// # Error
+    ///
+    /// It fails if the runtime can't be started, and propagates the error of the
+    /// handshake.
+    pub fn connect_with_password<'t, P, T>(
+        proxy: P,
+        target: T,
+        username: &str,
+        password: &str,
+    ) -> Result<Socks5Stream>
+    where
+        P: ToProxyAddrs,
+        T: IntoTargetAddr<'t>,
+    {
+        Self::block_on(tcp::Socks5Stream::connect_with_password(
+            proxy, target, username, password,
+        )?)
+    }
+
+    /// Connects to a target server through a SOCKS5 proxy with the configuration of
+    /// `connector`, blocking until the handshake completes.
+    ///
+    /// # Error
+    ///
+    /// It fails if the runtime can't be started, and propagates the error of the
+    /// handshake.
+    pub fn connect_with<'t, P, T>(
+        connector: &Socks5Connector,
+        proxy: P,
+        target: T,
+    ) -> Result<Socks5Stream>
+    where
+        P: ToProxyAddrs,
+        T: IntoTargetAddr<'t>,
+    {
+        Self::block_on(connector.connect(proxy, target)?)
+    }
+
+    fn block_on<F>(connect: F) -> Result<Socks5Stream>
+    where
+        F: Future<Item = tcp::Socks5Stream, Error = Error>,
+    {
+        let mut runtime = Runtime::new()?;
+        let inner = runtime.block_on(connect)?;
+        Ok(Socks5Stream { inner, runtime })
+    }
+
+    /// Returns the target address that the proxy server connects to.
+    pub fn target_addr(&self) -> &TargetAddr<'static> {
+        self.inner.target_addr()
+    }
+
+    /// Returns the reply of the proxy server to the request.
+    pub fn reply(&self) -> tcp::Reply {
+        self.inner.reply()
+    }
+
+    /// Returns the address of the proxy server.
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.get_ref().peer_addr()
+    }
+
+    /// Returns the local address of the connection to the proxy server.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.get_ref().local_addr()
+    }
+
+    /// Receives the data tunneled from the target without removing it from the queue,
+    /// blocking until some is available.
+    pub fn peek(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let inner = &mut self.inner;
+        self.runtime
+            .block_on(future::poll_fn(|| inner.poll_peek(buf)))
+    }
+
+    /// Shuts down the read, write, or both halves of the connection.
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        self.inner.get_ref().shutdown(how)
+    }
+
+    /// Shuts down the write half of the connection, while the data sent by the target can
+    /// still be read.
+    pub fn shutdown_write(&self) -> io::Result<()> {
+        self.inner.shutdown_write()
+    }
+
+    /// Consumes the `Socks5Stream`, returning the asynchronous stream along with the
+    /// runtime it's registered with.
+    pub fn into_inner(self) -> (tcp::Socks5Stream, Runtime) {
+        (self.inner, self.runtime)
+    }
+}
+
+impl Read for Socks5Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let inner = &mut self.inner;
+        self.runtime
+            .block_on(future::poll_fn(|| inner.poll_read(buf)))
+    }
+}
+
+impl Write for Socks5Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let inner = &mut self.inner;
+        self.runtime
+            .block_on(future::poll_fn(|| inner.poll_write(buf)))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let inner = &mut self.inner;
+        self.runtime
+            .block_on(future::poll_fn(|| inner.poll_flush()))
+    }
+}
+
+impl fmt::Debug for Socks5Stream {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Socks5Stream")
+            .field("inner", &self.inner)
+            .finish()
+    }
+}
@@ -0,0 +1,68 @@
+use std::time::{Duration, Instant};
+
+/// A token bucket refilled with `rate` tokens per second, which holds at most a second
+/// worth of tokens and starts full.
+///
+/// It limits both the bytes of a `Throttled` stream and the handshakes per second of a
+/// connector.
+#[derive(Debug)]
+pub(crate) struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl TokenBucket {
+    /// Creates a full bucket. `rate` must be positive.
+    pub(crate) fn new(rate: f64) -> TokenBucket {
+        debug_assert!(rate > 0.0);
+        TokenBucket {
+            rate,
+            tokens: rate,
+            refilled: Instant::now(),
+        }
+    }
+
+    /// Adds the tokens accrued since the last refill.
+    pub(crate) fn refill(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.refilled);
+        let elapsed = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.refilled = now;
+    }
+
+    /// Returns the number of tokens in the bucket as of the last refill.
+    pub(crate) fn tokens(&self) -> f64 {
+        self.tokens
+    }
+
+    pub(crate) fn consume(&mut self, tokens: f64) {
+        self.tokens -= tokens;
+    }
+
+    /// Returns how long after the last refill the bucket holds `tokens`, or is full if
+    /// it can't hold as many.
+    pub(crate) fn wait(&self, tokens: f64) -> Duration {
+        let missing = (tokens.min(self.rate) - self.tokens).max(0.0);
+        Duration::from_nanos((missing / self.rate * 1e9) as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refills_up_to_a_second_worth_of_tokens() {
+        let mut bucket = TokenBucket::new(100.0);
+        let start = bucket.refilled;
+        bucket.consume(100.0);
+        assert_eq!(bucket.wait(50.0), Duration::from_millis(500));
+        assert_eq!(bucket.wait(500.0), Duration::from_secs(1));
+        bucket.refill(start + Duration::from_millis(500));
+        assert_eq!(bucket.tokens() as u64, 50);
+        assert_eq!(bucket.wait(10.0), Duration::from_secs(0));
+        bucket.refill(start + Duration::from_secs(10));
+        assert_eq!(bucket.tokens() as u64, 100);
+    }
+}
@@ -0,0 +1,56 @@
+use bytes::BytesMut;
+use std::sync::Mutex;
+
+/// The size of the largest handshake message, a username/password authentication request.
+const BUFFER_CAPACITY: usize = 513;
+
+/// A pool of the buffers of the handshakes, shared by the clones of a connector so that
+/// each attempt doesn't allocate its own.
+#[derive(Debug)]
+pub(crate) struct BufferPool {
+    buffers: Mutex<Vec<BytesMut>>,
+    max_buffers: usize,
+}
+
+impl BufferPool {
+    pub(crate) fn new(max_buffers: usize) -> BufferPool {
+        BufferPool {
+            buffers: Mutex::new(Vec::with_capacity(max_buffers)),
+            max_buffers,
+        }
+    }
+
+    /// Takes a buffer out of the pool, or allocates one if the pool is empty.
+    pub(crate) fn get(&self) -> BytesMut {
+        self.buffers
+            .lock()
+            .unwrap()
+            .pop()
+            .unwrap_or_else(|| BytesMut::with_capacity(BUFFER_CAPACITY))
+    }
+
+    /// Returns a buffer to the pool, unless the pool is full.
+    pub(crate) fn put(&self, mut buf: BytesMut) {
+        buf.clear();
+        let mut buffers = self.buffers.lock().unwrap();
+        if buffers.len() < self.max_buffers {
+            buffers.push(buf);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_at_most_max_buffers() {
+        let pool = BufferPool::new(1);
+        let (first, second) = (pool.get(), pool.get());
+        pool.put(first);
+        pool.put(second);
+        assert_eq!(pool.buffers.lock().unwrap().len(), 1);
+        assert!(pool.get().capacity() >= BUFFER_CAPACITY);
+        assert!(pool.buffers.lock().unwrap().is_empty());
+    }
+}
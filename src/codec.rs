@@ -0,0 +1,313 @@
+//! The messages of the SOCKS5 handshake, as defined in RFC 1928 and RFC 1929.
+
+use crate::{connector::Compliance, consts::*, Error, TargetAddr};
+use bytes::{Bytes, BytesMut};
+use std::{
+    marker::PhantomData,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
+};
+use tokio_codec::{Decoder, Encoder};
+
+/// A message sent by the client.
+#[derive(Debug)]
+pub(crate) enum ClientMessage<'a> {
+    /// The authentication methods supported by the client.
+    Methods(&'a [u8]),
+    /// A username/password authentication request.
+    PasswordAuth {
+        username: &'a str,
+        password: &'a str,
+    },
+    /// A request for `command` to `target`.
+    Request {
+        command: u8,
+        target: &'a TargetAddr<'a>,
+    },
+}
+
+/// A message sent by the server.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum ServerMessage {
+    /// The authentication method selected by the server.
+    MethodSelection(u8),
+    /// The status of a username/password authentication, `0x00` on success.
+    PasswordAuthStatus(u8),
+    /// The reply to a request. The address is only read when the request succeeded.
+    Reply {
+        code: u8,
+        addr: Option<TargetAddr<'static>>,
+        raw: Bytes,
+    },
+}
+
+/// The kind of message expected from the server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Expect {
+    MethodSelection,
+    PasswordAuthStatus,
+    Reply,
+}
+
+/// Encodes the messages of the client.
+#[derive(Debug, Default)]
+pub(crate) struct ClientEncoder<'a>(PhantomData<&'a ()>);
+
+impl<'a> Encoder for ClientEncoder<'a> {
+    type Item = ClientMessage<'a>;
+    type Error = Error;
+
+    fn encode(&mut self, msg: ClientMessage<'a>, dst: &mut BytesMut) -> Result<(), Error> {
+        match msg {
+            ClientMessage::Methods(methods) => {
+                dst.extend_from_slice(&[SOCKS5_VERSION, methods.len() as u8]);
+                dst.extend_from_slice(methods);
+            }
+            ClientMessage::PasswordAuth { username, password } => {
+                dst.extend_from_slice(&[PASSWORD_AUTH_VERSION, username.len() as u8]);
+                dst.extend_from_slice(username.as_bytes());
+                dst.extend_from_slice(&[password.len() as u8]);
+                dst.extend_from_slice(password.as_bytes());
+            }
+            ClientMessage::Request { command, target } => {
+                dst.extend_from_slice(&[SOCKS5_VERSION, command, RESERVED]);
+                write_addr(target, |bytes| dst.extend_from_slice(bytes));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Decodes the messages of the server.
+///
+/// The driver reads `remaining` bytes before each call to `decode`, so that no byte past
+/// the end of the handshake is consumed.
+#[derive(Debug)]
+pub(crate) struct ServerDecoder {
+    expect: Expect,
+    compliance: Compliance,
+}
+
+impl ServerDecoder {
+    pub(crate) fn new(expect: Expect, compliance: Compliance) -> ServerDecoder {
+        ServerDecoder { expect, compliance }
+    }
+
+    /// Returns how many more bytes `src` needs to hold the next message, as far as it can
+    /// be told from the bytes already received.
+    pub(crate) fn remaining(&self, src: &[u8]) -> usize {
+        let len = match self.expect {
+            Expect::MethodSelection | Expect::PasswordAuthStatus => 2,
+            // A failed reply is reported without waiting for the address.
+            Expect::Reply if src.len() < 4 || src[1] != REPLY_SUCCEEDED => 4,
+            Expect::Reply => 3 + addr_len(&src[3..]).unwrap_or(2),
+        };
+        len.saturating_sub(src.len())
+    }
+}
+
+impl Decoder for ServerDecoder {
+    type Item = ServerMessage;
+    type Error = Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<ServerMessage>, Error> {
+        if self.remaining(src) > 0 {
+            return Ok(None);
+        }
+        let strict = self.compliance == Compliance::Strict;
+        let (msg, len) = match self.expect {
+            Expect::MethodSelection => {
+                if src[0] != SOCKS5_VERSION {
+                    Err(Error::InvalidResponseVersion)?
+                }
+                (ServerMessage::MethodSelection(src[1]), 2)
+            }
+            Expect::PasswordAuthStatus => {
+                // Some servers answer with the SOCKS version instead.
+                if src[0] != PASSWORD_AUTH_VERSION && strict {
+                    Err(Error::InvalidResponseVersion)?
+                }
+                (ServerMessage::PasswordAuthStatus(src[1]), 2)
+            }
+            Expect::Reply => {
+                if src[0] != SOCKS5_VERSION {
+                    Err(Error::InvalidResponseVersion)?
+                }
+                if src[2] != RESERVED && strict {
+                    Err(Error::InvalidReservedByte)?
+                }
+                let code = src[1];
+                if code != REPLY_SUCCEEDED {
+                    let raw = Bytes::from(&src[..4]);
+                    (
+                        ServerMessage::Reply {
+                            code,
+                            addr: None,
+                            raw,
+                        },
+                        4,
+                    )
+                } else {
+                    let len = 3 + addr_len(&src[3..]).unwrap();
+                    let addr = read_addr(&src[3..len], self.compliance)?;
+                    match &addr {
+                        TargetAddr::Domain(domain, _) if domain.is_empty() && strict => {
+                            Err(Error::InvalidTargetAddress("empty domain"))?
+                        }
+                        _ => {}
+                    }
+                    let addr = Some(addr);
+                    let raw = Bytes::from(&src[..len]);
+                    (ServerMessage::Reply { code, addr, raw }, len)
+                }
+            }
+        };
+        src.advance(len);
+        Ok(Some(msg))
+    }
+}
+
+/// Writes `target` as the ATYP, address and port fields of a request, passing the bytes
+/// to `put`.
+pub(crate) fn write_addr(target: &TargetAddr<'_>, mut put: impl FnMut(&[u8])) {
+    match target {
+        TargetAddr::Ip(SocketAddr::V4(addr)) => {
+            put(&[ATYP_IPV4]);
+            put(&addr.ip().octets());
+            put(&addr.port().to_be_bytes());
+        }
+        TargetAddr::Ip(SocketAddr::V6(addr)) => {
+            put(&[ATYP_IPV6]);
+            put(&addr.ip().octets());
+            put(&addr.port().to_be_bytes());
+        }
+        TargetAddr::Domain(domain, port) => {
+            put(&[ATYP_DOMAIN, domain.len() as u8]);
+            put(domain.as_bytes());
+            put(&port.to_be_bytes());
+        }
+    }
+}
+
+/// Returns the length of the ATYP, address and port fields starting `buf`, or `None` if
+/// `buf` is too short to tell. An unknown address type counts as a single byte, which
+/// `read_addr` rejects.
+pub(crate) fn addr_len(buf: &[u8]) -> Option<usize> {
+    match *buf.first()? {
+        ATYP_IPV4 => Some(7),
+        ATYP_IPV6 => Some(19),
+        ATYP_DOMAIN => buf.get(1).map(|&len| 4 + len as usize),
+        _ => Some(1),
+    }
+}
+
+/// Reads the ATYP, address and port fields filling `buf`, whose length is given by
+/// `addr_len`.
+///
+/// A domain which isn't valid UTF-8 is rejected, unless `compliance` is lenient, in which
+/// case its invalid sequences are replaced with U+FFFD.
+pub(crate) fn read_addr(buf: &[u8], compliance: Compliance) -> Result<TargetAddr<'static>, Error> {
+    let port = || u16::from_be_bytes([buf[buf.len() - 2], buf[buf.len() - 1]]);
+    let addr = match buf[0] {
+        ATYP_IPV4 => {
+            let ip = Ipv4Addr::new(buf[1], buf[2], buf[3], buf[4]);
+            TargetAddr::Ip(SocketAddrV4::new(ip, port()).into())
+        }
+        ATYP_IPV6 => {
+            let mut ip = [0; 16];
+            ip.copy_from_slice(&buf[1..17]);
+            TargetAddr::Ip(SocketAddrV6::new(Ipv6Addr::from(ip), port(), 0, 0).into())
+        }
+        ATYP_DOMAIN => {
+            let domain = &buf[2..(buf.len() - 2)];
+            let domain = match compliance {
+                Compliance::Strict => String::from_utf8(domain.to_vec())
+                    .map_err(|_| Error::InvalidTargetAddress("not a valid UTF-8 string"))?,
+                Compliance::Lenient => String::from_utf8_lossy(domain).into_owned(),
+            };
+            TargetAddr::Domain(domain.into(), port())
+        }
+        _ => Err(Error::UnknownAddressType)?,
+    };
+    Ok(addr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(expect: Expect, bytes: &[u8]) -> Result<Option<ServerMessage>, Error> {
+        let mut decoder = ServerDecoder::new(expect, Compliance::Strict);
+        let mut src = BytesMut::from(bytes);
+        decoder.decode(&mut src)
+    }
+
+    #[test]
+    fn round_trips_requests() {
+        for target in &["10.0.0.1:80", "[::1]:80", "example.com:80"] {
+            let target: TargetAddr = target.parse().unwrap();
+            let mut buf = BytesMut::new();
+            let request = ClientMessage::Request {
+                command: 0x01,
+                target: &target,
+            };
+            ClientEncoder::default().encode(request, &mut buf).unwrap();
+            // A reply has the same layout as a request.
+            buf[1] = 0x00;
+            assert_eq!(
+                decode(Expect::Reply, &buf).unwrap(),
+                Some(ServerMessage::Reply {
+                    code: 0x00,
+                    addr: Some(target.to_owned()),
+                    raw: Bytes::from(&buf[..]),
+                })
+            );
+            assert_eq!(decode(Expect::Reply, &buf[..buf.len() - 1]).unwrap(), None);
+        }
+    }
+
+    #[test]
+    fn reports_failed_replies_without_the_address() {
+        assert_eq!(
+            decode(Expect::Reply, &[0x05, 0x04, 0x00, 0x01]).unwrap(),
+            Some(ServerMessage::Reply {
+                code: 0x04,
+                addr: None,
+                raw: Bytes::from(&[0x05, 0x04, 0x00, 0x01][..]),
+            })
+        );
+        assert!(decode(Expect::Reply, &[0x05, 0x00, 0x00, 0x07]).is_err());
+    }
+
+    #[test]
+    fn tolerates_violations_when_lenient() {
+        let reply = [0x05, 0x00, 0xff, 0x03, 0x00, 0x00, 0x50];
+        assert!(decode(Expect::Reply, &reply).is_err());
+        assert!(decode(Expect::PasswordAuthStatus, &[0x05, 0x00]).is_err());
+        let mut decoder = ServerDecoder::new(Expect::Reply, Compliance::Lenient);
+        assert_eq!(
+            decoder.decode(&mut BytesMut::from(&reply[..])).unwrap(),
+            Some(ServerMessage::Reply {
+                code: 0x00,
+                addr: Some(TargetAddr::Domain("".into(), 80)),
+                raw: Bytes::from(&reply[..]),
+            })
+        );
+        let mut decoder = ServerDecoder::new(Expect::PasswordAuthStatus, Compliance::Lenient);
+        assert_eq!(
+            decoder
+                .decode(&mut BytesMut::from(&[0x05, 0x00][..]))
+                .unwrap(),
+            Some(ServerMessage::PasswordAuthStatus(0x00))
+        );
+    }
+
+    #[test]
+    fn replaces_invalid_utf8_domains_when_lenient() {
+        let addr = [0x03, 0x02, b'a', 0xff, 0x00, 0x50];
+        assert!(read_addr(&addr, Compliance::Strict).is_err());
+        assert_eq!(
+            read_addr(&addr, Compliance::Lenient).unwrap(),
+            TargetAddr::Domain("a\u{fffd}".into(), 80)
+        );
+    }
+}
@@ -0,0 +1,1624 @@
+use crate::{
+    abort::{self, AbortHandle, Abortable},
+    buffer::BufferPool,
+    dial,
+    dialer::{Dialer, Established, Handshake},
+    fallback::{Fallback, FallbackPolicy},
+    first_ok::ConnectFirstOk,
+    group::{Failover, ProxyGroup},
+    limit::{Limiter, Limits, Permit},
+    observer::{Chain, Direction, EventListener, Listener, Observer, SharedObserver, WireDump},
+    policy::{SharedPolicy, TargetPolicy},
+    proxy_protocol::ProxyHeader,
+    reconnect::ReconnectingStream,
+    resolve::{Resolve, Resolving, SharedResolver},
+    tcp::{Command, ConnectFuture, HandshakeStats, Reply, Socks5Stream},
+    AuthConfig, Authentication, Error, IntoTargetAddr, ProxySpec, Result, TargetAddr, ToProxyAddrs,
+};
+use futures::{future::Map, try_ready, Async, Future, Poll, Stream};
+use rand::{seq::SliceRandom, Rng};
+use socket2::Socket;
+use std::{
+    collections::VecDeque,
+    fmt, io,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+    vec,
+};
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_timer::Delay;
+
+pub use crate::limit::LimiterStats;
+#[cfg(feature = "bind")]
+use crate::tcp::{PeerVerification, Socks5Listener};
+
+/// The order in which the resolved addresses of a proxy server are tried.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "kebab-case"))]
+pub enum AddrOrder {
+    /// Try the addresses in the order they are resolved.
+    #[default]
+    Sequential,
+    /// Shuffle the addresses, spreading the load over all of them.
+    Random,
+    /// Try IPv6 addresses before IPv4 addresses, keeping the resolved order otherwise.
+    PreferIpv6,
+    /// Try IPv4 addresses before IPv6 addresses, keeping the resolved order otherwise.
+    PreferIpv4,
+}
+
+/// How strictly the replies of the proxy server are checked against RFC 1928 and RFC 1929.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
+pub enum Compliance {
+    /// Fail on any violation.
+    #[default]
+    Strict,
+    /// Tolerate the violations of real-world proxy servers which don't prevent the
+    /// handshake from completing: a wrong version byte in the reply to the username/password
+    /// authentication, a reserved byte which isn't zero and an empty domain in a reply.
+    /// A domain in a reply which isn't valid UTF-8 is converted lossily.
+    Lenient,
+}
+
+/// Where the domain names of the targets are resolved.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
+pub enum DnsPolicy {
+    /// Send the domain names to the proxy server, which resolves them.
+    #[default]
+    Remote,
+    /// Resolve the domain names locally and request the addresses from the proxy server.
+    ///
+    /// The system resolver is called on a separate thread, so that it doesn't block the
+    /// event loop. When a name resolves to both IPv4 and IPv6 addresses, the requests are raced
+    /// through the proxy (RFC 8305, "Happy Eyeballs"), with the stagger delay set with
+    /// `Socks5ConnectorBuilder::happy_eyeballs` or 250 milliseconds.
+    Local,
+}
+
+/// Decides whether and when a failed connection attempt is retried.
+///
+/// The delay before the n-th retry grows exponentially from the initial backoff,
+/// up to the maximum backoff. With jitter enabled, the delay is randomized between
+/// half and all of that value, so that clients failing together don't retry together.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    max_attempts: u32,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    jitter: bool,
+    retry_on: fn(&Error) -> bool,
+}
+
+impl RetryPolicy {
+    /// Creates a policy making at most `max_attempts` attempts, including the first one.
+    ///
+    /// The backoff starts at 100 milliseconds and is capped at 10 seconds, with jitter.
+    /// Only transient errors (see `Error::is_transient`) are retried.
+    pub fn new(max_attempts: u32) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(10),
+            jitter: true,
+            retry_on: Error::is_transient,
+        }
+    }
+
+    /// Sets the delay before the first retry and the maximum delay between two attempts.
+    pub fn backoff(mut self, initial: Duration, max: Duration) -> RetryPolicy {
+        self.initial_backoff = initial;
+        self.max_backoff = max;
+        self
+    }
+
+    /// Enables or disables the randomization of the backoff.
+    pub fn jitter(mut self, jitter: bool) -> RetryPolicy {
+        self.jitter = jitter;
+        self
+    }
+
+    /// Sets the function deciding whether an error is worth a retry.
+    pub fn retry_on(mut self, retry_on: fn(&Error) -> bool) -> RetryPolicy {
+        self.retry_on = retry_on;
+        self
+    }
+
+    /// Returns the delay before the next attempt, or `None` if the failed attempt
+    /// shouldn't be retried.
+    pub(crate) fn next_delay(&self, failed_attempts: u32, err: &Error) -> Option<Duration> {
+        if failed_attempts >= self.max_attempts || !(self.retry_on)(err) {
+            return None;
+        }
+        let factor = 1u32.checked_shl(failed_attempts - 1).unwrap_or(u32::MAX);
+        let backoff = self
+            .initial_backoff
+            .checked_mul(factor)
+            .map_or(self.max_backoff, |backoff| backoff.min(self.max_backoff));
+        if !self.jitter {
+            return Some(backoff);
+        }
+        let half = backoff / 2;
+        let max_jitter = (backoff - half).as_millis() as u64;
+        let jitter = rand::thread_rng().gen_range(0, max_jitter + 1);
+        Some(half + Duration::from_millis(jitter))
+    }
+}
+
+/// Options of the TCP connection to the proxy server. `None` keeps the system default.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct SocketConfig {
+    /// Sets `TCP_NODELAY`, disabling Nagle's algorithm when `true`.
+    pub nodelay: Option<bool>,
+    /// Enables `SO_KEEPALIVE` with the given parameters.
+    pub keepalive: Option<Keepalive>,
+    /// Sets `IP_TTL`.
+    pub ttl: Option<u32>,
+    /// Enables `SO_LINGER` with the given timeout.
+    pub linger: Option<Duration>,
+}
+
+/// TCP keepalive parameters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Keepalive {
+    /// Idle time before the first keepalive probe is sent.
+    pub time: Duration,
+    /// Interval between two probes. Only supported on Linux and Android.
+    pub interval: Option<Duration>,
+    /// Number of unanswered probes before the connection is dropped. Only supported on
+    /// Linux and Android.
+    pub retries: Option<u32>,
+}
+
+impl Keepalive {
+    /// Creates keepalive parameters sending the first probe after `time` of inactivity,
+    /// keeping the system defaults for the interval and number of probes.
+    pub fn new(time: Duration) -> Keepalive {
+        Keepalive {
+            time,
+            interval: None,
+            retries: None,
+        }
+    }
+}
+
+/// Options shared by every connection established through a `Socks5Connector`.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Config {
+    pub(crate) addr_order: AddrOrder,
+    pub(crate) happy_eyeballs: Option<Duration>,
+    pub(crate) retry: Option<RetryPolicy>,
+    pub(crate) local_addr: Option<SocketAddr>,
+    pub(crate) socket: SocketConfig,
+    pub(crate) prepare_socket: Option<PrepareSocket>,
+    pub(crate) fast_open: bool,
+    pub(crate) pipeline: bool,
+    pub(crate) compliance: Compliance,
+    pub(crate) dns_policy: DnsPolicy,
+    pub(crate) target_policy: Option<SharedPolicy>,
+    pub(crate) resolver: Option<SharedResolver>,
+    pub(crate) proxy_header: Option<ProxyHeader>,
+    pub(crate) fallback: FallbackPolicy,
+    pub(crate) limiter: Option<Arc<Limiter>>,
+    pub(crate) observer: Option<SharedObserver>,
+    pub(crate) wire_dump: Option<WireDump>,
+    pub(crate) buffer_pool: Option<Arc<BufferPool>>,
+}
+
+type PrepareFn = dyn Fn(&Socket) -> io::Result<()> + Send + Sync;
+
+/// A user-supplied function preparing the socket before it connects to the proxy server.
+#[derive(Clone)]
+pub(crate) struct PrepareSocket(pub(crate) Arc<PrepareFn>);
+
+impl fmt::Debug for PrepareSocket {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("PrepareSocket")
+    }
+}
+
+/// The `Future` returned by `Socks5Connector::connect_with_stats`.
+type WithStats<'t, P> =
+    Map<Connect<'static, 't, P>, fn(Socks5Stream) -> (Socks5Stream, HandshakeStats)>;
+
+/// The `Future` returned by `Socks5Connector::connect_with_reply`.
+type WithReply<'t, P> = Map<Connect<'static, 't, P>, fn(Socks5Stream) -> (Socks5Stream, Reply)>;
+
+/// A configurable SOCKS5 connector.
+///
+/// `Socks5Stream::connect` and friends use the default configuration. Build a
+/// `Socks5Connector` when you need to tune how the proxy server is reached.
+///
+/// The configuration can't be changed once built and is shared by the clones of the
+/// connector, so cloning it is cheap.
+#[derive(Debug, Clone, Default)]
+pub struct Socks5Connector {
+    config: Arc<Config>,
+}
+
+impl Socks5Connector {
+    /// Creates a connector with the default configuration.
+    pub fn new() -> Socks5Connector {
+        Socks5Connector::default()
+    }
+
+    /// Creates a builder to configure a connector.
+    pub fn builder() -> Socks5ConnectorBuilder {
+        Socks5ConnectorBuilder::default()
+    }
+
+    /// Returns the statistics of the handshake limits of the connector, or `None` if it
+    /// has no limits.
+    pub fn limiter_stats(&self) -> Option<LimiterStats> {
+        self.config.limiter.as_ref().map(|limiter| limiter.stats())
+    }
+
+    /// Connects to a target server through a SOCKS5 proxy.
+    ///
+    /// # Error
+    ///
+    /// It propagates the error that occurs in the conversion from `T` to `TargetAddr`.
+    pub fn connect<'t, P, T>(&self, proxy: P, target: T) -> Result<Connect<'static, 't, P>>
+    where
+        P: ToProxyAddrs,
+        T: IntoTargetAddr<'t>,
+    {
+        self.connect_raw(proxy, target, Authentication::None, Command::Connect)
+    }
+
+    /// Connects to a target server through a SOCKS5 proxy, resolving to the stream along
+    /// with the timings of its handshake.
+    ///
+    /// When the connection is retried, the timings are the ones of the last attempt.
+    ///
+    /// # Error
+    ///
+    /// It propagates the error that occurs in the conversion from `T` to `TargetAddr`.
+    pub fn connect_with_stats<'t, P, T>(&self, proxy: P, target: T) -> Result<WithStats<'t, P>>
+    where
+        P: ToProxyAddrs,
+        T: IntoTargetAddr<'t>,
+    {
+        let with_stats: fn(Socks5Stream) -> (Socks5Stream, HandshakeStats) = |stream| {
+            let stats = stream.handshake_stats();
+            (stream, stats)
+        };
+        Ok(self.connect(proxy, target)?.map(with_stats))
+    }
+
+    /// Connects to a target server through a SOCKS5 proxy, resolving to the stream along
+    /// with the reply of the proxy server, e.g. for diagnostics.
+    ///
+    /// # Error
+    ///
+    /// It propagates the error that occurs in the conversion from `T` to `TargetAddr`.
+    pub fn connect_with_reply<'t, P, T>(&self, proxy: P, target: T) -> Result<WithReply<'t, P>>
+    where
+        P: ToProxyAddrs,
+        T: IntoTargetAddr<'t>,
+    {
+        let with_reply: fn(Socks5Stream) -> (Socks5Stream, Reply) = |stream| {
+            let reply = stream.reply();
+            (stream, reply)
+        };
+        Ok(self.connect(proxy, target)?.map(with_reply))
+    }
+
+    /// Connects to a target server through a SOCKS5 proxy using given username and password.
+    ///
+    /// # Error
+    ///
+    /// It propagates the error that occurs in the conversion from `T` to `TargetAddr`.
+    pub fn connect_with_password<'a, 't, P, T>(
+        &self,
+        proxy: P,
+        target: T,
+        username: &'a str,
+        password: &'a str,
+    ) -> Result<Connect<'a, 't, P>>
+    where
+        P: ToProxyAddrs,
+        T: IntoTargetAddr<'t>,
+    {
+        self.connect_raw(
+            proxy,
+            target,
+            Authentication::Password {
+                username: username.into(),
+                password: password.into(),
+            },
+            Command::Connect,
+        )
+    }
+
+    /// Connects to a target server through the proxy described by `proxy`, authenticating
+    /// with its credentials if it has any.
+    ///
+    /// # Error
+    ///
+    /// It propagates the error that occurs in the conversion from `T` to `TargetAddr`.
+    pub fn connect_with_spec<'a, 't, P, T>(
+        &self,
+        proxy: &'a ProxySpec<P>,
+        target: T,
+    ) -> Result<Connect<'a, 't, &'a P>>
+    where
+        P: ToProxyAddrs,
+        T: IntoTargetAddr<'t>,
+    {
+        self.connect_raw(
+            &proxy.addrs,
+            target,
+            proxy.authentication(),
+            Command::Connect,
+        )
+    }
+
+    /// Connects to a target server through the proxy server reached by `dialer`,
+    /// authenticating as described by `auth`.
+    ///
+    /// See `dialer::Dialer`.
+    ///
+    /// # Error
+    ///
+    /// It propagates the error that occurs in the conversion from `T` to `TargetAddr`, and
+    /// fails if the credentials are invalid.
+    pub fn connect_with_dialer<'a, 't, D, T>(
+        &self,
+        dialer: D,
+        target: T,
+        auth: AuthConfig<'a>,
+    ) -> Result<Handshake<'a, 't, D>>
+    where
+        D: Dialer,
+        T: IntoTargetAddr<'t>,
+    {
+        auth.0.validate()?;
+        let target = target.into_target_addr()?;
+        if let Some(policy) = &self.config.target_policy {
+            policy.check(&target)?;
+        }
+        Ok(Handshake::new(self.config.clone(), &dialer, auth.0, target))
+    }
+
+    /// Connects to a target server through the proxy server at the other end of `stream`,
+    /// authenticating as described by `auth`.
+    ///
+    /// The handshake runs over any `AsyncRead + AsyncWrite`, such as a duplex provided by
+    /// the host environment, so that the protocol implementation is reused where the crate
+    /// can't open connections itself.
+    ///
+    /// # Error
+    ///
+    /// It propagates the error that occurs in the conversion from `T` to `TargetAddr`, and
+    /// fails if the credentials are invalid.
+    pub fn connect_over<'a, 't, S, T>(
+        &self,
+        stream: S,
+        target: T,
+        auth: AuthConfig<'a>,
+    ) -> Result<Handshake<'a, 't, Established<S>>>
+    where
+        S: AsyncRead + AsyncWrite,
+        T: IntoTargetAddr<'t>,
+    {
+        auth.0.validate()?;
+        let target = target.into_target_addr()?;
+        if let Some(policy) = &self.config.target_policy {
+            policy.check(&target)?;
+        }
+        Handshake::established(self.config.clone(), stream, auth.0, target)
+    }
+
+    /// Connects to a target server through a SOCKS5 proxy, authenticating as described by
+    /// `auth`.
+    ///
+    /// # Error
+    ///
+    /// It propagates the error that occurs in the conversion from `T` to `TargetAddr`, and
+    /// fails if the credentials are invalid.
+    pub fn connect_with_auth<'a, 't, P, T>(
+        &self,
+        proxy: P,
+        target: T,
+        auth: AuthConfig<'a>,
+    ) -> Result<Connect<'a, 't, P>>
+    where
+        P: ToProxyAddrs,
+        T: IntoTargetAddr<'t>,
+    {
+        self.connect_raw(proxy, target, auth.0, Command::Connect)
+    }
+
+    /// Initiates a BIND request to the specified proxy.
+    ///
+    /// # Error
+    ///
+    /// It propagates the error that occurs in the conversion from `T` to `TargetAddr`.
+    #[cfg(feature = "bind")]
+    pub fn bind<'t, P, T>(&self, proxy: P, target: T) -> Result<Bind<'static, 't, P>>
+    where
+        P: ToProxyAddrs,
+        T: IntoTargetAddr<'t>,
+    {
+        self.connect_raw(proxy, target, Authentication::None, Command::Bind)
+            .map(Bind)
+    }
+
+    /// Initiates a BIND request to the specified proxy using given username and password.
+    ///
+    /// # Error
+    ///
+    /// It propagates the error that occurs in the conversion from `T` to `TargetAddr`.
+    #[cfg(feature = "bind")]
+    pub fn bind_with_password<'a, 't, P, T>(
+        &self,
+        proxy: P,
+        target: T,
+        username: &'a str,
+        password: &'a str,
+    ) -> Result<Bind<'a, 't, P>>
+    where
+        P: ToProxyAddrs,
+        T: IntoTargetAddr<'t>,
+    {
+        self.connect_raw(
+            proxy,
+            target,
+            Authentication::Password {
+                username: username.into(),
+                password: password.into(),
+            },
+            Command::Bind,
+        )
+        .map(Bind)
+    }
+
+    /// Initiates a BIND request to the proxy described by `proxy`, authenticating
+    /// with its credentials if it has any.
+    ///
+    /// # Error
+    ///
+    /// It propagates the error that occurs in the conversion from `T` to `TargetAddr`.
+    #[cfg(feature = "bind")]
+    pub fn bind_with_spec<'a, 't, P, T>(
+        &self,
+        proxy: &'a ProxySpec<P>,
+        target: T,
+    ) -> Result<Bind<'a, 't, &'a P>>
+    where
+        P: ToProxyAddrs,
+        T: IntoTargetAddr<'t>,
+    {
+        self.connect_raw(&proxy.addrs, target, proxy.authentication(), Command::Bind)
+            .map(Bind)
+    }
+
+    /// Connects to the first of `targets` which accepts the connection through `proxy`,
+    /// resolving to the stream along with the index of that target.
+    ///
+    /// The targets are tried in order unless `ConnectFirstOk::race` is used.
+    ///
+    /// # Error
+    ///
+    /// It propagates the error that occurs in the conversion from `T` to `TargetAddr`.
+    pub fn connect_first_ok<'t, P, I, T>(&self, proxy: P, targets: I) -> Result<ConnectFirstOk<P>>
+    where
+        P: ToProxyAddrs + Clone,
+        I: IntoIterator<Item = T>,
+        T: IntoTargetAddr<'t>,
+    {
+        let targets = targets
+            .into_iter()
+            .map(|target| target.into_target_addr().map(|addr| addr.to_owned()))
+            .collect::<Result<_>>()?;
+        Ok(ConnectFirstOk::new(self.clone(), proxy, targets))
+    }
+
+    /// Connects to a target server through one of the proxies of `group`, failing over to
+    /// the next proxy when one is down.
+    ///
+    /// Every proxy is tried at most once, with the retry policy of the connector. When all
+    /// of them fail, the error of the last one is returned.
+    ///
+    /// # Error
+    ///
+    /// It propagates the error that occurs in the conversion from `T` to `TargetAddr`.
+    pub fn connect_with_group<'a, 't, P, T>(
+        &self,
+        group: &'a ProxyGroup<P>,
+        target: T,
+    ) -> Result<Failover<'a, 't, P>>
+    where
+        P: ToProxyAddrs,
+        T: IntoTargetAddr<'t>,
+    {
+        let target = target.into_target_addr()?;
+        if let Some(policy) = &self.config.target_policy {
+            policy.check(&target)?;
+        }
+        Ok(Failover::new(self.clone(), group, target))
+    }
+
+    /// Connects to a target server through the proxy described by `proxy`, or directly if
+    /// the proxy can't be used and the connector falls back to direct connections (see
+    /// `Socks5ConnectorBuilder::fallback`).
+    ///
+    /// Direct connections use the local address and the socket options of the connector.
+    /// If they fail too, the error of the proxy is returned.
+    ///
+    /// # Error
+    ///
+    /// It propagates the error that occurs in the conversion from `T` to `TargetAddr`.
+    pub fn connect_with_fallback<'a, 't, P, T>(
+        &self,
+        proxy: &'a ProxySpec<P>,
+        target: T,
+    ) -> Result<Fallback<'a, 't, P>>
+    where
+        P: ToProxyAddrs,
+        T: IntoTargetAddr<'t>,
+    {
+        let target = target.into_target_addr()?;
+        let conn = self.connect_with_spec(proxy, target.clone())?;
+        Ok(Fallback::new(self.config.clone(), target, conn))
+    }
+
+    pub(crate) fn connect_raw<'a, 't, P, T>(
+        &self,
+        proxy: P,
+        target: T,
+        auth: Authentication<'a>,
+        command: Command,
+    ) -> Result<Connect<'a, 't, P>>
+    where
+        P: ToProxyAddrs,
+        T: IntoTargetAddr<'t>,
+    {
+        auth.validate()?;
+        let target = target.into_target_addr()?;
+        if let Some(policy) = &self.config.target_policy {
+            policy.check(&target)?;
+        }
+        Ok(Connect::new(
+            self.config.clone(),
+            proxy,
+            target,
+            auth,
+            command,
+        ))
+    }
+}
+
+/// A builder of `Socks5Connector`.
+#[derive(Debug, Clone, Default)]
+pub struct Socks5ConnectorBuilder {
+    config: Config,
+    limits: Limits,
+}
+
+impl Socks5ConnectorBuilder {
+    /// Sets the order in which the resolved addresses of the proxy server are tried.
+    ///
+    /// Defaults to `AddrOrder::Sequential`.
+    pub fn addr_order(mut self, order: AddrOrder) -> Socks5ConnectorBuilder {
+        self.config.addr_order = order;
+        self
+    }
+
+    /// Races connections to the proxy addresses instead of trying them one after another
+    /// (RFC 8305, "Happy Eyeballs").
+    ///
+    /// Once every address is resolved, they are interleaved by address family and a new
+    /// connection attempt is started each time `stagger` elapses without an established
+    /// connection, or as soon as an attempt fails. The first established connection is
+    /// used for the handshake. RFC 8305 recommends a delay of 250 milliseconds.
+    pub fn happy_eyeballs(mut self, stagger: Duration) -> Socks5ConnectorBuilder {
+        self.config.happy_eyeballs = Some(stagger);
+        self
+    }
+
+    /// Retries failed connections according to `policy`.
+    ///
+    /// Each retry resolves the proxy addresses again and restarts the handshake from scratch.
+    pub fn retry_policy(mut self, policy: RetryPolicy) -> Socks5ConnectorBuilder {
+        self.config.retry = Some(policy);
+        self
+    }
+
+    /// Binds the socket connecting to the proxy server to a local address.
+    ///
+    /// This selects the outgoing interface on multi-homed hosts. Use port 0 to let the
+    /// system pick the source port. The address family must match the one of the proxy
+    /// addresses: addresses of another family fail to connect and are skipped.
+    pub fn local_addr(mut self, addr: SocketAddr) -> Socks5ConnectorBuilder {
+        self.config.local_addr = Some(addr);
+        self
+    }
+
+    /// Calls `prepare` with the socket connecting to the proxy server, before it is bound
+    /// (see `local_addr`) and connected.
+    ///
+    /// This gives access to the socket options which aren't supported by the connector, like
+    /// `SO_BINDTODEVICE`, `SO_MARK` or `IP_TOS`. Returning an error fails the connection
+    /// attempt to the current proxy address.
+    pub fn prepare_socket<F>(mut self, prepare: F) -> Socks5ConnectorBuilder
+    where
+        F: Fn(&Socket) -> io::Result<()> + Send + Sync + 'static,
+    {
+        self.config.prepare_socket = Some(PrepareSocket(Arc::new(prepare)));
+        self
+    }
+
+    /// Enables TCP Fast Open on the connection to the proxy server, so that the greeting
+    /// of the handshake is carried by the SYN packet, saving a round trip.
+    ///
+    /// This relies on `TCP_FASTOPEN_CONNECT`, available since Linux 4.11, and on the
+    /// client side of TFO being enabled (`net.ipv4.tcp_fastopen`). It is ignored on other
+    /// platforms. The kernel falls back to a regular handshake if the proxy server
+    /// doesn't support it.
+    pub fn fast_open(mut self, enable: bool) -> Socks5ConnectorBuilder {
+        self.config.fast_open = enable;
+        self
+    }
+
+    /// Sends the request along with the method selection when no authentication is
+    /// offered, saving a round trip and a write per handshake.
+    ///
+    /// RFC 1928 has the client wait for the method selection before sending the request,
+    /// and a few proxy servers discard the data which arrives early, so this is disabled
+    /// by default.
+    pub fn pipeline(mut self, enable: bool) -> Socks5ConnectorBuilder {
+        self.config.pipeline = enable;
+        self
+    }
+
+    /// Sets where the domain names of the targets are resolved. Defaults to
+    /// `DnsPolicy::Remote`.
+    pub fn dns_policy(mut self, policy: DnsPolicy) -> Socks5ConnectorBuilder {
+        self.config.dns_policy = policy;
+        self
+    }
+
+    /// Resolves the targets with `resolver` under `DnsPolicy::Local`, instead of the system
+    /// resolver. With a `ProxyResolver`, no DNS query leaves the proxy servers.
+    pub fn local_resolver<R>(mut self, resolver: R) -> Socks5ConnectorBuilder
+    where
+        R: Resolve + Send + Sync + 'static,
+        R::Future: Send + 'static,
+    {
+        let resolve = move |domain: &str| -> Resolving { Box::new(resolver.resolve(domain)) };
+        self.config.resolver = Some(SharedResolver(Arc::new(resolve)));
+        self
+    }
+
+    /// Checks every target against `policy` before connecting to it, failing with
+    /// `Error::TargetDenied` if it's denied.
+    ///
+    /// See `TargetPolicy`.
+    pub fn target_policy<T>(mut self, policy: T) -> Socks5ConnectorBuilder
+    where
+        T: TargetPolicy + 'static,
+    {
+        self.config.target_policy = Some(SharedPolicy(Arc::new(policy)));
+        self
+    }
+
+    /// Sets how strictly the replies of the proxy server are checked. Defaults to
+    /// `Compliance::Strict`.
+    pub fn compliance(mut self, compliance: Compliance) -> Socks5ConnectorBuilder {
+        self.config.compliance = compliance;
+        self
+    }
+
+    /// Sends `header` to the target right after the proxy server accepted a CONNECT
+    /// request, before the stream is handed over.
+    ///
+    /// The header isn't sent on the connections of BIND and UDP ASSOCIATE requests.
+    pub fn proxy_protocol(mut self, header: ProxyHeader) -> Socks5ConnectorBuilder {
+        self.config.proxy_header = Some(header);
+        self
+    }
+
+    /// Keeps up to `max_buffers` handshake buffers for reuse by the connections of the
+    /// connector and its clones, instead of allocating one per connection attempt.
+    pub fn buffer_pool(mut self, max_buffers: usize) -> Socks5ConnectorBuilder {
+        self.config.buffer_pool = Some(Arc::new(BufferPool::new(max_buffers)));
+        self
+    }
+
+    /// Reports the events of every handshake run through the connector to `observer`.
+    ///
+    /// It can be called several times, and combined with `event_listener`: the events are
+    /// reported to every observer and listener, in the order they were set.
+    pub fn observer<O>(mut self, observer: O) -> Socks5ConnectorBuilder
+    where
+        O: Observer + 'static,
+    {
+        let observer = SharedObserver(Arc::new(observer));
+        self.config.observer = Some(match self.config.observer.take() {
+            Some(previous) => SharedObserver(Arc::new(Chain(previous, observer))),
+            None => observer,
+        });
+        self
+    }
+
+    /// Reports the lifecycle of every connection established through the connector to
+    /// `listener`, from the resolution of the proxy address to the drop of the stream.
+    ///
+    /// The listener receives the events along with the observers set with `observer`.
+    pub fn event_listener<L>(self, listener: L) -> Socks5ConnectorBuilder
+    where
+        L: EventListener + 'static,
+    {
+        self.observer(Listener(listener))
+    }
+
+    /// Passes the bytes exchanged with the proxy server during the handshakes to `dump`,
+    /// to diagnose servers which don't follow the RFC.
+    ///
+    /// Each message sent is passed at once, while the messages received are passed as
+    /// they are read. The username and password are replaced with `*`.
+    pub fn wire_dump<F>(mut self, dump: F) -> Socks5ConnectorBuilder
+    where
+        F: Fn(Direction, &[u8]) + Send + Sync + 'static,
+    {
+        self.config.wire_dump = Some(WireDump(Arc::new(dump)));
+        self
+    }
+
+    /// Sets what `Socks5Connector::connect_with_fallback` does when the proxy server is
+    /// unreachable or rejects the target.
+    ///
+    /// Defaults to `FallbackPolicy::Never`.
+    pub fn fallback(mut self, policy: FallbackPolicy) -> Socks5ConnectorBuilder {
+        self.config.fallback = policy;
+        self
+    }
+
+    /// Sets the options of the TCP connection to the proxy server.
+    ///
+    /// They are applied as soon as the connection is established, before the handshake.
+    pub fn socket_config(mut self, config: SocketConfig) -> Socks5ConnectorBuilder {
+        self.config.socket = config;
+        self
+    }
+
+    /// Limits the number of handshakes running at the same time through the connector and
+    /// its clones. The other handshakes wait for one of them to finish.
+    ///
+    /// A handshake holds its slot until it succeeds or fails, and waits for another one
+    /// before being retried.
+    ///
+    /// # Panics
+    ///
+    /// It panics if `max` is 0.
+    pub fn max_in_flight(mut self, max: usize) -> Socks5ConnectorBuilder {
+        assert!(max > 0, "the in-flight limit must be positive");
+        self.limits.max_in_flight = Some(max);
+        self
+    }
+
+    /// Limits the number of handshakes started per second through the connector and its
+    /// clones, allowing bursts of as many handshakes.
+    ///
+    /// # Panics
+    ///
+    /// It panics if `max` is 0.
+    pub fn max_handshakes_per_second(mut self, max: u32) -> Socks5ConnectorBuilder {
+        assert!(max > 0, "the handshake rate must be positive");
+        self.limits.per_second = Some(max);
+        self
+    }
+
+    /// Creates the configured `Socks5Connector`.
+    pub fn build(mut self) -> Socks5Connector {
+        if !self.limits.is_unlimited() {
+            self.config.limiter = Some(Arc::new(Limiter::new(self.limits)));
+        }
+        Socks5Connector {
+            config: Arc::new(self.config),
+        }
+    }
+}
+
+/// A `Future` which resolves to a socket to the target server through proxy, retrying
+/// according to the `RetryPolicy` of the connector.
+pub struct Connect<'a, 't, P>
+where
+    P: ToProxyAddrs,
+{
+    config: Arc<Config>,
+    proxy: P,
+    target: TargetAddr<'t>,
+    auth: Authentication<'a>,
+    command: Command,
+    failed_attempts: u32,
+    state: ConnectState<'a, 't, P::Output>,
+    permit: Option<Permit>,
+    queued_since: Option<Instant>,
+    queue_time: Duration,
+    limit_timer: Option<Delay>,
+    deadline: Option<Delay>,
+}
+
+enum ConnectState<'a, 't, S>
+where
+    S: Stream<Item = SocketAddr, Error = Error>,
+{
+    Connecting(Box<ConnectFuture<'a, 't, S>>),
+    Racing(TargetRace<'a, 't, S>),
+    Resolving(Resolving),
+    Waiting(Option<Delay>),
+}
+
+impl<'a, 't, P> Connect<'a, 't, P>
+where
+    P: ToProxyAddrs,
+{
+    fn new(
+        config: Arc<Config>,
+        proxy: P,
+        target: TargetAddr<'t>,
+        auth: Authentication<'a>,
+        command: Command,
+    ) -> Connect<'a, 't, P> {
+        let mut conn = Connect {
+            config,
+            proxy,
+            target,
+            auth,
+            command,
+            failed_attempts: 0,
+            state: ConnectState::Waiting(None),
+            permit: None,
+            queued_since: None,
+            queue_time: Duration::from_secs(0),
+            limit_timer: None,
+            deadline: None,
+        };
+        // The target is only resolved locally once polled.
+        if conn.config.limiter.is_none() && conn.local_domain().is_none() {
+            conn.state = ConnectState::Connecting(Box::new(conn.attempt()));
+        }
+        conn
+    }
+
+    /// Returns a `Future` establishing a new connection to the same target through the
+    /// same proxy, with the same credentials.
+    pub(crate) fn restart(&self) -> Connect<'static, 'static, P>
+    where
+        P: Clone,
+    {
+        Connect::new(
+            self.config.clone(),
+            self.proxy.clone(),
+            self.target.to_owned(),
+            self.auth.to_owned(),
+            self.command,
+        )
+    }
+
+    /// Turns the connection into a stream which reconnects to the same target with the
+    /// same credentials whenever it fails, waiting between the reconnections according to
+    /// `policy`.
+    ///
+    /// See `ReconnectingStream`.
+    pub fn reconnecting(self, policy: RetryPolicy) -> ReconnectingStream<P>
+    where
+        P: Clone,
+    {
+        ReconnectingStream::new(self.restart(), policy)
+    }
+
+    fn attempt(&self) -> ConnectFuture<'a, 't, P::Output> {
+        self.attempt_to(self.target.clone())
+    }
+
+    fn attempt_to(&self, target: TargetAddr<'t>) -> ConnectFuture<'a, 't, P::Output> {
+        ConnectFuture::new(
+            self.config.clone(),
+            self.auth.clone(),
+            self.command,
+            self.proxy.to_proxy_addrs(),
+            target,
+        )
+    }
+
+    /// Waits before the next attempt according to the retry policy, or returns `e` if the
+    /// failed attempt isn't retried.
+    fn failed(&mut self, e: Error) -> Result<()> {
+        self.permit = None;
+        self.failed_attempts += 1;
+        let delay = match &self.config.retry {
+            Some(policy) => policy.next_delay(self.failed_attempts, &e),
+            None => None,
+        };
+        // A retry past the deadline would only fail with a timeout.
+        let delay = delay.filter(|&delay| match &self.deadline {
+            Some(deadline) => Instant::now() + delay < deadline.deadline(),
+            None => true,
+        });
+        match delay {
+            Some(delay) => {
+                self.state = ConnectState::Waiting(Some(Delay::new(Instant::now() + delay)));
+                Ok(())
+            }
+            None => Err(e),
+        }
+    }
+
+    /// Returns the domain and the port of the target if it's resolved locally.
+    ///
+    /// The domain of a RESOLVE request is the one to resolve through the proxy server.
+    fn local_domain(&self) -> Option<(&str, u16)> {
+        match (self.config.dns_policy, &self.target, self.command) {
+            (_, _, Command::Resolve) => None,
+            (DnsPolicy::Local, TargetAddr::Domain(domain, port), _) => Some((&**domain, *port)),
+            _ => None,
+        }
+    }
+
+    /// Starts a new attempt, resolving the target first if it's resolved locally.
+    fn start(&self) -> ConnectState<'a, 't, P::Output> {
+        let (domain, port) = match self.local_domain() {
+            Some(target) => target,
+            None => return ConnectState::Connecting(Box::new(self.attempt())),
+        };
+        if let Some(resolver) = &self.config.resolver {
+            return ConnectState::Resolving((resolver.0)(domain));
+        }
+        let lookup = dial::lookup(domain, Some(port))
+            .map(|addrs| addrs.into_iter().map(|addr| addr.ip()).collect())
+            .map_err(Error::from);
+        ConnectState::Resolving(Box::new(lookup))
+    }
+
+    /// Races the requests to the resolved addresses of the target.
+    fn race(&self, mut addrs: Vec<SocketAddr>) -> Result<ConnectState<'a, 't, P::Output>> {
+        if addrs.is_empty() {
+            let msg = format!("no address resolved for {}", self.target);
+            return Err(Error::Io(io::Error::new(io::ErrorKind::NotFound, msg)));
+        }
+        if let Some(policy) = &self.config.target_policy {
+            addrs.retain(|addr| policy.0.allows_resolved(*addr));
+            if addrs.is_empty() {
+                return Err(Error::TargetDenied(self.target.to_owned()));
+            }
+        }
+        debug_event!(
+            target = %self.target,
+            count = addrs.len(),
+            "resolved target addresses"
+        );
+        let attempts = dial::interleave(addrs)
+            .into_iter()
+            .map(|addr| self.attempt_to(TargetAddr::Ip(addr)))
+            .collect();
+        let stagger = self
+            .config
+            .happy_eyeballs
+            .unwrap_or_else(|| Duration::from_millis(250));
+        Ok(ConnectState::Racing(TargetRace::new(attempts, stagger)))
+    }
+
+    /// Fails the connection with `Error::Timeout` if it isn't established by `deadline`,
+    /// including the waits for the handshake limits, the retries, and the resolution of
+    /// the proxy and the target addresses.
+    ///
+    /// A retry which would start past the deadline isn't made, and the error of the last
+    /// attempt is returned instead.
+    pub fn deadline(mut self, deadline: Instant) -> Self {
+        self.deadline = Some(Delay::new(deadline));
+        self
+    }
+
+    /// Returns the future along with an `AbortHandle` to cancel the connection, including
+    /// its pending retries.
+    ///
+    /// See `abort::abortable`.
+    pub fn abortable(self) -> (Abortable<Self>, AbortHandle) {
+        abort::abortable(self)
+    }
+
+    /// Returns how long this connection waited for the handshake limits of the connector.
+    pub fn queue_time(&self) -> Duration {
+        match self.queued_since {
+            Some(queued_since) => self.queue_time + queued_since.elapsed(),
+            None => self.queue_time,
+        }
+    }
+}
+
+impl<'a, 't, P> Future for Connect<'a, 't, P>
+where
+    P: ToProxyAddrs,
+{
+    type Item = Socks5Stream;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Socks5Stream, Error> {
+        if let Some(deadline) = &mut self.deadline {
+            let expired = deadline.poll().map_err(io::Error::other)?;
+            if expired.is_ready() {
+                debug_event!("deadline expired");
+                Err(Error::Timeout)?
+            }
+        }
+        loop {
+            match &mut self.state {
+                ConnectState::Connecting(_) | ConnectState::Racing(_) => {
+                    let result = match &mut self.state {
+                        ConnectState::Connecting(conn) => conn.poll(),
+                        ConnectState::Racing(race) => race.poll(),
+                        ConnectState::Resolving(_) | ConnectState::Waiting(_) => unreachable!(),
+                    };
+                    match result {
+                        Ok(Async::NotReady) => return Ok(Async::NotReady),
+                        Ok(Async::Ready(stream)) => {
+                            self.permit = None;
+                            return Ok(Async::Ready(stream));
+                        }
+                        Err(e) => self.failed(e)?,
+                    }
+                }
+                ConnectState::Resolving(resolving) => {
+                    let started = match resolving.poll() {
+                        Ok(Async::NotReady) => return Ok(Async::NotReady),
+                        Ok(Async::Ready(ips)) => {
+                            let port = self.local_domain().unwrap().1;
+                            self.race(ips.into_iter().map(|ip| (ip, port).into()).collect())
+                        }
+                        Err(e) => Err(e),
+                    };
+                    match started {
+                        Ok(state) => self.state = state,
+                        Err(e) => self.failed(e)?,
+                    }
+                }
+                ConnectState::Waiting(delay) => {
+                    if let Some(delay) = delay {
+                        try_ready!(delay.poll().map_err(io::Error::other));
+                    }
+                    if let Some(limiter) = &self.config.limiter {
+                        let queued_since = *self.queued_since.get_or_insert_with(Instant::now);
+                        let permit = try_ready!(Limiter::poll_acquire(
+                            limiter,
+                            queued_since,
+                            &mut self.limit_timer
+                        ));
+                        self.queue_time += queued_since.elapsed();
+                        self.queued_since = None;
+                        self.permit = Some(permit);
+                    }
+                    self.state = self.start();
+                }
+            }
+        }
+    }
+}
+
+/// A `Future` racing requests to the addresses of a locally resolved target through the
+/// proxy, like `dial::HappyEyeballs` does with the connections to the proxy addresses.
+///
+/// The attempts are interleaved by address family. A new attempt is started each time the
+/// stagger delay elapses or an attempt fails, and the first established connection wins.
+struct TargetRace<'a, 't, S>
+where
+    S: Stream<Item = SocketAddr, Error = Error>,
+{
+    queued: VecDeque<ConnectFuture<'a, 't, S>>,
+    attempts: Vec<ConnectFuture<'a, 't, S>>,
+    stagger: Duration,
+    timer: Option<Delay>,
+    last_error: Option<Error>,
+}
+
+impl<'a, 't, S> TargetRace<'a, 't, S>
+where
+    S: Stream<Item = SocketAddr, Error = Error>,
+{
+    fn new(queued: VecDeque<ConnectFuture<'a, 't, S>>, stagger: Duration) -> Self {
+        TargetRace {
+            queued,
+            attempts: Vec::new(),
+            stagger,
+            timer: None,
+            last_error: None,
+        }
+    }
+}
+
+impl<'a, 't, S> Future for TargetRace<'a, 't, S>
+where
+    S: Stream<Item = SocketAddr, Error = Error>,
+{
+    type Item = Socks5Stream;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Socks5Stream, Error> {
+        loop {
+            let mut i = 0;
+            while i < self.attempts.len() {
+                match self.attempts[i].poll() {
+                    Ok(Async::Ready(stream)) => return Ok(Async::Ready(stream)),
+                    Ok(Async::NotReady) => i += 1,
+                    Err(e) => {
+                        self.attempts.swap_remove(i);
+                        self.last_error = Some(e);
+                        // Don't wait for the stagger delay after a failure
+                        self.timer = None;
+                    }
+                }
+            }
+            let due = match &mut self.timer {
+                Some(timer) => timer.poll().map_err(io::Error::other)?.is_ready(),
+                None => true,
+            };
+            if !due {
+                return Ok(Async::NotReady);
+            }
+            match self.queued.pop_front() {
+                Some(attempt) => {
+                    self.attempts.push(attempt);
+                    self.timer = Some(Delay::new(Instant::now() + self.stagger));
+                }
+                None if self.attempts.is_empty() => {
+                    return Err(self
+                        .last_error
+                        .take()
+                        .unwrap_or(Error::InvalidTargetAddress("no address resolved")))
+                }
+                None => return Ok(Async::NotReady),
+            }
+        }
+    }
+}
+
+/// A `Future` which resolves to a `Socks5Listener`, retrying according to the
+/// `RetryPolicy` of the connector.
+#[cfg(feature = "bind")]
+pub struct Bind<'a, 't, P>(Connect<'a, 't, P>)
+where
+    P: ToProxyAddrs;
+
+#[cfg(feature = "bind")]
+impl<'a, 't, P> Future for Bind<'a, 't, P>
+where
+    P: ToProxyAddrs,
+{
+    type Item = Socks5Listener;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Socks5Listener, Error> {
+        let inner = try_ready!(self.0.poll());
+        Ok(Async::Ready(Socks5Listener {
+            inner,
+            config: self.0.config.clone(),
+            auth: self.0.auth.to_owned(),
+            target: self.0.target.to_owned(),
+            peer_verification: PeerVerification::default(),
+            keepalive: None,
+        }))
+    }
+}
+
+/// A stream of proxy addresses reordered according to an `AddrOrder`.
+///
+/// Except for `AddrOrder::Sequential`, all the addresses have to be resolved before
+/// the first one is yielded.
+pub(crate) struct OrderedAddrs<S> {
+    inner: S,
+    order: AddrOrder,
+    resolved: Vec<SocketAddr>,
+    ordered: Option<vec::IntoIter<SocketAddr>>,
+}
+
+impl<S> OrderedAddrs<S>
+where
+    S: Stream<Item = SocketAddr, Error = Error>,
+{
+    pub(crate) fn new(inner: S, order: AddrOrder) -> OrderedAddrs<S> {
+        OrderedAddrs {
+            inner,
+            order,
+            resolved: Vec::new(),
+            ordered: None,
+        }
+    }
+}
+
+impl<S> Stream for OrderedAddrs<S>
+where
+    S: Stream<Item = SocketAddr, Error = Error>,
+{
+    type Item = SocketAddr;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<SocketAddr>, Error> {
+        if self.order == AddrOrder::Sequential {
+            return self.inner.poll();
+        }
+        loop {
+            if let Some(ordered) = &mut self.ordered {
+                return Ok(Async::Ready(ordered.next()));
+            }
+            match try_ready!(self.inner.poll()) {
+                Some(addr) => self.resolved.push(addr),
+                None => {
+                    let mut addrs = std::mem::take(&mut self.resolved);
+                    match self.order {
+                        AddrOrder::Sequential => {}
+                        AddrOrder::Random => addrs.shuffle(&mut rand::thread_rng()),
+                        AddrOrder::PreferIpv6 => addrs.sort_by_key(|addr| !addr.is_ipv6()),
+                        AddrOrder::PreferIpv4 => addrs.sort_by_key(|addr| !addr.is_ipv4()),
+                    }
+                    self.ordered = Some(addrs.into_iter());
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        consts::{REPLY_CONNECTION_REFUSED, REPLY_HOST_UNREACHABLE},
+        resolve::ProxyResolver,
+        ProxyAddrsStream, ReplyKind,
+    };
+    use futures::{future, stream};
+    use std::net::IpAddr;
+
+    fn ordered(addrs: &[SocketAddr], order: AddrOrder) -> Vec<SocketAddr> {
+        OrderedAddrs::new(stream::iter_ok(addrs.to_vec()), order)
+            .wait()
+            .collect::<Result<_>>()
+            .unwrap()
+    }
+
+    fn addrs() -> Vec<SocketAddr> {
+        vec![
+            "1.1.1.1:1080".parse().unwrap(),
+            "[::1]:1080".parse().unwrap(),
+            "8.8.8.8:1080".parse().unwrap(),
+            "[::2]:1080".parse().unwrap(),
+        ]
+    }
+
+    #[test]
+    fn backs_off_exponentially() {
+        let policy = RetryPolicy::new(5)
+            .backoff(Duration::from_millis(100), Duration::from_millis(300))
+            .jitter(false);
+        let err = Error::ProxyReply {
+            code: 0x06,
+            kind: ReplyKind::TtlExpired,
+        };
+        assert_eq!(policy.next_delay(1, &err), Some(Duration::from_millis(100)));
+        assert_eq!(policy.next_delay(2, &err), Some(Duration::from_millis(200)));
+        assert_eq!(policy.next_delay(3, &err), Some(Duration::from_millis(300)));
+        assert_eq!(policy.next_delay(4, &err), Some(Duration::from_millis(300)));
+        assert_eq!(policy.next_delay(5, &err), None);
+    }
+
+    #[test]
+    fn jitters_backoff() {
+        let policy =
+            RetryPolicy::new(5).backoff(Duration::from_millis(100), Duration::from_secs(1));
+        for _ in 0..100 {
+            let delay = policy
+                .next_delay(2, &Error::ProxyServerUnreachable)
+                .unwrap();
+            assert!(delay >= Duration::from_millis(100) && delay <= Duration::from_millis(200));
+        }
+    }
+
+    #[test]
+    fn retries_transient_errors_only() {
+        let policy = RetryPolicy::new(5);
+        assert!(policy
+            .next_delay(1, &Error::ProxyServerUnreachable)
+            .is_some());
+        let err = Error::ProxyReply {
+            code: 0x02,
+            kind: ReplyKind::ConnectionNotAllowed,
+        };
+        assert!(policy.next_delay(1, &err).is_none());
+        assert!(policy
+            .next_delay(1, &Error::PasswordAuthFailure(1))
+            .is_none());
+    }
+
+    #[test]
+    fn keeps_sequential_order() {
+        assert_eq!(ordered(&addrs(), AddrOrder::Sequential), addrs());
+    }
+
+    #[test]
+    fn prefers_ipv6_addrs() {
+        let addrs = addrs();
+        let res = ordered(&addrs, AddrOrder::PreferIpv6);
+        assert_eq!(res, vec![addrs[1], addrs[3], addrs[0], addrs[2]]);
+    }
+
+    #[test]
+    fn prefers_ipv4_addrs() {
+        let addrs = addrs();
+        let res = ordered(&addrs, AddrOrder::PreferIpv4);
+        assert_eq!(res, vec![addrs[0], addrs[2], addrs[1], addrs[3]]);
+    }
+
+    #[test]
+    fn futures_are_send() {
+        fn assert_send<T: Send + 'static>() {}
+        assert_send::<Connect<'static, 'static, String>>();
+        #[cfg(feature = "bind")]
+        assert_send::<Bind<'static, 'static, String>>();
+        assert_send::<ConnectFuture<'static, 'static, ProxyAddrsStream>>();
+        assert_send::<Socks5Stream>();
+        #[cfg(feature = "bind")]
+        assert_send::<Socks5Listener>();
+        assert_send::<Socks5Connector>();
+    }
+
+    #[test]
+    fn shuffles_all_addrs() {
+        let mut res = ordered(&addrs(), AddrOrder::Random);
+        let mut expected = addrs();
+        res.sort();
+        expected.sort();
+        assert_eq!(res, expected);
+    }
+
+    #[test]
+    fn times_out_at_the_deadline() {
+        // The handshake stalls, as the listener never answers.
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let conn = Socks5Connector::new()
+            .connect(listener.local_addr().unwrap(), "example.com:80")
+            .unwrap()
+            .deadline(Instant::now() + Duration::from_millis(50));
+        match tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(conn)
+        {
+            Err(Error::Timeout) => {}
+            _ => panic!("expected a timeout"),
+        }
+    }
+
+    #[test]
+    fn queues_the_handshakes_over_the_limit() {
+        // The handshakes stall, as the listener never answers.
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let proxy = listener.local_addr().unwrap();
+        let connector = Socks5Connector::builder().max_in_flight(1).build();
+        let mut first = connector.connect(proxy, "example.com:80").unwrap();
+        let mut second = connector
+            .connect(proxy, "example.com:80")
+            .unwrap()
+            .deadline(Instant::now() + Duration::from_millis(50));
+        let mut runtime = tokio::runtime::current_thread::Runtime::new().unwrap();
+        runtime
+            .block_on(future::lazy(|| first.poll().map(|_| ())))
+            .unwrap();
+        match runtime.block_on(&mut second) {
+            Err(Error::Timeout) => {}
+            _ => panic!("expected a timeout"),
+        }
+        assert!(second.queue_time() >= Duration::from_millis(40));
+        let stats = connector.limiter_stats().unwrap();
+        assert_eq!((stats.in_flight, stats.started), (1, 1));
+    }
+
+    #[test]
+    #[should_panic]
+    fn rejects_a_zero_in_flight_limit() {
+        Socks5Connector::builder().max_in_flight(0);
+    }
+
+    #[test]
+    #[should_panic]
+    fn rejects_a_zero_handshake_rate() {
+        Socks5Connector::builder().max_handshakes_per_second(0);
+    }
+
+    #[test]
+    fn pipelines_the_request() {
+        use std::io::{Read, Write};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let proxy = listener.local_addr().unwrap();
+        // The server only replies once it received both the method selection and the
+        // request, which a handshake waiting for the method selection never sends.
+        let server = std::thread::spawn(move || {
+            let (mut conn, _) = listener.accept().unwrap();
+            conn.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+            let mut received = [0; 13];
+            conn.read_exact(&mut received).unwrap();
+            conn.write_all(&[0x05, 0x00, 0x05, 0x00, 0x00, 0x01, 10, 0, 0, 1, 0, 80])
+                .unwrap();
+            received
+        });
+        let conn = Socks5Connector::builder()
+            .pipeline(true)
+            .build()
+            .connect(proxy, "10.0.0.1:80")
+            .unwrap();
+        let stream = tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(conn)
+            .unwrap();
+        assert_eq!(stream.target_addr().to_string(), "10.0.0.1:80");
+        let received = server.join().unwrap();
+        assert_eq!(received, [5, 1, 0, 5, 1, 0, 1, 10, 0, 0, 1, 0, 80]);
+    }
+
+    #[test]
+    fn resolves_domains_locally_by_policy() {
+        let local = Socks5Connector::builder()
+            .dns_policy(DnsPolicy::Local)
+            .build();
+        let conn = local.connect("127.0.0.1:1080", "example.com:80").unwrap();
+        assert_eq!(conn.local_domain(), Some(("example.com", 80)));
+        let conn = local.connect("127.0.0.1:1080", "127.0.0.1:80").unwrap();
+        assert_eq!(conn.local_domain(), None);
+        let remote = Socks5Connector::new();
+        let conn = remote.connect("127.0.0.1:1080", "example.com:80").unwrap();
+        assert_eq!(conn.local_domain(), None);
+    }
+
+    /// A proxy server connecting to 10.0.0.1:80, which replies with `reply`.
+    fn replay(reply: u8) -> crate::trace::Replay {
+        let trace = format!(
+            "
+            > 05 01 00
+            < 05 00
+            > 05 01 00 01 0a 00 00 01 00 50
+            < 05 {:02x} 00 01 0a 00 00 01 00 50
+            ",
+            reply
+        );
+        crate::trace::Replay::bind(trace.parse().unwrap()).unwrap()
+    }
+
+    fn race(proxies: &[SocketAddr], stagger: Duration) -> Result<Socks5Stream> {
+        let connector = Socks5Connector::new();
+        let attempts = proxies
+            .iter()
+            .map(|&proxy| connector.connect(proxy, "10.0.0.1:80").unwrap().attempt())
+            .collect();
+        tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(TargetRace::new(attempts, stagger))
+    }
+
+    #[test]
+    fn staggers_the_target_attempts() {
+        // The first proxy server never answers.
+        let stalled = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let replay = replay(0);
+        let proxies = [stalled.local_addr().unwrap(), replay.local_addr()];
+        let start = Instant::now();
+        let stream = race(&proxies, Duration::from_millis(100)).unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        assert_eq!(stream.get_ref().peer_addr().unwrap(), replay.local_addr());
+    }
+
+    #[test]
+    fn starts_the_next_target_attempt_on_failure() {
+        let refused = replay(REPLY_CONNECTION_REFUSED);
+        let replay = replay(0);
+        let proxies = [refused.local_addr(), replay.local_addr()];
+        let start = Instant::now();
+        let stream = race(&proxies, Duration::from_secs(10)).unwrap();
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert_eq!(stream.get_ref().peer_addr().unwrap(), replay.local_addr());
+    }
+
+    #[test]
+    fn fails_with_the_last_target_error() {
+        let unreachable = replay(REPLY_HOST_UNREACHABLE);
+        let refused = replay(REPLY_CONNECTION_REFUSED);
+        let proxies = [unreachable.local_addr(), refused.local_addr()];
+        match race(&proxies, Duration::from_secs(10)) {
+            Err(e) => assert_eq!(e.reply_kind(), Some(ReplyKind::ConnectionRefused)),
+            Ok(_) => panic!("expected the error of the last attempt"),
+        }
+    }
+
+    #[test]
+    fn resolves_targets_locally_off_the_event_loop() {
+        let trace = "
+            > 05 01 00
+            < 05 00
+            > 05 01 00 01 7f 00 00 01 00 50
+            < 05 00 00 01 7f 00 00 01 00 50
+        ";
+        let replay = crate::trace::Replay::bind(trace.parse().unwrap()).unwrap();
+        let conn = Socks5Connector::builder()
+            .dns_policy(DnsPolicy::Local)
+            .build()
+            .connect(replay.local_addr(), "localhost:80")
+            .unwrap();
+        let stream = tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(conn)
+            .unwrap();
+        assert_eq!(
+            stream.target_addr(),
+            &TargetAddr::Ip(SocketAddr::from(([127, 0, 0, 1], 80)))
+        );
+    }
+
+    #[test]
+    fn resolves_targets_with_the_local_resolver() {
+        let resolver = crate::trace::Replay::bind(
+            "
+            > 05 01 00
+            < 05 00
+            > 05 f0 00 03 0b 65 78 61 6d 70 6c 65 2e 63 6f 6d 00 00
+            < 05 00 00 01 0a 00 00 01 00 00
+            "
+            .parse()
+            .unwrap(),
+        )
+        .unwrap();
+        let proxy = replay(0);
+        let conn = Socks5Connector::builder()
+            .dns_policy(DnsPolicy::Local)
+            .local_resolver(ProxyResolver::new(
+                Socks5Connector::new(),
+                resolver.local_addr(),
+            ))
+            .build()
+            .connect(proxy.local_addr(), "example.com:80")
+            .unwrap();
+        let stream = tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(conn)
+            .unwrap();
+        assert_eq!(
+            stream.target_addr(),
+            &TargetAddr::Ip(SocketAddr::from(([10, 0, 0, 1], 80)))
+        );
+        assert_eq!(resolver.mismatches() + proxy.mismatches(), 0);
+    }
+
+    #[test]
+    fn resolves_targets_with_any_resolver() {
+        struct Hosts;
+
+        impl Resolve for Hosts {
+            type Future = future::FutureResult<Vec<IpAddr>, Error>;
+
+            fn resolve(&self, domain: &str) -> Self::Future {
+                assert_eq!(domain, "example.com");
+                future::ok(vec![IpAddr::from([10, 0, 0, 1])])
+            }
+        }
+
+        let proxy = replay(0);
+        let conn = Socks5Connector::builder()
+            .dns_policy(DnsPolicy::Local)
+            .local_resolver(Hosts)
+            .build()
+            .connect(proxy.local_addr(), "example.com:80")
+            .unwrap();
+        tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(conn)
+            .unwrap();
+        assert_eq!(proxy.mismatches(), 0);
+    }
+}
@@ -0,0 +1,61 @@
+//! The values of the fields of the SOCKS5 protocol, as defined in RFC 1928 and RFC 1929.
+//!
+//! The reply codes are also available as the `ReplyKind` enum, which can be displayed and
+//! converted from and to the raw code.
+
+pub use crate::error::ReplyKind;
+
+/// The version of the SOCKS protocol.
+pub const SOCKS5_VERSION: u8 = 0x05;
+/// The version of the username/password authentication subnegotiation.
+pub const PASSWORD_AUTH_VERSION: u8 = 0x01;
+
+/// The authentication method requiring no authentication.
+pub const METHOD_NO_AUTH: u8 = 0x00;
+/// The GSSAPI authentication method.
+pub const METHOD_GSSAPI: u8 = 0x01;
+/// The username/password authentication method.
+pub const METHOD_PASSWORD: u8 = 0x02;
+/// The method selected by the server when none of the methods of the client is acceptable.
+pub const METHOD_NO_ACCEPTABLE: u8 = 0xff;
+
+/// The status of a successful username/password authentication.
+pub const PASSWORD_AUTH_SUCCEEDED: u8 = 0x00;
+
+/// The CONNECT command.
+pub const CMD_CONNECT: u8 = 0x01;
+/// The BIND command.
+pub const CMD_BIND: u8 = 0x02;
+/// The UDP ASSOCIATE command.
+pub const CMD_UDP_ASSOCIATE: u8 = 0x03;
+/// The RESOLVE command, an extension of Tor.
+pub const CMD_TOR_RESOLVE: u8 = 0xf0;
+
+/// The type of an IPv4 address.
+pub const ATYP_IPV4: u8 = 0x01;
+/// The type of a domain name.
+pub const ATYP_DOMAIN: u8 = 0x03;
+/// The type of an IPv6 address.
+pub const ATYP_IPV6: u8 = 0x04;
+
+/// The value of the reserved fields.
+pub const RESERVED: u8 = 0x00;
+
+/// Succeeded
+pub const REPLY_SUCCEEDED: u8 = 0x00;
+/// General SOCKS server failure
+pub const REPLY_GENERAL_FAILURE: u8 = 0x01;
+/// Connection not allowed by ruleset
+pub const REPLY_CONNECTION_NOT_ALLOWED: u8 = 0x02;
+/// Network unreachable
+pub const REPLY_NETWORK_UNREACHABLE: u8 = 0x03;
+/// Host unreachable
+pub const REPLY_HOST_UNREACHABLE: u8 = 0x04;
+/// Connection refused
+pub const REPLY_CONNECTION_REFUSED: u8 = 0x05;
+/// TTL expired
+pub const REPLY_TTL_EXPIRED: u8 = 0x06;
+/// Command not supported
+pub const REPLY_COMMAND_NOT_SUPPORTED: u8 = 0x07;
+/// Address type not supported
+pub const REPLY_ADDRESS_TYPE_NOT_SUPPORTED: u8 = 0x08;
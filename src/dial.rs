@@ -0,0 +1,309 @@
+use crate::{
+    connector::{Config, Keepalive, PrepareSocket, SocketConfig},
+    Error,
+};
+use futures::{sync::oneshot, Async, Future, Poll};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::{
+    collections::VecDeque,
+    io,
+    net::{SocketAddr, ToSocketAddrs},
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+use tokio_reactor::Handle;
+use tokio_tcp::{ConnectFuture as TokioConnect, TcpStream};
+use tokio_timer::Delay;
+
+/// Starts a TCP connection to a proxy server. The socket is handed to the preparation
+/// hook of `config` and bound to its local address first, if any.
+pub(crate) fn connect(addr: &SocketAddr, config: &Config) -> TcpConnect {
+    if config.local_addr.is_none() && config.prepare_socket.is_none() && !config.fast_open {
+        return TcpConnect::Connecting(TcpStream::connect(addr));
+    }
+    let socket = (|| -> io::Result<Socket> {
+        let socket = Socket::new(domain(addr), Type::stream(), Some(Protocol::tcp()))?;
+        if config.fast_open {
+            set_fast_open(&socket)?;
+        }
+        if let Some(PrepareSocket(prepare)) = &config.prepare_socket {
+            prepare(&socket)?;
+        }
+        if let Some(local_addr) = config.local_addr {
+            if local_addr.port() != 0 {
+                socket.set_reuse_address(true)?;
+            }
+            socket.bind(&local_addr.into())?;
+        }
+        Ok(socket)
+    })();
+    match socket {
+        Ok(socket) => TcpConnect::Connecting(TcpStream::connect_std(
+            socket.into_tcp_stream(),
+            addr,
+            &Handle::default(),
+        )),
+        Err(e) => TcpConnect::Failed(Some(e)),
+    }
+}
+
+/// Applies the socket options of `config` to an established connection.
+pub(crate) fn configure(tcp: &TcpStream, config: &SocketConfig) -> io::Result<()> {
+    if let Some(nodelay) = config.nodelay {
+        tcp.set_nodelay(nodelay)?;
+    }
+    if let Some(keepalive) = &config.keepalive {
+        set_keepalive(tcp, keepalive)?;
+    }
+    if let Some(ttl) = config.ttl {
+        tcp.set_ttl(ttl)?;
+    }
+    if let Some(linger) = config.linger {
+        tcp.set_linger(Some(linger))?;
+    }
+    Ok(())
+}
+
+/// Enables TCP keepalive on `tcp` with the parameters of `keepalive`.
+pub(crate) fn set_keepalive(tcp: &TcpStream, keepalive: &Keepalive) -> io::Result<()> {
+    tcp.set_keepalive(Some(keepalive.time))?;
+    set_keepalive_params(tcp, keepalive)
+}
+
+/// Makes `connect` return immediately and send the first written data in the SYN packet.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn set_fast_open(socket: &Socket) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    setsockopt(socket.as_raw_fd(), libc::TCP_FASTOPEN_CONNECT, 1)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn set_fast_open(_socket: &Socket) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn set_keepalive_params(tcp: &TcpStream, keepalive: &Keepalive) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let fd = tcp.as_raw_fd();
+    if let Some(interval) = keepalive.interval {
+        setsockopt(fd, libc::TCP_KEEPINTVL, interval.as_secs() as libc::c_int)?;
+    }
+    if let Some(retries) = keepalive.retries {
+        setsockopt(fd, libc::TCP_KEEPCNT, retries as libc::c_int)?;
+    }
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn set_keepalive_params(_tcp: &TcpStream, _keepalive: &Keepalive) -> io::Result<()> {
+    Ok(())
+}
+
+/// Sets an `IPPROTO_TCP` level socket option.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn setsockopt(
+    fd: std::os::unix::io::RawFd,
+    name: libc::c_int,
+    value: libc::c_int,
+) -> io::Result<()> {
+    let ret = unsafe {
+        libc::setsockopt(
+            fd,
+            libc::IPPROTO_TCP,
+            name,
+            &value as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if ret == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+pub(crate) fn domain(addr: &SocketAddr) -> Domain {
+    match addr {
+        SocketAddr::V4(_) => Domain::ipv4(),
+        SocketAddr::V6(_) => Domain::ipv6(),
+    }
+}
+
+/// A `Future` which resolves to a TCP connection to a proxy server.
+#[derive(Debug)]
+pub(crate) enum TcpConnect {
+    Connecting(TokioConnect),
+    /// The socket couldn't be set up.
+    Failed(Option<io::Error>),
+}
+
+impl Future for TcpConnect {
+    type Item = TcpStream;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<TcpStream, io::Error> {
+        match self {
+            TcpConnect::Connecting(conn) => conn.poll(),
+            TcpConnect::Failed(e) => Err(e.take().expect("polled TcpConnect after completion")),
+        }
+    }
+}
+
+/// A `Future` racing connections to several proxy addresses (RFC 8305, "Happy Eyeballs").
+///
+/// The addresses are interleaved by address family. A new attempt is started each time
+/// the stagger delay elapses or an attempt fails, and the first established connection
+/// wins, along with the address it is connected to. Pending attempts are dropped then.
+#[derive(Debug)]
+pub(crate) struct HappyEyeballs {
+    addrs: VecDeque<SocketAddr>,
+    attempts: Vec<(SocketAddr, TcpConnect)>,
+    config: Arc<Config>,
+    stagger: Duration,
+    timer: Option<Delay>,
+    last_error: Option<io::Error>,
+}
+
+impl HappyEyeballs {
+    pub(crate) fn new(
+        addrs: Vec<SocketAddr>,
+        stagger: Duration,
+        config: Arc<Config>,
+    ) -> HappyEyeballs {
+        HappyEyeballs {
+            addrs: interleave(addrs),
+            attempts: Vec::new(),
+            config,
+            stagger,
+            timer: None,
+            last_error: None,
+        }
+    }
+}
+
+impl Future for HappyEyeballs {
+    type Item = (TcpStream, SocketAddr);
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<(TcpStream, SocketAddr), Error> {
+        loop {
+            let mut i = 0;
+            while i < self.attempts.len() {
+                let (addr, attempt) = &mut self.attempts[i];
+                match attempt.poll() {
+                    Ok(Async::Ready(tcp)) => return Ok(Async::Ready((tcp, *addr))),
+                    Ok(Async::NotReady) => i += 1,
+                    Err(e) => {
+                        self.attempts.swap_remove(i);
+                        self.last_error = Some(e);
+                        // Don't wait for the stagger delay after a failure
+                        self.timer = None;
+                    }
+                }
+            }
+            let due = match &mut self.timer {
+                Some(timer) => timer.poll().map_err(io::Error::other)?.is_ready(),
+                None => true,
+            };
+            if !due {
+                return Ok(Async::NotReady);
+            }
+            match self.addrs.pop_front() {
+                Some(addr) => {
+                    self.attempts.push((addr, connect(&addr, &self.config)));
+                    self.timer = Some(Delay::new(Instant::now() + self.stagger));
+                }
+                None if self.attempts.is_empty() => match self.last_error.take() {
+                    Some(e) => Err(Error::Io(e))?,
+                    None => Err(Error::ProxyServerUnreachable)?,
+                },
+                None => return Ok(Async::NotReady),
+            }
+        }
+    }
+}
+
+/// Interleaves addresses by family, starting with the family of the first address.
+pub(crate) fn interleave(addrs: Vec<SocketAddr>) -> VecDeque<SocketAddr> {
+    let first_is_ipv6 = addrs.first().is_none_or(SocketAddr::is_ipv6);
+    let (mut preferred, mut others): (VecDeque<_>, VecDeque<_>) = addrs
+        .into_iter()
+        .partition(|addr| addr.is_ipv6() == first_is_ipv6);
+    let mut res = VecDeque::with_capacity(preferred.len() + others.len());
+    loop {
+        match (preferred.pop_front(), others.pop_front()) {
+            (None, None) => return res,
+            (a, b) => {
+                res.extend(a);
+                res.extend(b);
+            }
+        }
+    }
+}
+
+/// Resolves `host` on a thread of its own, as the system resolver blocks and would stall
+/// the event loop. The port is part of `host` if `port` is `None`.
+pub(crate) fn lookup(host: &str, port: Option<u16>) -> Lookup {
+    let host = host.to_string();
+    let (tx, rx) = oneshot::channel();
+    // If the thread can't be spawned, the sender is dropped and the lookup fails.
+    let _ = thread::Builder::new()
+        .name("tokio-socks-resolver".into())
+        .spawn(move || {
+            let addrs = match port {
+                Some(port) => (host.as_str(), port).to_socket_addrs(),
+                None => host.to_socket_addrs(),
+            };
+            let _ = tx.send(addrs.map(Iterator::collect));
+        });
+    Lookup(rx)
+}
+
+/// A `Future` which resolves to the addresses of a host, returned by `lookup`.
+#[derive(Debug)]
+pub(crate) struct Lookup(oneshot::Receiver<io::Result<Vec<SocketAddr>>>);
+
+impl Future for Lookup {
+    type Item = Vec<SocketAddr>;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Vec<SocketAddr>, io::Error> {
+        match self.0.poll() {
+            Ok(Async::Ready(addrs)) => addrs.map(Async::Ready),
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Err(_) => Err(io::Error::other("the resolver thread failed")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interleaves_addr_families() {
+        let v4: Vec<SocketAddr> = vec![
+            "1.1.1.1:1080".parse().unwrap(),
+            "1.0.0.1:1080".parse().unwrap(),
+        ];
+        let v6: Vec<SocketAddr> =
+            vec!["[::1]:1080".parse().unwrap(), "[::2]:1080".parse().unwrap()];
+        let res = interleave(vec![v6[0], v6[1], v4[0], v4[1]]);
+        assert_eq!(res, vec![v6[0], v4[0], v6[1], v4[1]]);
+        let res = interleave(vec![v4[0], v4[1], v6[0]]);
+        assert_eq!(res, vec![v4[0], v6[0], v4[1]]);
+    }
+
+    #[test]
+    fn looks_up_off_the_event_loop() {
+        let addrs = lookup("127.0.0.1", Some(1080)).wait().unwrap();
+        assert_eq!(addrs, vec!["127.0.0.1:1080".parse().unwrap()]);
+        let addrs = lookup("127.0.0.1:1080", None).wait().unwrap();
+        assert_eq!(addrs, vec!["127.0.0.1:1080".parse().unwrap()]);
+        assert!(lookup("127.0.0.1", None).wait().is_err());
+    }
+}
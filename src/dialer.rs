@@ -0,0 +1,297 @@
+//! Handshakes over transports to the proxy server other than a TCP connection.
+//!
+//! A `Dialer` establishes the stream to the proxy server, and
+//! `Socks5Connector::connect_with_dialer` runs the handshake over it, resolving to a
+//! `Tunnel` wrapping the stream. A stream established by the caller, such as a duplex
+//! provided by the host environment, is passed to `Socks5Connector::connect_over` instead.
+
+use crate::{
+    connector::Config,
+    tcp::{Command, ConnectFuture, Reply},
+    AuthMethod, Authentication, Error, Result, TargetAddr,
+};
+use bytes::{Buf, BufMut, Bytes};
+use futures::{future, stream, try_ready, Async, Future, Poll};
+use std::{
+    io::{self, Read, Write},
+    marker::PhantomData,
+    net::SocketAddr,
+    sync::Arc,
+};
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_tcp::TcpStream;
+
+/// Establishes the stream to a proxy server, over which the handshake runs.
+///
+/// It's implemented by `SocketAddr`, connecting over TCP, and can be implemented to reach
+/// the proxy server through any other transport: a QUIC stream, a WebSocket tunnel, a
+/// vsock or a test double.
+pub trait Dialer {
+    /// The stream to the proxy server.
+    type Stream: AsyncRead + AsyncWrite;
+    /// The `Future` establishing the stream.
+    type Future: Future<Item = Self::Stream, Error = io::Error>;
+
+    /// Starts establishing a stream to the proxy server.
+    fn dial(&self) -> Self::Future;
+}
+
+impl Dialer for SocketAddr {
+    type Stream = TcpStream;
+    type Future = tokio_tcp::ConnectFuture;
+
+    fn dial(&self) -> tokio_tcp::ConnectFuture {
+        TcpStream::connect(self)
+    }
+}
+
+impl<D> Dialer for &D
+where
+    D: Dialer,
+{
+    type Stream = D::Stream;
+    type Future = D::Future;
+
+    fn dial(&self) -> D::Future {
+        (**self).dial()
+    }
+}
+
+/// The `Dialer` of a `Handshake` over a stream established by the caller, which is never
+/// dialed.
+#[derive(Debug)]
+pub struct Established<S>(PhantomData<S>);
+
+impl<S> Dialer for Established<S>
+where
+    S: AsyncRead + AsyncWrite,
+{
+    type Stream = S;
+    type Future = future::Empty<S, io::Error>;
+
+    fn dial(&self) -> future::Empty<S, io::Error> {
+        future::empty()
+    }
+}
+
+/// A SOCKS5 tunnel to a target server over the stream established by a `Dialer`.
+#[derive(Debug)]
+pub struct Tunnel<S> {
+    stream: S,
+    target: TargetAddr<'static>,
+    auth_method: AuthMethod,
+    reply: Bytes,
+}
+
+impl<S> Tunnel<S> {
+    pub(crate) fn new(
+        stream: S,
+        target: TargetAddr<'static>,
+        auth_method: AuthMethod,
+        reply: Bytes,
+    ) -> Tunnel<S> {
+        Tunnel {
+            stream,
+            target,
+            auth_method,
+            reply,
+        }
+    }
+
+    /// Returns the target address that the proxy server connects to.
+    pub fn target_addr(&self) -> &TargetAddr<'static> {
+        &self.target
+    }
+
+    /// Returns the authentication method selected by the proxy server.
+    pub fn auth_method(&self) -> AuthMethod {
+        self.auth_method
+    }
+
+    /// Returns the reply of the proxy server to the request which established this tunnel.
+    pub fn reply(&self) -> Reply {
+        Reply {
+            code: self.reply[1],
+            addr_type: self.reply[3],
+            bind_addr: self.target.clone(),
+            raw: self.reply.clone(),
+        }
+    }
+
+    /// Gets a reference to the stream to the proxy server.
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    /// Gets a mutable reference to the stream to the proxy server.
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.stream
+    }
+
+    /// Consumes the tunnel, returning the stream to the proxy server.
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+}
+
+impl<S: Read> Read for Tunnel<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stream.read(buf)
+    }
+}
+
+impl<S: Write> Write for Tunnel<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl<S: AsyncRead> AsyncRead for Tunnel<S> {
+    unsafe fn prepare_uninitialized_buffer(&self, buf: &mut [u8]) -> bool {
+        self.stream.prepare_uninitialized_buffer(buf)
+    }
+
+    fn read_buf<B: BufMut>(&mut self, buf: &mut B) -> Poll<usize, io::Error> {
+        AsyncRead::read_buf(&mut self.stream, buf)
+    }
+}
+
+impl<S: AsyncWrite> AsyncWrite for Tunnel<S> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        AsyncWrite::shutdown(&mut self.stream)
+    }
+
+    fn write_buf<B: Buf>(&mut self, buf: &mut B) -> Poll<usize, io::Error> {
+        self.stream.write_buf(buf)
+    }
+}
+
+/// A `Future` which resolves to a `Tunnel` through the proxy server reached by a `Dialer`,
+/// returned by `Socks5Connector::connect_with_dialer`.
+///
+/// The handshake is the one of `ConnectFuture`, run over the dialed stream, and follows
+/// the configuration of the connector except for the options about the TCP connection to
+/// the proxy server and the retries.
+///
+/// It only needs the stream to be an `AsyncRead + AsyncWrite`, and doesn't touch the
+/// network itself otherwise.
+pub struct Handshake<'a, 't, D>
+where
+    D: Dialer,
+{
+    dialing: Option<D::Future>,
+    conn: ConnectFuture<'a, 't, stream::Empty<SocketAddr, Error>, D::Stream>,
+}
+
+impl<'a, 't, D> Handshake<'a, 't, D>
+where
+    D: Dialer,
+{
+    pub(crate) fn new(
+        config: Arc<Config>,
+        dialer: &D,
+        auth: Authentication<'a>,
+        target: TargetAddr<'t>,
+    ) -> Handshake<'a, 't, D> {
+        Handshake {
+            dialing: Some(dialer.dial()),
+            conn: ConnectFuture::new(config, auth, Command::Connect, stream::empty(), target),
+        }
+    }
+}
+
+impl<'a, 't, S> Handshake<'a, 't, Established<S>>
+where
+    S: AsyncRead + AsyncWrite,
+{
+    pub(crate) fn established(
+        config: Arc<Config>,
+        stream: S,
+        auth: Authentication<'a>,
+        target: TargetAddr<'t>,
+    ) -> Result<Handshake<'a, 't, Established<S>>> {
+        let mut handshake = Handshake::new(config, &Established(PhantomData), auth, target);
+        handshake.dialing = None;
+        handshake.conn.set_stream(stream)?;
+        Ok(handshake)
+    }
+}
+
+impl<'a, 't, D> Future for Handshake<'a, 't, D>
+where
+    D: Dialer,
+{
+    type Item = Tunnel<D::Stream>;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Tunnel<D::Stream>, Error> {
+        let dialing = &mut self.dialing;
+        self.conn.poll_with(|conn| {
+            if let Some(dial) = dialing.as_mut() {
+                let stream = try_ready!(dial.poll());
+                debug_event!("dialed the proxy server");
+                *dialing = None;
+                conn.set_stream(stream)?;
+            }
+            try_ready!(conn.poll_exchange());
+            Ok(Async::Ready(conn.tunnel()))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connector::Socks5Connector;
+    use std::{net::TcpListener, thread};
+    use tokio::runtime::current_thread::Runtime;
+
+    /// Serves a single handshake without authentication, followed by `hello`.
+    fn serve() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (mut conn, _) = listener.accept().unwrap();
+            conn.read_exact(&mut [0; 3]).unwrap();
+            conn.write_all(&[0x05, 0x00]).unwrap();
+            conn.read_exact(&mut [0; 10]).unwrap();
+            conn.write_all(&[0x05, 0x00, 0x00, 0x01, 10, 0, 0, 1, 0, 80])
+                .unwrap();
+            conn.write_all(b"hello").unwrap();
+        });
+        addr
+    }
+
+    fn assert_tunnel<S: AsyncRead>(runtime: &mut Runtime, tunnel: Tunnel<S>) {
+        assert_eq!(tunnel.target_addr().to_string(), "10.0.0.1:80");
+        let (_, received) = runtime
+            .block_on(tokio_io::io::read_to_end(tunnel, Vec::new()))
+            .unwrap();
+        assert_eq!(received, b"hello");
+    }
+
+    #[test]
+    fn runs_the_handshake_over_the_dialed_stream() {
+        let mut runtime = Runtime::new().unwrap();
+        let conn = Socks5Connector::new()
+            .connect_with_dialer(serve(), "10.0.0.1:80", Default::default())
+            .unwrap();
+        let tunnel = runtime.block_on(conn).unwrap();
+        assert_tunnel(&mut runtime, tunnel);
+    }
+
+    #[test]
+    fn runs_the_handshake_over_an_established_stream() {
+        let mut runtime = Runtime::new().unwrap();
+        let stream = runtime.block_on(TcpStream::connect(&serve())).unwrap();
+        let conn = Socks5Connector::new()
+            .connect_over(stream, "10.0.0.1:80", Default::default())
+            .unwrap();
+        let tunnel = runtime.block_on(conn).unwrap();
+        assert_tunnel(&mut runtime, tunnel);
+    }
+}
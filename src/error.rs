@@ -1,4 +1,6 @@
+use crate::{consts::*, TargetAddr};
 use failure::Fail;
+use std::{fmt, net::SocketAddr};
 
 /// Error type of `tokio-socks`
 #[derive(Fail, Debug)]
@@ -12,7 +14,10 @@ pub enum Error {
     /// Failure due to invalid target address. It contains the detailed error message.
     #[fail(display = "Target address is invalid: {}", _0)]
     InvalidTargetAddress(&'static str),
-    /// Proxy server unreachable.
+    /// Proxy server unreachable, because no address was resolved for it.
+    ///
+    /// When connecting to the resolved addresses fails, the IO error of the last attempt
+    /// is returned as `Error::Io` instead.
     #[fail(display = "Proxy server unreachable")]
     ProxyServerUnreachable,
     /// Proxy server returns an invalid version number.
@@ -24,45 +29,255 @@ pub enum Error {
     /// Unknown auth method
     #[fail(display = "Unknown auth method")]
     UnknownAuthMethod,
+    /// The proxy server replied with a failure. `code` is the raw reply field.
+    #[fail(display = "{} (reply code {:#04x})", kind, code)]
+    ProxyReply { code: u8, kind: ReplyKind },
+    /// Invalid reserved byte
+    #[fail(display = "Invalid reserved byte")]
+    InvalidReservedByte,
+    /// Unknown address type
+    #[fail(display = "Unknown address type")]
+    UnknownAddressType,
+    /// Invalid UDP datagram received from the relay. It contains the detailed error message.
+    #[fail(display = "Invalid datagram: {}", _0)]
+    InvalidDatagram(&'static str),
+    /// The proxy server closed the TCP connection of a UDP association, which ended it.
+    #[fail(display = "UDP association closed by the proxy server")]
+    AssociationClosed,
+    /// Invalid authentication values. It contains the detailed error message.
+    #[fail(display = "Invalid auth values: {}", _0)]
+    InvalidAuthValues(&'static str),
+    /// Password auth failure
+    #[fail(display = "Password auth failure, code: {}", _0)]
+    PasswordAuthFailure(u8),
+    /// The operation didn't complete in time.
+    #[fail(display = "Timed out")]
+    Timeout,
+    /// The operation was cancelled by the caller.
+    #[fail(display = "Cancelled")]
+    Cancelled,
+    /// The peer which connected to a `Socks5Listener` doesn't match the target of the BIND
+    /// request. It contains the address of the peer.
+    #[fail(display = "Unexpected peer connected: {}", _0)]
+    UnexpectedPeer(TargetAddr<'static>),
+    /// The `TargetPolicy` of the connector denied the connection to the target, which
+    /// wasn't sent to the proxy server. It contains the denied target.
+    #[fail(display = "Target denied by policy: {}", _0)]
+    TargetDenied(TargetAddr<'static>),
+    /// Failure during a handshake with a proxy server. It contains the failure and the
+    /// context it happened in.
+    #[fail(display = "{}", _0)]
+    Handshake(Box<HandshakeError>),
+}
+
+/// The phases of a SOCKS5 handshake.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum HandshakePhase {
+    /// Resolving the addresses of the proxy server and connecting to it.
+    Connect,
+    /// Negotiating the authentication method.
+    MethodSelection,
+    /// Authenticating with the negotiated method.
+    Authentication,
+    /// Sending the request.
+    Request,
+    /// Reading the reply.
+    Reply,
+}
+
+impl fmt::Display for HandshakePhase {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            HandshakePhase::Connect => "connecting to the proxy server",
+            HandshakePhase::MethodSelection => "selecting the auth method",
+            HandshakePhase::Authentication => "authenticating",
+            HandshakePhase::Request => "sending the request",
+            HandshakePhase::Reply => "reading the reply",
+        })
+    }
+}
+
+/// A handshake failure with the context it happened in.
+#[derive(Debug)]
+pub struct HandshakeError {
+    /// The failure.
+    pub error: Error,
+    /// The phase of the handshake which failed.
+    pub phase: HandshakePhase,
+    /// The address of the proxy server, if a connection to it was attempted.
+    pub proxy: Option<SocketAddr>,
+    /// The target of the request.
+    pub target: TargetAddr<'static>,
+}
+
+impl fmt::Display for HandshakeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} while {}", self.error, self.phase)?;
+        if let Some(proxy) = self.proxy {
+            write!(f, " (proxy: {}, target: {})", proxy, self.target)
+        } else {
+            write!(f, " (target: {})", self.target)
+        }
+    }
+}
+
+impl Fail for HandshakeError {
+    fn cause(&self) -> Option<&dyn Fail> {
+        Some(&self.error)
+    }
+}
+
+/// The reply field of a SOCKS5 reply, as defined in RFC 1928.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ReplyKind {
+    /// Succeeded
+    Succeeded,
     /// General SOCKS server failure
-    #[fail(display = "General SOCKS server failure")]
-    GeneralSocksServerFailure,
+    GeneralFailure,
     /// Connection not allowed by ruleset
-    #[fail(display = "Connection not allowed by ruleset")]
-    ConnectionNotAllowedByRuleset,
+    ConnectionNotAllowed,
     /// Network unreachable
-    #[fail(display = "Network unreachable")]
     NetworkUnreachable,
     /// Host unreachable
-    #[fail(display = "Host unreachable")]
     HostUnreachable,
     /// Connection refused
-    #[fail(display = "Connection refused")]
     ConnectionRefused,
     /// TTL expired
-    #[fail(display = "TTL expired")]
     TtlExpired,
     /// Command not supported
-    #[fail(display = "Command not supported")]
     CommandNotSupported,
     /// Address type not supported
-    #[fail(display = "Address type not supported")]
     AddressTypeNotSupported,
-    /// Unknown error
-    #[fail(display = "Unknown error")]
-    UnknownError,
-    /// Invalid reserved byte
-    #[fail(display = "Invalid reserved byte")]
-    InvalidReservedByte,
-    /// Unknown address type
-    #[fail(display = "Unknown address type")]
-    UnknownAddressType,
-    /// Invalid authentication values. It contains the detailed error message.
-    #[fail(display = "Invalid auth values: {}", _0)]
-    InvalidAuthValues(&'static str),
-    /// Password auth failure
-    #[fail(display = "Password auth failure, code: {}", _0)]
-    PasswordAuthFailure(u8),
+    /// A code which is not defined in RFC 1928
+    Unknown(u8),
+}
+
+impl From<u8> for ReplyKind {
+    fn from(code: u8) -> ReplyKind {
+        match code {
+            REPLY_SUCCEEDED => ReplyKind::Succeeded,
+            REPLY_GENERAL_FAILURE => ReplyKind::GeneralFailure,
+            REPLY_CONNECTION_NOT_ALLOWED => ReplyKind::ConnectionNotAllowed,
+            REPLY_NETWORK_UNREACHABLE => ReplyKind::NetworkUnreachable,
+            REPLY_HOST_UNREACHABLE => ReplyKind::HostUnreachable,
+            REPLY_CONNECTION_REFUSED => ReplyKind::ConnectionRefused,
+            REPLY_TTL_EXPIRED => ReplyKind::TtlExpired,
+            REPLY_COMMAND_NOT_SUPPORTED => ReplyKind::CommandNotSupported,
+            REPLY_ADDRESS_TYPE_NOT_SUPPORTED => ReplyKind::AddressTypeNotSupported,
+            code => ReplyKind::Unknown(code),
+        }
+    }
+}
+
+impl From<ReplyKind> for u8 {
+    fn from(kind: ReplyKind) -> u8 {
+        match kind {
+            ReplyKind::Succeeded => REPLY_SUCCEEDED,
+            ReplyKind::GeneralFailure => REPLY_GENERAL_FAILURE,
+            ReplyKind::ConnectionNotAllowed => REPLY_CONNECTION_NOT_ALLOWED,
+            ReplyKind::NetworkUnreachable => REPLY_NETWORK_UNREACHABLE,
+            ReplyKind::HostUnreachable => REPLY_HOST_UNREACHABLE,
+            ReplyKind::ConnectionRefused => REPLY_CONNECTION_REFUSED,
+            ReplyKind::TtlExpired => REPLY_TTL_EXPIRED,
+            ReplyKind::CommandNotSupported => REPLY_COMMAND_NOT_SUPPORTED,
+            ReplyKind::AddressTypeNotSupported => REPLY_ADDRESS_TYPE_NOT_SUPPORTED,
+            ReplyKind::Unknown(code) => code,
+        }
+    }
+}
+
+impl fmt::Display for ReplyKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            ReplyKind::Succeeded => "Succeeded",
+            ReplyKind::GeneralFailure => "General SOCKS server failure",
+            ReplyKind::ConnectionNotAllowed => "Connection not allowed by ruleset",
+            ReplyKind::NetworkUnreachable => "Network unreachable",
+            ReplyKind::HostUnreachable => "Host unreachable",
+            ReplyKind::ConnectionRefused => "Connection refused",
+            ReplyKind::TtlExpired => "TTL expired",
+            ReplyKind::CommandNotSupported => "Command not supported",
+            ReplyKind::AddressTypeNotSupported => "Address type not supported",
+            ReplyKind::Unknown(_) => "Unknown error",
+        })
+    }
+}
+
+impl Error {
+    /// Returns the error without its handshake context.
+    pub fn root(&self) -> &Error {
+        match self {
+            Error::Handshake(e) => e.error.root(),
+            err => err,
+        }
+    }
+
+    /// Returns the handshake context of the error, if any.
+    pub fn handshake(&self) -> Option<&HandshakeError> {
+        match self {
+            Error::Handshake(e) => Some(e),
+            _ => None,
+        }
+    }
+
+    /// Returns the kind of the failure reported by the proxy server, if any.
+    pub fn reply_kind(&self) -> Option<ReplyKind> {
+        match self.root() {
+            Error::ProxyReply { kind, .. } => Some(*kind),
+            _ => None,
+        }
+    }
+
+    /// Returns `true` if the failure may not happen again, so that the operation is worth
+    /// retrying: IO errors, timeouts, unreachable proxy servers, general server failures and
+    /// expired TTLs.
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self.root(),
+            Error::Io(_)
+                | Error::Timeout
+                | Error::ProxyServerUnreachable
+                | Error::ProxyReply {
+                    kind: ReplyKind::GeneralFailure | ReplyKind::TtlExpired,
+                    ..
+                }
+        )
+    }
+
+    /// Returns `true` if the failure is caused by the authentication with the proxy server.
+    pub fn is_auth_failure(&self) -> bool {
+        matches!(
+            self.root(),
+            Error::NoAcceptableAuthMethods
+                | Error::UnknownAuthMethod
+                | Error::InvalidAuthValues(_)
+                | Error::PasswordAuthFailure(_)
+        )
+    }
+
+    /// Returns `true` if the proxy server reports that it couldn't reach the target.
+    pub fn is_target_unreachable(&self) -> bool {
+        matches!(
+            self.reply_kind(),
+            Some(
+                ReplyKind::NetworkUnreachable
+                    | ReplyKind::HostUnreachable
+                    | ReplyKind::ConnectionRefused
+                    | ReplyKind::TtlExpired
+            )
+        )
+    }
+
+    /// Returns `true` if the proxy server doesn't speak SOCKS5 as expected.
+    pub fn is_protocol_error(&self) -> bool {
+        matches!(
+            self.root(),
+            Error::InvalidResponseVersion
+                | Error::InvalidReservedByte
+                | Error::UnknownAddressType
+                | Error::InvalidDatagram(_)
+        )
+    }
 }
 
 impl From<std::io::Error> for Error {
@@ -71,5 +286,136 @@ impl From<std::io::Error> for Error {
     }
 }
 
+impl Error {
+    /// Returns the `io::ErrorKind` closest to the error. Errors with a handshake context
+    /// have the kind of the underlying failure.
+    fn io_kind(&self) -> std::io::ErrorKind {
+        use std::io::ErrorKind;
+
+        match self {
+            Error::Io(e) => e.kind(),
+            Error::InvalidTargetAddress(_) | Error::InvalidAuthValues(_) => ErrorKind::InvalidInput,
+            Error::ParseError(_)
+            | Error::InvalidResponseVersion
+            | Error::UnknownAuthMethod
+            | Error::InvalidReservedByte
+            | Error::UnknownAddressType
+            | Error::InvalidDatagram(_) => ErrorKind::InvalidData,
+            Error::NoAcceptableAuthMethods | Error::PasswordAuthFailure(_) => {
+                ErrorKind::PermissionDenied
+            }
+            Error::ProxyServerUnreachable => ErrorKind::Other,
+            Error::Timeout => ErrorKind::TimedOut,
+            Error::Cancelled => ErrorKind::Other,
+            Error::UnexpectedPeer(_) | Error::TargetDenied(_) => ErrorKind::PermissionDenied,
+            Error::AssociationClosed => ErrorKind::ConnectionAborted,
+            Error::Handshake(e) => e.error.io_kind(),
+            Error::ProxyReply { kind, .. } => match kind {
+                ReplyKind::ConnectionNotAllowed => ErrorKind::PermissionDenied,
+                ReplyKind::ConnectionRefused => ErrorKind::ConnectionRefused,
+                ReplyKind::TtlExpired => ErrorKind::TimedOut,
+                _ => ErrorKind::Other,
+            },
+        }
+    }
+}
+
+impl From<Error> for std::io::Error {
+    /// Converts the error to an `io::Error`, keeping IO errors as they are and mapping the
+    /// other errors to the closest `io::ErrorKind`. Errors with a handshake context keep
+    /// the kind of the underlying failure, and its OS error code if any.
+    fn from(err: Error) -> std::io::Error {
+        if let Error::Io(e) = err.root() {
+            if let Some(code) = e.raw_os_error() {
+                return std::io::Error::from_raw_os_error(code);
+            }
+        }
+        match err {
+            Error::Io(e) => e,
+            err => std::io::Error::new(err.io_kind(), err.compat()),
+        }
+    }
+}
+
 /// Result type of `tokio-socks`
 pub type Result<T> = std::result::Result<T, Error>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+
+    #[test]
+    fn keeps_unknown_reply_codes() {
+        let kind = ReplyKind::from(0x2a);
+        assert_eq!(kind, ReplyKind::Unknown(0x2a));
+        assert_eq!(u8::from(kind), 0x2a);
+        let err = Error::ProxyReply { code: 0x2a, kind };
+        assert_eq!(err.to_string(), "Unknown error (reply code 0x2a)");
+        assert!(!err.is_transient());
+    }
+
+    #[test]
+    fn converts_io_error_back_to_io_error() {
+        let err = Error::Io(io::Error::from_raw_os_error(111));
+        let err = io::Error::from(err);
+        assert_eq!(err.raw_os_error(), Some(111));
+    }
+
+    #[test]
+    fn classifies_errors_with_context() {
+        let err = Error::Handshake(Box::new(HandshakeError {
+            error: Error::ProxyReply {
+                code: 0x04,
+                kind: ReplyKind::HostUnreachable,
+            },
+            phase: HandshakePhase::Reply,
+            proxy: Some(SocketAddr::from(([127, 0, 0, 1], 1080))),
+            target: TargetAddr::Domain("example.com".into(), 80),
+        }));
+        assert!(err.is_target_unreachable());
+        assert_eq!(err.reply_kind(), Some(ReplyKind::HostUnreachable));
+        assert_eq!(
+            err.to_string(),
+            "Host unreachable (reply code 0x04) while reading the reply \
+             (proxy: 127.0.0.1:1080, target: example.com:80)"
+        );
+    }
+
+    #[test]
+    fn converts_socks_error_to_io_error() {
+        let err = io::Error::from(Error::ProxyReply {
+            code: 0x05,
+            kind: ReplyKind::ConnectionRefused,
+        });
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+        assert_eq!(err.to_string(), "Connection refused (reply code 0x05)");
+        let err = io::Error::from(Error::PasswordAuthFailure(1));
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        let err = io::Error::from(Error::Handshake(Box::new(HandshakeError {
+            error: Error::Timeout,
+            phase: HandshakePhase::Connect,
+            proxy: None,
+            target: TargetAddr::Domain("example.com".into(), 80),
+        })));
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn keeps_the_os_error_of_a_refused_connection() {
+        use crate::tcp::Socks5Stream;
+        use tokio::runtime::current_thread::Runtime;
+
+        // Nothing listens on the port once the listener is dropped.
+        let proxy = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let conn = Socks5Stream::connect(proxy, "example.com:80").unwrap();
+        let err = Runtime::new().unwrap().block_on(conn).unwrap_err();
+        assert!(err.handshake().is_some());
+        let err = io::Error::from(err);
+        assert_eq!(err.raw_os_error(), Some(libc::ECONNREFUSED));
+    }
+}
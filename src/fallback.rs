@@ -0,0 +1,330 @@
+//! Direct connections to the targets the proxy server can't be used to reach.
+
+use crate::{
+    connector::{Config, Connect},
+    dial::{self, Lookup, TcpConnect},
+    tcp::Socks5Stream,
+    Error, TargetAddr, ToProxyAddrs,
+};
+use bytes::{Buf, BufMut};
+use futures::{Async, Future, Poll};
+use std::{
+    io::{self, Read, Write},
+    net::SocketAddr,
+    sync::Arc,
+    vec,
+};
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_tcp::TcpStream;
+
+/// What a connector does when the proxy server can't be used to reach a target.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "kebab-case"))]
+pub enum FallbackPolicy {
+    /// Fail the connection.
+    #[default]
+    Never,
+    /// Connect to the target directly when the proxy server is unreachable or rejects
+    /// the target.
+    Direct,
+}
+
+/// A connection to a target, either through a proxy or direct.
+#[derive(Debug)]
+pub enum ProxyStream {
+    /// A connection through a SOCKS5 proxy.
+    Proxied(Box<Socks5Stream>),
+    /// A direct connection, established because the proxy couldn't be used.
+    Direct(TcpStream),
+}
+
+impl ProxyStream {
+    /// Returns `true` if the connection bypasses the proxy.
+    pub fn is_direct(&self) -> bool {
+        match self {
+            ProxyStream::Proxied(_) => false,
+            ProxyStream::Direct(_) => true,
+        }
+    }
+
+    /// Returns a shared reference to the underlying `tokio_tcp::TcpStream`.
+    pub fn get_ref(&self) -> &TcpStream {
+        match self {
+            ProxyStream::Proxied(stream) => stream.get_ref(),
+            ProxyStream::Direct(tcp) => tcp,
+        }
+    }
+
+    /// Consumes the `ProxyStream`, returning the underlying `tokio_tcp::TcpStream`.
+    pub fn into_inner(self) -> TcpStream {
+        match self {
+            ProxyStream::Proxied(stream) => stream.into_inner(),
+            ProxyStream::Direct(tcp) => tcp,
+        }
+    }
+}
+
+impl Read for ProxyStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            ProxyStream::Proxied(stream) => stream.read(buf),
+            ProxyStream::Direct(tcp) => tcp.read(buf),
+        }
+    }
+}
+
+impl Write for ProxyStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            ProxyStream::Proxied(stream) => stream.write(buf),
+            ProxyStream::Direct(tcp) => tcp.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            ProxyStream::Proxied(stream) => stream.flush(),
+            ProxyStream::Direct(tcp) => tcp.flush(),
+        }
+    }
+}
+
+impl AsyncRead for ProxyStream {
+    unsafe fn prepare_uninitialized_buffer(&self, buf: &mut [u8]) -> bool {
+        match self {
+            ProxyStream::Proxied(stream) => stream.prepare_uninitialized_buffer(buf),
+            ProxyStream::Direct(tcp) => tcp.prepare_uninitialized_buffer(buf),
+        }
+    }
+
+    fn read_buf<B: BufMut>(&mut self, buf: &mut B) -> Poll<usize, io::Error> {
+        match self {
+            ProxyStream::Proxied(stream) => AsyncRead::read_buf(stream, buf),
+            ProxyStream::Direct(tcp) => AsyncRead::read_buf(tcp, buf),
+        }
+    }
+}
+
+impl AsyncWrite for ProxyStream {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        match self {
+            ProxyStream::Proxied(stream) => AsyncWrite::shutdown(stream),
+            ProxyStream::Direct(tcp) => AsyncWrite::shutdown(tcp),
+        }
+    }
+
+    fn write_buf<B: Buf>(&mut self, buf: &mut B) -> Poll<usize, io::Error> {
+        match self {
+            ProxyStream::Proxied(stream) => stream.write_buf(buf),
+            ProxyStream::Direct(tcp) => tcp.write_buf(buf),
+        }
+    }
+}
+
+/// Returns `true` if the proxy server couldn't be reached or refused to connect to the
+/// target, rather than e.g. rejecting the credentials.
+fn falls_back(err: &Error) -> bool {
+    matches!(
+        err.root(),
+        Error::Io(_) | Error::ProxyServerUnreachable | Error::Timeout | Error::ProxyReply { .. }
+    )
+}
+
+/// A `Future` which resolves to a `ProxyStream`, connecting directly to the target if the
+/// proxy can't be used and the `FallbackPolicy` allows it.
+pub struct Fallback<'a, 't, P>
+where
+    P: ToProxyAddrs,
+{
+    config: Arc<Config>,
+    target: TargetAddr<'t>,
+    state: FallbackState<'a, 't, P>,
+}
+
+enum FallbackState<'a, 't, P>
+where
+    P: ToProxyAddrs,
+{
+    Proxy(Box<Connect<'a, 't, &'a P>>),
+    Resolving {
+        lookup: Lookup,
+        proxy_error: Option<Error>,
+    },
+    Direct {
+        addrs: vec::IntoIter<SocketAddr>,
+        conn: Option<TcpConnect>,
+        proxy_error: Option<Error>,
+    },
+}
+
+impl<'a, 't, P> Fallback<'a, 't, P>
+where
+    P: ToProxyAddrs,
+{
+    pub(crate) fn new(
+        config: Arc<Config>,
+        target: TargetAddr<'t>,
+        conn: Connect<'a, 't, &'a P>,
+    ) -> Fallback<'a, 't, P> {
+        Fallback {
+            config,
+            target,
+            state: FallbackState::Proxy(Box::new(conn)),
+        }
+    }
+
+    /// Connects directly to the addresses of the target which aren't denied by the
+    /// `TargetPolicy` of the connector.
+    fn connect_direct(&mut self, mut addrs: Vec<SocketAddr>, proxy_error: Option<Error>) {
+        if let Some(policy) = &self.config.target_policy {
+            addrs.retain(|addr| policy.0.allows_resolved(*addr));
+        }
+        self.state = FallbackState::Direct {
+            addrs: addrs.into_iter(),
+            conn: None,
+            proxy_error,
+        };
+    }
+}
+
+impl<'a, 't, P> Future for Fallback<'a, 't, P>
+where
+    P: ToProxyAddrs,
+{
+    type Item = ProxyStream;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<ProxyStream, Error> {
+        loop {
+            match &mut self.state {
+                FallbackState::Proxy(conn) => match conn.poll() {
+                    Ok(Async::Ready(stream)) => {
+                        return Ok(Async::Ready(ProxyStream::Proxied(Box::new(stream))));
+                    }
+                    Ok(Async::NotReady) => return Ok(Async::NotReady),
+                    Err(e) => {
+                        if self.config.fallback != FallbackPolicy::Direct || !falls_back(&e) {
+                            return Err(e);
+                        }
+                        match &self.target {
+                            TargetAddr::Ip(addr) => self.connect_direct(vec![*addr], Some(e)),
+                            TargetAddr::Domain(domain, port) => {
+                                self.state = FallbackState::Resolving {
+                                    lookup: dial::lookup(domain, Some(*port)),
+                                    proxy_error: Some(e),
+                                }
+                            }
+                        }
+                    }
+                },
+                FallbackState::Resolving {
+                    lookup,
+                    proxy_error,
+                } => match lookup.poll() {
+                    Ok(Async::Ready(addrs)) => {
+                        let proxy_error = proxy_error.take();
+                        self.connect_direct(addrs, proxy_error);
+                    }
+                    Ok(Async::NotReady) => return Ok(Async::NotReady),
+                    // A target which can't be resolved here fails with the proxy error.
+                    Err(_) => return Err(proxy_error.take().unwrap()),
+                },
+                FallbackState::Direct {
+                    addrs,
+                    conn,
+                    proxy_error,
+                } => {
+                    if let Some(tcp) = conn {
+                        match tcp.poll() {
+                            Ok(Async::Ready(tcp)) => {
+                                dial::configure(&tcp, &self.config.socket)?;
+                                return Ok(Async::Ready(ProxyStream::Direct(tcp)));
+                            }
+                            Ok(Async::NotReady) => return Ok(Async::NotReady),
+                            Err(_) => *conn = None,
+                        }
+                    }
+                    match addrs.next() {
+                        Some(addr) => *conn = Some(dial::connect(&addr, &self.config)),
+                        // The error of the proxy tells more than the one of the last
+                        // direct attempt.
+                        None => return Err(proxy_error.take().unwrap()),
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        connector::Socks5Connector,
+        trace::{Replay, Trace},
+        HandshakePhase, IntoTargetAddr, ProxySpec, Result,
+    };
+    use std::net::TcpListener;
+    use tokio::runtime::current_thread::Runtime;
+
+    /// Returns a local address on which nothing listens.
+    fn closed_port() -> SocketAddr {
+        TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+    }
+
+    fn connect<T>(policy: FallbackPolicy, proxy: SocketAddr, target: T) -> Result<ProxyStream>
+    where
+        T: IntoTargetAddr<'static>,
+    {
+        let connector = Socks5Connector::builder().fallback(policy).build();
+        let proxy = ProxySpec::new(proxy);
+        let conn = connector.connect_with_fallback(&proxy, target)?;
+        Runtime::new().unwrap().block_on(conn)
+    }
+
+    #[test]
+    fn uses_the_proxy_when_it_succeeds() {
+        let trace: Trace = "
+            > 05 01 00
+            < 05 00
+            > 05 01 00 01 0a 00 00 01 00 50
+            < 05 00 00 01 0a 00 00 01 00 50
+        "
+        .parse()
+        .unwrap();
+        let replay = Replay::bind(trace).unwrap();
+        let stream = connect(FallbackPolicy::Direct, replay.local_addr(), "10.0.0.1:80").unwrap();
+        assert!(!stream.is_direct());
+    }
+
+    #[test]
+    fn connects_directly_when_the_proxy_is_unreachable() {
+        let target = TcpListener::bind("127.0.0.1:0").unwrap();
+        let target_addr = target.local_addr().unwrap();
+        let stream = connect(FallbackPolicy::Direct, closed_port(), target_addr).unwrap();
+        assert!(stream.is_direct());
+        assert_eq!(stream.get_ref().peer_addr().unwrap(), target_addr);
+        // A domain is resolved locally.
+        let target = ("localhost", target_addr.port());
+        let stream = connect(FallbackPolicy::Direct, closed_port(), target).unwrap();
+        assert!(stream.is_direct());
+    }
+
+    #[test]
+    fn fails_with_the_proxy_error() {
+        let target = TcpListener::bind("127.0.0.1:0").unwrap();
+        let target_addr = target.local_addr().unwrap();
+        let res = connect(FallbackPolicy::Never, closed_port(), target_addr);
+        assert!(res.is_err());
+        // The direct connection fails too.
+        let res = connect(FallbackPolicy::Direct, closed_port(), closed_port());
+        match res {
+            Err(e) => assert_eq!(e.handshake().unwrap().phase, HandshakePhase::Connect),
+            Ok(_) => panic!("expected the error of the proxy"),
+        }
+    }
+}
@@ -0,0 +1,234 @@
+//! Connections to the first reachable of several targets through the same proxy server.
+
+use crate::{
+    connector::{Connect, Socks5Connector},
+    tcp::Socks5Stream,
+    Error, TargetAddr, ToProxyAddrs,
+};
+use futures::{try_ready, Async, Future, Poll};
+use std::fmt;
+
+/// A `Future` connecting to the first of several redundant targets which accepts the
+/// connection through the proxy, returned by `Socks5Connector::connect_first_ok`.
+///
+/// It resolves to the stream along with the index of the target which won. The targets
+/// are tried in order, one at a time, unless `race` is used. When all of them fail, the
+/// error of the last one is returned.
+pub struct ConnectFirstOk<P>
+where
+    P: ToProxyAddrs,
+{
+    connector: Socks5Connector,
+    proxy: P,
+    targets: Vec<TargetAddr<'static>>,
+    next: usize,
+    attempts: Attempts<Connect<'static, 'static, P>>,
+}
+
+impl<P> ConnectFirstOk<P>
+where
+    P: ToProxyAddrs + Clone,
+{
+    pub(crate) fn new(
+        connector: Socks5Connector,
+        proxy: P,
+        targets: Vec<TargetAddr<'static>>,
+    ) -> ConnectFirstOk<P> {
+        ConnectFirstOk {
+            connector,
+            proxy,
+            targets,
+            next: 0,
+            attempts: Attempts::new(),
+        }
+    }
+
+    /// Tries all the targets at once, keeping the first connection which succeeds and
+    /// dropping the others.
+    pub fn race(mut self) -> ConnectFirstOk<P> {
+        self.attempts.race = true;
+        self
+    }
+}
+
+impl<P> Future for ConnectFirstOk<P>
+where
+    P: ToProxyAddrs + Clone,
+{
+    type Item = (Socks5Stream, usize);
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<(Socks5Stream, usize), Error> {
+        let ConnectFirstOk {
+            connector,
+            proxy,
+            targets,
+            next,
+            attempts,
+        } = self;
+        let start = || {
+            let target = match targets.get(*next) {
+                Some(target) => target.clone(),
+                None => return Ok(None),
+            };
+            let conn = connector.connect(proxy.clone(), target)?;
+            *next += 1;
+            Ok(Some((*next - 1, conn)))
+        };
+        let (stream, index) = try_ready!(attempts
+            .poll(start, |_, _| true)
+            .map_err(|e| e.unwrap_or(Error::InvalidTargetAddress("no target"))));
+        debug_event!(target = %stream.target_addr(), "target won");
+        Ok(Async::Ready((stream, index)))
+    }
+}
+
+/// The connection attempts to a series of candidates, targets or proxies, run one at a
+/// time, or all at once with `race`, until one of them succeeds.
+///
+/// It drives both `ConnectFirstOk` and `group::Failover`.
+pub(crate) struct Attempts<F> {
+    pub(crate) race: bool,
+    pending: Vec<(usize, F)>,
+    last_error: Option<Error>,
+}
+
+impl<F> Attempts<F>
+where
+    F: Future<Error = Error>,
+{
+    pub(crate) fn new() -> Attempts<F> {
+        Attempts {
+            race: false,
+            pending: Vec::new(),
+            last_error: None,
+        }
+    }
+
+    /// Returns the number of attempts running.
+    pub(crate) fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Polls the attempts, starting new ones with `start` whenever one may run.
+    ///
+    /// `start` returns the next attempt along with the index of its candidate, or `None`
+    /// once every candidate was tried. `failed` is called with the index and the error of
+    /// each failed attempt, and returns whether the other candidates are still worth trying.
+    ///
+    /// It resolves to the item of the first attempt which succeeds along with the index of
+    /// its candidate. Once every attempt failed, it fails with the error of the last one, or
+    /// `None` if there was no candidate.
+    pub(crate) fn poll<S, E>(
+        &mut self,
+        mut start: S,
+        mut failed: E,
+    ) -> Poll<(F::Item, usize), Option<Error>>
+    where
+        S: FnMut() -> Result<Option<(usize, F)>, Error>,
+        E: FnMut(usize, &Error) -> bool,
+    {
+        loop {
+            while self.race || self.pending.is_empty() {
+                match start().map_err(Some)? {
+                    Some(attempt) => self.pending.push(attempt),
+                    None => break,
+                }
+            }
+            if self.pending.is_empty() {
+                return Err(self.last_error.take());
+            }
+            let mut any_failed = false;
+            let mut i = 0;
+            while i < self.pending.len() {
+                let (index, attempt) = &mut self.pending[i];
+                match attempt.poll() {
+                    Ok(Async::NotReady) => i += 1,
+                    Ok(Async::Ready(item)) => return Ok(Async::Ready((item, *index))),
+                    Err(e) => {
+                        debug_event!(candidate = *index, error = %e, "attempt failed");
+                        if !failed(*index, &e) {
+                            return Err(Some(e));
+                        }
+                        self.last_error = Some(e);
+                        self.pending.remove(i);
+                        any_failed = true;
+                    }
+                }
+            }
+            // The next candidate may be waiting for a failure to be tried.
+            if !any_failed {
+                return Ok(Async::NotReady);
+            }
+        }
+    }
+}
+
+impl<P> fmt::Debug for ConnectFirstOk<P>
+where
+    P: ToProxyAddrs + fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ConnectFirstOk")
+            .field("proxy", &self.proxy)
+            .field("targets", &self.targets)
+            .field("race", &self.attempts.race)
+            .field("pending", &self.attempts.pending())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fails_without_targets() {
+        let targets: Vec<&str> = Vec::new();
+        let conn = Socks5Connector::new()
+            .connect_first_ok("127.0.0.1:1080", targets)
+            .unwrap();
+        match conn.wait() {
+            Err(Error::InvalidTargetAddress(_)) => {}
+            _ => panic!("expected an invalid target"),
+        }
+    }
+
+    #[test]
+    fn tries_the_candidates_until_one_succeeds() {
+        use futures::future::{self, FutureResult};
+
+        let results = vec![Err(Error::Timeout), Ok("b"), Ok("c")];
+        let mut candidates = results.into_iter().enumerate();
+        let mut attempts = Attempts::<FutureResult<&str, Error>>::new();
+        let mut failures = Vec::new();
+        let polled = attempts.poll(
+            || Ok(candidates.next().map(|(i, res)| (i, future::result(res)))),
+            |index, _| {
+                failures.push(index);
+                true
+            },
+        );
+        assert_eq!(polled.unwrap(), Async::Ready(("b", 1)));
+        assert_eq!(failures, vec![0]);
+        assert_eq!(candidates.len(), 1);
+    }
+
+    #[test]
+    fn fails_with_the_last_error() {
+        use futures::future::{self, FutureResult};
+
+        let results = vec![Err(Error::Timeout), Err(Error::ProxyServerUnreachable)];
+        let mut candidates = results.into_iter().enumerate();
+        let mut attempts = Attempts::<FutureResult<(), Error>>::new();
+        attempts.race = true;
+        let polled = attempts.poll(
+            || Ok(candidates.next().map(|(i, res)| (i, future::result(res)))),
+            |_, _| true,
+        );
+        match polled {
+            Err(Some(Error::ProxyServerUnreachable)) => {}
+            _ => panic!("expected the last error"),
+        }
+    }
+}
@@ -0,0 +1,665 @@
+//! Forwarding of local TCP connections and UDP datagrams through a proxy server, like
+//! `ssh -L`.
+
+use crate::{
+    abort::{abortable, AbortHandle},
+    connector::{Connect, Socks5Connector},
+    relay::{relay, Relay},
+    Error, IntoTargetAddr, Result, TargetAddr, ToProxyAddrs,
+};
+#[cfg(target_os = "linux")]
+use crate::{
+    relay::{splice, Splice},
+    transparent,
+};
+use futures::{try_ready, Async, Future, Poll};
+use std::{
+    collections::HashMap,
+    fmt, io,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+use tokio_executor::{DefaultExecutor, Executor};
+use tokio_tcp::{TcpListener, TcpStream};
+#[cfg(feature = "udp")]
+use {
+    crate::udp::{AssociateFuture, Socks5Datagram},
+    tokio_udp::UdpSocket,
+};
+
+#[cfg(feature = "udp")]
+const MAX_DATAGRAM_SIZE: usize = 65535;
+
+/// A `Future` accepting local connections and tunneling each of them through a proxy
+/// server to a fixed target.
+///
+/// Each tunnel is spawned onto the default executor, so the forwarder must run on a Tokio
+/// runtime. The future runs until it's shut down with the handle returned by
+/// `shutdown_handle`, or until accepting a connection fails. The tunnels are closed when
+/// the forwarder resolves or is dropped. The failure of a tunnel only closes that tunnel,
+/// and is reported to the function set with `on_error`.
+pub struct TcpForwarder<P>
+where
+    P: ToProxyAddrs,
+{
+    listener: TcpListener,
+    connector: Socks5Connector,
+    proxy: P,
+    destination: Destination,
+    tunnels: Arc<Mutex<Tunnels>>,
+    shutdown: AbortHandle,
+    on_error: Option<OnError>,
+    #[cfg(target_os = "linux")]
+    splice: bool,
+}
+
+type OnError = Arc<dyn Fn(SocketAddr, &Error) + Send + Sync>;
+
+/// The handles aborting the spawned tunnels, removed when a tunnel completes.
+#[derive(Debug, Default)]
+struct Tunnels {
+    next_id: u64,
+    handles: HashMap<u64, AbortHandle>,
+}
+
+/// Where the connections accepted by a `TcpForwarder` are forwarded to.
+#[derive(Debug)]
+enum Destination {
+    Fixed(TargetAddr<'static>),
+    /// The destination of each connection before it was redirected to the listener, which
+    /// is the local address of the connection if `tproxy` is set.
+    #[cfg(target_os = "linux")]
+    Original {
+        tproxy: bool,
+    },
+}
+
+struct Tunnel<P>
+where
+    P: ToProxyAddrs,
+{
+    state: TunnelState<P>,
+    #[cfg(target_os = "linux")]
+    splice: bool,
+}
+
+enum TunnelState<P>
+where
+    P: ToProxyAddrs,
+{
+    Connecting(Option<TcpStream>, Box<Connect<'static, 'static, P>>),
+    Relaying(Relay<TcpStream, TcpStream>),
+    #[cfg(target_os = "linux")]
+    Splicing(Splice),
+}
+
+impl<P> TcpForwarder<P>
+where
+    P: ToProxyAddrs + Clone,
+{
+    /// Listens on `local_addr`, forwarding the connections to `target` through `proxy`
+    /// with the default connector.
+    ///
+    /// # Error
+    ///
+    /// It fails if `local_addr` can't be bound, and propagates the error that occurs in the
+    /// conversion from `T` to `TargetAddr`.
+    pub fn new<'t, T>(local_addr: SocketAddr, proxy: P, target: T) -> Result<TcpForwarder<P>>
+    where
+        T: IntoTargetAddr<'t>,
+    {
+        Ok(TcpForwarder {
+            listener: TcpListener::bind(&local_addr)?,
+            connector: Socks5Connector::default(),
+            proxy,
+            destination: Destination::Fixed(target.into_target_addr()?.to_owned()),
+            tunnels: Arc::default(),
+            shutdown: AbortHandle::new(),
+            on_error: None,
+            #[cfg(target_os = "linux")]
+            splice: false,
+        })
+    }
+
+    /// Forwards the connections redirected to `listener` by the firewall through `proxy`
+    /// to their original destination, with the default connector.
+    ///
+    /// The destination is recovered with `transparent::original_dst`. If `listener` is
+    /// transparent, as returned by `transparent::tproxy_listener`, the destination of the
+    /// connections redirected with `TPROXY` is their local address. The connections whose
+    /// destination can't be recovered are reported to the function set with `on_error`
+    /// and closed.
+    #[cfg(target_os = "linux")]
+    pub fn transparent(listener: TcpListener, proxy: P) -> TcpForwarder<P> {
+        let tproxy = transparent::is_transparent(&listener).unwrap_or(false);
+        TcpForwarder {
+            listener,
+            connector: Socks5Connector::default(),
+            proxy,
+            destination: Destination::Original { tproxy },
+            tunnels: Arc::default(),
+            shutdown: AbortHandle::new(),
+            on_error: None,
+            splice: false,
+        }
+    }
+
+    /// Sets the connector establishing the tunnels.
+    pub fn connector(mut self, connector: Socks5Connector) -> TcpForwarder<P> {
+        self.connector = connector;
+        self
+    }
+
+    /// Sets a function called with the address of the local peer and the error whenever a
+    /// tunnel fails.
+    pub fn on_error<F>(mut self, on_error: F) -> TcpForwarder<P>
+    where
+        F: Fn(SocketAddr, &Error) + Send + Sync + 'static,
+    {
+        self.on_error = Some(Arc::new(on_error));
+        self
+    }
+
+    /// Moves the bytes of the tunnels with `splice(2)` rather than copying them through
+    /// userspace, which spares CPU time on high-bandwidth tunnels. Disabled by default.
+    #[cfg(target_os = "linux")]
+    pub fn splice(mut self, enabled: bool) -> TcpForwarder<P> {
+        self.splice = enabled;
+        self
+    }
+
+    /// Returns the local address the forwarder listens on.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Returns a handle to shut the forwarder down. The future then resolves successfully,
+    /// closing all the tunnels.
+    pub fn shutdown_handle(&self) -> AbortHandle {
+        self.shutdown.clone()
+    }
+}
+
+impl<P> TcpForwarder<P>
+where
+    P: ToProxyAddrs,
+{
+    /// Returns the number of open tunnels.
+    pub fn tunnel_count(&self) -> usize {
+        self.tunnels.lock().unwrap().handles.len()
+    }
+
+    /// Spawns the tunnel of the connection accepted from `peer`.
+    fn spawn(&self, peer: SocketAddr, tunnel: Tunnel<P>)
+    where
+        P: Send + 'static,
+    {
+        let (tunnel, handle) = abortable(tunnel);
+        let id = {
+            let mut tunnels = self.tunnels.lock().unwrap();
+            let id = tunnels.next_id;
+            tunnels.next_id += 1;
+            tunnels.handles.insert(id, handle);
+            id
+        };
+        let tunnels = self.tunnels.clone();
+        let on_error = self.on_error.clone();
+        let tunnel = tunnel.then(move |res| {
+            tunnels.lock().unwrap().handles.remove(&id);
+            match res {
+                // The tunnel was closed by the forwarder.
+                Err(Error::Cancelled) => {}
+                Err(e) => report(&on_error, peer, &e),
+                Ok(()) => {}
+            }
+            Ok(())
+        });
+        if let Err(e) = DefaultExecutor::current().spawn(Box::new(tunnel)) {
+            self.tunnels.lock().unwrap().handles.remove(&id);
+            let e = io::Error::other(e);
+            report(&self.on_error, peer, &e.into());
+        }
+    }
+
+    fn close_tunnels(&self) {
+        for (_, handle) in self.tunnels.lock().unwrap().handles.drain() {
+            handle.abort();
+        }
+    }
+}
+
+impl<P> Future for TcpForwarder<P>
+where
+    P: ToProxyAddrs + Clone + Send + 'static,
+{
+    type Item = ();
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<(), Error> {
+        if self.shutdown.poll_aborted() {
+            self.close_tunnels();
+            return Ok(Async::Ready(()));
+        }
+        while let Async::Ready((tcp, peer)) = self.listener.poll_accept()? {
+            debug_event!(%peer, "accepted a connection to forward");
+            let target = match &self.destination {
+                Destination::Fixed(target) => target.clone(),
+                #[cfg(target_os = "linux")]
+                Destination::Original { tproxy } => {
+                    let addr = match transparent::original_dst(&tcp) {
+                        Err(_) if *tproxy => tcp.local_addr(),
+                        addr => addr,
+                    };
+                    match addr {
+                        Ok(addr) => TargetAddr::Ip(addr),
+                        Err(e) => {
+                            report(&self.on_error, peer, &e.into());
+                            continue;
+                        }
+                    }
+                }
+            };
+            let connect = self.connector.connect(self.proxy.clone(), target)?;
+            let tunnel = Tunnel {
+                state: TunnelState::Connecting(Some(tcp), Box::new(connect)),
+                #[cfg(target_os = "linux")]
+                splice: self.splice,
+            };
+            self.spawn(peer, tunnel);
+        }
+        Ok(Async::NotReady)
+    }
+}
+
+impl<P> Drop for TcpForwarder<P>
+where
+    P: ToProxyAddrs,
+{
+    fn drop(&mut self) {
+        self.close_tunnels();
+    }
+}
+
+fn report(on_error: &Option<OnError>, peer: SocketAddr, e: &Error) {
+    debug_event!(%peer, error = %e, "tunnel failed");
+    if let Some(on_error) = on_error {
+        on_error(peer, e);
+    }
+}
+
+impl<P> Future for Tunnel<P>
+where
+    P: ToProxyAddrs,
+{
+    type Item = ();
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<(), Error> {
+        loop {
+            match &mut self.state {
+                TunnelState::Connecting(local, connect) => {
+                    let remote = try_ready!(connect.poll()).into_inner();
+                    let local = local.take().unwrap();
+                    #[cfg(target_os = "linux")]
+                    {
+                        if self.splice {
+                            self.state = TunnelState::Splicing(splice(local, remote)?);
+                            continue;
+                        }
+                    }
+                    self.state = TunnelState::Relaying(relay(local, remote));
+                }
+                TunnelState::Relaying(relay) => {
+                    try_ready!(relay.poll());
+                    return Ok(Async::Ready(()));
+                }
+                #[cfg(target_os = "linux")]
+                TunnelState::Splicing(splice) => {
+                    try_ready!(splice.poll());
+                    return Ok(Async::Ready(()));
+                }
+            }
+        }
+    }
+}
+
+impl<P> fmt::Debug for TcpForwarder<P>
+where
+    P: ToProxyAddrs + fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("TcpForwarder")
+            .field("listener", &self.listener)
+            .field("connector", &self.connector)
+            .field("proxy", &self.proxy)
+            .field("destination", &self.destination)
+            .field("tunnels", &self.tunnel_count())
+            .finish()
+    }
+}
+
+/// A `Future` relaying the datagrams received on a local UDP socket to a fixed target
+/// through a proxy server, and the datagrams of the target back.
+///
+/// The datagrams of the target are sent to the local peer which sent the last datagram.
+/// When the proxy server closes the association, a new one is established, and the
+/// failure is reported to the function set with `on_error`, as are the invalid datagrams
+/// received from the relay. The future fails if an association can't be established, or if
+/// the local socket fails.
+#[cfg(feature = "udp")]
+pub struct UdpForwarder<P>
+where
+    P: ToProxyAddrs,
+{
+    socket: UdpSocket,
+    proxy: P,
+    target: TargetAddr<'static>,
+    association: Association<P>,
+    peer: Option<SocketAddr>,
+    // The length of the datagram in `send_buf` which waits to be sent to the relay.
+    pending: Option<usize>,
+    send_buf: Box<[u8]>,
+    recv_buf: Box<[u8]>,
+    shutdown: AbortHandle,
+    on_error: Option<OnDatagramError>,
+}
+
+#[cfg(feature = "udp")]
+type OnDatagramError = Arc<dyn Fn(&Error) + Send + Sync>;
+
+#[cfg(feature = "udp")]
+enum Association<P>
+where
+    P: ToProxyAddrs,
+{
+    Pending(AssociateFuture<'static, P::Output>),
+    Established(Socks5Datagram),
+}
+
+/// A failure of either side of a `UdpForwarder`.
+#[cfg(feature = "udp")]
+enum RelayError {
+    Local(io::Error),
+    Remote(Error),
+}
+
+#[cfg(feature = "udp")]
+impl<P> UdpForwarder<P>
+where
+    P: ToProxyAddrs + Clone,
+{
+    /// Binds a UDP socket to `local_addr`, forwarding the datagrams to `target` through
+    /// `proxy`.
+    ///
+    /// # Error
+    ///
+    /// It fails if `local_addr` can't be bound, and propagates the error that occurs in the
+    /// conversion from `T` to `TargetAddr`.
+    pub fn new<'t, T>(local_addr: SocketAddr, proxy: P, target: T) -> Result<UdpForwarder<P>>
+    where
+        T: IntoTargetAddr<'t>,
+    {
+        Ok(UdpForwarder {
+            socket: UdpSocket::bind(&local_addr)?,
+            association: Association::Pending(Socks5Datagram::bind(proxy.clone())?),
+            proxy,
+            target: target.into_target_addr()?.to_owned(),
+            peer: None,
+            pending: None,
+            send_buf: vec![0; MAX_DATAGRAM_SIZE].into_boxed_slice(),
+            recv_buf: vec![0; MAX_DATAGRAM_SIZE].into_boxed_slice(),
+            shutdown: AbortHandle::new(),
+            on_error: None,
+        })
+    }
+
+    /// Sets a function called with the errors which don't stop the forwarder.
+    pub fn on_error<F>(mut self, on_error: F) -> UdpForwarder<P>
+    where
+        F: Fn(&Error) + Send + Sync + 'static,
+    {
+        self.on_error = Some(Arc::new(on_error));
+        self
+    }
+
+    /// Returns the local address the forwarder receives datagrams on.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// Returns a handle to shut the forwarder down. The future then resolves successfully,
+    /// closing the association.
+    pub fn shutdown_handle(&self) -> AbortHandle {
+        self.shutdown.clone()
+    }
+
+    /// Relays the datagrams in both directions until neither side is ready.
+    fn poll_relay(&mut self) -> std::result::Result<(), RelayError> {
+        let datagram = match &mut self.association {
+            Association::Established(datagram) => datagram,
+            Association::Pending(_) => unreachable!(),
+        };
+        loop {
+            let mut progress = false;
+            if self.pending.is_none() {
+                match self.socket.poll_recv_from(&mut self.send_buf) {
+                    Ok(Async::Ready((n, peer))) => {
+                        self.peer = Some(peer);
+                        self.pending = Some(n);
+                    }
+                    Ok(Async::NotReady) => {}
+                    Err(e) => return Err(RelayError::Local(e)),
+                }
+            }
+            if let Some(n) = self.pending {
+                let sent = datagram
+                    .poll_send_to(&self.send_buf[..n], &self.target)
+                    .map_err(RelayError::Remote)?;
+                if sent.is_ready() {
+                    self.pending = None;
+                    progress = true;
+                }
+            }
+            let received = datagram
+                .poll_recv_from(&mut self.recv_buf)
+                .map_err(RelayError::Remote)?;
+            if let Async::Ready((n, _)) = received {
+                progress = true;
+                // A datagram which can't be sent right away is dropped, as it would be if
+                // the buffer of the socket were full.
+                if let Some(peer) = self.peer {
+                    self.socket
+                        .poll_send_to(&self.recv_buf[..n], &peer)
+                        .map_err(RelayError::Local)?;
+                }
+            }
+            if !progress {
+                return Ok(());
+            }
+        }
+    }
+}
+
+#[cfg(feature = "udp")]
+impl<P> Future for UdpForwarder<P>
+where
+    P: ToProxyAddrs + Clone,
+{
+    type Item = ();
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<(), Error> {
+        if self.shutdown.poll_aborted() {
+            return Ok(Async::Ready(()));
+        }
+        loop {
+            if let Association::Pending(associate) = &mut self.association {
+                let datagram = try_ready!(associate.poll());
+                debug_event!(relay = %datagram.relay_addr(), "established the association");
+                self.association = Association::Established(datagram);
+            }
+            match self.poll_relay() {
+                Ok(()) => return Ok(Async::NotReady),
+                Err(RelayError::Local(e)) => Err(e)?,
+                Err(RelayError::Remote(e)) => {
+                    debug_event!(error = %e, "failed to relay a datagram");
+                    if let Some(on_error) = &self.on_error {
+                        on_error(&e);
+                    }
+                    if let Error::InvalidDatagram(_) = e {
+                        continue;
+                    }
+                    let associate = Socks5Datagram::bind(self.proxy.clone())?;
+                    self.association = Association::Pending(associate);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(feature = "udp")]
+impl<P> fmt::Debug for UdpForwarder<P>
+where
+    P: ToProxyAddrs + fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("UdpForwarder")
+            .field("socket", &self.socket)
+            .field("proxy", &self.proxy)
+            .field("target", &self.target)
+            .field("peer", &self.peer)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::trace::{Replay, Trace};
+    use std::{
+        io::{Read, Write},
+        net::Shutdown,
+        sync::mpsc,
+        time::Duration,
+    };
+    use tokio::runtime::Runtime;
+
+    /// A proxy server connecting to 10.0.0.1:80, which answers `ping` with `pong`.
+    fn replay() -> Replay {
+        let trace: Trace = "
+            > 05 01 00
+            < 05 00
+            > 05 01 00 01 0a 00 00 01 00 50
+            < 05 00 00 01 0a 00 00 01 00 50
+            > 70 69 6e 67
+            < 70 6f 6e 67
+        "
+        .parse()
+        .unwrap();
+        Replay::bind(trace).unwrap()
+    }
+
+    fn forwarder(proxy: SocketAddr) -> TcpForwarder<SocketAddr> {
+        let local_addr = SocketAddr::from(([127, 0, 0, 1], 0));
+        TcpForwarder::new(local_addr, proxy, "10.0.0.1:80").unwrap()
+    }
+
+    fn connect(forwarder: &TcpForwarder<SocketAddr>) -> std::net::TcpStream {
+        let conn = std::net::TcpStream::connect(forwarder.local_addr().unwrap()).unwrap();
+        conn.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        conn
+    }
+
+    fn ping(conn: &mut std::net::TcpStream) {
+        conn.write_all(b"ping").unwrap();
+        let mut buf = [0; 4];
+        conn.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"pong");
+    }
+
+    #[test]
+    fn tunnels_connections_until_the_peer_closes() {
+        let replay = replay();
+        let forwarder = forwarder(replay.local_addr());
+        let (mut first, mut second) = (connect(&forwarder), connect(&forwarder));
+        let mut rt = Runtime::new().unwrap();
+        rt.spawn(forwarder.map_err(|e| panic!("{}", e)));
+        ping(&mut first);
+        // The proxy server closes the connection once it reads the end of the stream.
+        first.shutdown(Shutdown::Write).unwrap();
+        assert_eq!(first.read(&mut [0; 1]).unwrap(), 0);
+        ping(&mut second);
+        assert_eq!(replay.mismatches(), 0);
+    }
+
+    #[test]
+    fn closes_the_tunnels_when_shut_down() {
+        let replay = replay();
+        let forwarder = forwarder(replay.local_addr());
+        let shutdown = forwarder.shutdown_handle();
+        let mut conn = connect(&forwarder);
+        let mut rt = Runtime::new().unwrap();
+        rt.spawn(forwarder.map_err(|e| panic!("{}", e)));
+        ping(&mut conn);
+        shutdown.abort();
+        assert_eq!(conn.read(&mut [0; 1]).unwrap(), 0);
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn drops_connections_not_redirected() {
+        let replay = replay();
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let (tx, rx) = mpsc::channel();
+        let tx = Mutex::new(tx);
+        let forwarder =
+            TcpForwarder::transparent(listener, replay.local_addr()).on_error(move |peer, _| {
+                tx.lock().unwrap().send(peer).unwrap();
+            });
+        let mut conn = connect(&forwarder);
+        let mut rt = Runtime::new().unwrap();
+        rt.spawn(forwarder.map_err(|e| panic!("{}", e)));
+        let peer = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(peer, conn.local_addr().unwrap());
+        assert_eq!(conn.read(&mut [0; 1]).unwrap(), 0);
+    }
+
+    #[test]
+    fn reports_failed_tunnels() {
+        // Nothing listens on the proxy address once the listener is dropped.
+        let proxy = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let (tx, rx) = mpsc::channel();
+        let tx = Mutex::new(tx);
+        let forwarder = forwarder(proxy).on_error(move |peer, _| {
+            tx.lock().unwrap().send(peer).unwrap();
+        });
+        let mut conn = connect(&forwarder);
+        let mut rt = Runtime::new().unwrap();
+        rt.spawn(forwarder.map_err(|e| panic!("{}", e)));
+        let peer = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(peer, conn.local_addr().unwrap());
+        assert_eq!(conn.read(&mut [0; 1]).unwrap(), 0);
+    }
+
+    #[test]
+    fn resolves_once_shut_down() {
+        let local_addr = SocketAddr::from(([127, 0, 0, 1], 0));
+        let proxy = SocketAddr::from(([127, 0, 0, 1], 1080));
+        let forwarder = TcpForwarder::new(local_addr, proxy, "example.com:80").unwrap();
+        forwarder.shutdown_handle().abort();
+        assert!(forwarder.wait().is_ok());
+    }
+
+    #[test]
+    #[cfg(feature = "udp")]
+    fn udp_resolves_once_shut_down() {
+        let local_addr = SocketAddr::from(([127, 0, 0, 1], 0));
+        let proxy = SocketAddr::from(([127, 0, 0, 1], 1080));
+        let forwarder = UdpForwarder::new(local_addr, proxy, "example.com:53").unwrap();
+        forwarder.shutdown_handle().abort();
+        assert!(forwarder.wait().is_ok());
+    }
+}
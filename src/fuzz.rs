@@ -0,0 +1,154 @@
+//! Entry points to the parsers and encoders of the handshake messages, for fuzz targets and
+//! property tests.
+//!
+//! The parsers run on a byte slice holding what the proxy server sent so far, exactly as
+//! the handshake does, so a fuzz target only needs to feed them the bytes it's given:
+//!
+//! ```no_run
+//! use tokio_socks::{connector::Compliance, fuzz};
+//!
+//! fn fuzz_target(data: &[u8]) {
+//!     let _ = fuzz::parse_reply(data, Compliance::Strict);
+//!     let _ = fuzz::parse_reply(data, Compliance::Lenient);
+//! }
+//! ```
+//!
+//! With the `arbitrary` feature, `TargetAddr` implements `Arbitrary`, to build the inputs
+//! with the encoders instead.
+
+use crate::{
+    codec::{write_addr, ClientEncoder, ClientMessage, Expect, ServerDecoder, ServerMessage},
+    connector::Compliance,
+    consts::*,
+    tcp::Reply,
+    Error, ReplyKind, Result, TargetAddr,
+};
+use bytes::BytesMut;
+use tokio_codec::{Decoder, Encoder};
+
+/// Parses the method selection message at the start of `bytes`.
+///
+/// Returns the selected method and the number of bytes it takes, or `None` if `bytes` is
+/// too short to hold the message.
+pub fn parse_method_selection(bytes: &[u8], compliance: Compliance) -> Result<Option<(u8, usize)>> {
+    Ok(
+        decode(Expect::MethodSelection, bytes, compliance)?.map(|(msg, len)| match msg {
+            ServerMessage::MethodSelection(method) => (method, len),
+            _ => unreachable!(),
+        }),
+    )
+}
+
+/// Parses the username/password authentication status at the start of `bytes`.
+///
+/// Returns the status, `0x00` on success, and the number of bytes it takes, or `None` if
+/// `bytes` is too short to hold the message.
+pub fn parse_password_auth_status(
+    bytes: &[u8],
+    compliance: Compliance,
+) -> Result<Option<(u8, usize)>> {
+    Ok(
+        decode(Expect::PasswordAuthStatus, bytes, compliance)?.map(|(msg, len)| match msg {
+            ServerMessage::PasswordAuthStatus(status) => (status, len),
+            _ => unreachable!(),
+        }),
+    )
+}
+
+/// Parses the reply to a request at the start of `bytes`.
+///
+/// Returns the reply and the number of bytes it takes, or `None` if `bytes` is too short to
+/// hold it. A failed reply is an `Error::ProxyReply`, as when connecting.
+pub fn parse_reply(bytes: &[u8], compliance: Compliance) -> Result<Option<(Reply, usize)>> {
+    let (msg, len) = match decode(Expect::Reply, bytes, compliance)? {
+        Some(parsed) => parsed,
+        None => return Ok(None),
+    };
+    match msg {
+        ServerMessage::Reply {
+            code,
+            addr: Some(bind_addr),
+            raw,
+        } => {
+            let reply = Reply {
+                code,
+                addr_type: raw[3],
+                bind_addr,
+                raw,
+            };
+            Ok(Some((reply, len)))
+        }
+        ServerMessage::Reply { code, .. } => Err(Error::ProxyReply {
+            code,
+            kind: ReplyKind::from(code),
+        }),
+        _ => unreachable!(),
+    }
+}
+
+/// Encodes the method selection request of a client supporting `methods`.
+pub fn encode_methods(methods: &[u8]) -> Vec<u8> {
+    encode(ClientMessage::Methods(methods))
+}
+
+/// Encodes a username/password authentication request.
+pub fn encode_password_auth(username: &str, password: &str) -> Vec<u8> {
+    encode(ClientMessage::PasswordAuth { username, password })
+}
+
+/// Encodes a request for `command`, one of the `CMD_*` constants, to `target`.
+pub fn encode_request(command: u8, target: &TargetAddr<'_>) -> Vec<u8> {
+    encode(ClientMessage::Request { command, target })
+}
+
+/// Encodes a reply with `code` and the bound address `addr`.
+pub fn encode_reply(code: u8, addr: &TargetAddr<'_>) -> Vec<u8> {
+    let mut buf = vec![SOCKS5_VERSION, code, RESERVED];
+    write_addr(addr, |bytes| buf.extend_from_slice(bytes));
+    buf
+}
+
+fn decode(
+    expect: Expect,
+    bytes: &[u8],
+    compliance: Compliance,
+) -> Result<Option<(ServerMessage, usize)>> {
+    let mut src = BytesMut::from(bytes);
+    let msg = ServerDecoder::new(expect, compliance).decode(&mut src)?;
+    Ok(msg.map(|msg| (msg, bytes.len() - src.len())))
+}
+
+fn encode(msg: ClientMessage<'_>) -> Vec<u8> {
+    let mut buf = BytesMut::new();
+    ClientEncoder::default().encode(msg, &mut buf).unwrap();
+    buf.to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_truncated_replies_and_domains_of_any_length() {
+        for len in 0..=255 {
+            let domain = "a".repeat(len);
+            let addr = TargetAddr::Domain(domain.into(), 80);
+            let mut bytes = encode_reply(REPLY_SUCCEEDED, &addr);
+            for end in 0..bytes.len() {
+                assert!(parse_reply(&bytes[..end], Compliance::Strict)
+                    .unwrap()
+                    .is_none());
+            }
+            bytes.push(0xff);
+            match parse_reply(&bytes, Compliance::Strict) {
+                Ok(Some((reply, consumed))) => {
+                    assert_eq!(reply.bind_addr, addr);
+                    assert_eq!(consumed, bytes.len() - 1);
+                }
+                _ => assert_eq!(len, 0),
+            }
+        }
+        let refused = encode_reply(REPLY_CONNECTION_REFUSED, &"10.0.0.1:80".parse().unwrap());
+        assert!(parse_reply(&refused[..4], Compliance::Strict).is_err());
+    }
+}
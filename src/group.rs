@@ -0,0 +1,355 @@
+//! Groups of proxy servers tried in turn or in rotation, failing over to the next one
+//! when a proxy server is unreachable.
+
+use crate::{
+    connector::{Connect, Socks5Connector},
+    first_ok::Attempts,
+    tcp::Socks5Stream,
+    Error, ProxySpec, ReplyKind, TargetAddr, ToProxyAddrs,
+};
+use futures::{future, try_ready, Async, Future, Poll, Stream};
+use rand::seq::SliceRandom;
+use std::{
+    io,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+    vec,
+};
+use tokio_tcp::TcpStream;
+use tokio_timer::{Interval, Timeout};
+
+/// How the proxies of a `ProxyGroup` are picked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "kebab-case"))]
+pub enum Strategy {
+    /// Start with the next proxy after the one picked by the previous connection.
+    #[default]
+    RoundRobin,
+    /// Start with a random proxy.
+    Random,
+    /// Start with the proxy with the fewest consecutive failures, taking turns between
+    /// the proxies with as many failures.
+    LeastFailures,
+}
+
+/// A set of interchangeable proxies.
+///
+/// Connections through the group fail over to the other proxies when a proxy is down.
+/// The group can be shared between tasks in an `Arc`.
+#[derive(Debug)]
+pub struct ProxyGroup<P> {
+    proxies: Vec<ProxySpec<P>>,
+    strategy: Strategy,
+    breaker: Option<(usize, Duration)>,
+    next: AtomicUsize,
+    states: Vec<ProxyState>,
+}
+
+#[derive(Debug, Default)]
+struct ProxyState {
+    failures: AtomicUsize,
+    open_until: Mutex<Option<Instant>>,
+}
+
+/// The health of a proxy of a `ProxyGroup`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProxyHealth {
+    /// The number of consecutive failures of the proxy.
+    pub failures: usize,
+    /// Whether the circuit breaker removed the proxy from rotation.
+    pub open: bool,
+}
+
+impl<P> ProxyGroup<P> {
+    /// Creates a group picking among `proxies` according to `strategy`.
+    pub fn new(proxies: Vec<ProxySpec<P>>, strategy: Strategy) -> ProxyGroup<P> {
+        let states = proxies.iter().map(|_| ProxyState::default()).collect();
+        ProxyGroup {
+            proxies,
+            strategy,
+            breaker: None,
+            next: AtomicUsize::new(0),
+            states,
+        }
+    }
+
+    /// Enables a circuit breaker, which removes a proxy from rotation for `cooldown` after
+    /// `threshold` consecutive failures.
+    ///
+    /// Once the cooldown elapses, the proxy is tried again, and a single failure removes it
+    /// again until it succeeds.
+    pub fn circuit_breaker(mut self, threshold: usize, cooldown: Duration) -> ProxyGroup<P> {
+        self.breaker = Some((threshold, cooldown));
+        self
+    }
+
+    /// Returns the proxies of the group.
+    pub fn proxies(&self) -> &[ProxySpec<P>] {
+        &self.proxies
+    }
+
+    /// Returns the number of consecutive failures of the proxy at `index`.
+    pub fn failures(&self, index: usize) -> usize {
+        self.states[index].failures.load(Ordering::Relaxed)
+    }
+
+    /// Returns the health of the proxy at `index`.
+    pub fn health(&self, index: usize) -> ProxyHealth {
+        ProxyHealth {
+            failures: self.failures(index),
+            open: self.is_open(index, Instant::now()),
+        }
+    }
+
+    fn is_open(&self, index: usize, now: Instant) -> bool {
+        let open_until = *self.states[index].open_until.lock().unwrap();
+        open_until.is_some_and(|open_until| now < open_until)
+    }
+
+    /// Returns the indices of the proxies in the order they should be tried, leaving out
+    /// the ones removed by the circuit breaker.
+    fn order(&self) -> Vec<usize> {
+        let len = self.proxies.len();
+        let now = Instant::now();
+        let mut order: Vec<usize> = (0..len).collect();
+        match self.strategy {
+            Strategy::RoundRobin | Strategy::LeastFailures if len > 0 => {
+                let start = self.next.fetch_add(1, Ordering::Relaxed) % len;
+                order.rotate_left(start);
+                if self.strategy == Strategy::LeastFailures {
+                    order.sort_by_key(|&i| self.failures(i));
+                }
+            }
+            Strategy::Random => order.shuffle(&mut rand::thread_rng()),
+            _ => {}
+        }
+        order.retain(|&i| !self.is_open(i, now));
+        order
+    }
+
+    fn record(&self, index: usize, succeeded: bool) {
+        let state = &self.states[index];
+        if succeeded {
+            state.failures.store(0, Ordering::Relaxed);
+            *state.open_until.lock().unwrap() = None;
+            return;
+        }
+        let failures = state.failures.fetch_add(1, Ordering::Relaxed) + 1;
+        if let Some((threshold, cooldown)) = self.breaker {
+            if failures >= threshold {
+                *state.open_until.lock().unwrap() = Some(Instant::now() + cooldown);
+            }
+        }
+    }
+}
+
+impl<P> ProxyGroup<P>
+where
+    P: ToProxyAddrs + 'static,
+    P::Output: 'static,
+{
+    /// Returns a `Future` which checks the health of every proxy of the group every
+    /// `interval`, by opening a TCP connection to its first address.
+    ///
+    /// A check which doesn't succeed within `timeout` counts as a failure of the proxy,
+    /// like a failed connection through it. The future should be spawned, and never
+    /// resolves unless the timer fails.
+    pub fn health_check(
+        self: Arc<Self>,
+        interval: Duration,
+        timeout: Duration,
+    ) -> impl Future<Item = (), Error = Error> {
+        Interval::new(Instant::now(), interval)
+            .map_err(|e| Error::Io(io::Error::other(e)))
+            .for_each(move |_| {
+                let checks = (0..self.proxies.len())
+                    .map(|index| self.clone().check(index, timeout))
+                    .collect::<Vec<_>>();
+                future::join_all(checks).map(|_| ())
+            })
+    }
+
+    fn check(
+        self: Arc<Self>,
+        index: usize,
+        timeout: Duration,
+    ) -> impl Future<Item = (), Error = Error> {
+        let connect = self.proxies[index]
+            .addrs
+            .to_proxy_addrs()
+            .into_future()
+            .map_err(|(e, _)| e)
+            .and_then(|(addr, _)| addr.ok_or(Error::ProxyServerUnreachable))
+            .and_then(|addr| TcpStream::connect(&addr).map_err(Error::from));
+        Timeout::new(connect, timeout).then(move |res| -> Result<(), Error> {
+            self.record(index, res.is_ok());
+            Ok(())
+        })
+    }
+}
+
+/// Returns `true` if another proxy may succeed where this one failed, i.e. unless the
+/// proxy server reported that the target itself can't be reached.
+fn fails_over(err: &Error) -> bool {
+    match err.reply_kind() {
+        None | Some(ReplyKind::GeneralFailure) => true,
+        Some(_) => false,
+    }
+}
+
+/// A `Future` which resolves to a socket to the target server through one of the proxies
+/// of a `ProxyGroup`.
+///
+/// The proxies are tried one at a time, unless `race` is used.
+pub struct Failover<'a, 't, P>
+where
+    P: ToProxyAddrs,
+{
+    connector: Socks5Connector,
+    group: &'a ProxyGroup<P>,
+    target: TargetAddr<'t>,
+    order: vec::IntoIter<usize>,
+    attempts: Attempts<Connect<'a, 't, &'a P>>,
+}
+
+impl<'a, 't, P> Failover<'a, 't, P>
+where
+    P: ToProxyAddrs,
+{
+    pub(crate) fn new(
+        connector: Socks5Connector,
+        group: &'a ProxyGroup<P>,
+        target: TargetAddr<'t>,
+    ) -> Failover<'a, 't, P> {
+        Failover {
+            connector,
+            group,
+            target,
+            order: group.order().into_iter(),
+            attempts: Attempts::new(),
+        }
+    }
+
+    /// Starts the handshakes with all the proxies at once, keeping the first stream which
+    /// is negotiated and dropping the other handshakes.
+    ///
+    /// The dropped handshakes count neither as successes nor as failures of their proxies.
+    pub fn race(mut self) -> Failover<'a, 't, P> {
+        self.attempts.race = true;
+        self
+    }
+}
+
+impl<'a, 't, P> Future for Failover<'a, 't, P>
+where
+    P: ToProxyAddrs,
+{
+    type Item = Socks5Stream;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Socks5Stream, Error> {
+        let Failover {
+            connector,
+            group,
+            target,
+            order,
+            attempts,
+        } = self;
+        let start = || match order.next() {
+            Some(index) => {
+                let conn = connector.connect_with_spec(&group.proxies[index], target.clone())?;
+                Ok(Some((index, conn)))
+            }
+            None => Ok(None),
+        };
+        let failed = |index, e: &Error| {
+            group.record(index, false);
+            fails_over(e)
+        };
+        let (stream, index) = try_ready!(attempts
+            .poll(start, failed)
+            .map_err(|e| e.unwrap_or(Error::ProxyServerUnreachable)));
+        group.record(index, true);
+        Ok(Async::Ready(stream))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn group(strategy: Strategy) -> ProxyGroup<&'static str> {
+        let proxies = vec!["a:1080", "b:1080", "c:1080"];
+        ProxyGroup::new(proxies.into_iter().map(ProxySpec::new).collect(), strategy)
+    }
+
+    #[test]
+    fn takes_turns() {
+        let group = group(Strategy::RoundRobin);
+        assert_eq!(group.order(), vec![0, 1, 2]);
+        assert_eq!(group.order(), vec![1, 2, 0]);
+        assert_eq!(group.order(), vec![2, 0, 1]);
+        assert_eq!(group.order(), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn prefers_proxies_with_fewer_failures() {
+        let group = group(Strategy::LeastFailures);
+        group.record(0, false);
+        group.record(0, false);
+        group.record(1, false);
+        assert_eq!(group.order(), vec![2, 1, 0]);
+        group.record(0, true);
+        assert_eq!(group.order(), vec![2, 0, 1]);
+    }
+
+    #[test]
+    fn fails_over_unless_the_target_is_unreachable() {
+        assert!(fails_over(&Error::ProxyServerUnreachable));
+        assert!(fails_over(&Error::PasswordAuthFailure(1)));
+        assert!(!fails_over(&Error::ProxyReply {
+            code: 0x04,
+            kind: ReplyKind::HostUnreachable,
+        }));
+    }
+
+    #[test]
+    fn races_all_the_proxies() {
+        let closed = || {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            ProxySpec::new(listener.local_addr().unwrap())
+        };
+        let group = ProxyGroup::new(vec![closed(), closed()], Strategy::RoundRobin);
+        let conn = Socks5Connector::new()
+            .connect_with_group(&group, "example.com:80")
+            .unwrap()
+            .race();
+        assert!(tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(conn)
+            .is_err());
+        assert_eq!((group.failures(0), group.failures(1)), (1, 1));
+    }
+
+    #[test]
+    fn removes_failing_proxies_from_rotation() {
+        let group = group(Strategy::RoundRobin).circuit_breaker(2, Duration::from_secs(60));
+        group.record(1, false);
+        assert_eq!(group.order(), vec![0, 1, 2]);
+        group.record(1, false);
+        assert!(group.health(1).open);
+        assert_eq!(group.order(), vec![2, 0]);
+        group.record(1, true);
+        assert_eq!(
+            group.health(1),
+            ProxyHealth {
+                failures: 0,
+                open: false
+            }
+        );
+    }
+}
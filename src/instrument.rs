@@ -0,0 +1,183 @@
+//! Accounting of the traffic of proxied connections.
+
+use bytes::{Buf, BufMut};
+use futures::{Async, Poll};
+use std::{
+    io::{self, Read, Write},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+use tokio_io::{AsyncRead, AsyncWrite};
+
+/// A stream counting the bytes read from and written to the wrapped stream.
+///
+/// The counters can be read from other tasks through a `TrafficCounters` handle.
+#[derive(Debug)]
+pub struct Instrumented<S> {
+    inner: S,
+    counters: TrafficCounters,
+}
+
+/// A shared handle to the counters of an `Instrumented` stream.
+#[derive(Debug, Clone)]
+pub struct TrafficCounters(Arc<Counters>);
+
+#[derive(Debug)]
+struct Counters {
+    created: Instant,
+    read: AtomicU64,
+    written: AtomicU64,
+    // Nanoseconds since `created`.
+    last_activity: AtomicU64,
+}
+
+/// A snapshot of the counters of an `Instrumented` stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TrafficStats {
+    /// The number of bytes read from the stream.
+    pub bytes_read: u64,
+    /// The number of bytes written to the stream.
+    pub bytes_written: u64,
+    /// When the stream was wrapped.
+    pub created: Instant,
+    /// When bytes were last read or written, or when the stream was wrapped if none were.
+    pub last_activity: Instant,
+}
+
+impl TrafficCounters {
+    /// Returns the current values of the counters.
+    pub fn snapshot(&self) -> TrafficStats {
+        let counters = &self.0;
+        let last_activity = counters.last_activity.load(Ordering::Relaxed);
+        TrafficStats {
+            bytes_read: counters.read.load(Ordering::Relaxed),
+            bytes_written: counters.written.load(Ordering::Relaxed),
+            created: counters.created,
+            last_activity: counters.created + Duration::from_nanos(last_activity),
+        }
+    }
+
+    fn record(&self, counter: &AtomicU64, n: usize) {
+        if n == 0 {
+            return;
+        }
+        counter.fetch_add(n as u64, Ordering::Relaxed);
+        let since_created = self.0.created.elapsed();
+        let nanos =
+            since_created.as_secs() * 1_000_000_000 + u64::from(since_created.subsec_nanos());
+        self.0.last_activity.store(nanos, Ordering::Relaxed);
+    }
+}
+
+impl<S> Instrumented<S> {
+    /// Wraps `inner`, starting the counters at zero.
+    pub fn new(inner: S) -> Instrumented<S> {
+        Instrumented {
+            inner,
+            counters: TrafficCounters(Arc::new(Counters {
+                created: Instant::now(),
+                read: AtomicU64::new(0),
+                written: AtomicU64::new(0),
+                last_activity: AtomicU64::new(0),
+            })),
+        }
+    }
+
+    /// Returns a handle to the counters, which stays valid after the stream is dropped.
+    pub fn counters(&self) -> TrafficCounters {
+        self.counters.clone()
+    }
+
+    /// Returns the current values of the counters.
+    pub fn snapshot(&self) -> TrafficStats {
+        self.counters.snapshot()
+    }
+
+    /// Returns a shared reference to the wrapped stream.
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    /// Returns a mutable reference to the wrapped stream.
+    ///
+    /// The bytes read or written through it aren't counted.
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+
+    /// Consumes the `Instrumented`, returning the wrapped stream.
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<S: Read> Read for Instrumented<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.counters.record(&self.counters.0.read, n);
+        Ok(n)
+    }
+}
+
+impl<S: Write> Write for Instrumented<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.counters.record(&self.counters.0.written, n);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<S: AsyncRead> AsyncRead for Instrumented<S> {
+    unsafe fn prepare_uninitialized_buffer(&self, buf: &mut [u8]) -> bool {
+        self.inner.prepare_uninitialized_buffer(buf)
+    }
+
+    fn read_buf<B: BufMut>(&mut self, buf: &mut B) -> Poll<usize, io::Error> {
+        let n = match AsyncRead::read_buf(&mut self.inner, buf)? {
+            Async::Ready(n) => n,
+            Async::NotReady => return Ok(Async::NotReady),
+        };
+        self.counters.record(&self.counters.0.read, n);
+        Ok(Async::Ready(n))
+    }
+}
+
+impl<S: AsyncWrite> AsyncWrite for Instrumented<S> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.inner.shutdown()
+    }
+
+    fn write_buf<B: Buf>(&mut self, buf: &mut B) -> Poll<usize, io::Error> {
+        let n = match self.inner.write_buf(buf)? {
+            Async::Ready(n) => n,
+            Async::NotReady => return Ok(Async::NotReady),
+        };
+        self.counters.record(&self.counters.0.written, n);
+        Ok(Async::Ready(n))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn counts_bytes() {
+        let mut stream = Instrumented::new(Cursor::new(b"hello world".to_vec()));
+        let counters = stream.counters();
+        let mut buf = [0; 5];
+        stream.read_exact(&mut buf).unwrap();
+        stream.write_all(b"!!").unwrap();
+        let stats = counters.snapshot();
+        assert_eq!((stats.bytes_read, stats.bytes_written), (5, 2));
+        assert!(stats.last_activity >= stats.created);
+    }
+}
@@ -1,25 +1,43 @@
 use either::Either;
 use futures::{
     stream::{self, IterOk, Once, Stream},
-    Async, Poll,
+    Async, Future, Poll,
 };
 use std::{
+    borrow::Cow,
     io,
-    iter::Cloned,
+    iter::{Cloned, FromIterator},
+    fmt,
+    mem,
     net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, ToSocketAddrs},
+    ops::Deref,
     slice::Iter,
+    str::FromStr,
+    sync::Arc,
     vec,
 };
 
-pub use error::Error;
+pub use error::{Error, HandshakeError, HandshakePhase, ReplyKind};
+pub use relay::relay;
 use error::Result;
 
+/// Emits a `tracing` event at the debug level if the `tracing` feature is enabled.
+macro_rules! debug_event {
+    ($($arg:tt)*) => {
+        #[cfg(feature = "tracing")]
+        tracing::debug!($($arg)*);
+    };
+}
+
 /// A trait for objects which can be converted or resolved to one or more `SocketAddr` values,
 /// which are going to be connected as the the proxy server.
 ///
 /// This trait is similar to `std::net::ToSocketAddrs` but allows asynchronous name resolution.
+///
+/// The stream of addresses is polled by the futures of the connections, so it must be `Send`
+/// for them to be spawned onto a multi-threaded executor.
 pub trait ToProxyAddrs {
-    type Output: Stream<Item = SocketAddr, Error = Error>;
+    type Output: Stream<Item = SocketAddr, Error = Error> + Send;
 
     fn to_proxy_addrs(&self) -> Self::Output;
 }
@@ -55,7 +73,7 @@ impl ToProxyAddrs for str {
     type Output = ProxyAddrsStream;
 
     fn to_proxy_addrs(&self) -> Self::Output {
-        ProxyAddrsStream(Some(self.to_socket_addrs()))
+        ProxyAddrsStream::new(self, None)
     }
 }
 
@@ -63,7 +81,59 @@ impl<'a> ToProxyAddrs for (&'a str, u16) {
     type Output = ProxyAddrsStream;
 
     fn to_proxy_addrs(&self) -> Self::Output {
-        ProxyAddrsStream(Some(self.to_socket_addrs()))
+        ProxyAddrsStream::new(self.0, Some(self.1))
+    }
+}
+
+impl ToProxyAddrs for String {
+    type Output = ProxyAddrsStream;
+
+    fn to_proxy_addrs(&self) -> Self::Output {
+        self.as_str().to_proxy_addrs()
+    }
+}
+
+impl ToProxyAddrs for [String] {
+    type Output = ProxyListStream;
+
+    fn to_proxy_addrs(&self) -> Self::Output {
+        ProxyListStream::new(self)
+    }
+}
+
+impl ToProxyAddrs for [&str] {
+    type Output = ProxyListStream;
+
+    fn to_proxy_addrs(&self) -> Self::Output {
+        ProxyListStream::new(self)
+    }
+}
+
+impl ToProxyAddrs for Vec<String> {
+    type Output = ProxyListStream;
+
+    fn to_proxy_addrs(&self) -> Self::Output {
+        self.as_slice().to_proxy_addrs()
+    }
+}
+
+/// A list of `host:port` strings, e.g. read from a configuration file.
+///
+/// It can be collected from any iterator of strings.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct ProxyList(Vec<String>);
+
+impl<S: Into<String>> FromIterator<S> for ProxyList {
+    fn from_iter<I: IntoIterator<Item = S>>(iter: I) -> ProxyList {
+        ProxyList(iter.into_iter().map(Into::into).collect())
+    }
+}
+
+impl ToProxyAddrs for ProxyList {
+    type Output = ProxyListStream;
+
+    fn to_proxy_addrs(&self) -> Self::Output {
+        self.0.to_proxy_addrs()
     }
 }
 
@@ -75,39 +145,266 @@ impl<'a, T: ToProxyAddrs + ?Sized> ToProxyAddrs for &'a T {
     }
 }
 
-pub struct ProxyAddrsStream(Option<io::Result<vec::IntoIter<SocketAddr>>>);
+/// A stream of the addresses a host name resolves to.
+///
+/// The name is resolved on a separate thread when the stream is first polled, so the event
+/// loop isn't blocked while it's resolved. An IP address is used as is. The stream ends after
+/// yielding an error, so it can be polled again safely.
+#[derive(Debug)]
+pub struct ProxyAddrsStream(ResolveState);
+
+#[derive(Debug)]
+enum ResolveState {
+    Unresolved { host: String, port: Option<u16> },
+    Resolving(dial::Lookup),
+    Resolved(vec::IntoIter<SocketAddr>),
+    Done,
+}
+
+impl ProxyAddrsStream {
+    /// Resolves `host`, which includes the port if `port` is `None`.
+    fn new(host: &str, port: Option<u16>) -> ProxyAddrsStream {
+        ProxyAddrsStream(ResolveState::Unresolved {
+            host: host.to_string(),
+            port,
+        })
+    }
+}
 
 impl Stream for ProxyAddrsStream {
     type Item = SocketAddr;
     type Error = Error;
 
     fn poll(&mut self) -> Poll<Option<SocketAddr>, Self::Error> {
-        if let Some(res) = &mut self.0 {
-            if let Ok(iter) = res {
-                return Ok(Async::Ready(iter.next()));
+        loop {
+            match mem::replace(&mut self.0, ResolveState::Done) {
+                ResolveState::Unresolved { host, port } => {
+                    let addr = match port {
+                        Some(port) => host.parse().map(|ip: IpAddr| SocketAddr::new(ip, port)),
+                        None => host.parse(),
+                    };
+                    self.0 = match addr {
+                        Ok(addr) => ResolveState::Resolved(vec![addr].into_iter()),
+                        Err(_) => ResolveState::Resolving(dial::lookup(&host, port)),
+                    };
+                }
+                ResolveState::Resolving(mut lookup) => match lookup.poll()? {
+                    Async::Ready(addrs) => self.0 = ResolveState::Resolved(addrs.into_iter()),
+                    Async::NotReady => {
+                        self.0 = ResolveState::Resolving(lookup);
+                        return Ok(Async::NotReady);
+                    }
+                },
+                ResolveState::Resolved(mut addrs) => {
+                    let addr = addrs.next();
+                    if addr.is_some() {
+                        self.0 = ResolveState::Resolved(addrs);
+                    }
+                    return Ok(Async::Ready(addr));
+                }
+                ResolveState::Done => return Ok(Async::Ready(None)),
+            }
+        }
+    }
+}
+
+/// A stream of the addresses of a list of hosts, resolved one after another.
+///
+/// A host which fails to resolve is skipped. The error is only yielded if no host of the
+/// list resolved to any address.
+#[derive(Debug)]
+pub struct ProxyListStream {
+    hosts: vec::IntoIter<ProxyAddrsStream>,
+    current: Option<ProxyAddrsStream>,
+    resolved: bool,
+    last_error: Option<Error>,
+}
+
+impl ProxyListStream {
+    fn new<S: AsRef<str>>(hosts: &[S]) -> ProxyListStream {
+        let hosts: Vec<_> = hosts
+            .iter()
+            .map(|host| ProxyAddrsStream::new(host.as_ref(), None))
+            .collect();
+        ProxyListStream {
+            hosts: hosts.into_iter(),
+            current: None,
+            resolved: false,
+            last_error: None,
+        }
+    }
+}
+
+impl Stream for ProxyListStream {
+    type Item = SocketAddr;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<SocketAddr>, Self::Error> {
+        loop {
+            if let Some(current) = &mut self.current {
+                match current.poll() {
+                    Ok(Async::Ready(Some(addr))) => {
+                        self.resolved = true;
+                        return Ok(Async::Ready(Some(addr)));
+                    }
+                    Ok(Async::Ready(None)) => {}
+                    Ok(Async::NotReady) => return Ok(Async::NotReady),
+                    Err(e) => self.last_error = Some(e),
+                }
+            }
+            self.current = self.hosts.next();
+            if self.current.is_none() {
+                return match self.last_error.take() {
+                    Some(e) if !self.resolved => Err(e),
+                    _ => Ok(Async::Ready(None)),
+                };
             }
-            // res is err
-            let _ = self.0.take().unwrap()?;
         }
-        unreachable!()
+    }
+}
+
+/// The protocol spoken by a proxy server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
+pub enum ProxyProtocol {
+    /// SOCKS version 5.
+    #[default]
+    Socks5,
+}
+
+/// Username and password used to authenticate against a proxy server.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+}
+
+impl Credentials {
+    /// Creates credentials for username/password authentication.
+    pub fn new(username: &str, password: &str) -> Credentials {
+        Credentials {
+            username: username.to_string(),
+            password: password.to_string(),
+        }
+    }
+
+    fn authentication(&self) -> Authentication<'_> {
+        Authentication::Password {
+            username: self.username.as_str().into(),
+            password: self.password.as_str().into(),
+        }
+    }
+}
+
+/// How to authenticate against a proxy server, passed to the `connect_with_auth` functions.
+///
+/// New authentication methods are added as new constructors, so that they don't need
+/// functions of their own.
+#[derive(Debug, Clone)]
+pub struct AuthConfig<'a>(Authentication<'a>);
+
+impl AuthConfig<'static> {
+    /// Offers no authentication.
+    pub fn none() -> AuthConfig<'static> {
+        AuthConfig(Authentication::None)
+    }
+}
+
+impl<'a> AuthConfig<'a> {
+    /// Authenticates with a username and a password (RFC 1929), if the proxy server
+    /// requires it.
+    pub fn password(username: &'a str, password: &'a str) -> AuthConfig<'a> {
+        AuthConfig(Authentication::Password {
+            username: username.into(),
+            password: password.into(),
+        })
+    }
+
+    /// Returns the id of the method the proxy server is asked to select.
+    pub fn method(&self) -> AuthMethod {
+        AuthMethod::from(self.0.id())
+    }
+}
+
+impl Default for AuthConfig<'static> {
+    fn default() -> AuthConfig<'static> {
+        AuthConfig::none()
+    }
+}
+
+impl<'a> From<&'a Credentials> for AuthConfig<'a> {
+    fn from(credentials: &'a Credentials) -> AuthConfig<'a> {
+        AuthConfig(credentials.authentication())
+    }
+}
+
+impl<'a> From<Option<&'a Credentials>> for AuthConfig<'a> {
+    fn from(credentials: Option<&'a Credentials>) -> AuthConfig<'a> {
+        match credentials {
+            Some(credentials) => credentials.into(),
+            None => AuthConfig(Authentication::None),
+        }
+    }
+}
+
+/// A proxy server, described by its addresses, the protocol it speaks and the credentials
+/// used to authenticate against it.
+///
+/// Keeping credentials next to the addresses allows every proxy of a multi-proxy setup
+/// to have its own credentials.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ProxySpec<P> {
+    /// Addresses of the proxy server.
+    pub addrs: P,
+    /// Protocol spoken by the proxy server.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub protocol: ProxyProtocol,
+    /// Credentials, or `None` if the proxy server doesn't require authentication.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub auth: Option<Credentials>,
+}
+
+impl<P> ProxySpec<P> {
+    /// Creates a SOCKS5 proxy description without authentication.
+    pub fn new(addrs: P) -> ProxySpec<P> {
+        ProxySpec {
+            addrs,
+            protocol: ProxyProtocol::Socks5,
+            auth: None,
+        }
+    }
+
+    /// Sets the username and password used to authenticate against the proxy server.
+    pub fn with_password(mut self, username: &str, password: &str) -> ProxySpec<P> {
+        self.auth = Some(Credentials::new(username, password));
+        self
+    }
+
+    fn authentication(&self) -> Authentication<'_> {
+        self.auth
+            .as_ref()
+            .map_or(Authentication::None, Credentials::authentication)
     }
 }
 
 /// A SOCKS connection target.
-#[derive(Debug, PartialEq, Eq)]
-pub enum TargetAddr {
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+pub enum TargetAddr<'a> {
     /// Connect to an IP address.
     Ip(SocketAddr),
 
     /// Connect to a fully-qualified domain name.
     ///
     /// The domain name will be passed along to the proxy server and DNS lookup will happen there.
-    Domain(String, u16),
+    Domain(Cow<'a, str>, u16),
 }
 
-impl TargetAddr {
+impl<'a> TargetAddr<'a> {
     /// Creates owned `TargetAddr` by cloning. It is usually used to eliminate the lifetime bound.
-    pub fn to_owned(&self) -> TargetAddr {
+    pub fn to_owned(&self) -> TargetAddr<'static> {
         match self {
             TargetAddr::Ip(addr) => TargetAddr::Ip(*addr),
             TargetAddr::Domain(domain, port) => {
@@ -117,7 +414,7 @@ impl TargetAddr {
     }
 }
 
-impl ToSocketAddrs for TargetAddr {
+impl<'a> ToSocketAddrs for TargetAddr<'a> {
     type Iter = Either<std::option::IntoIter<SocketAddr>, std::vec::IntoIter<SocketAddr>>;
 
     fn to_socket_addrs(&self) -> io::Result<Self::Iter> {
@@ -130,16 +427,79 @@ impl ToSocketAddrs for TargetAddr {
     }
 }
 
+impl<'a> fmt::Display for TargetAddr<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TargetAddr::Ip(addr) => write!(f, "{}", addr),
+            TargetAddr::Domain(domain, port) => write!(f, "{}:{}", domain, port),
+        }
+    }
+}
+
+impl FromStr for TargetAddr<'static> {
+    type Err = Error;
+
+    /// Parses a `TargetAddr` from strings like `example.com:443`, `1.1.1.1:53` or `[::1]:80`.
+    fn from_str(s: &str) -> Result<TargetAddr<'static>> {
+        s.into_target_addr().map(|addr| addr.to_owned())
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'a> serde::Serialize for TargetAddr<'a> {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.collect_str(self)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for TargetAddr<'static> {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<TargetAddr<'static>, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// Generates IP addresses and domains of up to 255 ASCII characters, including empty domains
+/// and control characters, as a proxy server may send back.
+#[cfg(feature = "arbitrary")]
+impl arbitrary::Arbitrary for TargetAddr<'static> {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'_>) -> arbitrary::Result<Self> {
+        let port = u.arbitrary()?;
+        Ok(match u.int_in_range(0..=2u8)? {
+            0 => {
+                let ip = Ipv4Addr::from(u.arbitrary::<[u8; 4]>()?);
+                TargetAddr::Ip(SocketAddrV4::new(ip, port).into())
+            }
+            1 => {
+                let ip = Ipv6Addr::from(u.arbitrary::<[u8; 16]>()?);
+                TargetAddr::Ip(SocketAddrV6::new(ip, port, 0, 0).into())
+            }
+            _ => {
+                let len = u.int_in_range(0..=255usize)?;
+                let domain = u.get_bytes(len)?.iter().map(|&b| char::from(b & 0x7f));
+                TargetAddr::Domain(domain.collect::<String>().into(), port)
+            }
+        })
+    }
+}
+
 /// A trait for objects that can be converted to `TargetAddr`.
-pub trait IntoTargetAddr {
+pub trait IntoTargetAddr<'a> {
     /// Converts the value of self to a `TargetAddr`.
-    fn into_target_addr(self) -> Result<TargetAddr>;
+    fn into_target_addr(self) -> Result<TargetAddr<'a>>;
 }
 
 macro_rules! trivial_impl_into_target_addr {
     ($t: ty) => {
-        impl IntoTargetAddr for $t {
-            fn into_target_addr(self) -> Result<TargetAddr> {
+        impl<'a> IntoTargetAddr<'a> for $t {
+            fn into_target_addr(self) -> Result<TargetAddr<'a>> {
                 Ok(TargetAddr::Ip(SocketAddr::from(self)))
             }
         }
@@ -153,8 +513,14 @@ trivial_impl_into_target_addr!((Ipv6Addr, u16));
 trivial_impl_into_target_addr!(SocketAddrV4);
 trivial_impl_into_target_addr!(SocketAddrV6);
 
-impl IntoTargetAddr for (&str, u16) {
-    fn into_target_addr(self) -> Result<TargetAddr> {
+impl<'a> IntoTargetAddr<'a> for TargetAddr<'a> {
+    fn into_target_addr(self) -> Result<TargetAddr<'a>> {
+        Ok(self)
+    }
+}
+
+impl<'a> IntoTargetAddr<'a> for (&'a str, u16) {
+    fn into_target_addr(self) -> Result<TargetAddr<'a>> {
         // Try IP address first
         if let Ok(addr) = self.0.parse::<IpAddr>() {
             return (addr, self.1).into_target_addr();
@@ -171,8 +537,8 @@ impl IntoTargetAddr for (&str, u16) {
     }
 }
 
-impl IntoTargetAddr for &str {
-    fn into_target_addr(self) -> Result<TargetAddr> {
+impl<'a> IntoTargetAddr<'a> for &'a str {
+    fn into_target_addr(self) -> Result<TargetAddr<'a>> {
         // Try IP address first
         if let Ok(addr) = self.parse::<SocketAddr>() {
             return addr.into_target_addr();
@@ -190,8 +556,8 @@ impl IntoTargetAddr for &str {
     }
 }
 
-impl IntoTargetAddr for (String, u16) {
-    fn into_target_addr(self) -> Result<TargetAddr> {
+impl IntoTargetAddr<'static> for (String, u16) {
+    fn into_target_addr(self) -> Result<TargetAddr<'static>> {
         let addr = (self.0.as_str(), self.1).into_target_addr()?;
         if let TargetAddr::Ip(addr) = addr {
             Ok(TargetAddr::Ip(addr))
@@ -201,36 +567,218 @@ impl IntoTargetAddr for (String, u16) {
     }
 }
 
-impl<T> IntoTargetAddr for &T
+impl<'a, T> IntoTargetAddr<'a> for &'a T
 where
-    T: IntoTargetAddr + Copy,
+    T: IntoTargetAddr<'a> + Copy,
 {
-    fn into_target_addr(self) -> Result<TargetAddr> {
+    fn into_target_addr(self) -> Result<TargetAddr<'a>> {
         (*self).into_target_addr()
     }
 }
 
+/// Returns the well-known port of the given URI scheme.
+#[cfg(any(feature = "url", feature = "http"))]
+fn default_port(scheme: &str) -> Option<u16> {
+    match scheme {
+        "http" | "ws" => Some(80),
+        "https" | "wss" => Some(443),
+        "ftp" => Some(21),
+        _ => None,
+    }
+}
+
+/// Extracts the host and the port of a URL. When the URL has no explicit port,
+/// the default port of its scheme is used.
+#[cfg(feature = "url")]
+impl<'a> IntoTargetAddr<'a> for &'a url::Url {
+    fn into_target_addr(self) -> Result<TargetAddr<'a>> {
+        let port = self
+            .port()
+            .or_else(|| default_port(self.scheme()))
+            .ok_or(Error::InvalidTargetAddress("unknown port"))?;
+        match self.host() {
+            Some(url::Host::Domain(domain)) => (domain, port).into_target_addr(),
+            Some(url::Host::Ipv4(ip)) => (ip, port).into_target_addr(),
+            Some(url::Host::Ipv6(ip)) => (ip, port).into_target_addr(),
+            None => Err(Error::InvalidTargetAddress("missing host")),
+        }
+    }
+}
+
+/// Extracts the host and the port of a URI. When the URI has no explicit port,
+/// the default port of its scheme is used.
+#[cfg(feature = "http")]
+impl<'a> IntoTargetAddr<'a> for &'a http::Uri {
+    fn into_target_addr(self) -> Result<TargetAddr<'a>> {
+        let host = self
+            .host()
+            .ok_or(Error::InvalidTargetAddress("missing host"))?;
+        // IPv6 hosts may keep their brackets
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let port = self
+            .port_u16()
+            .or_else(|| self.scheme_str().and_then(default_port))
+            .ok_or(Error::InvalidTargetAddress("unknown port"))?;
+        (host, port).into_target_addr()
+    }
+}
+
+/// An authentication method selected by a SOCKS5 server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AuthMethod {
+    /// No authentication required (`0x00`).
+    None,
+    /// GSSAPI (`0x01`).
+    Gssapi,
+    /// Username/password (`0x02`).
+    Password,
+    /// Any other method, identified by its id.
+    Other(u8),
+}
+
+impl From<u8> for AuthMethod {
+    fn from(id: u8) -> AuthMethod {
+        match id {
+            consts::METHOD_NO_AUTH => AuthMethod::None,
+            consts::METHOD_GSSAPI => AuthMethod::Gssapi,
+            consts::METHOD_PASSWORD => AuthMethod::Password,
+            id => AuthMethod::Other(id),
+        }
+    }
+}
+
+impl AuthMethod {
+    /// Returns the id of the method.
+    pub fn id(self) -> u8 {
+        match self {
+            AuthMethod::None => consts::METHOD_NO_AUTH,
+            AuthMethod::Gssapi => consts::METHOD_GSSAPI,
+            AuthMethod::Password => consts::METHOD_PASSWORD,
+            AuthMethod::Other(id) => id,
+        }
+    }
+}
+
 /// Authentication methods
-#[derive(Debug)]
-enum Authentication {
+#[derive(Debug, Clone)]
+enum Authentication<'a> {
     Password {
-        username: String,
-        password: String,
+        username: SharedStr<'a>,
+        password: SharedStr<'a>,
     },
     None,
 }
 
-impl Authentication {
+/// A string borrowed from the caller, or shared by the clones of an `Authentication` so that
+/// retries and listeners don't copy the credentials.
+#[derive(Debug, Clone)]
+enum SharedStr<'a> {
+    Borrowed(&'a str),
+    Shared(Arc<str>),
+}
+
+impl<'a> SharedStr<'a> {
+    fn to_owned(&self) -> SharedStr<'static> {
+        match self {
+            SharedStr::Borrowed(s) => SharedStr::Shared(Arc::from(*s)),
+            SharedStr::Shared(s) => SharedStr::Shared(s.clone()),
+        }
+    }
+}
+
+impl<'a> From<&'a str> for SharedStr<'a> {
+    fn from(s: &'a str) -> SharedStr<'a> {
+        SharedStr::Borrowed(s)
+    }
+}
+
+impl<'a> Deref for SharedStr<'a> {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        match self {
+            SharedStr::Borrowed(s) => s,
+            SharedStr::Shared(s) => s,
+        }
+    }
+}
+
+impl<'a> Authentication<'a> {
+    fn to_owned(&self) -> Authentication<'static> {
+        match self {
+            Authentication::Password { username, password } => Authentication::Password {
+                username: username.to_owned(),
+                password: password.to_owned(),
+            },
+            Authentication::None => Authentication::None,
+        }
+    }
+
     fn id(&self) -> u8 {
         match self {
-            Authentication::Password { .. } => 0x02,
-            Authentication::None => 0x00,
+            Authentication::Password { .. } => consts::METHOD_PASSWORD,
+            Authentication::None => consts::METHOD_NO_AUTH,
         }
     }
+
+    fn validate(&self) -> Result<()> {
+        if let Authentication::Password { username, password } = self {
+            if !(1..=255).contains(&username.len()) {
+                Err(Error::InvalidAuthValues(
+                    "username length should between 1 to 255",
+                ))?
+            }
+            if !(1..=255).contains(&password.len()) {
+                Err(Error::InvalidAuthValues(
+                    "password length should between 1 to 255",
+                ))?
+            }
+        }
+        Ok(())
+    }
 }
 
+pub mod abort;
+#[cfg(feature = "blocking")]
+pub mod blocking;
+mod bucket;
+mod buffer;
+mod codec;
+pub mod connector;
+pub mod consts;
+mod dial;
+pub mod dialer;
 mod error;
+pub mod fallback;
+pub mod first_ok;
+pub mod forward;
+#[cfg(feature = "fuzz")]
+pub mod fuzz;
+pub mod group;
+pub mod instrument;
+mod limit;
+pub mod mux;
+pub mod observer;
+pub mod policy;
+pub mod pool;
+pub mod proxy_protocol;
+pub mod reconnect;
+pub mod relay;
+pub mod resolve;
+#[cfg(target_os = "linux")]
+mod splice;
 pub mod tcp;
+#[cfg(any(test, feature = "test-util"))]
+pub mod test_util;
+pub mod throttle;
+#[cfg(any(test, feature = "test-util"))]
+pub mod trace;
+#[cfg(target_os = "linux")]
+pub mod transparent;
+#[cfg(feature = "udp")]
+pub mod udp;
+#[cfg(feature = "websocket")]
+pub mod websocket;
 
 #[cfg(test)]
 mod tests {
@@ -267,9 +815,9 @@ mod tests {
         Ok(())
     }
 
-    fn into_target_addr<T>(t: T) -> Result<TargetAddr>
+    fn into_target_addr<'a, T>(t: T) -> Result<TargetAddr<'a>>
     where
-        T: IntoTargetAddr,
+        T: IntoTargetAddr<'a>,
     {
         t.into_target_addr()
     }
@@ -313,7 +861,7 @@ mod tests {
         let domain = "www.example.com:80";
         let res = into_target_addr(domain)?;
         assert_eq!(
-            TargetAddr::Domain("www.example.com".to_string(), 80),
+            TargetAddr::Domain("www.example.com".into(), 80),
             res
         );
         Ok(())
@@ -324,7 +872,7 @@ mod tests {
         let domain = "www.example.com";
         let res = into_target_addr((domain, 80))?;
         assert_eq!(
-            TargetAddr::Domain("www.example.com".to_string(), 80),
+            TargetAddr::Domain("www.example.com".into(), 80),
             res
         );
         Ok(())
@@ -338,6 +886,70 @@ mod tests {
         assert!(into_target_addr((domain.as_str(), 80)).is_err());
     }
 
+    #[test]
+    fn displays_target_addr() {
+        let addr = TargetAddr::Ip(SocketAddr::from(([1, 1, 1, 1], 443)));
+        assert_eq!(addr.to_string(), "1.1.1.1:443");
+        let addr = TargetAddr::Ip(SocketAddr::from((Ipv6Addr::LOCALHOST, 80)));
+        assert_eq!(addr.to_string(), "[::1]:80");
+        let addr = TargetAddr::Domain("example.com".into(), 443);
+        assert_eq!(addr.to_string(), "example.com:443");
+    }
+
+    #[test]
+    fn parses_target_addr_from_display_output() -> Result<()> {
+        let addrs = vec![
+            TargetAddr::Ip(SocketAddr::from(([1, 1, 1, 1], 443))),
+            TargetAddr::Ip(SocketAddr::from((Ipv6Addr::LOCALHOST, 80))),
+            TargetAddr::Domain("example.com".into(), 443),
+        ];
+        for addr in addrs {
+            assert_eq!(addr.to_string().parse::<TargetAddr>()?, addr);
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serializes_target_addr_as_string() -> Result<()> {
+        let addr = TargetAddr::Domain("example.com".into(), 443);
+        let json = serde_json::to_string(&addr).unwrap();
+        assert_eq!(json, "\"example.com:443\"");
+        let parsed: TargetAddr = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed, addr);
+        assert!(serde_json::from_str::<TargetAddr>("\"example.com\"").is_err());
+        Ok(())
+    }
+
+    #[cfg(feature = "url")]
+    #[test]
+    fn converts_url_to_target_addr() -> Result<()> {
+        let url = url::Url::parse("https://www.example.com/index.html").unwrap();
+        let res = into_target_addr(&url)?;
+        assert_eq!(TargetAddr::Domain("www.example.com".into(), 443), res);
+        let url = url::Url::parse("http://[::1]:8080/").unwrap();
+        let res = into_target_addr(&url)?;
+        assert_eq!(
+            TargetAddr::Ip(SocketAddr::from((Ipv6Addr::LOCALHOST, 8080))),
+            res
+        );
+        Ok(())
+    }
+
+    #[cfg(feature = "http")]
+    #[test]
+    fn converts_uri_to_target_addr() -> Result<()> {
+        let uri: http::Uri = "http://www.example.com/index.html".parse().unwrap();
+        let res = into_target_addr(&uri)?;
+        assert_eq!(TargetAddr::Domain("www.example.com".into(), 80), res);
+        let uri: http::Uri = "https://1.1.1.1:8443/".parse().unwrap();
+        let res = into_target_addr(&uri)?;
+        assert_eq!(TargetAddr::Ip(SocketAddr::from(([1, 1, 1, 1], 8443))), res);
+        let uri: http::Uri = "/relative".parse().unwrap();
+        assert!(into_target_addr(&uri).is_err());
+        Ok(())
+    }
+
     #[test]
     fn addr_with_invalid_port_to_target_addr_should_fail() {
         let addr = "[ffff::1]:65536";
@@ -345,4 +957,62 @@ mod tests {
         let addr = "www.example.com:65536";
         assert!(into_target_addr(addr).is_err());
     }
+
+    #[test]
+    fn ends_proxy_addrs_after_errors() {
+        let mut addrs = "not an address".to_proxy_addrs().wait();
+        assert!(addrs.next().unwrap().is_err());
+        assert!(addrs.next().is_none());
+    }
+
+    #[test]
+    fn resolves_proxy_hosts_off_the_event_loop() -> Result<()> {
+        // IP addresses are used without a lookup, so they're ready outside of a task.
+        let addr = SocketAddr::from(([127, 0, 0, 1], 1080));
+        let mut addrs = ("127.0.0.1", 1080).to_proxy_addrs();
+        assert_eq!(addrs.poll()?, Async::Ready(Some(addr)));
+        let res = to_proxy_addrs("localhost:1080")?;
+        assert!(!res.is_empty());
+        for addr in res {
+            assert!(addr.ip().is_loopback());
+            assert_eq!(addr.port(), 1080);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn converts_host_lists_to_proxy_addrs() -> Result<()> {
+        let hosts = vec!["not an address".to_string(), "127.0.0.1:1080".to_string()];
+        let res = to_proxy_addrs(hosts)?;
+        assert_eq!(&res[..], &[SocketAddr::from(([127, 0, 0, 1], 1080))]);
+        let hosts: ProxyList = ["127.0.0.1:1080", "[::1]:1080"].iter().cloned().collect();
+        assert_eq!(to_proxy_addrs(hosts)?.len(), 2);
+        assert!(to_proxy_addrs(&["not an address"][..]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn shares_owned_credentials() {
+        let auth = Authentication::Password {
+            username: "user".into(),
+            password: "pass".into(),
+        };
+        let owned = auth.to_owned();
+        match (owned.clone(), owned) {
+            (
+                Authentication::Password {
+                    username: SharedStr::Shared(first),
+                    ..
+                },
+                Authentication::Password {
+                    username: SharedStr::Shared(second),
+                    ..
+                },
+            ) => {
+                assert!(Arc::ptr_eq(&first, &second));
+                assert_eq!(&*first, "user");
+            }
+            _ => panic!("credentials aren't shared"),
+        }
+    }
 }
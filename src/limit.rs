@@ -0,0 +1,193 @@
+use crate::{bucket::TokenBucket, Error};
+use futures::{task, try_ready, Async, Future, Poll};
+use std::{
+    io,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio_timer::Delay;
+
+/// Limits on the handshakes run through a connector.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct Limits {
+    pub(crate) max_in_flight: Option<usize>,
+    pub(crate) per_second: Option<u32>,
+}
+
+impl Limits {
+    pub(crate) fn is_unlimited(&self) -> bool {
+        self.max_in_flight.is_none() && self.per_second.is_none()
+    }
+}
+
+/// Statistics of the handshake limits of a connector.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct LimiterStats {
+    /// The number of handshakes currently running.
+    pub in_flight: usize,
+    /// The number of handshakes which were allowed to start.
+    pub started: u64,
+    /// The total time the started handshakes waited for the limits.
+    pub total_wait: Duration,
+}
+
+/// A semaphore for the handshakes in flight, combined with a token bucket for the
+/// handshakes per second.
+#[derive(Debug)]
+pub(crate) struct Limiter {
+    limits: Limits,
+    state: Mutex<State>,
+}
+
+#[derive(Debug)]
+struct State {
+    stats: LimiterStats,
+    bucket: Option<TokenBucket>,
+    waiters: Vec<task::Task>,
+}
+
+impl Limiter {
+    pub(crate) fn new(limits: Limits) -> Limiter {
+        Limiter {
+            limits,
+            state: Mutex::new(State {
+                stats: LimiterStats::default(),
+                bucket: limits
+                    .per_second
+                    .map(|rate| TokenBucket::new(f64::from(rate))),
+                waiters: Vec::new(),
+            }),
+        }
+    }
+
+    pub(crate) fn stats(&self) -> LimiterStats {
+        self.state.lock().unwrap().stats
+    }
+
+    /// Polls for a permit to start a handshake. `queued_since` is when the handshake started
+    /// waiting, and `timer` keeps the delay until the next token when the rate is exceeded.
+    pub(crate) fn poll_acquire(
+        limiter: &Arc<Limiter>,
+        queued_since: Instant,
+        timer: &mut Option<Delay>,
+    ) -> Poll<Permit, Error> {
+        loop {
+            if let Some(delay) = timer {
+                try_ready!(delay.poll().map_err(io::Error::other));
+                *timer = None;
+            }
+            let now = Instant::now();
+            let mut state = limiter.state.lock().unwrap();
+            if let Some(max) = limiter.limits.max_in_flight {
+                if state.stats.in_flight >= max {
+                    // A handshake is polled again while it waits, but only notified once.
+                    if !state.waiters.iter().any(task::Task::will_notify_current) {
+                        state.waiters.push(task::current());
+                    }
+                    return Ok(Async::NotReady);
+                }
+            }
+            if let Some(bucket) = &mut state.bucket {
+                bucket.refill(now);
+                if bucket.tokens() < 1.0 {
+                    *timer = Some(Delay::new(now + bucket.wait(1.0)));
+                    continue;
+                }
+                bucket.consume(1.0);
+            }
+            state.stats.in_flight += 1;
+            state.stats.started += 1;
+            state.stats.total_wait += now.duration_since(queued_since);
+            return Ok(Async::Ready(Permit(limiter.clone())));
+        }
+    }
+}
+
+/// A permit to run a handshake, released when dropped.
+#[derive(Debug)]
+pub(crate) struct Permit(Arc<Limiter>);
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let mut state = self.0.state.lock().unwrap();
+        state.stats.in_flight -= 1;
+        for waiter in state.waiters.drain(..) {
+            waiter.notify();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future;
+    use tokio::runtime::current_thread::Runtime;
+
+    #[test]
+    fn counts_handshakes_in_flight() {
+        let limiter = Arc::new(Limiter::new(Limits {
+            max_in_flight: Some(2),
+            per_second: None,
+        }));
+        let mut timer = None;
+        let mut acquire = || {
+            future::poll_fn(|| Limiter::poll_acquire(&limiter, Instant::now(), &mut timer)).wait()
+        };
+        let first = acquire().unwrap();
+        let second = acquire().unwrap();
+        assert_eq!(limiter.stats().in_flight, 2);
+        drop(first);
+        drop(second);
+        let stats = limiter.stats();
+        assert_eq!((stats.in_flight, stats.started), (0, 2));
+    }
+
+    #[test]
+    fn registers_each_waiter_once() {
+        let limiter = Arc::new(Limiter::new(Limits {
+            max_in_flight: Some(1),
+            per_second: None,
+        }));
+        let mut timer = None;
+        let _permit =
+            future::poll_fn(|| Limiter::poll_acquire(&limiter, Instant::now(), &mut timer))
+                .wait()
+                .unwrap();
+        future::lazy(|| {
+            for _ in 0..3 {
+                let poll = Limiter::poll_acquire(&limiter, Instant::now(), &mut timer);
+                assert!(poll.unwrap().is_not_ready());
+            }
+            Ok::<_, ()>(())
+        })
+        .wait()
+        .unwrap();
+        assert_eq!(limiter.state.lock().unwrap().waiters.len(), 1);
+    }
+
+    #[test]
+    fn limits_handshakes_per_second() {
+        let limiter = Arc::new(Limiter::new(Limits {
+            max_in_flight: None,
+            per_second: Some(20),
+        }));
+        let mut runtime = Runtime::new().unwrap();
+        let mut timer = None;
+        let mut acquire = |queued_since| {
+            runtime
+                .block_on(future::poll_fn(|| {
+                    Limiter::poll_acquire(&limiter, queued_since, &mut timer)
+                }))
+                .unwrap()
+        };
+        // The bucket starts full.
+        for _ in 0..20 {
+            acquire(Instant::now());
+        }
+        assert!(limiter.stats().total_wait < Duration::from_millis(10));
+        let queued_since = Instant::now();
+        acquire(queued_since);
+        assert!(queued_since.elapsed() >= Duration::from_millis(40));
+        assert!(limiter.stats().total_wait >= Duration::from_millis(40));
+    }
+}
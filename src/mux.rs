@@ -0,0 +1,535 @@
+//! Multiplexing of many logical streams over a single proxied connection, for the proxy
+//! providers billing per connection.
+//!
+//! The streams are framed with version 1 of the smux protocol, so the other end of the
+//! connection must be a cooperating smux server, e.g. one relaying each stream to a fixed
+//! target.
+
+use crate::Error;
+use bytes::{Buf, BufMut, Bytes, BytesMut, IntoBuf};
+use futures::{
+    task::{self, Task},
+    try_ready, Async, Future, Poll,
+};
+use std::{
+    collections::HashMap,
+    fmt,
+    io::{self, Read, Write},
+    sync::{Arc, Mutex, MutexGuard},
+};
+use tokio_io::{AsyncRead, AsyncWrite};
+
+const VERSION: u8 = 1;
+const CMD_SYN: u8 = 0;
+const CMD_FIN: u8 = 1;
+const CMD_PSH: u8 = 2;
+const CMD_NOP: u8 = 3;
+const HEADER_LEN: usize = 8;
+const MAX_FRAME_SIZE: usize = 32 * 1024;
+// The number of bytes waiting to be written to the connection above which the streams
+// stop accepting writes.
+const MAX_PENDING: usize = 256 * 1024;
+
+/// A handle opening logical streams over a connection shared with a `MuxConnection`.
+///
+/// It's cheap to clone.
+pub struct MuxConnector {
+    shared: Arc<Mutex<Shared>>,
+}
+
+/// A `Future` driving the I/O of a multiplexed connection, which must be spawned for the
+/// streams to make progress.
+///
+/// It resolves once the other end closed the connection, or once all the `MuxConnector`s
+/// and the streams were dropped.
+pub struct MuxConnection<S> {
+    stream: S,
+    shared: Arc<Mutex<Shared>>,
+    read_buf: BytesMut,
+}
+
+/// A logical stream opened with `MuxConnector::open`.
+///
+/// Dropping it closes the stream.
+pub struct MuxStream {
+    id: u32,
+    shared: Arc<Mutex<Shared>>,
+    fin_sent: bool,
+}
+
+struct Shared {
+    streams: HashMap<u32, Slot>,
+    next_id: u32,
+    // The frames waiting to be written to the connection.
+    pending: BytesMut,
+    writers: Vec<Task>,
+    connection: Option<Task>,
+    // The number of `MuxConnector`s and streams alive.
+    handles: usize,
+    // Set once the connection was closed, to the kind of the error which closed it if any.
+    closed: Option<Option<io::ErrorKind>>,
+}
+
+#[derive(Default)]
+struct Slot {
+    buf: BytesMut,
+    fin: bool,
+    reader: Option<Task>,
+}
+
+impl MuxConnector {
+    /// Multiplexes `stream`, usually a `Socks5Stream` to a smux server.
+    pub fn new<S>(stream: S) -> (MuxConnector, MuxConnection<S>)
+    where
+        S: AsyncRead + AsyncWrite,
+    {
+        let shared = Arc::new(Mutex::new(Shared {
+            streams: HashMap::new(),
+            next_id: 1,
+            pending: BytesMut::new(),
+            writers: Vec::new(),
+            connection: None,
+            handles: 1,
+            closed: None,
+        }));
+        let connection = MuxConnection {
+            stream,
+            shared: shared.clone(),
+            read_buf: BytesMut::new(),
+        };
+        (MuxConnector { shared }, connection)
+    }
+
+    /// Opens a new logical stream.
+    ///
+    /// # Error
+    ///
+    /// It fails if the connection was closed.
+    pub fn open(&self) -> io::Result<MuxStream> {
+        let mut shared = lock(&self.shared);
+        if shared.closed.is_some() {
+            return Err(io::ErrorKind::NotConnected.into());
+        }
+        let id = shared.next_id;
+        shared.next_id = shared.next_id.wrapping_add(2);
+        shared.streams.insert(id, Slot::default());
+        shared.handles += 1;
+        shared.send(CMD_SYN, id, &[]);
+        Ok(MuxStream {
+            id,
+            shared: self.shared.clone(),
+            fin_sent: false,
+        })
+    }
+
+    /// Returns the number of open streams.
+    pub fn stream_count(&self) -> usize {
+        lock(&self.shared).streams.len()
+    }
+}
+
+impl Clone for MuxConnector {
+    fn clone(&self) -> MuxConnector {
+        lock(&self.shared).handles += 1;
+        MuxConnector {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl Drop for MuxConnector {
+    fn drop(&mut self) {
+        lock(&self.shared).release();
+    }
+}
+
+impl fmt::Debug for MuxConnector {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("MuxConnector")
+            .field("streams", &self.stream_count())
+            .finish()
+    }
+}
+
+fn lock(shared: &Mutex<Shared>) -> MutexGuard<'_, Shared> {
+    shared.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+impl Shared {
+    /// Queues a frame and wakes the connection up to write it.
+    fn send(&mut self, cmd: u8, id: u32, payload: &[u8]) {
+        encode_frame(cmd, id, payload, &mut self.pending);
+        if let Some(connection) = self.connection.take() {
+            connection.notify();
+        }
+    }
+
+    /// Drops a handle, waking the connection up to close it if it was the last one.
+    fn release(&mut self) {
+        self.handles -= 1;
+        if self.handles == 0 {
+            if let Some(connection) = self.connection.take() {
+                connection.notify();
+            }
+        }
+    }
+
+    fn close(&mut self, error: Option<io::ErrorKind>) {
+        self.closed = Some(error);
+        for slot in self.streams.values_mut() {
+            if let Some(reader) = slot.reader.take() {
+                reader.notify();
+            }
+        }
+        for writer in self.writers.drain(..) {
+            writer.notify();
+        }
+    }
+
+    /// Returns the error the streams fail with once the connection was closed.
+    fn closed_error(&self) -> Option<io::Error> {
+        self.closed
+            .map(|e| e.unwrap_or(io::ErrorKind::BrokenPipe).into())
+    }
+}
+
+/// Appends a frame to `buf`.
+fn encode_frame(cmd: u8, id: u32, payload: &[u8], buf: &mut BytesMut) {
+    buf.reserve(HEADER_LEN + payload.len());
+    buf.put_u8(VERSION);
+    buf.put_u8(cmd);
+    buf.put_u16_le(payload.len() as u16);
+    buf.put_u32_le(id);
+    buf.put_slice(payload);
+}
+
+/// Takes a complete frame off `buf`, returning its command, its stream and its payload.
+fn decode_frame(buf: &mut BytesMut) -> io::Result<Option<(u8, u32, Bytes)>> {
+    if buf.len() < HEADER_LEN {
+        return Ok(None);
+    }
+    let mut header = (&buf[..HEADER_LEN]).into_buf();
+    if header.get_u8() != VERSION {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "unsupported smux version",
+        ))?
+    }
+    let cmd = header.get_u8();
+    let len = header.get_u16_le() as usize;
+    let id = header.get_u32_le();
+    if buf.len() < HEADER_LEN + len {
+        return Ok(None);
+    }
+    buf.advance(HEADER_LEN);
+    Ok(Some((cmd, id, buf.split_to(len).freeze())))
+}
+
+impl<S> MuxConnection<S>
+where
+    S: AsyncRead + AsyncWrite,
+{
+    /// Reads the frames from the connection, dispatching them to the streams.
+    fn poll_read(&mut self) -> Poll<(), io::Error> {
+        loop {
+            while let Some((cmd, id, payload)) = decode_frame(&mut self.read_buf)? {
+                let mut shared = lock(&self.shared);
+                match cmd {
+                    CMD_PSH | CMD_FIN => {
+                        if let Some(slot) = shared.streams.get_mut(&id) {
+                            if cmd == CMD_FIN {
+                                slot.fin = true;
+                            } else if !slot.fin {
+                                slot.buf.extend_from_slice(&payload);
+                            }
+                            if let Some(reader) = slot.reader.take() {
+                                reader.notify();
+                            }
+                        }
+                    }
+                    // Streams opened by the other end aren't accepted.
+                    CMD_SYN => {
+                        if !shared.streams.contains_key(&id) {
+                            shared.send(CMD_FIN, id, &[]);
+                        }
+                    }
+                    CMD_NOP => {}
+                    _ => Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "unknown smux command",
+                    ))?,
+                }
+            }
+            self.read_buf.reserve(HEADER_LEN + MAX_FRAME_SIZE);
+            if try_ready!(AsyncRead::read_buf(&mut self.stream, &mut self.read_buf)) == 0 {
+                return Ok(Async::Ready(()));
+            }
+        }
+    }
+
+    /// Writes the pending frames to the connection.
+    fn poll_write(&mut self) -> Poll<(), io::Error> {
+        loop {
+            let mut shared = lock(&self.shared);
+            if shared.pending.is_empty() {
+                break;
+            }
+            let n = try_ready!(self.stream.poll_write(&shared.pending));
+            if n == 0 {
+                return Err(io::ErrorKind::WriteZero.into());
+            }
+            shared.pending.advance(n);
+            if shared.pending.len() < MAX_PENDING {
+                for writer in shared.writers.drain(..) {
+                    writer.notify();
+                }
+            }
+        }
+        self.stream.poll_flush()
+    }
+
+    fn poll_io(&mut self) -> Poll<(), io::Error> {
+        lock(&self.shared).connection = Some(task::current());
+        if self.poll_read()?.is_ready() {
+            return Ok(Async::Ready(()));
+        }
+        try_ready!(self.poll_write());
+        if lock(&self.shared).handles == 0 {
+            try_ready!(self.stream.shutdown());
+            return Ok(Async::Ready(()));
+        }
+        Ok(Async::NotReady)
+    }
+}
+
+impl<S> Future for MuxConnection<S>
+where
+    S: AsyncRead + AsyncWrite,
+{
+    type Item = ();
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<(), Error> {
+        match self.poll_io() {
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Ok(Async::Ready(())) => {
+                debug_event!("multiplexed connection closed");
+                lock(&self.shared).close(None);
+                Ok(Async::Ready(()))
+            }
+            Err(e) => {
+                lock(&self.shared).close(Some(e.kind()));
+                Err(e)?
+            }
+        }
+    }
+}
+
+impl<S> fmt::Debug for MuxConnection<S>
+where
+    S: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("MuxConnection")
+            .field("stream", &self.stream)
+            .finish()
+    }
+}
+
+impl MuxStream {
+    /// Returns the identifier of the stream in the connection.
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+}
+
+impl Read for MuxStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut shared = lock(&self.shared);
+        let error = shared.closed_error();
+        let slot = shared.streams.get_mut(&self.id).unwrap();
+        if !slot.buf.is_empty() {
+            let n = buf.len().min(slot.buf.len());
+            buf[..n].copy_from_slice(&slot.buf.split_to(n));
+            return Ok(n);
+        }
+        if slot.fin {
+            return Ok(0);
+        }
+        if let Some(e) = error {
+            return Err(e);
+        }
+        slot.reader = Some(task::current());
+        Err(io::ErrorKind::WouldBlock.into())
+    }
+}
+
+impl Write for MuxStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut shared = lock(&self.shared);
+        if let Some(e) = shared.closed_error() {
+            return Err(e);
+        }
+        if self.fin_sent {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+        if shared.pending.len() >= MAX_PENDING {
+            shared.writers.push(task::current());
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        let n = buf.len().min(MAX_FRAME_SIZE);
+        shared.send(CMD_PSH, self.id, &buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let mut shared = lock(&self.shared);
+        if shared.pending.is_empty() || shared.closed.is_some() {
+            return Ok(());
+        }
+        shared.writers.push(task::current());
+        Err(io::ErrorKind::WouldBlock.into())
+    }
+}
+
+impl AsyncRead for MuxStream {}
+
+impl AsyncWrite for MuxStream {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        if !self.fin_sent {
+            self.fin_sent = true;
+            lock(&self.shared).send(CMD_FIN, self.id, &[]);
+        }
+        Ok(Async::Ready(()))
+    }
+}
+
+impl Drop for MuxStream {
+    fn drop(&mut self) {
+        let mut shared = lock(&self.shared);
+        shared.streams.remove(&self.id);
+        if !self.fin_sent && shared.closed.is_none() {
+            shared.send(CMD_FIN, self.id, &[]);
+        }
+        shared.release();
+    }
+}
+
+impl fmt::Debug for MuxStream {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("MuxStream").field("id", &self.id).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future;
+    use std::{
+        net::{Shutdown, TcpListener},
+        thread,
+    };
+    use tokio::runtime::current_thread::Runtime;
+    use tokio_tcp::TcpStream;
+
+    fn frame(cmd: u8, id: u32, payload: &[u8]) -> Vec<u8> {
+        let mut buf = BytesMut::new();
+        encode_frame(cmd, id, payload, &mut buf);
+        buf.to_vec()
+    }
+
+    /// Multiplexes a loopback connection, whose other end is handed to `server`.
+    fn connect<F, T>(runtime: &mut Runtime, server: F) -> (MuxConnector, thread::JoinHandle<T>)
+    where
+        F: FnOnce(std::net::TcpStream) -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || server(listener.accept().unwrap().0));
+        let stream = runtime.block_on(TcpStream::connect(&addr)).unwrap();
+        let (connector, connection) = MuxConnector::new(stream);
+        runtime.spawn(connection.map_err(|e| panic!("{}", e)));
+        (connector, server)
+    }
+
+    #[test]
+    fn round_trips_frames() {
+        let mut buf = BytesMut::new();
+        encode_frame(CMD_PSH, 3, b"hello", &mut buf);
+        encode_frame(CMD_FIN, 3, &[], &mut buf);
+        assert_eq!(&buf[..8], &[1, 2, 5, 0, 3, 0, 0, 0]);
+        let mut partial = BytesMut::from(&buf[..10]);
+        assert_eq!(decode_frame(&mut partial).unwrap(), None);
+        assert_eq!(
+            decode_frame(&mut buf).unwrap(),
+            Some((CMD_PSH, 3, Bytes::from(&b"hello"[..])))
+        );
+        assert_eq!(
+            decode_frame(&mut buf).unwrap(),
+            Some((CMD_FIN, 3, Bytes::new()))
+        );
+        assert!(buf.is_empty());
+        let mut invalid = BytesMut::from(&[2, 0, 0, 0, 1, 0, 0, 0][..]);
+        assert!(decode_frame(&mut invalid).is_err());
+    }
+
+    #[test]
+    fn exchanges_the_bytes_of_the_streams() {
+        let mut runtime = Runtime::new().unwrap();
+        let (connector, server) = connect(&mut runtime, |mut conn| {
+            let mut received = vec![0; 37];
+            conn.read_exact(&mut received).unwrap();
+            conn.write_all(&frame(CMD_PSH, 1, b"world")).unwrap();
+            conn.write_all(&frame(CMD_FIN, 1, &[])).unwrap();
+            conn.read_to_end(&mut received).unwrap();
+            received
+        });
+        let stream = connector.open().unwrap();
+        assert_eq!(stream.id(), 1);
+        // Dropping a stream closes it.
+        drop(connector.open().unwrap());
+        assert_eq!(connector.stream_count(), 1);
+        let response = runtime
+            .block_on(
+                tokio_io::io::write_all(stream, b"hello")
+                    .and_then(|(stream, _)| tokio_io::io::read_to_end(stream, Vec::new()))
+                    .and_then(|(mut stream, response)| {
+                        future::poll_fn(move || stream.shutdown()).map(|()| response)
+                    }),
+            )
+            .unwrap();
+        assert_eq!(&response[..], b"world");
+        assert_eq!(connector.stream_count(), 0);
+        // The connection is closed once the last handle is dropped.
+        drop(connector);
+        runtime.run().unwrap();
+        let expected = [
+            frame(CMD_SYN, 1, &[]),
+            frame(CMD_SYN, 3, &[]),
+            frame(CMD_FIN, 3, &[]),
+            frame(CMD_PSH, 1, b"hello"),
+            frame(CMD_FIN, 1, &[]),
+        ]
+        .concat();
+        assert_eq!(server.join().unwrap(), expected);
+    }
+
+    #[test]
+    fn fails_the_streams_once_the_connection_is_closed() {
+        let mut runtime = Runtime::new().unwrap();
+        let (connector, server) = connect(&mut runtime, |conn| {
+            conn.shutdown(Shutdown::Both).unwrap();
+        });
+        let stream = connector.open().unwrap();
+        server.join().unwrap();
+        let e = runtime
+            .block_on(tokio_io::io::read_to_end(stream, Vec::new()))
+            .unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::BrokenPipe);
+        assert_eq!(
+            connector.open().unwrap_err().kind(),
+            io::ErrorKind::NotConnected
+        );
+    }
+}
@@ -0,0 +1,236 @@
+//! Hooks to observe the handshakes of a connector, e.g. to export metrics.
+
+use crate::{AuthMethod, Error, HandshakePhase, TargetAddr};
+use std::{fmt, net::SocketAddr, sync::Arc, time::Duration};
+
+/// An event in a handshake with a proxy server.
+///
+/// Every connection attempt, including the retries of a `Connect`, starts with
+/// `AttemptStarted` and ends with either `Completed` or `Failed`. The stream of a completed
+/// attempt reports `Closed` once it's dropped.
+#[derive(Debug)]
+pub enum HandshakeEvent<'a> {
+    /// An attempt to establish a connection through the proxy started.
+    AttemptStarted {
+        /// The target requested from the proxy server.
+        target: &'a TargetAddr<'a>,
+    },
+    /// An address of the proxy server was resolved.
+    Resolved {
+        /// The resolved address.
+        proxy: SocketAddr,
+    },
+    /// The TCP connection to the proxy server is established.
+    Connected {
+        /// The address of the proxy server.
+        proxy: SocketAddr,
+        /// The time it took to establish the TCP connection, including the attempts to
+        /// the previous addresses of the proxy server.
+        rtt: Duration,
+    },
+    /// The client authenticated to the proxy server, or the server requires no
+    /// authentication.
+    Authenticated {
+        /// The authentication method selected by the proxy server.
+        method: AuthMethod,
+    },
+    /// The proxy server replied to the request.
+    ReplyReceived {
+        /// The reply code, `0x00` on success.
+        code: u8,
+    },
+    /// The handshake completed.
+    Completed {
+        /// The time since the attempt started.
+        duration: Duration,
+    },
+    /// The handshake failed.
+    Failed {
+        /// The phase of the handshake which failed.
+        phase: HandshakePhase,
+        /// The cause of the failure.
+        error: &'a Error,
+        /// The time since the attempt started.
+        duration: Duration,
+    },
+    /// The stream established by the handshake was dropped, or consumed by `into_inner`.
+    Closed {
+        /// The target requested from the proxy server.
+        target: &'a TargetAddr<'a>,
+    },
+}
+
+/// An observer of the handshakes run through a `Socks5Connector`.
+///
+/// It's called from the task polling the connection, so it should return quickly, e.g.
+/// by updating counters or histograms.
+pub trait Observer: Send + Sync {
+    /// Called for every event of every handshake.
+    fn on_event(&self, event: &HandshakeEvent<'_>);
+}
+
+impl<F> Observer for F
+where
+    F: Fn(&HandshakeEvent<'_>) + Send + Sync,
+{
+    fn on_event(&self, event: &HandshakeEvent<'_>) {
+        self(event)
+    }
+}
+
+/// A listener of the lifecycle of the connections established through a
+/// `Socks5Connector`, with a method per event. See `Socks5ConnectorBuilder::event_listener`.
+///
+/// The methods do nothing by default. Like an `Observer`, they are called from the task
+/// polling the connection, so they should return quickly.
+pub trait EventListener: Send + Sync {
+    /// Called when an address of the proxy server is resolved.
+    fn on_resolve(&self, _proxy: SocketAddr) {}
+
+    /// Called when the TCP connection to the proxy server is established, `rtt` after the
+    /// first address was dialed.
+    fn on_tcp_connect(&self, _proxy: SocketAddr, _rtt: Duration) {}
+
+    /// Called when the client authenticated, or the server requires no authentication.
+    fn on_auth(&self, _method: AuthMethod) {}
+
+    /// Called with the code of the reply of the proxy server, `0x00` on success.
+    fn on_reply(&self, _code: u8) {}
+
+    /// Called when a stream established through the proxy is dropped.
+    fn on_close(&self, _target: &TargetAddr<'_>) {}
+}
+
+/// Reports the events of the handshakes to the methods of an `EventListener`.
+pub(crate) struct Listener<L>(pub(crate) L);
+
+impl<L> Observer for Listener<L>
+where
+    L: EventListener,
+{
+    fn on_event(&self, event: &HandshakeEvent<'_>) {
+        match *event {
+            HandshakeEvent::Resolved { proxy } => self.0.on_resolve(proxy),
+            HandshakeEvent::Connected { proxy, rtt } => self.0.on_tcp_connect(proxy, rtt),
+            HandshakeEvent::Authenticated { method } => self.0.on_auth(method),
+            HandshakeEvent::ReplyReceived { code } => self.0.on_reply(code),
+            HandshakeEvent::Closed { target } => self.0.on_close(target),
+            HandshakeEvent::AttemptStarted { .. }
+            | HandshakeEvent::Completed { .. }
+            | HandshakeEvent::Failed { .. } => {}
+        }
+    }
+}
+
+/// The direction of the bytes passed to the function set with
+/// `Socks5ConnectorBuilder::wire_dump`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// Bytes sent to the proxy server.
+    Sent,
+    /// Bytes received from the proxy server.
+    Received,
+}
+
+type DumpFn = dyn Fn(Direction, &[u8]) + Send + Sync;
+
+/// A user-supplied function receiving the bytes of the handshakes.
+#[derive(Clone)]
+pub(crate) struct WireDump(pub(crate) Arc<DumpFn>);
+
+impl fmt::Debug for WireDump {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("WireDump")
+    }
+}
+
+/// An `Observer` shared by the connections of a connector.
+#[derive(Clone)]
+pub(crate) struct SharedObserver(pub(crate) Arc<dyn Observer>);
+
+impl fmt::Debug for SharedObserver {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("SharedObserver")
+    }
+}
+
+/// Reports the events to two observers in turn.
+pub(crate) struct Chain(pub(crate) SharedObserver, pub(crate) SharedObserver);
+
+impl Observer for Chain {
+    fn on_event(&self, event: &HandshakeEvent<'_>) {
+        (self.0).0.on_event(event);
+        (self.1).0.on_event(event);
+    }
+}
+
+/// Reports `HandshakeEvent::Closed` when dropped along with the stream it belongs to.
+#[derive(Debug)]
+pub(crate) struct OnClose {
+    pub(crate) observer: SharedObserver,
+    pub(crate) target: TargetAddr<'static>,
+}
+
+impl Drop for OnClose {
+    fn drop(&mut self) {
+        self.observer.0.on_event(&HandshakeEvent::Closed {
+            target: &self.target,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    #[derive(Default)]
+    struct Calls(Mutex<Vec<&'static str>>);
+
+    impl EventListener for Arc<Calls> {
+        fn on_auth(&self, _method: AuthMethod) {
+            self.0.lock().unwrap().push("auth");
+        }
+
+        fn on_close(&self, _target: &TargetAddr<'_>) {
+            self.0.lock().unwrap().push("close");
+        }
+    }
+
+    #[test]
+    fn dispatches_events_to_listeners() {
+        let calls = Arc::new(Calls::default());
+        let observer = SharedObserver(Arc::new(Listener(calls.clone())));
+        observer.0.on_event(&HandshakeEvent::Authenticated {
+            method: AuthMethod::None,
+        });
+        observer.0.on_event(&HandshakeEvent::Completed {
+            duration: Duration::from_secs(1),
+        });
+        drop(OnClose {
+            observer,
+            target: TargetAddr::Ip(([127, 0, 0, 1], 80).into()),
+        });
+        assert_eq!(*calls.0.lock().unwrap(), vec!["auth", "close"]);
+    }
+
+    #[test]
+    fn chains_observers() {
+        let calls = Arc::new(Calls::default());
+        let events = Arc::new(Mutex::new(0));
+        let counter = events.clone();
+        let observer = SharedObserver(Arc::new(move |_: &HandshakeEvent<'_>| {
+            *counter.lock().unwrap() += 1;
+        }));
+        let listener = SharedObserver(Arc::new(Listener(calls.clone())));
+        let chain = Chain(observer, listener);
+        chain.on_event(&HandshakeEvent::Authenticated {
+            method: AuthMethod::None,
+        });
+        chain.on_event(&HandshakeEvent::Completed {
+            duration: Duration::from_secs(1),
+        });
+        assert_eq!(*events.lock().unwrap(), 2);
+        assert_eq!(*calls.0.lock().unwrap(), vec!["auth"]);
+    }
+}
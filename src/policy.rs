@@ -0,0 +1,217 @@
+//! Policies deciding which targets may be reached through the proxy, e.g. to protect the
+//! services connecting to user-supplied URLs from SSRF.
+
+use crate::{Error, Result, TargetAddr};
+use std::{
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+};
+
+/// A policy checked before connecting to a target through a `Socks5Connector`.
+///
+/// The target is checked before any byte is sent to the proxy server. When the connector
+/// resolves domains locally, every resolved address is checked too, and the denied ones
+/// are skipped.
+pub trait TargetPolicy: Send + Sync {
+    /// Returns `true` if the connections to `target` are allowed.
+    fn allows(&self, target: &TargetAddr<'_>) -> bool;
+
+    /// Returns `true` if the connections to `addr`, resolved locally from an allowed
+    /// domain, are allowed.
+    ///
+    /// Defaults to checking it as an IP target with `allows`.
+    fn allows_resolved(&self, addr: SocketAddr) -> bool {
+        self.allows(&TargetAddr::Ip(addr))
+    }
+}
+
+impl<F> TargetPolicy for F
+where
+    F: Fn(&TargetAddr<'_>) -> bool + Send + Sync,
+{
+    fn allows(&self, target: &TargetAddr<'_>) -> bool {
+        self(target)
+    }
+}
+
+/// A `TargetPolicy` denying the addresses which aren't reachable on the internet:
+/// loopback, private (RFC 1918, unique local), link-local (including the cloud metadata
+/// endpoints), shared, unspecified, broadcast, multicast and reserved addresses, as well
+/// as the `localhost` domains.
+///
+/// Domain names can't be checked until they are resolved, so use it along with
+/// `DnsPolicy::Local` to check the addresses they resolve to as well.
+#[derive(Debug, Clone, Default)]
+pub struct PublicTargets {
+    deny_ip_literals: bool,
+}
+
+impl PublicTargets {
+    /// Creates a policy allowing the domain names and the public addresses.
+    pub fn new() -> PublicTargets {
+        PublicTargets::default()
+    }
+
+    /// Denies the targets given as IP addresses rather than domain names, even public
+    /// ones. The addresses resolved locally from a domain are still allowed if public.
+    pub fn deny_ip_literals(mut self, deny: bool) -> PublicTargets {
+        self.deny_ip_literals = deny;
+        self
+    }
+}
+
+impl TargetPolicy for PublicTargets {
+    fn allows(&self, target: &TargetAddr<'_>) -> bool {
+        match target {
+            TargetAddr::Domain(domain, _) => {
+                let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+                domain != "localhost" && !domain.ends_with(".localhost")
+            }
+            TargetAddr::Ip(addr) => !self.deny_ip_literals && is_public(addr.ip()),
+        }
+    }
+
+    fn allows_resolved(&self, addr: SocketAddr) -> bool {
+        is_public(addr.ip())
+    }
+}
+
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_v4(ip),
+        IpAddr::V6(ip) => is_public_v6(ip),
+    }
+}
+
+fn is_public_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, c, _] = ip.octets();
+    !(ip.is_private()
+        || ip.is_loopback()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || ip.is_multicast()
+        // 0.0.0.0/8, "this network"
+        || a == 0
+        // 100.64.0.0/10, shared address space
+        || (a == 100 && b & 0xc0 == 64)
+        // 192.0.0.0/24, IETF protocol assignments
+        || (a == 192 && b == 0 && c == 0)
+        // 198.18.0.0/15, benchmarking
+        || (a == 198 && b & 0xfe == 18)
+        // 240.0.0.0/4, reserved
+        || a >= 240)
+}
+
+fn is_public_v6(ip: Ipv6Addr) -> bool {
+    if let Some(ip) = to_ipv4(ip) {
+        return is_public_v4(ip);
+    }
+    let first = ip.segments()[0];
+    !(ip.is_loopback()
+        || ip.is_unspecified()
+        || ip.is_multicast()
+        // fc00::/7, unique local
+        || first & 0xfe00 == 0xfc00
+        // fe80::/10, link-local
+        || first & 0xffc0 == 0xfe80
+        // 2001:db8::/32, documentation
+        || (first == 0x2001 && ip.segments()[1] == 0x0db8)
+        // 64:ff9b:1::/48, local-use NAT64, which may embed any IPv4 address anywhere
+        || (first == 0x64 && ip.segments()[1] == 0xff9b && ip.segments()[2] == 1))
+}
+
+/// Returns the IPv4 address embedded in an IPv4-mapped, IPv4-compatible, NAT64
+/// (64:ff9b::/96) or 6to4 (2002::/16) address.
+fn to_ipv4(ip: Ipv6Addr) -> Option<Ipv4Addr> {
+    let (hi, lo) = match ip.segments() {
+        [0, 0, 0, 0, 0, 0xffff, hi, lo]
+        | [0, 0, 0, 0, 0, 0, hi, lo]
+        | [0x64, 0xff9b, 0, 0, 0, 0, hi, lo]
+        | [0x2002, hi, lo, ..] => (hi, lo),
+        _ => return None,
+    };
+    Some(Ipv4Addr::from(u32::from(hi) << 16 | u32::from(lo)))
+}
+
+#[derive(Clone)]
+pub(crate) struct SharedPolicy(pub(crate) Arc<dyn TargetPolicy>);
+
+impl SharedPolicy {
+    /// Fails with `Error::TargetDenied` if the policy doesn't allow `target`.
+    pub(crate) fn check(&self, target: &TargetAddr<'_>) -> Result<()> {
+        if self.0.allows(target) {
+            Ok(())
+        } else {
+            debug_event!(target = %target, "target denied by policy");
+            Err(Error::TargetDenied(target.to_owned()))
+        }
+    }
+}
+
+impl fmt::Debug for SharedPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("SharedPolicy")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connector::Socks5Connector;
+
+    #[test]
+    fn denies_targets_before_connecting() {
+        let connector = Socks5Connector::builder()
+            .target_policy(|target: &TargetAddr<'_>| match target {
+                TargetAddr::Domain(domain, _) => !domain.ends_with(".internal"),
+                TargetAddr::Ip(addr) => addr.port() != 25,
+            })
+            .build();
+        match connector.connect("127.0.0.1:1080", "db.internal:5432") {
+            Err(Error::TargetDenied(target)) => assert_eq!(target.to_string(), "db.internal:5432"),
+            _ => panic!("expected the target to be denied"),
+        }
+        match connector.connect("127.0.0.1:1080", "10.0.0.1:25") {
+            Err(Error::TargetDenied(_)) => {}
+            _ => panic!("expected the target to be denied"),
+        }
+        assert!(connector
+            .connect("127.0.0.1:1080", "example.com:443")
+            .is_ok());
+    }
+
+    #[test]
+    fn allows_only_public_targets() {
+        let policy = PublicTargets::new();
+        let allows = |target: &str| policy.allows(&target.parse().unwrap());
+        assert!(allows("example.com:443"));
+        assert!(allows("93.184.216.34:80"));
+        assert!(allows("[2606:2800:220:1::1]:80"));
+        assert!(!allows("localhost:80"));
+        assert!(!allows("db.localhost.:80"));
+        assert!(!allows("127.0.0.1:80"));
+        assert!(!allows("10.1.2.3:80"));
+        assert!(!allows("172.16.0.1:80"));
+        assert!(!allows("192.168.1.1:80"));
+        assert!(!allows("169.254.169.254:80"));
+        assert!(!allows("100.64.0.1:80"));
+        assert!(!allows("0.0.0.0:80"));
+        assert!(!allows("[::1]:80"));
+        assert!(!allows("[fd00::1]:80"));
+        assert!(!allows("[fe80::1]:80"));
+        assert!(!allows("[::ffff:127.0.0.1]:80"));
+        assert!(!allows("[::7f00:1]:80"));
+        assert!(!allows("[2002:7f00:1::]:80"));
+        assert!(!allows("[2002:a01:203::1]:80"));
+        assert!(allows("[2002:5db8:d822::1]:80"));
+        assert!(!allows("[64:ff9b::7f00:1]:80"));
+        assert!(!allows("[64:ff9b:1::5db8:d822]:80"));
+
+        let policy = PublicTargets::new().deny_ip_literals(true);
+        assert!(!policy.allows(&"93.184.216.34:80".parse().unwrap()));
+        assert!(policy.allows_resolved("93.184.216.34:80".parse().unwrap()));
+        assert!(!policy.allows_resolved("127.0.0.1:80".parse().unwrap()));
+    }
+}
@@ -0,0 +1,371 @@
+//! Pools of idle tunnels to the targets, reused instead of running a new handshake.
+
+use crate::{
+    connector::{Connect, Socks5Connector},
+    tcp::Socks5Stream,
+    Error, IntoTargetAddr, ProxySpec, Result, TargetAddr, ToProxyAddrs,
+};
+use futures::{try_ready, Async, Future, Poll};
+use std::{
+    collections::HashMap,
+    fmt,
+    hash::Hash,
+    io::{self, Read, Write},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio_io::{AsyncRead, AsyncWrite};
+
+type Key<P> = (ProxySpec<P>, TargetAddr<'static>);
+
+/// A pool of proxied connections, keyed by the proxy and the target.
+///
+/// A connection checked out with `get` is only returned to the pool with
+/// `Pooled::release`, once the caller knows that the tunneled protocol left it in a
+/// reusable state. Dropping a `Pooled` closes the connection.
+///
+/// Idle connections which were closed by the proxy server, or which outlived the idle
+/// timeout or the maximum lifetime, are discarded at checkout, and a new connection is
+/// established instead.
+pub struct SocksPool<P> {
+    connector: Socks5Connector,
+    max_idle: usize,
+    idle_timeout: Option<Duration>,
+    max_lifetime: Option<Duration>,
+    idle: Arc<Mutex<HashMap<Key<P>, Vec<Idle>>>>,
+}
+
+struct Idle {
+    stream: Socks5Stream,
+    created: Instant,
+    idle_since: Instant,
+}
+
+impl<P> SocksPool<P>
+where
+    P: ToProxyAddrs + Clone + Eq + Hash,
+{
+    /// Creates a pool establishing its connections with `connector`.
+    ///
+    /// At most 8 idle connections are kept per proxy and target, for at most 90 seconds.
+    /// The lifetime of the connections isn't limited.
+    pub fn new(connector: Socks5Connector) -> SocksPool<P> {
+        SocksPool {
+            connector,
+            max_idle: 8,
+            idle_timeout: Some(Duration::from_secs(90)),
+            max_lifetime: None,
+            idle: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Sets the maximum number of idle connections kept per proxy and target.
+    pub fn max_idle(mut self, max_idle: usize) -> SocksPool<P> {
+        self.max_idle = max_idle;
+        self
+    }
+
+    /// Sets how long a connection may stay idle in the pool, or `None` for no limit.
+    pub fn idle_timeout(mut self, idle_timeout: Option<Duration>) -> SocksPool<P> {
+        self.idle_timeout = idle_timeout;
+        self
+    }
+
+    /// Sets how long a connection may be used since it was established, or `None` for no
+    /// limit.
+    pub fn max_lifetime(mut self, max_lifetime: Option<Duration>) -> SocksPool<P> {
+        self.max_lifetime = max_lifetime;
+        self
+    }
+
+    /// Returns a `Future` which resolves to an idle connection to `target` through `proxy`,
+    /// or to a new one if there is none.
+    ///
+    /// # Error
+    ///
+    /// It propagates the error that occurs in the conversion from `T` to `TargetAddr`.
+    pub fn get<'a, 't, T>(&self, proxy: &'a ProxySpec<P>, target: T) -> Result<Checkout<'a, P>>
+    where
+        T: IntoTargetAddr<'t>,
+    {
+        Ok(Checkout {
+            pool: self.clone(),
+            proxy,
+            target: target.into_target_addr()?.to_owned(),
+            connect: None,
+        })
+    }
+
+    /// Drops all the idle connections.
+    pub fn clear(&self) {
+        self.idle.lock().unwrap().clear();
+    }
+
+    /// Returns the number of idle connections in the pool.
+    pub fn idle_count(&self) -> usize {
+        self.idle.lock().unwrap().values().map(Vec::len).sum()
+    }
+
+    fn is_expired(&self, created: Instant, idle_since: Instant, now: Instant) -> bool {
+        let expired = |since: Instant, limit: Option<Duration>| {
+            limit.is_some_and(|limit| now.duration_since(since) >= limit)
+        };
+        expired(created, self.max_lifetime) || expired(idle_since, self.idle_timeout)
+    }
+
+    /// Takes a live idle connection for `key`, discarding the stale ones on the way.
+    fn take_idle(&self, key: &Key<P>) -> Option<Idle> {
+        let now = Instant::now();
+        let mut idle = self.idle.lock().unwrap();
+        let conns = idle.get_mut(key)?;
+        let mut found = None;
+        while let Some(mut conn) = conns.pop() {
+            if !self.is_expired(conn.created, conn.idle_since, now) && is_alive(&mut conn.stream) {
+                found = Some(conn);
+                break;
+            }
+        }
+        if conns.is_empty() {
+            idle.remove(key);
+        }
+        found
+    }
+
+    fn put_idle(&self, key: Key<P>, stream: Socks5Stream, created: Instant) {
+        let now = Instant::now();
+        if self.is_expired(created, now, now) {
+            return;
+        }
+        let mut idle = self.idle.lock().unwrap();
+        let conns = idle.entry(key).or_default();
+        if conns.len() < self.max_idle {
+            conns.push(Idle {
+                stream,
+                created,
+                idle_since: now,
+            });
+        }
+    }
+}
+
+/// Returns `true` if the proxy server neither closed the connection nor sent unexpected
+/// data on it while it was idle.
+fn is_alive(stream: &mut Socks5Stream) -> bool {
+    let mut buf = [0; 1];
+    matches!(stream.get_mut().poll_peek(&mut buf), Ok(Async::NotReady))
+}
+
+impl<P> Clone for SocksPool<P> {
+    fn clone(&self) -> SocksPool<P> {
+        SocksPool {
+            connector: self.connector.clone(),
+            max_idle: self.max_idle,
+            idle_timeout: self.idle_timeout,
+            max_lifetime: self.max_lifetime,
+            idle: self.idle.clone(),
+        }
+    }
+}
+
+impl<P> fmt::Debug for SocksPool<P> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SocksPool")
+            .field("connector", &self.connector)
+            .field("max_idle", &self.max_idle)
+            .field("idle_timeout", &self.idle_timeout)
+            .field("max_lifetime", &self.max_lifetime)
+            .finish()
+    }
+}
+
+/// A `Future` which resolves to a connection checked out of a `SocksPool`.
+pub struct Checkout<'a, P>
+where
+    P: ToProxyAddrs,
+{
+    pool: SocksPool<P>,
+    proxy: &'a ProxySpec<P>,
+    target: TargetAddr<'static>,
+    connect: Option<Connect<'a, 'static, &'a P>>,
+}
+
+impl<'a, P> Future for Checkout<'a, P>
+where
+    P: ToProxyAddrs + Clone + Eq + Hash,
+{
+    type Item = Pooled<P>;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Pooled<P>, Error> {
+        if self.connect.is_none() {
+            let key = (self.proxy.clone(), self.target.clone());
+            if let Some(conn) = self.pool.take_idle(&key) {
+                return Ok(Async::Ready(Pooled {
+                    pool: self.pool.clone(),
+                    key,
+                    stream: conn.stream,
+                    created: conn.created,
+                }));
+            }
+            let connect = self
+                .pool
+                .connector
+                .connect_with_spec(self.proxy, self.target.clone())?;
+            self.connect = Some(connect);
+        }
+        let stream = try_ready!(self.connect.as_mut().unwrap().poll());
+        Ok(Async::Ready(Pooled {
+            pool: self.pool.clone(),
+            key: (self.proxy.clone(), self.target.clone()),
+            stream,
+            created: Instant::now(),
+        }))
+    }
+}
+
+/// A connection checked out of a `SocksPool`.
+pub struct Pooled<P> {
+    pool: SocksPool<P>,
+    key: Key<P>,
+    stream: Socks5Stream,
+    created: Instant,
+}
+
+impl<P> Pooled<P>
+where
+    P: ToProxyAddrs + Clone + Eq + Hash,
+{
+    /// Returns the connection to the pool, so that it can be reused for the same proxy
+    /// and target.
+    pub fn release(self) {
+        self.pool.put_idle(self.key, self.stream, self.created);
+    }
+
+    /// Takes the connection out of the pool for good.
+    pub fn into_inner(self) -> Socks5Stream {
+        self.stream
+    }
+
+    /// Returns a shared reference to the connection.
+    pub fn get_ref(&self) -> &Socks5Stream {
+        &self.stream
+    }
+
+    /// Returns a mutable reference to the connection.
+    pub fn get_mut(&mut self) -> &mut Socks5Stream {
+        &mut self.stream
+    }
+}
+
+impl<P> fmt::Debug for Pooled<P> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Pooled")
+            .field("stream", &self.stream)
+            .field("created", &self.created)
+            .finish()
+    }
+}
+
+impl<P> Read for Pooled<P> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stream.read(buf)
+    }
+}
+
+impl<P> Write for Pooled<P> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl<P> AsyncRead for Pooled<P> {}
+
+impl<P> AsyncWrite for Pooled<P> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.stream.shutdown()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::trace::{Replay, Trace};
+    use std::net::SocketAddr;
+    use tokio::runtime::current_thread::Runtime;
+
+    /// Accepts the requests to any `10.0.0.x:80`, keeping the connections open.
+    fn replay() -> Replay {
+        let trace: Trace = "
+            > 05 01 00
+            < 05 00
+            > 05 01 00 01 0a 00 00 2a 00 50
+            < 05 00 00 01 0a 00 00 01 00 50
+        "
+        .parse()
+        .unwrap();
+        Replay::bind(trace).unwrap()
+    }
+
+    fn local_addr(pooled: &Pooled<SocketAddr>) -> SocketAddr {
+        pooled.get_ref().get_ref().local_addr().unwrap()
+    }
+
+    #[test]
+    fn expires_connections() {
+        let pool = SocksPool::<String>::new(Socks5Connector::new())
+            .idle_timeout(Some(Duration::from_secs(10)))
+            .max_lifetime(Some(Duration::from_secs(60)));
+        let now = Instant::now();
+        let secs = Duration::from_secs;
+        assert!(!pool.is_expired(now, now + secs(30), now + secs(35)));
+        assert!(pool.is_expired(now, now + secs(30), now + secs(40)));
+        assert!(pool.is_expired(now, now + secs(55), now + secs(60)));
+    }
+
+    #[test]
+    fn reuses_released_connections() {
+        let replay = replay();
+        let proxy = ProxySpec::new(replay.local_addr());
+        let pool = SocksPool::new(Socks5Connector::new());
+        let mut runtime = Runtime::new().unwrap();
+        let pooled = runtime
+            .block_on(pool.get(&proxy, "10.0.0.1:80").unwrap())
+            .unwrap();
+        let addr = local_addr(&pooled);
+        pooled.release();
+        assert_eq!(pool.idle_count(), 1);
+        let pooled = runtime
+            .block_on(pool.get(&proxy, "10.0.0.1:80").unwrap())
+            .unwrap();
+        assert_eq!(local_addr(&pooled), addr);
+        assert_eq!(pool.idle_count(), 0);
+        // A dropped connection isn't returned to the pool.
+        drop(pooled);
+        assert_eq!(pool.idle_count(), 0);
+    }
+
+    #[test]
+    fn keys_idle_connections_by_target() {
+        let replay = replay();
+        let proxy = ProxySpec::new(replay.local_addr());
+        let pool = SocksPool::new(Socks5Connector::new()).max_idle(1);
+        let mut runtime = Runtime::new().unwrap();
+        let mut checkout = |target| runtime.block_on(pool.get(&proxy, target).unwrap()).unwrap();
+        let first = checkout("10.0.0.1:80");
+        let second = checkout("10.0.0.1:80");
+        let other = checkout("10.0.0.2:80");
+        let other_addr = local_addr(&other);
+        first.release();
+        second.release();
+        other.release();
+        // The second connection to the same target exceeds `max_idle`.
+        assert_eq!(pool.idle_count(), 2);
+        let other = checkout("10.0.0.2:80");
+        assert_eq!(local_addr(&other), other_addr);
+        assert_eq!(pool.idle_count(), 1);
+    }
+}
@@ -0,0 +1,160 @@
+//! Emission of a HAProxy PROXY protocol header on the connections established through a
+//! proxy server, for backends which expect to learn the address of the original client.
+
+use crate::TargetAddr;
+use bytes::BytesMut;
+use std::net::SocketAddr;
+
+/// The signature starting a version 2 header.
+const V2_SIGNATURE: [u8; 12] = [
+    0x0d, 0x0a, 0x0d, 0x0a, 0x00, 0x0d, 0x0a, 0x51, 0x55, 0x49, 0x54, 0x0a,
+];
+
+/// The version of the PROXY protocol.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
+pub enum ProxyProtocolVersion {
+    /// The human-readable version 1.
+    V1,
+    /// The binary version 2.
+    V2,
+}
+
+/// A PROXY protocol header sent to the target as the first bytes of the connection,
+/// right after the proxy server accepted the CONNECT request.
+///
+/// The header describes a connection from the source address to the destination address.
+/// If either is unknown, or if they aren't of the same family, the header tells the target
+/// that the addresses are unknown.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProxyHeader {
+    version: ProxyProtocolVersion,
+    source: Option<SocketAddr>,
+    destination: Option<SocketAddr>,
+}
+
+impl ProxyHeader {
+    /// Creates a header of the given version, without a source address.
+    pub fn new(version: ProxyProtocolVersion) -> ProxyHeader {
+        ProxyHeader {
+            version,
+            source: None,
+            destination: None,
+        }
+    }
+
+    /// Sets the address of the original client.
+    pub fn source(mut self, addr: SocketAddr) -> ProxyHeader {
+        self.source = Some(addr);
+        self
+    }
+
+    /// Sets the destination address, which defaults to the target of the connection when
+    /// it's an IP address.
+    pub fn destination(mut self, addr: SocketAddr) -> ProxyHeader {
+        self.destination = Some(addr);
+        self
+    }
+
+    /// Appends the header of a connection to `target` to `buf`.
+    pub(crate) fn encode(&self, target: &TargetAddr<'_>, buf: &mut BytesMut) {
+        let destination = match (self.destination, target) {
+            (Some(addr), _) | (None, &TargetAddr::Ip(addr)) => Some(addr),
+            (None, TargetAddr::Domain(..)) => None,
+        };
+        let addrs = match (self.source, destination) {
+            (Some(src), Some(dst)) if src.is_ipv4() == dst.is_ipv4() => Some((src, dst)),
+            _ => None,
+        };
+        match self.version {
+            ProxyProtocolVersion::V1 => encode_v1(addrs, buf),
+            ProxyProtocolVersion::V2 => encode_v2(addrs, buf),
+        }
+    }
+}
+
+fn encode_v1(addrs: Option<(SocketAddr, SocketAddr)>, buf: &mut BytesMut) {
+    let line = match addrs {
+        Some((src, dst)) => format!(
+            "PROXY {} {} {} {} {}\r\n",
+            if src.is_ipv4() { "TCP4" } else { "TCP6" },
+            src.ip(),
+            dst.ip(),
+            src.port(),
+            dst.port()
+        ),
+        None => "PROXY UNKNOWN\r\n".to_string(),
+    };
+    buf.extend_from_slice(line.as_bytes());
+}
+
+fn encode_v2(addrs: Option<(SocketAddr, SocketAddr)>, buf: &mut BytesMut) {
+    buf.extend_from_slice(&V2_SIGNATURE);
+    match addrs {
+        Some((SocketAddr::V4(src), SocketAddr::V4(dst))) => {
+            // PROXY command, TCP over IPv4
+            buf.extend_from_slice(&[0x21, 0x11]);
+            buf.extend_from_slice(&12u16.to_be_bytes());
+            buf.extend_from_slice(&src.ip().octets());
+            buf.extend_from_slice(&dst.ip().octets());
+            buf.extend_from_slice(&src.port().to_be_bytes());
+            buf.extend_from_slice(&dst.port().to_be_bytes());
+        }
+        Some((SocketAddr::V6(src), SocketAddr::V6(dst))) => {
+            // PROXY command, TCP over IPv6
+            buf.extend_from_slice(&[0x21, 0x21]);
+            buf.extend_from_slice(&36u16.to_be_bytes());
+            buf.extend_from_slice(&src.ip().octets());
+            buf.extend_from_slice(&dst.ip().octets());
+            buf.extend_from_slice(&src.port().to_be_bytes());
+            buf.extend_from_slice(&dst.port().to_be_bytes());
+        }
+        // LOCAL command, unspecified family
+        _ => buf.extend_from_slice(&[0x20, 0x00, 0x00, 0x00]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(header: ProxyHeader, target: &str) -> BytesMut {
+        let mut buf = BytesMut::new();
+        header.encode(&target.parse().unwrap(), &mut buf);
+        buf
+    }
+
+    #[test]
+    fn encodes_v1_headers() {
+        let header = ProxyHeader::new(ProxyProtocolVersion::V1);
+        let src = "192.0.2.1:56324".parse().unwrap();
+        assert_eq!(
+            &encode(header.clone().source(src), "198.51.100.1:443")[..],
+            &b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\n"[..]
+        );
+        assert_eq!(
+            &encode(header.clone().source(src), "example.com:443")[..],
+            &b"PROXY UNKNOWN\r\n"[..]
+        );
+        assert_eq!(
+            &encode(header, "198.51.100.1:443")[..],
+            &b"PROXY UNKNOWN\r\n"[..]
+        );
+    }
+
+    #[test]
+    fn encodes_v2_headers() {
+        let header = ProxyHeader::new(ProxyProtocolVersion::V2)
+            .source("192.0.2.1:56324".parse().unwrap())
+            .destination("198.51.100.1:443".parse().unwrap());
+        let buf = encode(header, "example.com:443");
+        assert_eq!(&buf[..12], &V2_SIGNATURE);
+        assert_eq!(
+            &buf[12..],
+            &[0x21, 0x11, 0x00, 0x0c, 192, 0, 2, 1, 198, 51, 100, 1, 0xdc, 0x04, 0x01, 0xbb][..]
+        );
+        let buf = encode(ProxyHeader::new(ProxyProtocolVersion::V2), "[::1]:443");
+        assert_eq!(&buf[12..], &[0x20, 0x00, 0x00, 0x00][..]);
+    }
+}
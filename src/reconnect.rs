@@ -0,0 +1,222 @@
+//! A stream reconnecting to its target through the proxy server whenever it fails, for the
+//! long-lived tunnels over flaky networks.
+
+use crate::{
+    connector::{Connect, RetryPolicy},
+    tcp::Socks5Stream,
+    Error, ToProxyAddrs,
+};
+use futures::{try_ready, Async, Future, Poll};
+use std::{
+    fmt,
+    io::{self, Read, Write},
+    time::Instant,
+};
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_timer::Delay;
+
+/// A stream through a proxy server which redials the proxy server and repeats the
+/// handshake to the same target with the same credentials when reading or writing fails,
+/// returned by `Connect::reconnecting`.
+///
+/// Reading and writing wait for the connection to be established again. The bytes in
+/// flight when the connection failed are lost, so the tunneled protocol has to be able to
+/// resume. Once a reconnection is refused by the `RetryPolicy`, the last error is returned.
+pub struct ReconnectingStream<P>
+where
+    P: ToProxyAddrs,
+{
+    connect: Connect<'static, 'static, P>,
+    policy: RetryPolicy,
+    state: State<P>,
+    // The number of failures since the stream last transferred any byte.
+    failures: u32,
+    reconnects: u64,
+}
+
+enum State<P>
+where
+    P: ToProxyAddrs,
+{
+    Connecting(Box<Connect<'static, 'static, P>>),
+    Connected(Box<Socks5Stream>),
+    Waiting(Delay),
+}
+
+impl<P> ReconnectingStream<P>
+where
+    P: ToProxyAddrs,
+{
+    /// Returns the current connection, or `None` while reconnecting.
+    pub fn get_ref(&self) -> Option<&Socks5Stream> {
+        match &self.state {
+            State::Connected(stream) => Some(&**stream),
+            _ => None,
+        }
+    }
+
+    /// Returns the number of times the stream reconnected.
+    pub fn reconnects(&self) -> u64 {
+        self.reconnects
+    }
+}
+
+impl<P> ReconnectingStream<P>
+where
+    P: ToProxyAddrs + Clone,
+{
+    pub(crate) fn new(connect: Connect<'static, 'static, P>, policy: RetryPolicy) -> Self {
+        ReconnectingStream {
+            state: State::Connecting(Box::new(connect.restart())),
+            connect,
+            policy,
+            failures: 0,
+            reconnects: 0,
+        }
+    }
+
+    /// Drops the current connection after `err`, waiting before the next one according to
+    /// the policy, or returns `err` if the policy refuses to reconnect.
+    fn fail(&mut self, err: Error) -> io::Result<()> {
+        self.failures += 1;
+        match self.policy.next_delay(self.failures, &err) {
+            Some(delay) => {
+                debug_event!(error = %err, ?delay, "reconnecting");
+                self.state = State::Waiting(Delay::new(Instant::now() + delay));
+                Ok(())
+            }
+            None => Err(err.into()),
+        }
+    }
+
+    /// Waits until the stream is connected.
+    fn poll_connected(&mut self) -> Poll<&mut Socks5Stream, io::Error> {
+        loop {
+            match &mut self.state {
+                State::Connected(_) => break,
+                State::Connecting(connect) => match connect.poll() {
+                    Ok(Async::Ready(stream)) => self.state = State::Connected(Box::new(stream)),
+                    Ok(Async::NotReady) => return Ok(Async::NotReady),
+                    Err(e) => self.fail(e)?,
+                },
+                State::Waiting(delay) => {
+                    try_ready!(delay.poll().map_err(io::Error::other));
+                    self.reconnects += 1;
+                    self.state = State::Connecting(Box::new(self.connect.restart()));
+                }
+            }
+        }
+        match &mut self.state {
+            State::Connected(stream) => Ok(Async::Ready(&mut **stream)),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Runs `op` on the connection, reconnecting if it fails.
+    fn with_stream<T, F>(&mut self, mut op: F) -> io::Result<T>
+    where
+        F: FnMut(&mut Socks5Stream) -> io::Result<T>,
+    {
+        loop {
+            let stream = match self.poll_connected()? {
+                Async::Ready(stream) => stream,
+                Async::NotReady => return Err(io::ErrorKind::WouldBlock.into()),
+            };
+            match op(stream) {
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    return Err(io::ErrorKind::WouldBlock.into())
+                }
+                Err(e) => self.fail(e.into())?,
+                Ok(t) => return Ok(t),
+            }
+        }
+    }
+}
+
+impl<P> Read for ReconnectingStream<P>
+where
+    P: ToProxyAddrs + Clone,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.with_stream(|stream| stream.read(buf))?;
+        if n > 0 {
+            self.failures = 0;
+        }
+        Ok(n)
+    }
+}
+
+impl<P> Write for ReconnectingStream<P>
+where
+    P: ToProxyAddrs + Clone,
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.with_stream(|stream| stream.write(buf))?;
+        if n > 0 {
+            self.failures = 0;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.with_stream(|stream| stream.flush())
+    }
+}
+
+impl<P> AsyncRead for ReconnectingStream<P> where P: ToProxyAddrs + Clone {}
+
+impl<P> AsyncWrite for ReconnectingStream<P>
+where
+    P: ToProxyAddrs + Clone,
+{
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        match &mut self.state {
+            State::Connected(stream) => AsyncWrite::shutdown(stream),
+            _ => Ok(Async::Ready(())),
+        }
+    }
+}
+
+impl<P> fmt::Debug for ReconnectingStream<P>
+where
+    P: ToProxyAddrs,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ReconnectingStream")
+            .field("policy", &self.policy)
+            .field("connected", &self.get_ref().is_some())
+            .field("reconnects", &self.reconnects)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::connector::{RetryPolicy, Socks5Connector};
+    use futures::future;
+    use std::{net::TcpListener, time::Duration};
+    use tokio::runtime::current_thread::Runtime;
+    use tokio_io::AsyncRead;
+
+    #[test]
+    fn gives_up_according_to_the_policy() {
+        let proxy = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let policy = RetryPolicy::new(2)
+            .backoff(Duration::from_millis(1), Duration::from_millis(1))
+            .jitter(false);
+        let mut stream = Socks5Connector::new()
+            .connect(proxy, "example.com:80")
+            .unwrap()
+            .reconnecting(policy);
+        let mut buf = [0; 1];
+        let result = Runtime::new()
+            .unwrap()
+            .block_on(future::poll_fn(|| stream.poll_read(&mut buf)));
+        assert!(result.is_err());
+        assert_eq!(stream.reconnects(), 1);
+        assert!(stream.get_ref().is_none());
+    }
+}
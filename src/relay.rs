@@ -0,0 +1,355 @@
+//! Copying of the bytes between two streams, e.g. a local connection and a proxied one.
+//!
+//! On Linux, `splice` moves the bytes between two TCP streams without copying them to
+//! userspace, which spares CPU time on high-bandwidth tunnels.
+
+use crate::{tcp::Socks5Stream, Error, Result};
+use futures::{try_ready, Async, Future, Poll};
+use std::{
+    io,
+    net::Shutdown,
+    time::{Duration, Instant},
+};
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_tcp::TcpStream;
+use tokio_timer::Delay;
+
+#[cfg(target_os = "linux")]
+pub use crate::splice::{splice, Splice};
+
+const BUFFER_SIZE: usize = 8 * 1024;
+
+/// Copies the bytes read from `a` to `b` and the bytes read from `b` to `a`, until both
+/// reach the end of their stream.
+///
+/// When one of the streams reaches the end, the writing side of the other one is shut
+/// down, so that a half-close is propagated while the bytes keep flowing in the other
+/// direction.
+pub fn relay<A, B>(a: A, b: B) -> Relay<A, B>
+where
+    A: AsyncRead + HalfClose,
+    B: AsyncRead + HalfClose,
+{
+    Relay {
+        a,
+        b,
+        a_to_b: Half::new(),
+        b_to_a: Half::new(),
+        idle: Idle::default(),
+    }
+}
+
+/// A stream whose writing side can be shut down while it can still be read.
+///
+/// `AsyncWrite::shutdown` doesn't send a FIN on a `TcpStream`, so it can't be used to
+/// propagate a half-close.
+pub trait HalfClose: AsyncWrite {
+    /// Shuts down the writing side of the stream.
+    fn shutdown_write(&mut self) -> io::Result<()>;
+}
+
+impl HalfClose for TcpStream {
+    fn shutdown_write(&mut self) -> io::Result<()> {
+        TcpStream::shutdown(self, Shutdown::Write)
+    }
+}
+
+impl HalfClose for Socks5Stream {
+    fn shutdown_write(&mut self) -> io::Result<()> {
+        Socks5Stream::shutdown_write(self)
+    }
+}
+
+impl<T: HalfClose + ?Sized> HalfClose for &mut T {
+    fn shutdown_write(&mut self) -> io::Result<()> {
+        (**self).shutdown_write()
+    }
+}
+
+/// A `Future` copying the bytes between two streams, returned by `relay`.
+///
+/// It resolves to the number of bytes copied in each direction.
+#[derive(Debug)]
+pub struct Relay<A, B> {
+    a: A,
+    b: B,
+    a_to_b: Half,
+    b_to_a: Half,
+    idle: Idle,
+}
+
+/// The number of bytes copied by a `Relay`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct RelayStats {
+    /// The bytes read from the first stream and written to the second one.
+    pub a_to_b: u64,
+    /// The bytes read from the second stream and written to the first one.
+    pub b_to_a: u64,
+}
+
+/// The idle timeout of a relay, reset whenever a byte is copied.
+#[derive(Debug, Default)]
+pub(crate) struct Idle {
+    timeout: Option<Duration>,
+    timer: Option<Delay>,
+}
+
+impl Idle {
+    pub(crate) fn set(&mut self, timeout: Duration) {
+        self.timeout = Some(timeout);
+    }
+
+    /// Fails with `Error::Timeout` once the timeout elapsed since the last poll with
+    /// `active` set.
+    pub(crate) fn poll(&mut self, active: bool) -> Result<()> {
+        if let Some(timeout) = self.timeout {
+            let deadline = Instant::now() + timeout;
+            match &mut self.timer {
+                Some(timer) if active => timer.reset(deadline),
+                Some(_) => {}
+                None => self.timer = Some(Delay::new(deadline)),
+            }
+            let timer = self.timer.as_mut().unwrap();
+            if timer.poll().map_err(io::Error::other)?.is_ready() {
+                Err(Error::Timeout)?
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The state of the copy in one direction.
+#[derive(Debug)]
+struct Half {
+    buf: Box<[u8]>,
+    pos: usize,
+    cap: usize,
+    read_done: bool,
+    done: bool,
+    amt: u64,
+}
+
+impl Half {
+    fn new() -> Half {
+        Half {
+            buf: vec![0; BUFFER_SIZE].into_boxed_slice(),
+            pos: 0,
+            cap: 0,
+            read_done: false,
+            done: false,
+            amt: 0,
+        }
+    }
+
+    /// Copies from `reader` to `writer` until `reader` reaches the end of its stream, and
+    /// then shuts the writing side of `writer` down. `active` is set if any byte was
+    /// copied.
+    fn poll_copy<R, W>(
+        &mut self,
+        reader: &mut R,
+        writer: &mut W,
+        active: &mut bool,
+    ) -> Poll<(), io::Error>
+    where
+        R: AsyncRead,
+        W: HalfClose,
+    {
+        while !self.done {
+            if self.pos == self.cap && !self.read_done {
+                let n = try_ready!(reader.poll_read(&mut self.buf));
+                if n == 0 {
+                    self.read_done = true;
+                } else {
+                    self.pos = 0;
+                    self.cap = n;
+                    *active = true;
+                }
+            }
+            while self.pos < self.cap {
+                let n = try_ready!(writer.poll_write(&self.buf[self.pos..self.cap]));
+                if n == 0 {
+                    return Err(io::ErrorKind::WriteZero.into());
+                }
+                self.pos += n;
+                self.amt += n as u64;
+                *active = true;
+            }
+            if self.read_done {
+                try_ready!(writer.poll_flush());
+                writer.shutdown_write()?;
+                self.done = true;
+            }
+        }
+        Ok(Async::Ready(()))
+    }
+}
+
+impl<A, B> Relay<A, B> {
+    /// Fails the relay with `Error::Timeout` if no byte is copied in either direction for
+    /// `timeout`.
+    pub fn idle_timeout(mut self, timeout: Duration) -> Relay<A, B> {
+        self.idle.set(timeout);
+        self
+    }
+
+    /// Returns the number of bytes copied so far.
+    pub fn stats(&self) -> RelayStats {
+        RelayStats {
+            a_to_b: self.a_to_b.amt,
+            b_to_a: self.b_to_a.amt,
+        }
+    }
+
+    /// Consumes the `Relay`, returning the two streams.
+    pub fn into_inner(self) -> (A, B) {
+        (self.a, self.b)
+    }
+}
+
+impl<A, B> Future for Relay<A, B>
+where
+    A: AsyncRead + HalfClose,
+    B: AsyncRead + HalfClose,
+{
+    type Item = RelayStats;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<RelayStats, Error> {
+        let mut active = false;
+        let a_to_b = self
+            .a_to_b
+            .poll_copy(&mut self.a, &mut self.b, &mut active)?;
+        let b_to_a = self
+            .b_to_a
+            .poll_copy(&mut self.b, &mut self.a, &mut active)?;
+        if a_to_b.is_ready() && b_to_a.is_ready() {
+            return Ok(Async::Ready(self.stats()));
+        }
+        self.idle.poll(active)?;
+        Ok(Async::NotReady)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        io::{Cursor, Read, Write},
+        net::TcpListener,
+        thread,
+    };
+    use tokio::runtime::current_thread::Runtime;
+    use tokio_reactor::Handle;
+
+    /// A stream reading from `input` and writing to `output`.
+    struct Pipe {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>,
+        shut_down: bool,
+    }
+
+    impl Pipe {
+        fn new(input: &[u8]) -> Pipe {
+            Pipe {
+                input: Cursor::new(input.to_vec()),
+                output: Vec::new(),
+                shut_down: false,
+            }
+        }
+    }
+
+    impl Read for Pipe {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for Pipe {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl AsyncRead for Pipe {}
+
+    impl AsyncWrite for Pipe {
+        fn shutdown(&mut self) -> Poll<(), io::Error> {
+            Ok(Async::Ready(()))
+        }
+    }
+
+    impl HalfClose for Pipe {
+        fn shutdown_write(&mut self) -> io::Result<()> {
+            self.shut_down = true;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn copies_both_ways_and_shuts_down() {
+        let mut relay = relay(Pipe::new(b"request"), Pipe::new(b"response!"));
+        let stats = match relay.poll().unwrap() {
+            Async::Ready(stats) => stats,
+            Async::NotReady => panic!("the relay didn't complete"),
+        };
+        assert_eq!(
+            stats,
+            RelayStats {
+                a_to_b: 7,
+                b_to_a: 9,
+            }
+        );
+        let (a, b) = relay.into_inner();
+        assert_eq!(&a.output[..], &b"response!"[..]);
+        assert_eq!(&b.output[..], &b"request"[..]);
+        assert!(a.shut_down && b.shut_down);
+    }
+
+    fn connected_pair() -> (std::net::TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = std::net::TcpStream::connect(addr).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        (
+            client,
+            TcpStream::from_std(server, &Handle::default()).unwrap(),
+        )
+    }
+
+    #[test]
+    fn propagates_half_closes_over_tcp() {
+        let (mut a, a_end) = connected_pair();
+        let (mut b, b_end) = connected_pair();
+        let peers = thread::spawn(move || {
+            a.write_all(b"request").unwrap();
+            a.shutdown(Shutdown::Write).unwrap();
+            // `b` only reads the end of the stream if the relay shut its side down.
+            b.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+            let mut from_a = Vec::new();
+            b.read_to_end(&mut from_a).unwrap();
+            b.write_all(b"response!").unwrap();
+            b.shutdown(Shutdown::Write).unwrap();
+            let mut from_b = Vec::new();
+            a.read_to_end(&mut from_b).unwrap();
+            (from_a, from_b)
+        });
+        let stats = Runtime::new()
+            .unwrap()
+            .block_on(relay(a_end, b_end))
+            .unwrap();
+        let (from_a, from_b) = peers.join().unwrap();
+        assert_eq!(&from_a[..], &b"request"[..]);
+        assert_eq!(&from_b[..], &b"response!"[..]);
+        assert_eq!(
+            stats,
+            RelayStats {
+                a_to_b: 7,
+                b_to_a: 9,
+            }
+        );
+    }
+}
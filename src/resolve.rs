@@ -0,0 +1,529 @@
+//! Resolution of domain names through the proxy server, so that no DNS query leaves the
+//! tunnel.
+
+#[cfg(feature = "dns-over-tls")]
+use crate::tcp::Socks5Stream;
+use crate::{
+    connector::Socks5Connector, tcp::Command, AuthConfig, Authentication, Error, ReplyKind,
+    TargetAddr, ToProxyAddrs,
+};
+use futures::{
+    future::{self, Either},
+    Future,
+};
+use std::{
+    fmt, io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+};
+use tokio_io::{
+    io::{read_exact, write_all},
+    AsyncRead, AsyncWrite,
+};
+
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+const CLASS_IN: u16 = 1;
+const RCODE_NXDOMAIN: u8 = 3;
+
+pub(crate) type Resolving = Box<dyn Future<Item = Vec<IpAddr>, Error = Error> + Send>;
+
+/// Resolves domain names to IP addresses.
+///
+/// It's implemented by `ProxyResolver`, and can be implemented to resolve the targets of a
+/// connector with any other resolver, set with `Socks5ConnectorBuilder::local_resolver`.
+pub trait Resolve {
+    /// The `Future` resolving a domain name.
+    type Future: Future<Item = Vec<IpAddr>, Error = Error>;
+
+    /// Starts resolving `domain`.
+    fn resolve(&self, domain: &str) -> Self::Future;
+}
+
+/// A `Resolve` set as the local resolver of a connector.
+#[derive(Clone)]
+pub(crate) struct SharedResolver(pub(crate) Arc<dyn Fn(&str) -> Resolving + Send + Sync>);
+
+impl fmt::Debug for SharedResolver {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("SharedResolver")
+    }
+}
+
+/// Resolves domain names through a proxy server.
+///
+/// The domains are resolved with the RESOLVE extension of Tor. If a DNS server is set and
+/// the proxy server doesn't support RESOLVE, they are resolved by querying the DNS server
+/// over TCP through a connection established by the proxy server instead.
+///
+/// It can be set as the resolver of the targets of a connector resolving them locally with
+/// `Socks5ConnectorBuilder::local_resolver`.
+#[derive(Debug, Clone)]
+pub struct ProxyResolver<P> {
+    connector: Socks5Connector,
+    proxy: P,
+    auth: Authentication<'static>,
+    dns_server: Option<SocketAddr>,
+    #[cfg(feature = "dns-over-tls")]
+    tls_name: Option<String>,
+}
+
+impl<P> ProxyResolver<P>
+where
+    P: ToProxyAddrs + Clone + Send + 'static,
+    P::Output: Send + 'static,
+{
+    /// Creates a resolver sending the requests to `proxy` through `connector`.
+    pub fn new(connector: Socks5Connector, proxy: P) -> ProxyResolver<P> {
+        ProxyResolver {
+            connector,
+            proxy,
+            auth: Authentication::None,
+            dns_server: None,
+            #[cfg(feature = "dns-over-tls")]
+            tls_name: None,
+        }
+    }
+
+    /// Sets how to authenticate against the proxy server.
+    pub fn auth(mut self, auth: AuthConfig<'static>) -> ProxyResolver<P> {
+        self.auth = auth.0;
+        self
+    }
+
+    /// Sets the DNS server queried when the proxy server doesn't support RESOLVE.
+    pub fn dns_server(mut self, server: SocketAddr) -> ProxyResolver<P> {
+        self.dns_server = Some(server);
+        self
+    }
+
+    /// Queries the DNS server at `server` over TLS (RFC 7858) for every domain, without
+    /// trying RESOLVE first. The certificate of the server is verified against `name`.
+    ///
+    /// The domains are resolved by the DNS server of choice, and neither the proxy server
+    /// nor the network path to the DNS server can read or tamper with the queries.
+    #[cfg(feature = "dns-over-tls")]
+    pub fn dns_over_tls(mut self, server: SocketAddr, name: &str) -> ProxyResolver<P> {
+        self.dns_server = Some(server);
+        self.tls_name = Some(name.to_owned());
+        self
+    }
+
+    /// Returns the DNS server to query over TLS, if any.
+    fn tls_server(&self) -> Option<SocketAddr> {
+        #[cfg(feature = "dns-over-tls")]
+        {
+            self.tls_name.as_ref().and(self.dns_server)
+        }
+        #[cfg(not(feature = "dns-over-tls"))]
+        {
+            None
+        }
+    }
+
+    /// Queries the A and AAAA records of `domain` from `server` through the proxy server.
+    fn query(&self, server: SocketAddr, domain: &str) -> Resolving {
+        let queries = encode_query(1, domain, TYPE_A).and_then(|a| {
+            let aaaa = encode_query(2, domain, TYPE_AAAA)?;
+            Ok([a, aaaa].concat())
+        });
+        let conn = self.connector.connect_with_auth(
+            self.proxy.clone(),
+            server,
+            AuthConfig(self.auth.clone()),
+        );
+        #[cfg(feature = "dns-over-tls")]
+        let tls_name = self.tls_name.clone();
+        let queried = future::result(queries.and_then(|queries| Ok((conn?, queries))))
+            .and_then(|(conn, queries)| conn.map(|stream| (stream, queries)))
+            .and_then(move |(stream, queries)| -> Resolving {
+                #[cfg(feature = "dns-over-tls")]
+                {
+                    if let Some(name) = tls_name {
+                        return exchange_over_tls(stream, &name, queries);
+                    }
+                }
+                Box::new(exchange(stream, queries))
+            });
+        Box::new(queried)
+    }
+}
+
+impl<P> Resolve for ProxyResolver<P>
+where
+    P: ToProxyAddrs + Clone + Send + 'static,
+    P::Output: Send + 'static,
+{
+    type Future = Box<dyn Future<Item = Vec<IpAddr>, Error = Error> + Send>;
+
+    /// Resolves `domain` through the proxy server.
+    ///
+    /// RESOLVE returns a single address, and fails with the reply of the proxy server if
+    /// the domain doesn't exist. A DNS server returns all the IPv4 and IPv6 addresses, and
+    /// none if the domain doesn't exist.
+    fn resolve(&self, domain: &str) -> Resolving {
+        if let Ok(ip) = domain.parse::<IpAddr>() {
+            return Box::new(future::ok(vec![ip]));
+        }
+        if let Some(server) = self.tls_server() {
+            return self.query(server, domain);
+        }
+        let resolver = self.clone();
+        let domain = domain.to_owned();
+        let target = TargetAddr::Domain(domain.clone().into(), 0);
+        Box::new(
+            future::result(self.connector.connect_raw(
+                self.proxy.clone(),
+                target,
+                self.auth.clone(),
+                Command::Resolve,
+            ))
+            .flatten()
+            .and_then(|stream| match stream.target_addr() {
+                TargetAddr::Ip(addr) => Ok(vec![addr.ip()]),
+                TargetAddr::Domain(..) => Err(Error::UnknownAddressType),
+            })
+            .or_else(move |e| match resolver.dns_server {
+                Some(server) if e.reply_kind() == Some(ReplyKind::CommandNotSupported) => {
+                    debug_event!("RESOLVE not supported, querying the DNS server");
+                    Either::A(resolver.query(server, &domain))
+                }
+                _ => Either::B(future::err(e)),
+            }),
+        )
+    }
+}
+
+/// Sends the queries and reads the responses to the A and AAAA queries.
+fn exchange<S>(stream: S, queries: Vec<u8>) -> impl Future<Item = Vec<IpAddr>, Error = Error>
+where
+    S: AsyncRead + AsyncWrite,
+{
+    write_all(stream, queries)
+        .map_err(Error::from)
+        .and_then(|(stream, _)| read_response(stream))
+        .and_then(|(stream, first)| read_response(stream).map(|(_, second)| (first, second)))
+        .and_then(|(first, second)| {
+            // The responses may come in any order, but must answer both queries.
+            let (first_id, mut addrs) = decode_response(&first)?;
+            let (second_id, more) = decode_response(&second)?;
+            if first_id.min(second_id) != 1 || first_id.max(second_id) != 2 {
+                return Err(invalid_response());
+            }
+            addrs.extend(more);
+            Ok(addrs)
+        })
+}
+
+#[cfg(feature = "dns-over-tls")]
+fn exchange_over_tls(stream: Socks5Stream, name: &str, queries: Vec<u8>) -> Resolving {
+    let tls_error = |e| Error::Io(io::Error::other(e));
+    let tls = match native_tls::TlsConnector::new() {
+        Ok(tls) => tokio_tls::TlsConnector::from(tls),
+        Err(e) => return Box::new(future::err(tls_error(e))),
+    };
+    debug_event!(name, "querying the DNS server over TLS");
+    let queried = tls
+        .connect(name, stream)
+        .map_err(tls_error)
+        .and_then(|stream| exchange(stream, queries));
+    Box::new(queried)
+}
+
+fn read_response<S>(stream: S) -> impl Future<Item = (S, Vec<u8>), Error = Error>
+where
+    S: AsyncRead,
+{
+    read_exact(stream, [0; 2])
+        .and_then(|(stream, len)| read_exact(stream, vec![0; u16::from_be_bytes(len) as usize]))
+        .map_err(Error::from)
+}
+
+/// Encodes a recursive query for the records of `qtype` of `domain`, prefixed with its
+/// length as sent over TCP.
+fn encode_query(id: u16, domain: &str, qtype: u16) -> Result<Vec<u8>, Error> {
+    let mut buf = vec![0, 0];
+    buf.extend_from_slice(&id.to_be_bytes());
+    // Recursion desired, a single question.
+    buf.extend_from_slice(&[0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+    for label in domain.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            Err(Error::InvalidTargetAddress("invalid domain label"))?
+        }
+        buf.push(label.len() as u8);
+        buf.extend_from_slice(label.as_bytes());
+    }
+    buf.push(0);
+    // The name follows the 2 bytes of the length and the 12 of the header.
+    if buf.len() - 14 > 255 {
+        Err(Error::InvalidTargetAddress("domain name too long"))?
+    }
+    buf.extend_from_slice(&qtype.to_be_bytes());
+    buf.extend_from_slice(&CLASS_IN.to_be_bytes());
+    let len = buf.len() as u16 - 2;
+    buf[..2].copy_from_slice(&len.to_be_bytes());
+    Ok(buf)
+}
+
+fn invalid_response() -> Error {
+    Error::Io(io::Error::new(
+        io::ErrorKind::InvalidData,
+        "invalid DNS response",
+    ))
+}
+
+/// Returns the ID of a DNS response and the addresses in the A and AAAA records of its
+/// answers.
+fn decode_response(msg: &[u8]) -> Result<(u16, Vec<IpAddr>), Error> {
+    // Only responses, not queries.
+    if msg.len() < 12 || msg[2] & 0x80 == 0 {
+        return Err(invalid_response());
+    }
+    let id = u16::from_be_bytes([msg[0], msg[1]]);
+    match msg[3] & 0x0f {
+        0 => {}
+        RCODE_NXDOMAIN => return Ok((id, Vec::new())),
+        rcode => {
+            let msg = format!("DNS server failure, rcode {}", rcode);
+            return Err(Error::Io(io::Error::other(msg)));
+        }
+    }
+    let count = |i: usize| u16::from_be_bytes([msg[i], msg[i + 1]]);
+    let mut pos = 12;
+    for _ in 0..count(4) {
+        pos = skip_name(msg, pos).ok_or_else(invalid_response)? + 4;
+    }
+    let mut addrs = Vec::new();
+    for _ in 0..count(6) {
+        pos = skip_name(msg, pos).ok_or_else(invalid_response)?;
+        let header = msg.get(pos..pos + 10).ok_or_else(invalid_response)?;
+        let rtype = u16::from_be_bytes([header[0], header[1]]);
+        let len = u16::from_be_bytes([header[8], header[9]]) as usize;
+        let data = msg
+            .get(pos + 10..pos + 10 + len)
+            .ok_or_else(invalid_response)?;
+        match (rtype, len) {
+            (TYPE_A, 4) => addrs.push(Ipv4Addr::new(data[0], data[1], data[2], data[3]).into()),
+            (TYPE_AAAA, 16) => {
+                let mut ip = [0; 16];
+                ip.copy_from_slice(data);
+                addrs.push(Ipv6Addr::from(ip).into());
+            }
+            // CNAME records and the like.
+            _ => {}
+        }
+        pos += 10 + len;
+    }
+    Ok((id, addrs))
+}
+
+/// Returns the position after the name starting at `pos`, which ends with either an empty
+/// label or a pointer to another name.
+fn skip_name(msg: &[u8], mut pos: usize) -> Option<usize> {
+    loop {
+        let len = *msg.get(pos)? as usize;
+        match len {
+            0 => return Some(pos + 1),
+            _ if len & 0xc0 == 0xc0 => return Some(pos + 2),
+            _ => pos += 1 + len,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        observer::Direction,
+        trace::{Replay, Trace},
+    };
+    use futures::stream::{self, Once};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::runtime::current_thread::Runtime;
+
+    /// Proxy addresses handing out a different server to each connection, in order.
+    #[derive(Debug, Clone)]
+    struct Proxies {
+        addrs: Vec<SocketAddr>,
+        next: Arc<AtomicUsize>,
+    }
+
+    impl Proxies {
+        fn new(replays: &[&Replay]) -> Proxies {
+            Proxies {
+                addrs: replays.iter().map(|replay| replay.local_addr()).collect(),
+                next: Arc::default(),
+            }
+        }
+    }
+
+    impl ToProxyAddrs for Proxies {
+        type Output = Once<SocketAddr, Error>;
+
+        fn to_proxy_addrs(&self) -> Self::Output {
+            stream::once(Ok(self.addrs[self.next.fetch_add(1, Ordering::SeqCst)]))
+        }
+    }
+
+    /// A proxy server replying to the RESOLVE request for example.com with `reply`.
+    fn resolve_replay(reply: &str) -> Replay {
+        let trace = format!(
+            "
+            > 05 01 00
+            < 05 00
+            > 05 f0 00 03 0b 65 78 61 6d 70 6c 65 2e 63 6f 6d 00 00
+            < {}
+            ",
+            reply
+        );
+        Replay::bind(trace.parse().unwrap()).unwrap()
+    }
+
+    /// Returns the response to `query` with the A record of 10.0.0.2 if `answer` is set.
+    fn response(query: &[u8], answer: bool) -> Vec<u8> {
+        let mut response = query[2..].to_vec();
+        response[2] |= 0x80;
+        if answer {
+            response[7] = 1;
+            response.extend_from_slice(&[0xc0, 12, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 10, 0, 0, 2]);
+        }
+        let mut msg = (response.len() as u16).to_be_bytes().to_vec();
+        msg.extend_from_slice(&response);
+        msg
+    }
+
+    fn resolve<P>(resolver: ProxyResolver<P>) -> Result<Vec<IpAddr>, Error>
+    where
+        P: ToProxyAddrs + Clone + Send + 'static,
+        P::Output: Send + 'static,
+    {
+        Runtime::new()
+            .unwrap()
+            .block_on(resolver.resolve("example.com"))
+    }
+
+    #[test]
+    fn resolves_with_the_resolve_command() {
+        let replay = resolve_replay("05 00 00 01 0a 00 00 01 00 00");
+        let resolver = ProxyResolver::new(Socks5Connector::new(), replay.local_addr());
+        assert_eq!(
+            resolve(resolver).unwrap(),
+            vec![IpAddr::from([10, 0, 0, 1])]
+        );
+        let resolver = ProxyResolver::new(Socks5Connector::new(), replay.local_addr());
+        assert_eq!(
+            Runtime::new()
+                .unwrap()
+                .block_on(resolver.resolve("10.0.0.3"))
+                .unwrap(),
+            vec![IpAddr::from([10, 0, 0, 3])]
+        );
+        assert_eq!(replay.mismatches(), 0);
+    }
+
+    #[test]
+    fn queries_the_dns_server_without_resolve() {
+        let unsupported = resolve_replay("05 07 00 01 00 00 00 00 00 00");
+        let a = encode_query(1, "example.com", TYPE_A).unwrap();
+        let aaaa = encode_query(2, "example.com", TYPE_AAAA).unwrap();
+        let mut trace: Trace = "
+            > 05 01 00
+            < 05 00
+            > 05 01 00 01 0a 00 00 35 00 35
+            < 05 00 00 01 0a 00 00 35 00 35
+        "
+        .parse()
+        .unwrap();
+        trace.push(Direction::Sent, &[a.clone(), aaaa.clone()].concat());
+        trace.push(Direction::Received, &response(&aaaa, false));
+        trace.push(Direction::Received, &response(&a, true));
+        let dns = Replay::bind(trace).unwrap();
+        let resolver =
+            ProxyResolver::new(Socks5Connector::new(), Proxies::new(&[&unsupported, &dns]))
+                .dns_server("10.0.0.53:53".parse().unwrap());
+        assert_eq!(
+            resolve(resolver).unwrap(),
+            vec![IpAddr::from([10, 0, 0, 2])]
+        );
+        assert_eq!(unsupported.mismatches() + dns.mismatches(), 0);
+        // Without a DNS server, the reply of the proxy server is returned.
+        let resolver = ProxyResolver::new(Socks5Connector::new(), unsupported.local_addr());
+        let e = resolve(resolver).unwrap_err();
+        assert_eq!(e.reply_kind(), Some(ReplyKind::CommandNotSupported));
+    }
+
+    #[test]
+    fn rejects_responses_to_other_queries() {
+        let unsupported = resolve_replay("05 07 00 01 00 00 00 00 00 00");
+        let a = encode_query(1, "example.com", TYPE_A).unwrap();
+        let aaaa = encode_query(2, "example.com", TYPE_AAAA).unwrap();
+        let mut trace: Trace = "
+            > 05 01 00
+            < 05 00
+            > 05 01 00 01 0a 00 00 35 00 35
+            < 05 00 00 01 0a 00 00 35 00 35
+        "
+        .parse()
+        .unwrap();
+        trace.push(Direction::Sent, &[a.clone(), aaaa].concat());
+        // Two responses to the A query, and none to the AAAA one.
+        trace.push(Direction::Received, &response(&a, true));
+        trace.push(Direction::Received, &response(&a, true));
+        let dns = Replay::bind(trace).unwrap();
+        let resolver =
+            ProxyResolver::new(Socks5Connector::new(), Proxies::new(&[&unsupported, &dns]))
+                .dns_server("10.0.0.53:53".parse().unwrap());
+        match resolve(resolver) {
+            Err(Error::Io(ref e)) if e.kind() == io::ErrorKind::InvalidData => {}
+            res => panic!("expected an invalid response, got {:?}", res),
+        }
+    }
+
+    #[test]
+    fn decodes_the_answers_of_a_query() {
+        let query = encode_query(1, "example.com.", TYPE_A).unwrap();
+        assert_eq!(query[..2], [0, 29]);
+        assert_eq!(query[14..27], b"\x07example\x03com\x00"[..]);
+        assert!(encode_query(1, "example..com", TYPE_A).is_err());
+        let long = vec!["a".repeat(63); 4].join(".");
+        assert!(encode_query(1, &long, TYPE_A).is_err());
+        assert!(encode_query(1, &long[2..], TYPE_A).is_ok());
+
+        let mut response = query[2..].to_vec();
+        response[2] |= 0x80;
+        response[7] = 2;
+        // A CNAME to a name, and its A record, both pointing to the question.
+        response.extend_from_slice(&[0xc0, 12, 0, 5, 0, 1, 0, 0, 0, 60, 0, 2, 0xc0, 12]);
+        response.extend_from_slice(&[0xc0, 12, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 10, 0, 0, 1]);
+        assert_eq!(
+            decode_response(&response).unwrap(),
+            (1, vec![IpAddr::from([10, 0, 0, 1])])
+        );
+        assert!(decode_response(&response[..response.len() - 1]).is_err());
+        // The query itself, without the QR bit.
+        assert!(decode_response(&query[2..]).is_err());
+        response[3] = RCODE_NXDOMAIN;
+        assert!(decode_response(&response).unwrap().1.is_empty());
+    }
+
+    #[test]
+    #[cfg(feature = "dns-over-tls")]
+    fn queries_the_dns_server_over_tls_without_resolve() {
+        // The DNS server rejects the TLS handshake with a handshake_failure alert.
+        let trace = "
+            > 05 01 00
+            < 05 00
+            > 05 01 00 01 0a 00 00 35 03 55
+            < 05 00 00 01 0a 00 00 35 03 55
+            < 15 03 03 00 02 02 28
+        ";
+        let replay = Replay::bind(trace.parse().unwrap()).unwrap();
+        let resolver = ProxyResolver::new(Socks5Connector::new(), replay.local_addr())
+            .dns_over_tls("10.0.0.53:853".parse().unwrap(), "dns.example.com");
+        match resolve(resolver) {
+            Err(Error::Io(_)) => {}
+            res => panic!("expected the TLS handshake to fail, got {:?}", res),
+        }
+        assert_eq!(replay.mismatches(), 0);
+    }
+}
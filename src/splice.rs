@@ -0,0 +1,272 @@
+//! A relay moving the bytes between two TCP streams inside the kernel with `splice(2)`,
+//! without copying them to userspace.
+
+use crate::{
+    relay::{Idle, RelayStats},
+    Error,
+};
+use futures::{try_ready, Async, Future, Poll};
+use std::{io, net::Shutdown, os::unix::io::AsRawFd, ptr, time::Duration};
+use tokio_io::AsyncWrite;
+use tokio_tcp::TcpStream;
+
+// The default capacity of a pipe
+const PIPE_SIZE: usize = 64 * 1024;
+const BUFFER_SIZE: usize = 8 * 1024;
+
+/// Relays the bytes between `a` and `b` like `relay`, moving them through a pipe with
+/// `splice(2)` rather than through a buffer in userspace.
+///
+/// # Error
+///
+/// It fails if the pipes can't be created.
+pub fn splice(a: TcpStream, b: TcpStream) -> io::Result<Splice> {
+    Ok(Splice {
+        a,
+        b,
+        a_to_b: Half::new()?,
+        b_to_a: Half::new()?,
+        idle: Idle::default(),
+    })
+}
+
+/// A `Future` moving the bytes between two TCP streams with `splice(2)`, returned by
+/// `splice`.
+///
+/// It resolves to the number of bytes moved in each direction.
+#[derive(Debug)]
+pub struct Splice {
+    a: TcpStream,
+    b: TcpStream,
+    a_to_b: Half,
+    b_to_a: Half,
+    idle: Idle,
+}
+
+/// The ends of a pipe, closed on drop.
+#[derive(Debug)]
+struct Pipe {
+    read: libc::c_int,
+    write: libc::c_int,
+}
+
+impl Pipe {
+    fn new() -> io::Result<Pipe> {
+        let mut fds = [0; 2];
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Pipe {
+            read: fds[0],
+            write: fds[1],
+        })
+    }
+}
+
+impl Drop for Pipe {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.read);
+            libc::close(self.write);
+        }
+    }
+}
+
+/// Moves up to `len` bytes from `from` to `to`, one of which must be a pipe.
+fn splice_fd(from: libc::c_int, to: libc::c_int, len: usize) -> io::Result<usize> {
+    let flags = libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK;
+    let n = unsafe { libc::splice(from, ptr::null_mut(), to, ptr::null_mut(), len, flags) };
+    if n < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(n as usize)
+    }
+}
+
+/// The state of the transfer in one direction.
+#[derive(Debug)]
+struct Half {
+    pipe: Pipe,
+    // The number of bytes in the pipe.
+    piped: usize,
+    // The bytes taken out of the pipe when the writer wasn't ready, so that the writer is
+    // woken up by the reactor once it is.
+    buf: Vec<u8>,
+    pos: usize,
+    read_done: bool,
+    done: bool,
+    amt: u64,
+}
+
+impl Half {
+    fn new() -> io::Result<Half> {
+        Ok(Half {
+            pipe: Pipe::new()?,
+            piped: 0,
+            buf: Vec::new(),
+            pos: 0,
+            read_done: false,
+            done: false,
+            amt: 0,
+        })
+    }
+
+    /// Moves the bytes from `reader` to `writer` until `reader` reaches the end of its
+    /// stream, and then shuts `writer` down. `active` is set if any byte was moved.
+    fn poll_splice(
+        &mut self,
+        reader: &mut TcpStream,
+        writer: &mut TcpStream,
+        active: &mut bool,
+    ) -> Poll<(), io::Error> {
+        while !self.done {
+            if self.pos < self.buf.len() {
+                let n = try_ready!(AsyncWrite::poll_write(writer, &self.buf[self.pos..]));
+                if n == 0 {
+                    return Err(io::ErrorKind::WriteZero.into());
+                }
+                self.pos += n;
+                self.amt += n as u64;
+                *active = true;
+            } else if self.piped > 0 {
+                match splice_fd(self.pipe.read, writer.as_raw_fd(), self.piped) {
+                    Ok(n) => {
+                        self.piped -= n;
+                        self.amt += n as u64;
+                        *active = true;
+                    }
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        // Falls back to `poll_write` to register the interest in the
+                        // writability of the stream.
+                        self.buf.resize(BUFFER_SIZE.min(self.piped), 0);
+                        let n = unsafe {
+                            libc::read(
+                                self.pipe.read,
+                                self.buf.as_mut_ptr() as *mut libc::c_void,
+                                self.buf.len(),
+                            )
+                        };
+                        if n < 0 {
+                            return Err(io::Error::last_os_error());
+                        }
+                        self.buf.truncate(n as usize);
+                        self.pos = 0;
+                        self.piped -= n as usize;
+                    }
+                    Err(e) => return Err(e),
+                }
+            } else if !self.read_done {
+                // Peeking registers the interest in the readability of the stream.
+                if try_ready!(reader.poll_peek(&mut [0])) == 0 {
+                    self.read_done = true;
+                    continue;
+                }
+                match splice_fd(reader.as_raw_fd(), self.pipe.write, PIPE_SIZE) {
+                    Ok(0) => self.read_done = true,
+                    Ok(n) => self.piped = n,
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                    Err(e) => return Err(e),
+                }
+            } else {
+                TcpStream::shutdown(writer, Shutdown::Write)?;
+                self.done = true;
+            }
+        }
+        Ok(Async::Ready(()))
+    }
+}
+
+impl Splice {
+    /// Fails the relay with `Error::Timeout` if no byte is moved in either direction for
+    /// `timeout`.
+    pub fn idle_timeout(mut self, timeout: Duration) -> Splice {
+        self.idle.set(timeout);
+        self
+    }
+
+    /// Returns the number of bytes moved so far.
+    pub fn stats(&self) -> RelayStats {
+        RelayStats {
+            a_to_b: self.a_to_b.amt,
+            b_to_a: self.b_to_a.amt,
+        }
+    }
+
+    /// Consumes the `Splice`, returning the two streams.
+    pub fn into_inner(self) -> (TcpStream, TcpStream) {
+        (self.a, self.b)
+    }
+}
+
+impl Future for Splice {
+    type Item = RelayStats;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<RelayStats, Error> {
+        let mut active = false;
+        let a_to_b = self
+            .a_to_b
+            .poll_splice(&mut self.a, &mut self.b, &mut active)?;
+        let b_to_a = self
+            .b_to_a
+            .poll_splice(&mut self.b, &mut self.a, &mut active)?;
+        if a_to_b.is_ready() && b_to_a.is_ready() {
+            return Ok(Async::Ready(self.stats()));
+        }
+        self.idle.poll(active)?;
+        Ok(Async::NotReady)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future;
+    use std::{
+        io::{Read, Write},
+        net::TcpListener,
+        thread,
+    };
+    use tokio_reactor::Handle;
+
+    fn connected_pair() -> (std::net::TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = std::net::TcpStream::connect(addr).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        (
+            client,
+            TcpStream::from_std(server, &Handle::default()).unwrap(),
+        )
+    }
+
+    #[test]
+    fn moves_both_ways_and_shuts_down() {
+        let (mut a, a_end) = connected_pair();
+        let (mut b, b_end) = connected_pair();
+        let peers = thread::spawn(move || {
+            a.write_all(b"request").unwrap();
+            a.shutdown(Shutdown::Write).unwrap();
+            b.write_all(b"response!").unwrap();
+            b.shutdown(Shutdown::Write).unwrap();
+            let (mut from_b, mut from_a) = (Vec::new(), Vec::new());
+            a.read_to_end(&mut from_b).unwrap();
+            b.read_to_end(&mut from_a).unwrap();
+            (from_a, from_b)
+        });
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        let stats = rt
+            .block_on(future::lazy(move || splice(a_end, b_end).unwrap()))
+            .unwrap();
+        assert_eq!(
+            stats,
+            RelayStats {
+                a_to_b: 7,
+                b_to_a: 9,
+            }
+        );
+        let (from_a, from_b) = peers.join().unwrap();
+        assert_eq!(&from_a[..], &b"request"[..]);
+        assert_eq!(&from_b[..], &b"response!"[..]);
+    }
+}
@@ -1,29 +1,91 @@
-use crate::{Authentication, Error, IntoTargetAddr, Result, TargetAddr, ToProxyAddrs};
-use bytes::{Buf, BufMut};
-use derefable::Derefable;
+use crate::{
+    abort::{self, AbortHandle, Abortable},
+    codec::{ClientEncoder, ClientMessage, Expect, ServerDecoder, ServerMessage},
+    connector::{Config, OrderedAddrs},
+    consts::{
+        self, CMD_CONNECT, METHOD_NO_ACCEPTABLE, METHOD_NO_AUTH, METHOD_PASSWORD,
+        PASSWORD_AUTH_SUCCEEDED, REPLY_SUCCEEDED,
+    },
+    dial::{self, HappyEyeballs, TcpConnect},
+    dialer::Tunnel,
+    observer::{Direction, HandshakeEvent, OnClose},
+    AuthConfig, AuthMethod, Authentication, Error, HandshakeError, HandshakePhase, IntoTargetAddr,
+    ProxySpec, ReplyKind, Result, TargetAddr, ToProxyAddrs,
+};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
 use futures::{stream, try_ready, Async, Future, Poll, Stream};
+use mio::Ready;
 use std::borrow::Borrow;
 use std::io::{self, Read, Write};
-use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::mem;
+use std::net::{Shutdown, SocketAddr, TcpStream as StdTcpStream};
+use std::ops::{Deref, DerefMut};
+#[cfg(unix)]
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+#[cfg(windows)]
+use std::os::windows::io::{AsRawSocket, FromRawSocket, RawSocket};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio_codec::{Decoder, Encoder};
 use tokio_io::{AsyncRead, AsyncWrite};
-use tokio_tcp::{ConnectFuture as TokioConnect, TcpStream};
+use tokio_reactor::Handle;
+use tokio_tcp::TcpStream;
+#[cfg(feature = "bind")]
+use {crate::connector::Keepalive, futures::future, std::net::Ipv4Addr, tokio_timer::Timeout};
 
 #[repr(u8)]
 #[derive(Clone, Copy)]
-enum Command {
-    Connect = 0x01,
-    Bind = 0x02,
-    Associate = 0x03,
+pub(crate) enum Command {
+    Connect = CMD_CONNECT,
+    #[cfg(feature = "bind")]
+    Bind = consts::CMD_BIND,
+    #[cfg(feature = "udp")]
+    Associate = consts::CMD_UDP_ASSOCIATE,
+    Resolve = consts::CMD_TOR_RESOLVE,
 }
 
 /// A SOCKS5 client.
 ///
-/// For convenience, it can be dereferenced to `tokio_tcp::TcpStream`.
-#[derive(Debug, Derefable)]
+/// Use `get_ref` and `get_mut` to access the underlying `tokio_tcp::TcpStream`.
+#[derive(Debug)]
 pub struct Socks5Stream {
-    #[deref(mutable)]
     tcp: TcpStream,
-    target: TargetAddr,
+    target: TargetAddr<'static>,
+    bind_addr: Option<TargetAddr<'static>>,
+    auth_method: AuthMethod,
+    proxy_addr: SocketAddr,
+    stats: HandshakeStats,
+    reply: Bytes,
+    // Only held to report the close of the stream when it's dropped.
+    _on_close: Option<OnClose>,
+}
+
+/// The reply of a proxy server to a request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reply {
+    /// The reply code, `0x00` on success.
+    pub code: u8,
+    /// The type of the bound address: `0x01` for IPv4, `0x03` for a domain and `0x04` for
+    /// IPv6.
+    pub addr_type: u8,
+    /// The bound address.
+    pub bind_addr: TargetAddr<'static>,
+    /// The bytes of the reply, as received.
+    pub raw: Bytes,
+}
+
+/// Timings of the handshake which established a `Socks5Stream`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct HandshakeStats {
+    /// The time spent resolving the address of the proxy server.
+    pub resolve: Duration,
+    /// The time spent establishing the TCP connection to the proxy server, including the
+    /// attempts to its previous addresses.
+    pub connect: Duration,
+    /// The number of round trips of the method selection and the authentication.
+    pub auth_round_trips: u32,
+    /// The duration of the whole handshake.
+    pub total: Duration,
 }
 
 impl Socks5Stream {
@@ -32,12 +94,18 @@ impl Socks5Stream {
     /// # Error
     ///
     /// It propagates the error that occurs in the conversion from `T` to `TargetAddr`.
-    pub fn connect<P, T>(proxy: P, target: T) -> Result<ConnectFuture<P::Output>>
+    pub fn connect<'t, P, T>(proxy: P, target: T) -> Result<ConnectFuture<'static, 't, P::Output>>
     where
         P: ToProxyAddrs,
-        T: IntoTargetAddr,
+        T: IntoTargetAddr<'t>,
     {
-        Self::connect_raw(proxy, target, Authentication::None, Command::Connect)
+        Self::connect_raw(
+            Arc::new(Config::default()),
+            proxy,
+            target,
+            Authentication::None,
+            Command::Connect,
+        )
     }
 
     /// Connects to a target server through a SOCKS5 proxy using given username and password.
@@ -45,52 +113,121 @@ impl Socks5Stream {
     /// # Error
     ///
     /// It propagates the error that occurs in the conversion from `T` to `TargetAddr`.
-    pub fn connect_with_password<P, T>(
+    pub fn connect_with_password<'a, 't, P, T>(
         proxy: P,
         target: T,
-        username: &str,
-        password: &str,
-    ) -> Result<ConnectFuture<P::Output>>
+        username: &'a str,
+        password: &'a str,
+    ) -> Result<ConnectFuture<'a, 't, P::Output>>
     where
         P: ToProxyAddrs,
-        T: IntoTargetAddr,
+        T: IntoTargetAddr<'t>,
     {
         Self::connect_raw(
+            Arc::new(Config::default()),
             proxy,
             target,
-            Authentication::Password { username: username.to_string(), password: password.to_string() },
+            Authentication::Password {
+                username: username.into(),
+                password: password.into(),
+            },
+            Command::Connect,
+        )
+    }
+
+    /// Connects to a target server through the proxy described by `proxy`, authenticating
+    /// with its credentials if it has any.
+    ///
+    /// # Error
+    ///
+    /// It propagates the error that occurs in the conversion from `T` to `TargetAddr`.
+    pub fn connect_with_spec<'a, 't, P, T>(
+        proxy: &'a ProxySpec<P>,
+        target: T,
+    ) -> Result<ConnectFuture<'a, 't, P::Output>>
+    where
+        P: ToProxyAddrs,
+        T: IntoTargetAddr<'t>,
+    {
+        Self::connect_raw(
+            Arc::new(Config::default()),
+            &proxy.addrs,
+            target,
+            proxy.authentication(),
             Command::Connect,
         )
     }
 
-    fn connect_raw<P, T>(
+    /// Connects to a target server through a SOCKS5 proxy, authenticating as described by
+    /// `auth`.
+    ///
+    /// # Error
+    ///
+    /// It propagates the error that occurs in the conversion from `T` to `TargetAddr`, and
+    /// fails if the credentials are invalid.
+    pub fn connect_with_auth<'a, 't, P, T>(
         proxy: P,
         target: T,
-        auth: Authentication,
+        auth: AuthConfig<'a>,
+    ) -> Result<ConnectFuture<'a, 't, P::Output>>
+    where
+        P: ToProxyAddrs,
+        T: IntoTargetAddr<'t>,
+    {
+        Self::connect_raw(
+            Arc::new(Config::default()),
+            proxy,
+            target,
+            auth.0,
+            Command::Connect,
+        )
+    }
+
+    /// Runs the handshake on `stream`, which is already connected to the proxy server,
+    /// e.g. taken from a socket pool or inherited from the parent process, authenticating
+    /// as described by `auth`.
+    ///
+    /// The stream is registered with the default reactor.
+    ///
+    /// # Error
+    ///
+    /// It propagates the error that occurs in the conversion from `T` to `TargetAddr`, and
+    /// fails if the credentials are invalid or the stream can't be registered.
+    pub fn connect_with_std<'a, 't, T>(
+        stream: StdTcpStream,
+        target: T,
+        auth: AuthConfig<'a>,
+    ) -> Result<ConnectFuture<'a, 't, stream::Empty<SocketAddr, Error>>>
+    where
+        T: IntoTargetAddr<'t>,
+    {
+        let auth = auth.0;
+        auth.validate()?;
+        let mut conn = ConnectFuture::new(
+            Arc::new(Config::default()),
+            auth,
+            Command::Connect,
+            stream::empty(),
+            target.into_target_addr()?,
+        );
+        conn.use_stream(TcpStream::from_std(stream, &Handle::default())?)?;
+        Ok(conn)
+    }
+
+    pub(crate) fn connect_raw<'a, 't, P, T>(
+        config: Arc<Config>,
+        proxy: P,
+        target: T,
+        auth: Authentication<'a>,
         command: Command,
-    ) -> Result<ConnectFuture<P::Output>>
+    ) -> Result<ConnectFuture<'a, 't, P::Output>>
     where
         P: ToProxyAddrs,
-        T: IntoTargetAddr,
+        T: IntoTargetAddr<'t>,
     {
-        let auth = if let Authentication::Password { username, password } = auth {
-            let username_len = username.as_bytes().len();
-            if username_len < 1 || username_len > 255 {
-                Err(Error::InvalidAuthValues(
-                    "username length should between 1 to 255",
-                ))?
-            }
-            let password_len = password.as_bytes().len();
-            if password_len < 1 || password_len > 255 {
-                Err(Error::InvalidAuthValues(
-                    "password length should between 1 to 255",
-                ))?
-            }
-            Authentication::Password { username, password }
-        } else {
-            auth
-        };
+        auth.validate()?;
         Ok(ConnectFuture::new(
+            config,
             auth,
             command,
             proxy.to_proxy_addrs(),
@@ -103,319 +240,752 @@ impl Socks5Stream {
         self.tcp
     }
 
+    /// Consumes the `Socks5Stream`, returning a blocking `std::net::TcpStream` tunneled to
+    /// the target, e.g. to hand it over to another runtime or to blocking code.
+    ///
+    /// The socket is duplicated before the stream is deregistered from its reactor.
+    pub fn into_std(self) -> io::Result<StdTcpStream> {
+        let tcp = clone_std(&self.tcp)?;
+        drop(self);
+        tcp.set_nonblocking(false)?;
+        Ok(tcp)
+    }
+
+    /// Returns a shared reference to the inner `tokio_tcp::TcpStream`.
+    pub fn get_ref(&self) -> &TcpStream {
+        &self.tcp
+    }
+
+    /// Returns a mutable reference to the inner `tokio_tcp::TcpStream`.
+    ///
+    /// Reading from or writing to the inner stream directly bypasses this wrapper, so
+    /// care should be taken not to mess up the tunneled data.
+    pub fn get_mut(&mut self) -> &mut TcpStream {
+        &mut self.tcp
+    }
+
+    /// Receives the data tunneled from the target without removing it from the queue, e.g.
+    /// to sniff the protocol spoken by the target before reading it.
+    ///
+    /// Successive calls return the same data until it's read.
+    pub fn poll_peek(&mut self, buf: &mut [u8]) -> Poll<usize, io::Error> {
+        self.tcp.poll_peek(buf)
+    }
+
+    /// Checks whether the tunneled data can be read, without reading it, like
+    /// `TcpStream::poll_read_ready`. If not, the current task is notified once it can.
+    ///
+    /// `mask` is the readiness to check, e.g. `Ready::readable()`. The readiness is cleared
+    /// when a read would block.
+    pub fn poll_read_ready(&self, mask: Ready) -> Poll<Ready, io::Error> {
+        self.tcp.poll_read_ready(mask)
+    }
+
+    /// Checks whether data can be written to the tunnel, without writing it, like
+    /// `TcpStream::poll_write_ready`. If not, the current task is notified once it can.
+    ///
+    /// The readiness is cleared when a write would block.
+    pub fn poll_write_ready(&self) -> Poll<Ready, io::Error> {
+        self.tcp.poll_write_ready()
+    }
+
+    /// Shuts down the write half of the connection, so that the proxy server forwards a
+    /// FIN to the target, while the data sent by the target can still be read.
+    ///
+    /// Unlike this, `AsyncWrite::shutdown` leaves both halves of the connection open.
+    pub fn shutdown_write(&self) -> io::Result<()> {
+        self.tcp.shutdown(Shutdown::Write)
+    }
+
+    /// Returns the authentication method selected by the proxy server.
+    pub fn auth_method(&self) -> AuthMethod {
+        self.auth_method
+    }
+
+    /// Gets the value of the `TCP_NODELAY` option on the connection to the proxy server.
+    pub fn nodelay(&self) -> io::Result<bool> {
+        self.tcp.nodelay()
+    }
+
+    /// Sets the value of the `TCP_NODELAY` option on the connection to the proxy server.
+    pub fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        self.tcp.set_nodelay(nodelay)
+    }
+
+    /// Returns the idle time before keepalive probes are sent, or `None` if `SO_KEEPALIVE`
+    /// is disabled.
+    pub fn keepalive(&self) -> io::Result<Option<Duration>> {
+        self.tcp.keepalive()
+    }
+
+    /// Sets the idle time before keepalive probes are sent, or disables `SO_KEEPALIVE`
+    /// with `None`.
+    pub fn set_keepalive(&self, keepalive: Option<Duration>) -> io::Result<()> {
+        self.tcp.set_keepalive(keepalive)
+    }
+
+    /// Gets the value of the `IP_TTL` option on the connection to the proxy server.
+    pub fn ttl(&self) -> io::Result<u32> {
+        self.tcp.ttl()
+    }
+
+    /// Sets the value of the `IP_TTL` option on the connection to the proxy server.
+    pub fn set_ttl(&self, ttl: u32) -> io::Result<()> {
+        self.tcp.set_ttl(ttl)
+    }
+
+    /// Gets the value of the `SO_LINGER` option on the connection to the proxy server.
+    pub fn linger(&self) -> io::Result<Option<Duration>> {
+        self.tcp.linger()
+    }
+
+    /// Sets the value of the `SO_LINGER` option on the connection to the proxy server.
+    pub fn set_linger(&self, linger: Option<Duration>) -> io::Result<()> {
+        self.tcp.set_linger(linger)
+    }
+
+    /// Returns the address of the proxy server this stream is connected to.
+    ///
+    /// When the proxy server has several addresses, this is the one which was reached.
+    pub fn proxy_addr(&self) -> SocketAddr {
+        self.proxy_addr
+    }
+
+    /// Returns the timings of the handshake which established this stream.
+    pub fn handshake_stats(&self) -> HandshakeStats {
+        self.stats
+    }
+
+    /// Returns the reply of the proxy server to the request which established this stream.
+    ///
+    /// For a stream accepted from a `Socks5Listener`, this is the second BIND reply.
+    pub fn reply(&self) -> Reply {
+        Reply {
+            code: self.reply[1],
+            addr_type: self.reply[3],
+            bind_addr: self.target.clone(),
+            raw: self.reply.clone(),
+        }
+    }
+
     /// Returns the target address that the proxy server connects to.
-    pub fn target_addr(&self) -> TargetAddr {
-        match &self.target {
-            TargetAddr::Ip(addr) => TargetAddr::Ip(*addr),
-            TargetAddr::Domain(domain, port) => {
-                let domain: &str = domain.borrow();
-                TargetAddr::Domain(domain.into(), *port)
-            }
+    ///
+    /// For a stream accepted from a `Socks5Listener`, this is the address of the peer which
+    /// connected to the proxy server.
+    pub fn target_addr(&self) -> &TargetAddr<'static> {
+        &self.target
+    }
+
+    /// Like `target_addr`, but returns an owned copy of the address.
+    pub fn target_addr_owned(&self) -> TargetAddr<'static> {
+        self.target.clone()
+    }
+
+    /// Returns the address the proxy server listened on, as reported in the first BIND
+    /// reply, or `None` if this stream wasn't accepted from a `Socks5Listener`.
+    pub fn bind_addr(&self) -> Option<TargetAddr<'_>> {
+        self.bind_addr.as_ref().map(borrow_addr)
+    }
+
+    /// Returns the address of the peer which connected to the proxy server, as reported in
+    /// the second BIND reply, or `None` if this stream wasn't accepted from a
+    /// `Socks5Listener`.
+    pub fn bind_peer_addr(&self) -> Option<TargetAddr<'_>> {
+        self.bind_addr.as_ref().map(|_| borrow_addr(&self.target))
+    }
+}
+
+fn borrow_addr<'a>(addr: &'a TargetAddr<'_>) -> TargetAddr<'a> {
+    match addr {
+        TargetAddr::Ip(addr) => TargetAddr::Ip(*addr),
+        TargetAddr::Domain(domain, port) => {
+            let domain: &str = domain.borrow();
+            TargetAddr::Domain(domain.into(), *port)
         }
     }
 }
 
 /// A `Future` which resolves to a socket to the target server through proxy.
-pub struct ConnectFuture<S>
+///
+/// The handshake runs over a `TcpStream` to the proxy server by default, which it
+/// establishes itself. Over any other stream `T`, the stream is handed to the future
+/// already established, see `dialer::Handshake`.
+pub struct ConnectFuture<'a, 't, S, T = TcpStream>
 where
     S: Stream<Item = SocketAddr, Error = Error>,
 {
-    auth: Authentication,
+    config: Arc<Config>,
+    auth: Authentication<'a>,
     command: Command,
-    proxy: S,
-    target: TargetAddr,
+    proxy: OrderedAddrs<S>,
+    target: TargetAddr<'t>,
+    bind_addr: Option<TargetAddr<'static>>,
+    reply_addr: Option<TargetAddr<'static>>,
+    reply: Bytes,
     state: ConnectState,
-    buf: [u8; 513],
-    ptr: usize,
-    len: usize,
+    tcp: Option<T>,
+    pipelined: bool,
+    auth_method: AuthMethod,
+    proxy_addr: Option<SocketAddr>,
+    last_error: Option<io::Error>,
+    buf: BytesMut,
+    started: Option<Instant>,
+    dial_started: Option<Instant>,
+    stats: HandshakeStats,
+    #[cfg(feature = "tracing")]
+    span: tracing::Span,
 }
 
-impl<S> ConnectFuture<S>
+impl<'a, 't, S, T> ConnectFuture<'a, 't, S, T>
 where
     S: Stream<Item = SocketAddr, Error = Error>,
+    T: AsyncRead + AsyncWrite,
 {
-    fn new(auth: Authentication, command: Command, proxy: S, target: TargetAddr) -> Self {
+    pub(crate) fn new(
+        config: Arc<Config>,
+        auth: Authentication<'a>,
+        command: Command,
+        proxy: S,
+        target: TargetAddr<'t>,
+    ) -> Self {
+        let buf = match &config.buffer_pool {
+            Some(pool) => pool.get(),
+            None => BytesMut::new(),
+        };
         ConnectFuture {
+            #[cfg(feature = "tracing")]
+            span: tracing::debug_span!(
+                "socks5_handshake",
+                target = %target,
+                proxy = tracing::field::Empty,
+            ),
+            proxy: OrderedAddrs::new(proxy, config.addr_order),
+            config,
             auth,
             command,
-            proxy,
             target,
+            bind_addr: None,
+            reply_addr: None,
+            reply: Bytes::new(),
             state: ConnectState::Uninitialized,
-            buf: [0; 513],
-            ptr: 0,
-            len: 0,
+            tcp: None,
+            pipelined: false,
+            auth_method: AuthMethod::None,
+            proxy_addr: None,
+            last_error: None,
+            buf,
+            started: None,
+            dial_started: None,
+            stats: HandshakeStats::default(),
         }
     }
 
-    fn prepare_send_method_selection(&mut self) {
-        self.ptr = 0;
-        self.buf[0] = 0x05;
-        match self.auth {
-            Authentication::None => {
-                self.buf[1..3].copy_from_slice(&[1, 0x00]);
-                self.len = 3;
+    #[cfg(feature = "udp")]
+    pub(crate) fn set_target(&mut self, target: TargetAddr<'t>) {
+        self.target = target;
+    }
+
+    /// Runs the handshake on `stream`, already connected to the proxy server.
+    pub(crate) fn set_stream(&mut self, stream: T) -> Result<()> {
+        self.tcp = Some(stream);
+        self.send(Step::MethodSelection)
+    }
+
+    /// Encodes the message of `step` into the buffer and starts sending it.
+    fn send(&mut self, step: Step) -> Result<()> {
+        let mut encoder = ClientEncoder::default();
+        self.buf.clear();
+        match step {
+            Step::MethodSelection => {
+                let methods: &[u8] = match self.auth {
+                    Authentication::None => &[METHOD_NO_AUTH],
+                    Authentication::Password { .. } => &[METHOD_NO_AUTH, METHOD_PASSWORD],
+                };
+                encoder.encode(ClientMessage::Methods(methods), &mut self.buf)?;
+                // Without authentication, the only acceptable method selection leads to the
+                // request, so it can be sent right away.
+                self.pipelined = self.config.pipeline && self.auth.id() == METHOD_NO_AUTH;
+                if self.pipelined {
+                    let request = ClientMessage::Request {
+                        command: self.command as u8,
+                        target: &self.target,
+                    };
+                    encoder.encode(request, &mut self.buf)?;
+                }
+                dump(&self.config, Direction::Sent, &self.buf);
+            }
+            Step::Authentication => {
+                if let Authentication::Password { username, password } = &self.auth {
+                    let auth = ClientMessage::PasswordAuth { username, password };
+                    encoder.encode(auth, &mut self.buf)?;
+                } else {
+                    unreachable!()
+                }
+                if self.config.wire_dump.is_some() {
+                    let redacted = redact_password_auth(&self.buf);
+                    dump(&self.config, Direction::Sent, &redacted);
+                }
             }
-            Authentication::Password { .. } => {
-                self.buf[1..4].copy_from_slice(&[2, 0x00, 0x02]);
-                self.len = 4;
+            Step::Request => {
+                let request = ClientMessage::Request {
+                    command: self.command as u8,
+                    target: &self.target,
+                };
+                encoder.encode(request, &mut self.buf)?;
+                dump(&self.config, Direction::Sent, &self.buf);
             }
         }
+        self.state = ConnectState::Sending(step);
+        Ok(())
     }
 
-    fn prepare_recv_method_selection(&mut self) {
-        self.ptr = 0;
-        self.len = 2;
-    }
-
-    fn prepare_send_password_auth(&mut self) {
-        if let Authentication::Password { username, password } = &self.auth {
-            self.ptr = 0;
-            self.buf[0] = 0x01;
-            let username_bytes = username.as_bytes();
-            let username_len = username_bytes.len();
-            self.buf[1] = username_len as u8;
-            self.buf[2..(2 + username_len)].copy_from_slice(username_bytes);
-            let password_bytes = password.as_bytes();
-            let password_len = password_bytes.len();
-            self.len = 3 + username_len + password_len;
-            self.buf[(2 + username_len)] = password_len as u8;
-            self.buf[(3 + username_len)..self.len].copy_from_slice(password_bytes);
+    /// Sends the request, unless it was pipelined with the method selection.
+    fn authenticated(&mut self) -> Result<()> {
+        observe(
+            &self.config,
+            HandshakeEvent::Authenticated {
+                method: self.auth_method,
+            },
+        );
+        if self.pipelined {
+            self.state = ConnectState::Receiving(Step::Request);
+            Ok(())
         } else {
-            unreachable!()
+            self.send(Step::Request)
         }
     }
 
-    fn prepare_recv_password_auth(&mut self) {
-        self.ptr = 0;
-        self.len = 2;
+    /// Takes the stream to the proxy server and the address of the reply once the
+    /// handshake succeeded.
+    fn take_stream(&mut self) -> (T, TargetAddr<'static>) {
+        let target = self.reply_addr.take().unwrap();
+        debug_event!(bind_addr = %target, "handshake completed");
+        self.stats.total = elapsed(self.started);
+        observe(
+            &self.config,
+            HandshakeEvent::Completed {
+                duration: self.stats.total,
+            },
+        );
+        (self.tcp.take().unwrap(), target)
     }
 
-    fn prepare_send_request(&mut self) {
-        self.ptr = 0;
-        self.buf[..3].copy_from_slice(&[0x05, self.command as u8, 0x00]);
-        match &self.target {
-            TargetAddr::Ip(SocketAddr::V4(addr)) => {
-                self.buf[3] = 0x01;
-                self.buf[4..8].copy_from_slice(&addr.ip().octets());
-                self.buf[8..10].copy_from_slice(&addr.port().to_be_bytes());
-                self.len = 10;
-            }
-            TargetAddr::Ip(SocketAddr::V6(addr)) => {
-                self.buf[3] = 0x04;
-                self.buf[4..20].copy_from_slice(&addr.ip().octets());
-                self.buf[20..22].copy_from_slice(&addr.port().to_be_bytes());
-                self.len = 22;
-            }
-            TargetAddr::Domain(domain, port) => {
-                self.buf[3] = 0x03;
-                let domain = domain.as_bytes();
-                let len = domain.len();
-                self.buf[4] = len as u8;
-                self.buf[5..5 + len].copy_from_slice(domain);
-                self.buf[(5 + len)..(7 + len)].copy_from_slice(&port.to_be_bytes());
-                self.len = 7 + len;
-            }
-        }
+    /// Hands the stream over to a `Tunnel` once the handshake succeeded.
+    pub(crate) fn tunnel(&mut self) -> Tunnel<T> {
+        let (stream, target) = self.take_stream();
+        let reply = mem::replace(&mut self.reply, Bytes::new());
+        Tunnel::new(stream, target, self.auth_method, reply)
     }
 
-    fn prepare_recv_reply(&mut self) {
-        self.ptr = 0;
-        self.len = 4;
+    /// Polls the handshake with `poll` within its span, reporting its errors and wrapping
+    /// them in a `HandshakeError`.
+    pub(crate) fn poll_with<R, F>(&mut self, poll: F) -> Poll<R, Error>
+    where
+        F: FnOnce(&mut Self) -> Poll<R, Error>,
+    {
+        #[cfg(feature = "tracing")]
+        let span = self.span.clone();
+        #[cfg(feature = "tracing")]
+        let _enter = span.enter();
+        if self.started.is_none() {
+            self.started = Some(Instant::now());
+            observe(
+                &self.config,
+                HandshakeEvent::AttemptStarted {
+                    target: &self.target,
+                },
+            );
+        }
+        poll(self).map_err(|error| {
+            debug_event!(phase = %self.state.phase(), %error, "handshake failed");
+            observe(
+                &self.config,
+                HandshakeEvent::Failed {
+                    phase: self.state.phase(),
+                    error: &error,
+                    duration: elapsed(self.started),
+                },
+            );
+            Error::Handshake(Box::new(HandshakeError {
+                error,
+                phase: self.state.phase(),
+                proxy: self.proxy_addr,
+                target: self.target.to_owned(),
+            }))
+        })
     }
-}
-
-impl<S> Future for ConnectFuture<S>
-where
-    S: Stream<Item = SocketAddr, Error = Error>,
-{
-    type Item = Socks5Stream;
-    type Error = Error;
 
-    fn poll(&mut self) -> Poll<Socks5Stream, Error> {
+    /// Exchanges the messages of the handshake over the stream to the proxy server.
+    ///
+    /// Resolves once the successful reply is received and the PROXY protocol header, if
+    /// any, is sent.
+    pub(crate) fn poll_exchange(&mut self) -> Poll<(), Error> {
         loop {
             match self.state {
-                ConnectState::Uninitialized => match try_ready!(self.proxy.poll()) {
-                    Some(addr) => self.state = ConnectState::Created(TcpStream::connect(&addr)),
-                    None => Err(Error::ProxyServerUnreachable)?,
-                },
-                ConnectState::Created(ref mut conn_fut) => match conn_fut.poll() {
-                    Ok(Async::Ready(tcp)) => {
-                        self.state = ConnectState::Connected(Some(tcp));
-                        self.prepare_send_method_selection()
-                    }
-                    Ok(Async::NotReady) => return Ok(Async::NotReady),
-                    Err(_e) => self.state = ConnectState::Uninitialized,
-                },
-                ConnectState::Connected(ref mut opt) => {
-                    let tcp = opt.as_mut().unwrap();
-                    self.ptr += try_ready!(tcp.poll_write(&self.buf[self.ptr..self.len]));
-                    ;
-                    if self.ptr == self.len {
-                        self.state = ConnectState::MethodSent(opt.take());
-                        self.prepare_recv_method_selection();
+                ConnectState::Sending(step) => {
+                    try_ready!(poll_write_buf(self.tcp.as_mut().unwrap(), &mut self.buf));
+                    if let Step::Request = step {
+                        debug_event!(command = self.command as u8, "sent request");
                     }
+                    self.state = ConnectState::Receiving(step);
                 }
-                ConnectState::MethodSent(ref mut opt) => {
-                    let tcp = opt.as_mut().unwrap();
-                    self.ptr += try_ready!(tcp.poll_read(&mut self.buf[self.ptr..self.len]));
-                    if self.ptr == self.len {
-                        if self.buf[0] != 0x05 {
-                            Err(Error::InvalidResponseVersion)?
+                ConnectState::Receiving(step) => {
+                    let mut decoder = ServerDecoder::new(step.expect(), self.config.compliance);
+                    let msg = loop {
+                        if let Some(msg) = decoder.decode(&mut self.buf)? {
+                            break msg;
                         }
-                        match self.buf[1] {
-                            0x00 => self.state = ConnectState::PrepareRequest(opt.take()),
-                            0xff => Err(Error::NoAcceptableAuthMethods)?,
-                            0x02 => {
-                                self.state = ConnectState::PasswordAuth(opt.take());
-                                self.prepare_send_password_auth();
+                        // Only the bytes of the message are read, the stream belongs to the
+                        // caller once the handshake completes.
+                        let len = self.buf.len() + decoder.remaining(&self.buf);
+                        let tcp = self.tcp.as_mut().unwrap();
+                        try_ready!(poll_read_buf(tcp, &mut self.buf, len, &self.config));
+                    };
+                    match msg {
+                        ServerMessage::MethodSelection(method) => {
+                            self.stats.auth_round_trips += 1;
+                            self.auth_method = AuthMethod::from(method);
+                            match method {
+                                METHOD_NO_AUTH => self.authenticated()?,
+                                METHOD_NO_ACCEPTABLE => Err(Error::NoAcceptableAuthMethods)?,
+                                METHOD_PASSWORD if self.auth.id() == METHOD_PASSWORD => {
+                                    self.send(Step::Authentication)?
+                                }
+                                // The server selected a method we didn't offer
+                                _ => Err(Error::UnknownAuthMethod)?,
                             }
-                            m if m != self.auth.id() => Err(Error::UnknownAuthMethod)?,
-                            _ => unimplemented!(),
+                            debug_event!(method = ?self.auth_method, "selected authentication method");
                         }
-                    }
-                }
-                ConnectState::PasswordAuth(ref mut opt) => {
-                    let tcp = opt.as_mut().unwrap();
-                    self.ptr += try_ready!(tcp.poll_write(&self.buf[self.ptr..self.len]));
-                    if self.ptr == self.len {
-                        self.state = ConnectState::PasswordAuthSent(opt.take());
-                        self.prepare_recv_password_auth();
-                    }
-                }
-                ConnectState::PasswordAuthSent(ref mut opt) => {
-                    let tcp = opt.as_mut().unwrap();
-                    self.ptr += try_ready!(tcp.poll_read(&mut self.buf[self.ptr..self.len]));
-                    if self.ptr == self.len {
-                        if self.buf[0] != 0x01 {
-                            Err(Error::InvalidResponseVersion)?
+                        ServerMessage::PasswordAuthStatus(status) => {
+                            self.stats.auth_round_trips += 1;
+                            if status != PASSWORD_AUTH_SUCCEEDED {
+                                Err(Error::PasswordAuthFailure(status))?
+                            }
+                            debug_event!("authenticated");
+                            self.authenticated()?
                         }
-                        if self.buf[1] != 0x00 {
-                            Err(Error::PasswordAuthFailure(self.buf[1]))?
+                        ServerMessage::Reply { code, addr, raw } => {
+                            debug_event!(code, "received reply");
+                            observe(&self.config, HandshakeEvent::ReplyReceived { code });
+                            if code != REPLY_SUCCEEDED {
+                                Err(Error::ProxyReply {
+                                    code,
+                                    kind: ReplyKind::from(code),
+                                })?
+                            }
+                            self.reply_addr = addr;
+                            self.reply = raw;
+                            match (&self.config.proxy_header, self.command) {
+                                (Some(header), Command::Connect) => {
+                                    self.buf.clear();
+                                    header.encode(&self.target, &mut self.buf);
+                                    dump(&self.config, Direction::Sent, &self.buf);
+                                    self.state = ConnectState::SendProxyHeader;
+                                }
+                                _ => return Ok(Async::Ready(())),
+                            }
                         }
-                        self.state = ConnectState::PrepareRequest(opt.take());
                     }
                 }
-                ConnectState::PrepareRequest(ref mut opt) => {
-                    self.state = ConnectState::SendRequest(opt.take());
-                    self.prepare_send_request();
+                ConnectState::SendProxyHeader => {
+                    try_ready!(poll_write_buf(self.tcp.as_mut().unwrap(), &mut self.buf));
+                    debug_event!("sent PROXY protocol header");
+                    return Ok(Async::Ready(()));
                 }
-                ConnectState::SendRequest(ref mut opt) => {
-                    let tcp = opt.as_mut().unwrap();
-                    self.ptr += try_ready!(tcp.poll_write(&self.buf[self.ptr..self.len]));
-                    if self.ptr == self.len {
-                        self.state = ConnectState::RequestSent(opt.take());
-                        self.prepare_recv_reply();
-                    }
+                ConnectState::Uninitialized
+                | ConnectState::Resolving(_)
+                | ConnectState::Racing(_)
+                | ConnectState::Created(_) => unreachable!(),
+            }
+        }
+    }
+}
+
+impl<'a, 't, S> ConnectFuture<'a, 't, S>
+where
+    S: Stream<Item = SocketAddr, Error = Error>,
+{
+    /// Runs the handshake on `tcp`, already connected to the proxy server, instead of
+    /// connecting to the resolved proxy addresses.
+    fn use_stream(&mut self, tcp: TcpStream) -> Result<()> {
+        let proxy_addr = tcp.peer_addr()?;
+        #[cfg(feature = "tracing")]
+        self.span
+            .record("proxy", tracing::field::display(proxy_addr));
+        self.proxy_addr = Some(proxy_addr);
+        self.set_stream(tcp)
+    }
+
+    fn start_dialing(&mut self) {
+        self.stats.resolve = elapsed(self.started);
+        self.dial_started = Some(Instant::now());
+    }
+
+    fn connected(&mut self) {
+        #[cfg(feature = "tracing")]
+        self.span
+            .record("proxy", tracing::field::display(self.proxy_addr.unwrap()));
+        debug_event!("connected to the proxy server");
+        self.stats.connect = self.dial_started.unwrap().elapsed();
+        observe(
+            &self.config,
+            HandshakeEvent::Connected {
+                proxy: self.proxy_addr.unwrap(),
+                rtt: self.stats.connect,
+            },
+        );
+    }
+
+    /// Returns the future along with an `AbortHandle` to cancel the connection.
+    ///
+    /// See `abort::abortable`.
+    pub fn abortable(self) -> (Abortable<Self>, AbortHandle) {
+        abort::abortable(self)
+    }
+
+    /// Hands the connection over to a `Socks5Stream` once the handshake succeeded.
+    fn complete(&mut self) -> Socks5Stream {
+        let (tcp, target) = self.take_stream();
+        Socks5Stream {
+            tcp,
+            target,
+            bind_addr: self.bind_addr.take(),
+            auth_method: self.auth_method,
+            proxy_addr: self.proxy_addr.unwrap(),
+            stats: self.stats,
+            reply: mem::replace(&mut self.reply, Bytes::new()),
+            _on_close: self.config.observer.clone().map(|observer| OnClose {
+                observer,
+                target: self.target.to_owned(),
+            }),
+        }
+    }
+
+    /// The error to return once every proxy address has been tried.
+    fn unreachable(&mut self) -> Error {
+        match self.last_error.take() {
+            Some(e) => Error::Io(e),
+            None => Error::ProxyServerUnreachable,
+        }
+    }
+
+    fn poll_handshake(&mut self) -> Poll<Socks5Stream, Error> {
+        loop {
+            match self.state {
+                ConnectState::Uninitialized if self.config.happy_eyeballs.is_some() => {
+                    self.state = ConnectState::Resolving(Vec::new())
                 }
-                ConnectState::RequestSent(ref mut opt) => {
-                    let tcp = opt.as_mut().unwrap();
-                    self.ptr += try_ready!(tcp.poll_read(&mut self.buf[self.ptr..self.len]));
-                    if self.ptr == self.len {
-                        if self.buf[0] != 0x05 {
-                            Err(Error::InvalidResponseVersion)?
-                        }
-                        if self.buf[2] != 0x00 {
-                            Err(Error::InvalidReservedByte)?
-                        }
-                        match self.buf[1] {
-                            0x00 => {} // succeeded
-                            0x01 => Err(Error::GeneralSocksServerFailure)?,
-                            0x02 => Err(Error::ConnectionNotAllowedByRuleset)?,
-                            0x03 => Err(Error::NetworkUnreachable)?,
-                            0x04 => Err(Error::HostUnreachable)?,
-                            0x05 => Err(Error::ConnectionRefused)?,
-                            0x06 => Err(Error::TtlExpired)?,
-                            0x07 => Err(Error::CommandNotSupported)?,
-                            0x08 => Err(Error::AddressTypeNotSupported)?,
-                            _ => Err(Error::UnknownAuthMethod)?,
-                        }
-                        match self.buf[3] {
-                            // IPv4
-                            0x01 => {
-                                self.len = 10;
-                                self.state = ConnectState::ReadAddress(opt.take())
-                            }
-                            // IPv6
-                            0x04 => {
-                                self.len = 22;
-                                self.state = ConnectState::ReadAddress(opt.take())
-                            }
-                            // Domain
-                            0x03 => {
-                                self.len = 5;
-                                self.state = ConnectState::PrepareReadAddress(opt.take())
-                            }
-                            _ => Err(Error::UnknownAddressType)?,
+                ConnectState::Uninitialized => match try_ready!(self.proxy.poll()) {
+                    Some(addr) => {
+                        debug_event!(proxy = %addr, "resolved proxy address");
+                        observe(&self.config, HandshakeEvent::Resolved { proxy: addr });
+                        if self.dial_started.is_none() {
+                            self.start_dialing();
                         }
+                        self.proxy_addr = Some(addr);
+                        self.state =
+                            ConnectState::Created(Box::new(dial::connect(&addr, &self.config)))
                     }
-                }
-                ConnectState::PrepareReadAddress(ref mut opt) => {
-                    let tcp = opt.as_mut().unwrap();
-                    self.ptr += try_ready!(tcp.poll_read(&mut self.buf[self.ptr..self.len]));
-                    if self.ptr == self.len {
-                        self.len += self.buf[4] as usize + 2;
-                        self.state = ConnectState::ReadAddress(opt.take());
+                    None => Err(self.unreachable())?,
+                },
+                ConnectState::Resolving(ref mut addrs) => match try_ready!(self.proxy.poll()) {
+                    Some(addr) => {
+                        observe(&self.config, HandshakeEvent::Resolved { proxy: addr });
+                        addrs.push(addr)
+                    }
+                    None => {
+                        debug_event!(count = addrs.len(), "resolved proxy addresses");
+                        let addrs = std::mem::take(addrs);
+                        self.start_dialing();
+                        let stagger = self.config.happy_eyeballs.unwrap();
+                        self.state = ConnectState::Racing(Box::new(HappyEyeballs::new(
+                            addrs,
+                            stagger,
+                            self.config.clone(),
+                        )));
                     }
+                },
+                ConnectState::Racing(ref mut race) => {
+                    let (tcp, addr) = try_ready!(race.poll());
+                    self.proxy_addr = Some(addr);
+                    self.connected();
+                    dial::configure(&tcp, &self.config.socket)?;
+                    self.set_stream(tcp)?
                 }
-                ConnectState::ReadAddress(ref mut opt) => {
-                    let tcp = opt.as_mut().unwrap();
-                    self.ptr += try_ready!(tcp.poll_read(&mut self.buf[self.ptr..self.len]));
-                    if self.ptr == self.len {
-                        let target: TargetAddr = match self.buf[3] {
-                            // IPv4
-                            0x01 => {
-                                let mut ip = [0; 4];
-                                ip[..].copy_from_slice(&self.buf[4..8]);
-                                let ip = Ipv4Addr::from(ip);
-                                let port = u16::from_be_bytes([self.buf[8], self.buf[9]]);
-                                (ip, port).into_target_addr()?
-                            }
-                            // IPv6
-                            0x04 => {
-                                let mut ip = [0; 16];
-                                ip[..].copy_from_slice(&self.buf[4..20]);
-                                let ip = Ipv6Addr::from(ip);
-                                let port = u16::from_be_bytes([self.buf[20], self.buf[21]]);
-                                (ip, port).into_target_addr()?
-                            }
-                            // Domain
-                            0x03 => {
-                                let domain_bytes = (&self.buf[5..(self.len - 2)]).to_vec();
-                                let domain = String::from_utf8(domain_bytes).map_err(|_| {
-                                    Error::InvalidTargetAddress("not a valid UTF-8 string")
-                                })?;
-                                let port = u16::from_be_bytes([
-                                    self.buf[self.len - 2],
-                                    self.buf[self.len - 1],
-                                ]);
-                                TargetAddr::Domain(domain.into(), port)
-                            }
-                            _ => unreachable!(),
-                        };
-                        return Ok(Async::Ready(Socks5Stream {
-                            tcp: opt.take().unwrap(),
-                            target,
-                        }));
+                ConnectState::Created(ref mut conn_fut) => match conn_fut.poll() {
+                    Ok(Async::Ready(tcp)) => {
+                        self.connected();
+                        dial::configure(&tcp, &self.config.socket)?;
+                        self.set_stream(tcp)?
+                    }
+                    Ok(Async::NotReady) => return Ok(Async::NotReady),
+                    Err(e) => {
+                        debug_event!(error = %e, "failed to connect to the proxy server");
+                        self.last_error = Some(e);
+                        self.state = ConnectState::Uninitialized
                     }
+                },
+                ConnectState::Sending(_)
+                | ConnectState::Receiving(_)
+                | ConnectState::SendProxyHeader => {
+                    try_ready!(self.poll_exchange());
+                    return Ok(Async::Ready(self.complete()));
                 }
             }
         }
     }
 }
 
+impl<'a, 't, S> Future for ConnectFuture<'a, 't, S>
+where
+    S: Stream<Item = SocketAddr, Error = Error>,
+{
+    type Item = Socks5Stream;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Socks5Stream, Error> {
+        self.poll_with(Self::poll_handshake)
+    }
+}
+
+impl<'a, 't, S, T> Drop for ConnectFuture<'a, 't, S, T>
+where
+    S: Stream<Item = SocketAddr, Error = Error>,
+{
+    fn drop(&mut self) {
+        if let Some(pool) = &self.config.buffer_pool {
+            pool.put(mem::replace(&mut self.buf, BytesMut::new()));
+        }
+    }
+}
+
+/// Writes the whole of `buf` to `tcp`, consuming it.
+fn poll_write_buf<T>(tcp: &mut T, buf: &mut BytesMut) -> Poll<(), io::Error>
+where
+    T: AsyncWrite,
+{
+    while !buf.is_empty() {
+        let n = try_ready!(tcp.poll_write(buf));
+        if n == 0 {
+            return Err(io::ErrorKind::WriteZero.into());
+        }
+        buf.advance(n);
+    }
+    Ok(Async::Ready(()))
+}
+
+/// Reads from `tcp` until `buf` holds `len` bytes, passing the bytes read to the wire dump.
+fn poll_read_buf<T>(
+    tcp: &mut T,
+    buf: &mut BytesMut,
+    len: usize,
+    config: &Config,
+) -> Poll<(), io::Error>
+where
+    T: AsyncRead,
+{
+    while buf.len() < len {
+        let start = buf.len();
+        buf.resize(len, 0);
+        let n = match tcp.poll_read(&mut buf[start..]) {
+            Ok(Async::Ready(n)) => n,
+            Ok(Async::NotReady) => {
+                buf.truncate(start);
+                return Ok(Async::NotReady);
+            }
+            Err(e) => {
+                buf.truncate(start);
+                return Err(e);
+            }
+        };
+        buf.truncate(start + n);
+        if n == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        dump(config, Direction::Received, &buf[start..]);
+    }
+    Ok(Async::Ready(()))
+}
+
+fn observe(config: &Config, event: HandshakeEvent<'_>) {
+    if let Some(observer) = &config.observer {
+        observer.0.on_event(&event);
+    }
+}
+
+fn dump(config: &Config, direction: Direction, bytes: &[u8]) {
+    if let Some(wire_dump) = &config.wire_dump {
+        (wire_dump.0)(direction, bytes);
+    }
+}
+
+/// Masks the username and password of a username/password authentication request,
+/// keeping their lengths.
+fn redact_password_auth(request: &[u8]) -> Vec<u8> {
+    let mut redacted = request.to_vec();
+    let username_end = 2 + redacted[1] as usize;
+    let password_end = username_end + 1 + redacted[username_end] as usize;
+    for byte in &mut redacted[2..username_end] {
+        *byte = b'*';
+    }
+    for byte in &mut redacted[(username_end + 1)..password_end] {
+        *byte = b'*';
+    }
+    redacted
+}
+
+fn elapsed(started: Option<Instant>) -> Duration {
+    started.map_or(Duration::from_secs(0), |started| started.elapsed())
+}
+
 #[derive(Debug)]
 enum ConnectState {
     Uninitialized,
-    Created(TokioConnect),
-    Connected(Option<TcpStream>),
-    MethodSent(Option<TcpStream>),
-    PasswordAuth(Option<TcpStream>),
-    PasswordAuthSent(Option<TcpStream>),
-    PrepareRequest(Option<TcpStream>),
-    SendRequest(Option<TcpStream>),
-    RequestSent(Option<TcpStream>),
-    PrepareReadAddress(Option<TcpStream>),
-    ReadAddress(Option<TcpStream>),
+    Resolving(Vec<SocketAddr>),
+    // The futures establishing the connection are boxed to keep `ConnectFuture` small.
+    Racing(Box<HappyEyeballs>),
+    Created(Box<TcpConnect>),
+    Sending(Step),
+    Receiving(Step),
+    SendProxyHeader,
+}
+
+impl ConnectState {
+    fn phase(&self) -> HandshakePhase {
+        match self {
+            ConnectState::Uninitialized
+            | ConnectState::Resolving(_)
+            | ConnectState::Racing(_)
+            | ConnectState::Created(_) => HandshakePhase::Connect,
+            ConnectState::Sending(Step::MethodSelection)
+            | ConnectState::Receiving(Step::MethodSelection) => HandshakePhase::MethodSelection,
+            ConnectState::Sending(Step::Authentication)
+            | ConnectState::Receiving(Step::Authentication) => HandshakePhase::Authentication,
+            ConnectState::Sending(Step::Request) => HandshakePhase::Request,
+            ConnectState::Receiving(Step::Request) | ConnectState::SendProxyHeader => {
+                HandshakePhase::Reply
+            }
+        }
+    }
+}
+
+/// A message exchange of the handshake, sending a message of the client and receiving
+/// the answer of the server.
+#[derive(Debug, Clone, Copy)]
+enum Step {
+    MethodSelection,
+    Authentication,
+    Request,
+}
+
+impl Step {
+    fn expect(self) -> Expect {
+        match self {
+            Step::MethodSelection => Expect::MethodSelection,
+            Step::Authentication => Expect::PasswordAuthStatus,
+            Step::Request => Expect::Reply,
+        }
+    }
 }
 
 /// A SOCKS5 BIND client.
@@ -423,10 +993,64 @@ enum ConnectState {
 /// Once you get an instance of `Socks5Listener`, you should send the `bind_addr`
 /// to the remote process via the primary connection. Then, call the `accept` function
 /// and wait for the other end connecting to the rendezvous address.
+#[cfg(feature = "bind")]
 pub struct Socks5Listener {
-    inner: Socks5Stream,
+    pub(crate) inner: Socks5Stream,
+    pub(crate) config: Arc<Config>,
+    pub(crate) auth: Authentication<'static>,
+    pub(crate) target: TargetAddr<'static>,
+    pub(crate) peer_verification: PeerVerification,
+    pub(crate) keepalive: Option<Keepalive>,
+}
+
+/// Whether the peer which connects to a `Socks5Listener` must match the target of the BIND
+/// request.
+///
+/// Proxy servers differ in how they filter incoming connections, so the peer address they
+/// report is only checked on demand.
+#[cfg(feature = "bind")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PeerVerification {
+    /// The peer address is reported by `Socks5Stream::bind_peer_addr` but not checked.
+    #[default]
+    Lenient,
+    /// `accept` fails with `Error::UnexpectedPeer` if the peer address doesn't match the
+    /// target. An unspecified IP address or a zero port in the target matches any value,
+    /// and the host of a domain target is only checked against a domain peer address.
+    Strict,
+}
+
+#[cfg(feature = "bind")]
+impl PeerVerification {
+    fn check(self, target: &TargetAddr<'_>, peer: &TargetAddr<'_>) -> Result<()> {
+        if self == PeerVerification::Lenient || is_expected_peer(target, peer) {
+            Ok(())
+        } else {
+            Err(Error::UnexpectedPeer(peer.to_owned()))
+        }
+    }
+}
+
+#[cfg(feature = "bind")]
+fn is_expected_peer(target: &TargetAddr<'_>, peer: &TargetAddr<'_>) -> bool {
+    let (host_matches, port, peer_port) = match (target, peer) {
+        (TargetAddr::Ip(target), TargetAddr::Ip(peer)) => (
+            target.ip().is_unspecified() || target.ip() == peer.ip(),
+            target.port(),
+            peer.port(),
+        ),
+        (TargetAddr::Ip(target), TargetAddr::Domain(_, peer_port)) => {
+            (target.ip().is_unspecified(), target.port(), *peer_port)
+        }
+        (TargetAddr::Domain(domain, port), TargetAddr::Domain(peer, peer_port)) => {
+            (domain.eq_ignore_ascii_case(peer), *port, *peer_port)
+        }
+        (TargetAddr::Domain(_, port), TargetAddr::Ip(peer)) => (true, *port, peer.port()),
+    };
+    host_matches && (port == 0 || port == peer_port)
 }
 
+#[cfg(feature = "bind")]
 impl Socks5Listener {
     /// Initiates a BIND request to the specified proxy.
     ///
@@ -436,17 +1060,19 @@ impl Socks5Listener {
     /// # Error
     ///
     /// It propagates the error that occurs in the conversion from `T` to `TargetAddr`.
-    pub fn bind<P, T>(proxy: P, target: T) -> Result<BindFuture<P::Output>>
+    pub fn bind<'t, P, T>(proxy: P, target: T) -> Result<BindFuture<'static, 't, P::Output>>
     where
         P: ToProxyAddrs,
-        T: IntoTargetAddr,
+        T: IntoTargetAddr<'t>,
     {
-        Ok(BindFuture(ConnectFuture::new(
+        Socks5Stream::connect_raw(
+            Arc::new(Config::default()),
+            proxy,
+            target,
             Authentication::None,
             Command::Bind,
-            proxy.to_proxy_addrs(),
-            target.into_target_addr()?,
-        )))
+        )
+        .map(BindFuture)
     }
 
     /// Initiates a BIND request to the specified proxy using given username
@@ -458,30 +1084,90 @@ impl Socks5Listener {
     /// # Error
     ///
     /// It propagates the error that occurs in the conversion from `T` to `TargetAddr`.
-    pub fn bind_with_password<P, T>(
+    pub fn bind_with_password<'a, 't, P, T>(
         proxy: P,
         target: T,
-        username: &str,
-        password: &str,
-    ) -> Result<BindFuture<P::Output>>
+        username: &'a str,
+        password: &'a str,
+    ) -> Result<BindFuture<'a, 't, P::Output>>
     where
         P: ToProxyAddrs,
-        T: IntoTargetAddr,
+        T: IntoTargetAddr<'t>,
     {
-        Ok(BindFuture(ConnectFuture::new(
-            Authentication::Password { username: username.to_string(), password: password.to_string() },
+        Socks5Stream::connect_raw(
+            Arc::new(Config::default()),
+            proxy,
+            target,
+            Authentication::Password {
+                username: username.into(),
+                password: password.into(),
+            },
             Command::Bind,
-            proxy.to_proxy_addrs(),
-            target.into_target_addr()?,
-        )))
+        )
+        .map(BindFuture)
+    }
+
+    /// Initiates a BIND request to the specified proxy, accepting a connection from any peer.
+    ///
+    /// # Error
+    ///
+    /// It propagates the error that occurs in the conversion from `T` to `TargetAddr`.
+    pub fn bind_any<P>(proxy: P) -> Result<BindFuture<'static, 'static, P::Output>>
+    where
+        P: ToProxyAddrs,
+    {
+        Self::bind(proxy, SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)))
+    }
+
+    /// Initiates a BIND request to the proxy described by `proxy`, authenticating
+    /// with its credentials if it has any.
+    ///
+    /// The proxy will filter incoming connections based on the value of
+    /// `target`.
+    ///
+    /// # Error
+    ///
+    /// It propagates the error that occurs in the conversion from `T` to `TargetAddr`.
+    pub fn bind_with_spec<'a, 't, P, T>(
+        proxy: &'a ProxySpec<P>,
+        target: T,
+    ) -> Result<BindFuture<'a, 't, P::Output>>
+    where
+        P: ToProxyAddrs,
+        T: IntoTargetAddr<'t>,
+    {
+        Socks5Stream::connect_raw(
+            Arc::new(Config::default()),
+            &proxy.addrs,
+            target,
+            proxy.authentication(),
+            Command::Bind,
+        )
+        .map(BindFuture)
     }
 
     /// Returns the address of the proxy-side TCP listener.
     ///
     /// This should be forwarded to the remote process, which should open a
     /// connection to it.
-    pub fn bind_addr(&self) -> TargetAddr {
-        self.inner.target_addr()
+    pub fn bind_addr(&self) -> TargetAddr<'_> {
+        borrow_addr(&self.inner.target)
+    }
+
+    /// Sets whether the peer which connects must match the target of the BIND request.
+    ///
+    /// Defaults to `PeerVerification::Lenient`.
+    pub fn peer_verification(mut self, peer_verification: PeerVerification) -> Self {
+        self.peer_verification = peer_verification;
+        self
+    }
+
+    /// Enables TCP keepalive with the given parameters on the connection to the proxy
+    /// server while `accept` waits for the remote process, so that the NATs on the way
+    /// don't drop it as idle. It overrides the keepalive of the connector.
+    pub fn keepalive(mut self, keepalive: Keepalive) -> Self {
+        self.keepalive = Some(keepalive);
+        self
     }
 
     /// Consumes this listener, returning a `Future` which resolves to the `Socks5Stream`
@@ -490,18 +1176,94 @@ impl Socks5Listener {
     /// The value of `bind_addr` should be forwarded to the remote process
     /// before this method is called.
     pub fn accept(self) -> impl Future<Item = Socks5Stream, Error = Error> {
-        let mut conn_fut = ConnectFuture {
-            auth: Authentication::None,
-            command: Command::Bind,
-            proxy: stream::empty(),
-            target: self.inner.target,
-            state: ConnectState::RequestSent(Some(self.inner.tcp)),
-            buf: [0; 513],
-            ptr: 0,
-            len: 0,
+        let keepalive = match &self.keepalive {
+            Some(keepalive) => dial::set_keepalive(&self.inner.tcp, keepalive),
+            None => Ok(()),
         };
-        conn_fut.prepare_recv_reply();
-        conn_fut
+        let target = self.target;
+        let peer_verification = self.peer_verification;
+        let mut conn_fut = ConnectFuture::new(
+            self.config,
+            self.auth,
+            Command::Bind,
+            stream::empty(),
+            self.inner.target.clone(),
+        );
+        conn_fut.bind_addr = Some(self.inner.target);
+        conn_fut.tcp = Some(self.inner.tcp);
+        conn_fut.state = ConnectState::Receiving(Step::Request);
+        conn_fut.auth_method = self.inner.auth_method;
+        conn_fut.proxy_addr = Some(self.inner.proxy_addr);
+        future::result(keepalive)
+            .from_err::<Error>()
+            .and_then(|()| conn_fut)
+            .and_then(move |stream| {
+                peer_verification.check(&target, &stream.target)?;
+                Ok(stream)
+            })
+    }
+
+    /// Like `accept`, but fails with `Error::Timeout` if the remote process doesn't connect
+    /// within `timeout`.
+    ///
+    /// The connection to the proxy server is closed when the timeout elapses.
+    pub fn accept_timeout(
+        self,
+        timeout: Duration,
+    ) -> impl Future<Item = Socks5Stream, Error = Error> {
+        Timeout::new(self.accept(), timeout).map_err(|err| {
+            if err.is_elapsed() {
+                Error::Timeout
+            } else if err.is_inner() {
+                err.into_inner().unwrap()
+            } else {
+                Error::Io(io::Error::other(err.into_timer().unwrap()))
+            }
+        })
+    }
+
+    /// Like `accept`, but fails with `Error::Cancelled` as soon as `cancel` resolves, e.g. when
+    /// the sending half of a `futures::sync::oneshot` channel is used.
+    ///
+    /// The connection to the proxy server is closed on cancellation. If `cancel` fails, it is
+    /// ignored and the rendezvous is awaited without it.
+    pub fn accept_until<C>(self, cancel: C) -> impl Future<Item = Socks5Stream, Error = Error>
+    where
+        C: Future,
+    {
+        Cancellable {
+            inner: self.accept(),
+            cancel: Some(cancel),
+        }
+    }
+}
+
+/// A `Future` which fails with `Error::Cancelled` when `cancel` resolves before `inner`.
+#[cfg(feature = "bind")]
+struct Cancellable<F, C> {
+    inner: F,
+    cancel: Option<C>,
+}
+
+#[cfg(feature = "bind")]
+impl<F, C> Future for Cancellable<F, C>
+where
+    F: Future<Error = Error>,
+    C: Future,
+{
+    type Item = F::Item;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<F::Item, Error> {
+        if let Some(cancel) = &mut self.cancel {
+            match cancel.poll() {
+                Ok(Async::Ready(_)) => return Err(Error::Cancelled),
+                Ok(Async::NotReady) => {}
+                // The token can't fire anymore.
+                Err(_) => self.cancel = None,
+            }
+        }
+        self.inner.poll()
     }
 }
 
@@ -509,11 +1271,13 @@ impl Socks5Listener {
 ///
 /// After this future is resolved, the SOCKS5 client has finished the negotiation
 /// with the proxy server.
-pub struct BindFuture<S>(ConnectFuture<S>)
+#[cfg(feature = "bind")]
+pub struct BindFuture<'a, 't, S>(ConnectFuture<'a, 't, S>)
 where
     S: Stream<Item = SocketAddr, Error = Error>;
 
-impl<S> Future for BindFuture<S>
+#[cfg(feature = "bind")]
+impl<'a, 't, S> Future for BindFuture<'a, 't, S>
 where
     S: Stream<Item = SocketAddr, Error = Error>,
 {
@@ -522,10 +1286,68 @@ where
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
         let tcp = try_ready!(self.0.poll());
-        Ok(Async::Ready(Socks5Listener { inner: tcp }))
+        Ok(Async::Ready(Socks5Listener {
+            inner: tcp,
+            config: self.0.config.clone(),
+            auth: self.0.auth.to_owned(),
+            target: self.0.target.to_owned(),
+            peer_verification: PeerVerification::default(),
+            keepalive: None,
+        }))
     }
 }
 
+/// Deprecated: use `Socks5Stream::get_ref` instead.
+///
+/// Dereferencing exposes the whole `TcpStream` API and will be removed in a future release.
+impl Deref for Socks5Stream {
+    type Target = TcpStream;
+
+    fn deref(&self) -> &TcpStream {
+        &self.tcp
+    }
+}
+
+/// Deprecated: use `Socks5Stream::get_mut` instead.
+///
+/// Dereferencing exposes the whole `TcpStream` API and will be removed in a future release.
+impl DerefMut for Socks5Stream {
+    fn deref_mut(&mut self) -> &mut TcpStream {
+        &mut self.tcp
+    }
+}
+
+#[cfg(unix)]
+impl AsRawFd for Socks5Stream {
+    fn as_raw_fd(&self) -> RawFd {
+        self.tcp.as_raw_fd()
+    }
+}
+
+#[cfg(windows)]
+impl AsRawSocket for Socks5Stream {
+    fn as_raw_socket(&self) -> RawSocket {
+        self.tcp.as_raw_socket()
+    }
+}
+
+/// Duplicates the socket of `tcp` into a `std::net::TcpStream`.
+#[cfg(unix)]
+fn clone_std(tcp: &TcpStream) -> io::Result<StdTcpStream> {
+    // The borrowed socket is still owned by `tcp`, so it must not be closed here.
+    let borrowed = mem::ManuallyDrop::new(unsafe { StdTcpStream::from_raw_fd(tcp.as_raw_fd()) });
+    borrowed.try_clone()
+}
+
+/// Duplicates the socket of `tcp` into a `std::net::TcpStream`.
+#[cfg(windows)]
+fn clone_std(tcp: &TcpStream) -> io::Result<StdTcpStream> {
+    // The borrowed socket is still owned by `tcp`, so it must not be closed here.
+    let borrowed =
+        mem::ManuallyDrop::new(unsafe { StdTcpStream::from_raw_socket(tcp.as_raw_socket()) });
+    borrowed.try_clone()
+}
+
 impl Read for Socks5Stream {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         self.tcp.read(buf)
@@ -547,7 +1369,7 @@ impl AsyncRead for Socks5Stream {
     }
 
     fn read_buf<B: BufMut>(&mut self, buf: &mut B) -> Poll<usize, io::Error> {
-        self.tcp.read_buf(buf)
+        AsyncRead::read_buf(&mut self.tcp, buf)
     }
 }
 
@@ -596,3 +1418,33 @@ impl AsyncWrite for &Socks5Stream {
         AsyncWrite::write_buf(&mut &self.tcp, buf)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg(feature = "bind")]
+    fn verifies_bind_peer() {
+        let peer: TargetAddr = "10.0.0.1:4000".parse().unwrap();
+        let accepts = |verification: PeerVerification, target: &str| {
+            verification.check(&target.parse().unwrap(), &peer).is_ok()
+        };
+        assert!(accepts(PeerVerification::Strict, "10.0.0.1:4000"));
+        assert!(accepts(PeerVerification::Strict, "10.0.0.1:0"));
+        assert!(accepts(PeerVerification::Strict, "0.0.0.0:0"));
+        assert!(accepts(PeerVerification::Strict, "example.com:4000"));
+        assert!(!accepts(PeerVerification::Strict, "10.0.0.2:4000"));
+        assert!(!accepts(PeerVerification::Strict, "10.0.0.1:4001"));
+        assert!(accepts(PeerVerification::Lenient, "10.0.0.2:4000"));
+    }
+
+    #[test]
+    fn redacts_credentials() {
+        let request = b"\x01\x04user\x06secret";
+        assert_eq!(
+            redact_password_auth(request),
+            b"\x01\x04****\x06******".to_vec()
+        );
+    }
+}
@@ -0,0 +1,236 @@
+//! In-memory streams for unit tests of code built on top of the handshake, running without
+//! any network.
+//!
+//! `pair` returns a `Duplex` whose other end replays a `Trace`, to be passed to
+//! `Socks5Connector::connect_over`:
+//!
+//! ```
+//! # use tokio_socks::{connector::Socks5Connector, test_util};
+//! # use futures::Future;
+//! let (stream, script) = test_util::pair(
+//!     "
+//!     > 05 01 00
+//!     < 05 00
+//!     > 05 01 00 01 0a 00 00 01 00 50
+//!     < 05 00 00 01 0a 00 00 01 00 50
+//!     "
+//!     .parse()
+//!     .unwrap(),
+//! );
+//! let tunnel = Socks5Connector::new()
+//!     .connect_over(stream, "10.0.0.1:80", Default::default())
+//!     .unwrap()
+//!     .wait()
+//!     .unwrap();
+//! assert_eq!(tunnel.target_addr().to_string(), "10.0.0.1:80");
+//! assert_eq!(script.mismatches(), 0);
+//! ```
+
+use crate::trace::{self, Trace};
+use futures::{
+    task::{self, Task},
+    Async, Poll,
+};
+use std::{
+    collections::VecDeque,
+    io::{self, Read, Write},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    thread,
+};
+use tokio_io::{AsyncRead, AsyncWrite};
+
+/// The bytes written to one end of a `Duplex` and not yet read from the other.
+#[derive(Debug, Default)]
+struct Pipe {
+    buf: VecDeque<u8>,
+    closed: bool,
+    reader: Option<Task>,
+}
+
+#[derive(Debug, Default)]
+struct Half {
+    pipe: Mutex<Pipe>,
+    ready: Condvar,
+}
+
+impl Half {
+    fn close(&self) {
+        let mut pipe = self.pipe.lock().unwrap();
+        pipe.closed = true;
+        self.wake(&mut pipe);
+    }
+
+    fn wake(&self, pipe: &mut Pipe) {
+        if let Some(task) = pipe.reader.take() {
+            task.notify();
+        }
+        self.ready.notify_all();
+    }
+}
+
+/// One end of an in-memory stream, created by `duplex` or `pair`.
+///
+/// Reading an end returns the bytes written to the other one, and its end of file once
+/// the other end is shut down or dropped.
+#[derive(Debug)]
+pub struct Duplex {
+    read: Arc<Half>,
+    write: Arc<Half>,
+    // Whether reads block the thread, rather than returning `WouldBlock`.
+    blocking: bool,
+}
+
+/// Returns the two ends of an in-memory stream.
+pub fn duplex() -> (Duplex, Duplex) {
+    let (a, b) = (Arc::new(Half::default()), Arc::new(Half::default()));
+    let end = |read: &Arc<Half>, write: &Arc<Half>| Duplex {
+        read: read.clone(),
+        write: write.clone(),
+        blocking: false,
+    };
+    (end(&a, &b), end(&b, &a))
+}
+
+/// Returns an in-memory stream to a server replaying `trace`, as `trace::Replay` does over
+/// TCP, and the `Script` reporting on it.
+///
+/// The server runs on a thread of its own until the stream is dropped.
+pub fn pair(trace: Trace) -> (Duplex, Script) {
+    let (client, mut server) = duplex();
+    server.blocking = true;
+    let script = Script {
+        mismatches: Arc::new(AtomicUsize::new(0)),
+    };
+    let mismatches = script.mismatches.clone();
+    thread::spawn(move || {
+        if let Err(e) = trace::serve(&mut server, &trace) {
+            if e.kind() == io::ErrorKind::InvalidData {
+                mismatches.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+        // Closed once the mismatch is counted.
+        drop(server);
+    });
+    (client, script)
+}
+
+/// The server at the other end of the stream returned by `pair`.
+#[derive(Debug)]
+pub struct Script {
+    mismatches: Arc<AtomicUsize>,
+}
+
+impl Script {
+    /// Returns 1 if the stream was closed because the client didn't send the bytes of the
+    /// trace, 0 otherwise.
+    pub fn mismatches(&self) -> usize {
+        self.mismatches.load(Ordering::SeqCst)
+    }
+}
+
+impl Read for Duplex {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut pipe = self.read.pipe.lock().unwrap();
+        loop {
+            if !pipe.buf.is_empty() {
+                let n = buf.len().min(pipe.buf.len());
+                for (byte, read) in buf.iter_mut().zip(pipe.buf.drain(..n)) {
+                    *byte = read;
+                }
+                return Ok(n);
+            }
+            if pipe.closed {
+                return Ok(0);
+            }
+            if !self.blocking {
+                pipe.reader = Some(task::current());
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            pipe = self.read.ready.wait(pipe).unwrap();
+        }
+    }
+}
+
+impl Write for Duplex {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut pipe = self.write.pipe.lock().unwrap();
+        if pipe.closed {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+        pipe.buf.extend(buf);
+        self.write.wake(&mut pipe);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl AsyncRead for Duplex {}
+
+impl AsyncWrite for Duplex {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.write.close();
+        Ok(Async::Ready(()))
+    }
+}
+
+impl Drop for Duplex {
+    fn drop(&mut self) {
+        self.read.close();
+        self.write.close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connector::Socks5Connector;
+    use tokio::runtime::current_thread::Runtime;
+    use tokio_io::io::{read_to_end, write_all};
+
+    #[test]
+    fn carries_the_bytes_between_the_ends() {
+        let mut runtime = Runtime::new().unwrap();
+        let (a, b) = duplex();
+        let (a, _) = runtime.block_on(write_all(a, b"hello")).unwrap();
+        drop(a);
+        let (_, received) = runtime.block_on(read_to_end(b, Vec::new())).unwrap();
+        assert_eq!(received, b"hello");
+    }
+
+    #[test]
+    fn runs_the_handshake_against_the_script() {
+        let mut runtime = Runtime::new().unwrap();
+        let trace = "
+            > 05 01 00
+            < 05 00
+            > 05 01 00 01 0a 00 00 01 00 50
+            < 05 00 00 01 0a 00 00 01 00 50
+            < 68 65 6c 6c 6f
+        ";
+        let (stream, script) = pair(trace.parse().unwrap());
+        let conn = Socks5Connector::new()
+            .connect_over(stream, "10.0.0.1:80", Default::default())
+            .unwrap();
+        let tunnel = runtime.block_on(conn).unwrap();
+        let mut received = [0; 5];
+        let (_, received) = runtime
+            .block_on(tokio_io::io::read_exact(tunnel, &mut received))
+            .unwrap();
+        assert_eq!(received, b"hello");
+        assert_eq!(script.mismatches(), 0);
+
+        // The script expects another target.
+        let (stream, script) = pair(trace.parse().unwrap());
+        let conn = Socks5Connector::new()
+            .connect_over(stream, "10.0.0.2:80", Default::default())
+            .unwrap();
+        assert!(runtime.block_on(conn).is_err());
+        assert_eq!(script.mismatches(), 1);
+    }
+}
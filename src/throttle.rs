@@ -0,0 +1,197 @@
+//! Bandwidth limiting of proxied connections.
+
+use crate::bucket::TokenBucket;
+use futures::{Async, Future, Poll};
+use std::{
+    cmp,
+    io::{self, Read, Write},
+    time::Instant,
+};
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_timer::Delay;
+
+/// A stream limiting the rate of the bytes read from and written to the wrapped stream.
+///
+/// Each direction has its own token bucket, which allows bursts of up to a second worth of
+/// bytes. A read or write exceeding the rate returns `WouldBlock` and wakes up the task
+/// once enough bytes are allowed, so the stream must be used from a task.
+#[derive(Debug)]
+pub struct Throttled<S> {
+    inner: S,
+    read: Option<Bucket>,
+    write: Option<Bucket>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    bucket: TokenBucket,
+    delay: Option<Delay>,
+}
+
+impl Bucket {
+    fn new(rate: u64) -> Bucket {
+        Bucket {
+            bucket: TokenBucket::new(rate as f64),
+            delay: None,
+        }
+    }
+
+    /// Polls for the number of bytes which can be transferred, waiting until `wanted`
+    /// bytes or a full bucket are available.
+    fn poll_available(&mut self, wanted: usize) -> Poll<usize, io::Error> {
+        loop {
+            if let Some(delay) = &mut self.delay {
+                match delay.poll() {
+                    Ok(Async::Ready(())) => self.delay = None,
+                    Ok(Async::NotReady) => return Ok(Async::NotReady),
+                    Err(e) => return Err(io::Error::other(e)),
+                }
+            }
+            let now = Instant::now();
+            self.bucket.refill(now);
+            if self.bucket.tokens() >= 1.0 {
+                return Ok(Async::Ready(self.bucket.tokens() as usize));
+            }
+            let wait = self.bucket.wait(wanted.max(1) as f64);
+            self.delay = Some(Delay::new(now + wait));
+        }
+    }
+
+    fn consume(&mut self, n: usize) {
+        self.bucket.consume(n as f64);
+    }
+}
+
+impl<S> Throttled<S> {
+    /// Wraps `inner` without limits.
+    pub fn new(inner: S) -> Throttled<S> {
+        Throttled {
+            inner,
+            read: None,
+            write: None,
+        }
+    }
+
+    /// Limits the bytes read to `bytes_per_second`.
+    ///
+    /// # Panics
+    ///
+    /// It panics if `bytes_per_second` is 0.
+    pub fn read_limit(mut self, bytes_per_second: u64) -> Throttled<S> {
+        assert!(bytes_per_second > 0, "the read limit must be positive");
+        self.read = Some(Bucket::new(bytes_per_second));
+        self
+    }
+
+    /// Limits the bytes written to `bytes_per_second`.
+    ///
+    /// # Panics
+    ///
+    /// It panics if `bytes_per_second` is 0.
+    pub fn write_limit(mut self, bytes_per_second: u64) -> Throttled<S> {
+        assert!(bytes_per_second > 0, "the write limit must be positive");
+        self.write = Some(Bucket::new(bytes_per_second));
+        self
+    }
+
+    /// Returns a shared reference to the wrapped stream.
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    /// Returns a mutable reference to the wrapped stream.
+    ///
+    /// The bytes read or written through it aren't limited.
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+
+    /// Consumes the `Throttled`, returning the wrapped stream.
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+/// Returns how many of `len` bytes can be transferred now, or `WouldBlock`.
+fn available(bucket: &mut Option<Bucket>, len: usize) -> io::Result<usize> {
+    match bucket {
+        Some(bucket) if len > 0 => match bucket.poll_available(len)? {
+            Async::Ready(available) => Ok(cmp::min(len, available)),
+            Async::NotReady => Err(io::ErrorKind::WouldBlock.into()),
+        },
+        _ => Ok(len),
+    }
+}
+
+fn consume(bucket: &mut Option<Bucket>, n: usize) {
+    if let Some(bucket) = bucket {
+        bucket.consume(n);
+    }
+}
+
+impl<S: Read> Read for Throttled<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = available(&mut self.read, buf.len())?;
+        let n = self.inner.read(&mut buf[..len])?;
+        consume(&mut self.read, n);
+        Ok(n)
+    }
+}
+
+impl<S: Write> Write for Throttled<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = available(&mut self.write, buf.len())?;
+        let n = self.inner.write(&buf[..len])?;
+        consume(&mut self.write, n);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<S: AsyncRead> AsyncRead for Throttled<S> {
+    unsafe fn prepare_uninitialized_buffer(&self, buf: &mut [u8]) -> bool {
+        self.inner.prepare_uninitialized_buffer(buf)
+    }
+}
+
+impl<S: AsyncWrite> AsyncWrite for Throttled<S> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.inner.shutdown()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future;
+    use std::time::Duration;
+    use tokio::runtime::current_thread::Runtime;
+
+    #[test]
+    fn waits_for_the_bucket_to_refill() {
+        let mut stream = Throttled::new(io::Cursor::new(vec![0; 150])).read_limit(100);
+        let mut runtime = Runtime::new().unwrap();
+        let start = Instant::now();
+        let (stream, _) = runtime
+            .block_on(future::lazy(move || {
+                let mut buf = [0; 150];
+                assert_eq!(stream.read(&mut buf).unwrap(), 100);
+                let e = stream.read(&mut buf[..10]).unwrap_err();
+                assert_eq!(e.kind(), io::ErrorKind::WouldBlock);
+                // The task is woken up once the 10 bytes are allowed.
+                tokio_io::io::read_exact(stream, [0; 10])
+            }))
+            .unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(90));
+        assert_eq!(stream.get_ref().position(), 110);
+    }
+
+    #[test]
+    #[should_panic]
+    fn rejects_a_zero_limit() {
+        Throttled::new(io::Cursor::new(Vec::<u8>::new())).write_limit(0);
+    }
+}
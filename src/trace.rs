@@ -0,0 +1,266 @@
+//! Recording of the bytes of handshakes with real proxy servers, and their replay by a
+//! local server, to lock in the quirks of the proxy implementations as regression tests.
+//!
+//! A `Trace` is saved as a fixture with one message per line, prefixed with `>` for the
+//! bytes sent to the proxy server and `<` for the bytes received from it, in hexadecimal:
+//!
+//! ```text
+//! # Dante 1.4, no authentication
+//! > 05 01 00
+//! < 05 00
+//! ```
+
+use crate::{connector::Socks5ConnectorBuilder, observer::Direction};
+use std::{
+    fmt, fs,
+    io::{self, Read, Write},
+    net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream},
+    path::Path,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+};
+
+/// The bytes exchanged with a proxy server, in order.
+///
+/// The username and password are replaced with `*`, as in the wire dumps.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Trace {
+    messages: Vec<(Direction, Vec<u8>)>,
+}
+
+impl Trace {
+    /// Creates an empty trace.
+    pub fn new() -> Trace {
+        Trace::default()
+    }
+
+    /// Appends bytes to the trace, extending the last message if it has the same direction.
+    pub fn push(&mut self, direction: Direction, bytes: &[u8]) {
+        match self.messages.last_mut() {
+            Some((last, message)) if *last == direction => message.extend_from_slice(bytes),
+            _ => self.messages.push((direction, bytes.to_vec())),
+        }
+    }
+
+    /// Returns the messages of the trace.
+    pub fn messages(&self) -> &[(Direction, Vec<u8>)] {
+        &self.messages
+    }
+
+    /// Reads a trace from a fixture file.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Trace> {
+        fs::read_to_string(path)?.parse()
+    }
+
+    /// Writes the trace to a fixture file.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        fs::write(path, self.to_string())
+    }
+}
+
+impl fmt::Display for Trace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (direction, message) in &self.messages {
+            f.write_str(match direction {
+                Direction::Sent => ">",
+                Direction::Received => "<",
+            })?;
+            for byte in message {
+                write!(f, " {:02x}", byte)?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Trace {
+    type Err = io::Error;
+
+    /// Parses a fixture, ignoring the empty lines and the comments starting with `#`.
+    fn from_str(s: &str) -> io::Result<Trace> {
+        let invalid = |line: &str| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid trace line: {}", line),
+            )
+        };
+        let mut trace = Trace::new();
+        for line in s.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let direction = match line.as_bytes()[0] {
+                b'>' => Direction::Sent,
+                b'<' => Direction::Received,
+                _ => return Err(invalid(line)),
+            };
+            let hex: String = line[1..].split_whitespace().collect();
+            if !hex.is_ascii() || !hex.len().is_multiple_of(2) {
+                return Err(invalid(line));
+            }
+            let message = (0..hex.len())
+                .step_by(2)
+                .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|_| invalid(line))?;
+            trace.messages.push((direction, message));
+        }
+        Ok(trace)
+    }
+}
+
+/// Records the handshakes run through a connector into a `Trace`.
+#[derive(Debug, Clone, Default)]
+pub struct Recorder {
+    trace: Arc<Mutex<Trace>>,
+}
+
+impl Recorder {
+    /// Creates a recorder with an empty trace.
+    pub fn new() -> Recorder {
+        Recorder::default()
+    }
+
+    /// Records the handshakes of the connector built by `builder`.
+    ///
+    /// It's set as the wire dump of the connector, replacing any other.
+    pub fn record(&self, builder: Socks5ConnectorBuilder) -> Socks5ConnectorBuilder {
+        let trace = self.trace.clone();
+        builder.wire_dump(move |direction, bytes| trace.lock().unwrap().push(direction, bytes))
+    }
+
+    /// Returns the trace recorded so far.
+    pub fn trace(&self) -> Trace {
+        self.trace.lock().unwrap().clone()
+    }
+}
+
+/// A local server replaying a `Trace` to every client which connects to it, in place of
+/// the proxy server it was recorded against.
+///
+/// The bytes received from the client are checked against the ones sent in the trace,
+/// where any byte matches a `*`. On a mismatch, the connection is closed. Once the trace
+/// is replayed, the connection is kept open until the client closes it.
+///
+/// The server runs on a thread of its own until the `Replay` is dropped.
+#[derive(Debug)]
+pub struct Replay {
+    addr: SocketAddr,
+    closed: Arc<AtomicBool>,
+    mismatches: Arc<AtomicUsize>,
+}
+
+impl Replay {
+    /// Starts replaying `trace` on a local port.
+    pub fn bind(trace: Trace) -> io::Result<Replay> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+        let replay = Replay {
+            addr: listener.local_addr()?,
+            closed: Arc::new(AtomicBool::new(false)),
+            mismatches: Arc::new(AtomicUsize::new(0)),
+        };
+        let closed = replay.closed.clone();
+        let mismatches = replay.mismatches.clone();
+        let trace = Arc::new(trace);
+        thread::spawn(move || {
+            for conn in listener.incoming() {
+                if closed.load(Ordering::SeqCst) {
+                    break;
+                }
+                let conn = match conn {
+                    Ok(conn) => conn,
+                    Err(_) => continue,
+                };
+                let trace = trace.clone();
+                let mismatches = mismatches.clone();
+                thread::spawn(move || {
+                    if let Err(e) = serve(conn, &trace) {
+                        if e.kind() == io::ErrorKind::InvalidData {
+                            mismatches.fetch_add(1, Ordering::SeqCst);
+                        }
+                    }
+                });
+            }
+        });
+        Ok(replay)
+    }
+
+    /// Returns the address to use as the proxy address.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Returns the number of connections closed because the client didn't send the bytes
+    /// of the trace.
+    pub fn mismatches(&self) -> usize {
+        self.mismatches.load(Ordering::SeqCst)
+    }
+}
+
+impl Drop for Replay {
+    fn drop(&mut self) {
+        self.closed.store(true, Ordering::SeqCst);
+        // Wake the server up from `accept`.
+        let _ = TcpStream::connect(self.addr);
+    }
+}
+
+pub(crate) fn serve<S: Read + Write>(mut conn: S, trace: &Trace) -> io::Result<()> {
+    for (direction, message) in trace.messages() {
+        match direction {
+            Direction::Sent => {
+                let mut received = vec![0; message.len()];
+                conn.read_exact(&mut received)?;
+                let matches = message
+                    .iter()
+                    .zip(&received)
+                    .all(|(expected, byte)| expected == byte || *expected == b'*');
+                if !matches {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "unexpected bytes from the client",
+                    ));
+                }
+            }
+            Direction::Received => conn.write_all(message)?,
+        }
+    }
+    io::copy(&mut conn, &mut io::sink())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connector::Socks5Connector;
+    use tokio::runtime::current_thread::Runtime;
+
+    const NO_AUTH: &str = "
+        # no authentication
+        > 05 01 00
+        < 05 00
+        > 05 01 00 03 0b 65 78 61 6d 70 6c 65 2e 63 6f 6d 00 50
+        < 05 00 00 01 7f 00 00 01 04 38
+    ";
+
+    #[test]
+    fn replays_recorded_handshakes() {
+        let trace: Trace = NO_AUTH.parse().unwrap();
+        assert_eq!(trace.to_string().parse::<Trace>().unwrap(), trace);
+        let replay = Replay::bind(trace.clone()).unwrap();
+        let recorder = Recorder::new();
+        let connector = recorder.record(Socks5Connector::builder()).build();
+        let conn = connector
+            .connect(replay.local_addr(), "example.com:80")
+            .unwrap();
+        let stream = Runtime::new().unwrap().block_on(conn).unwrap();
+        assert_eq!(stream.target_addr().to_string(), "127.0.0.1:1080");
+        assert_eq!(recorder.trace(), trace);
+        assert_eq!(replay.mismatches(), 0);
+    }
+}
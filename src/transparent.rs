@@ -0,0 +1,137 @@
+//! Helpers to relay the connections redirected to a local port by the firewall of a Linux
+//! gateway, with either the `REDIRECT` or the `TPROXY` target of iptables.
+//!
+//! See `TcpForwarder::transparent` to relay them through a proxy server.
+
+use crate::dial;
+use socket2::{Protocol, Socket, Type};
+use std::{
+    io, mem,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
+    os::unix::io::AsRawFd,
+};
+use tokio_reactor::Handle;
+use tokio_tcp::{TcpListener, TcpStream};
+
+/// Returns the destination of a connection before it was redirected with the `REDIRECT`
+/// target, as recorded by the connection tracking of the kernel.
+///
+/// The connections redirected with `TPROXY` keep their destination, which is the local
+/// address of the stream.
+pub fn original_dst(tcp: &TcpStream) -> io::Result<SocketAddr> {
+    let (level, name) = match tcp.local_addr()? {
+        SocketAddr::V4(_) => (libc::SOL_IP, libc::SO_ORIGINAL_DST),
+        SocketAddr::V6(_) => (libc::SOL_IPV6, libc::IP6T_SO_ORIGINAL_DST),
+    };
+    let mut addr: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let mut len = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(
+            tcp.as_raw_fd(),
+            level,
+            name,
+            &mut addr as *mut libc::sockaddr_storage as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if ret == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    match libc::c_int::from(addr.ss_family) {
+        libc::AF_INET => {
+            let addr = unsafe { &*(&addr as *const _ as *const libc::sockaddr_in) };
+            let ip = Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr));
+            Ok(SocketAddrV4::new(ip, u16::from_be(addr.sin_port)).into())
+        }
+        libc::AF_INET6 => {
+            let addr = unsafe { &*(&addr as *const _ as *const libc::sockaddr_in6) };
+            let ip = Ipv6Addr::from(addr.sin6_addr.s6_addr);
+            let port = u16::from_be(addr.sin6_port);
+            Ok(SocketAddrV6::new(ip, port, addr.sin6_flowinfo, addr.sin6_scope_id).into())
+        }
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "unsupported address family",
+        )),
+    }
+}
+
+/// Listens on `addr` with `IP_TRANSPARENT` set, so that the connections redirected with
+/// `TPROXY` to a port of the listener are accepted.
+///
+/// Setting the option requires the `CAP_NET_ADMIN` capability.
+pub fn tproxy_listener(addr: &SocketAddr) -> io::Result<TcpListener> {
+    let socket = Socket::new(dial::domain(addr), Type::stream(), Some(Protocol::tcp()))?;
+    let (level, name) = transparent_option(addr);
+    let enable: libc::c_int = 1;
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            &enable as *const libc::c_int as *const libc::c_void,
+            mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if ret == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    socket.set_reuse_address(true)?;
+    socket.bind(&(*addr).into())?;
+    socket.listen(1024)?;
+    TcpListener::from_std(socket.into_tcp_listener(), &Handle::default())
+}
+
+/// Returns `true` if `IP_TRANSPARENT` is set on `listener`, as on the listeners returned
+/// by `tproxy_listener`.
+pub fn is_transparent(listener: &TcpListener) -> io::Result<bool> {
+    let (level, name) = transparent_option(&listener.local_addr()?);
+    let mut enabled: libc::c_int = 0;
+    let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(
+            listener.as_raw_fd(),
+            level,
+            name,
+            &mut enabled as *mut libc::c_int as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if ret == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(enabled != 0)
+}
+
+/// Returns the level and the name of the `IP_TRANSPARENT` option for the family of `addr`.
+fn transparent_option(addr: &SocketAddr) -> (libc::c_int, libc::c_int) {
+    match addr {
+        SocketAddr::V4(_) => (libc::SOL_IP, libc::IP_TRANSPARENT),
+        SocketAddr::V6(_) => (libc::SOL_IPV6, libc::IPV6_TRANSPARENT),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpStream as StdTcpStream;
+
+    #[test]
+    fn fails_to_recover_the_destination_of_connections_not_redirected() {
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let tcp = StdTcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let tcp = TcpStream::from_std(tcp, &Handle::default()).unwrap();
+        assert!(original_dst(&tcp).is_err());
+    }
+
+    #[test]
+    fn sets_ip_transparent_on_tproxy_listeners() {
+        let addr = "127.0.0.1:0".parse().unwrap();
+        assert!(!is_transparent(&TcpListener::bind(&addr).unwrap()).unwrap());
+        match tproxy_listener(&addr) {
+            Ok(listener) => assert!(is_transparent(&listener).unwrap()),
+            // Without `CAP_NET_ADMIN`.
+            Err(e) => assert_eq!(e.kind(), io::ErrorKind::PermissionDenied),
+        }
+    }
+}
@@ -0,0 +1,487 @@
+//! UDP datagrams relayed by the proxy server with the UDP ASSOCIATE command.
+
+use crate::{
+    codec,
+    connector::{Compliance, Config},
+    tcp::{Command, ConnectFuture, Socks5Stream},
+    Authentication, Error, ProxySpec, Result, TargetAddr, ToProxyAddrs,
+};
+use bytes::Bytes;
+use futures::{try_ready, Async, AsyncSink, Future, Poll, Sink, StartSend, Stream};
+use std::{
+    io, mem,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio_io::AsyncRead;
+use tokio_timer::Delay;
+use tokio_udp::UdpSocket;
+
+const MAX_DATAGRAM_SIZE: usize = 65535;
+// RSV, FRAG, ATYP, the longest domain with its length and DST.PORT
+const MAX_HEADER_SIZE: usize = 262;
+
+/// A SOCKS5 UDP ASSOCIATE client.
+///
+/// Datagrams are relayed by the proxy server as long as the TCP connection used for the
+/// ASSOCIATE request is open, so it is kept alongside the UDP socket. Once the proxy server
+/// closes it, sending and receiving fail with `Error::AssociationClosed`.
+///
+/// Besides `poll_send_to` and `poll_recv_from`, a `Socks5Datagram` is a `Sink` and a `Stream`
+/// of `(Bytes, TargetAddr)` pairs.
+#[derive(Debug)]
+pub struct Socks5Datagram {
+    socket: UdpSocket,
+    stream: Socks5Stream,
+    relay_addr: SocketAddr,
+    recv_buf: Box<[u8]>,
+    // Reused for the datagrams sent, unless one is pending.
+    send_buf: Vec<u8>,
+    pending: Option<Vec<u8>>,
+    fragment_policy: FragmentPolicy,
+    reassembly: Reassembly,
+    reassembly_timer: Option<Delay>,
+    closed: bool,
+}
+
+/// How fragmented datagrams received from the relay are handled.
+///
+/// Datagrams sent through the relay are never fragmented.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FragmentPolicy {
+    /// Receiving a fragment fails with `Error::InvalidDatagram`.
+    #[default]
+    Reject,
+    /// Fragments are silently dropped.
+    Drop,
+    /// Fragments are reassembled, as described in RFC 1928. An incomplete datagram is
+    /// dropped once the timeout elapses after its first fragment is received, or when a
+    /// fragment is missing.
+    Reassemble(Duration),
+}
+
+impl Socks5Datagram {
+    /// Initiates a UDP ASSOCIATE request to the specified proxy.
+    pub fn bind<P>(proxy: P) -> Result<AssociateFuture<'static, P::Output>>
+    where
+        P: ToProxyAddrs,
+    {
+        Self::bind_raw(proxy, Authentication::None)
+    }
+
+    /// Initiates a UDP ASSOCIATE request to the specified proxy using given username and
+    /// password.
+    ///
+    /// # Error
+    ///
+    /// It fails if the username or password is empty or longer than 255 bytes.
+    pub fn bind_with_password<'a, P>(
+        proxy: P,
+        username: &'a str,
+        password: &'a str,
+    ) -> Result<AssociateFuture<'a, P::Output>>
+    where
+        P: ToProxyAddrs,
+    {
+        Self::bind_raw(
+            proxy,
+            Authentication::Password {
+                username: username.into(),
+                password: password.into(),
+            },
+        )
+    }
+
+    /// Initiates a UDP ASSOCIATE request to the proxy described by `proxy`, authenticating
+    /// with its credentials if it has any.
+    ///
+    /// # Error
+    ///
+    /// It fails if the username or password is empty or longer than 255 bytes.
+    pub fn bind_with_spec<'a, P>(proxy: &'a ProxySpec<P>) -> Result<AssociateFuture<'a, P::Output>>
+    where
+        P: ToProxyAddrs,
+    {
+        Self::bind_raw(&proxy.addrs, proxy.authentication())
+    }
+
+    fn bind_raw<'a, P>(proxy: P, auth: Authentication<'a>) -> Result<AssociateFuture<'a, P::Output>>
+    where
+        P: ToProxyAddrs,
+    {
+        // The address the datagrams will be sent from is not known yet.
+        let unspecified = SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0));
+        let inner = Socks5Stream::connect_raw(
+            Arc::new(Config::default()),
+            proxy,
+            unspecified,
+            auth,
+            Command::Associate,
+        )?;
+        Ok(AssociateFuture {
+            inner,
+            local_addr: None,
+            socket: None,
+        })
+    }
+
+    /// Returns the address of the relay the datagrams are sent to.
+    pub fn relay_addr(&self) -> SocketAddr {
+        self.relay_addr
+    }
+
+    /// Returns a shared reference to the UDP socket.
+    pub fn get_ref(&self) -> &UdpSocket {
+        &self.socket
+    }
+
+    /// Returns a shared reference to the TCP connection of the association.
+    pub fn control_stream(&self) -> &Socks5Stream {
+        &self.stream
+    }
+
+    /// Consumes the `Socks5Datagram`, returning the UDP socket and the TCP connection of
+    /// the association.
+    pub fn into_inner(self) -> (UdpSocket, Socks5Stream) {
+        (self.socket, self.stream)
+    }
+
+    /// Sets how fragmented datagrams received from the relay are handled.
+    ///
+    /// Defaults to `FragmentPolicy::Reject`.
+    pub fn set_fragment_policy(&mut self, policy: FragmentPolicy) {
+        self.fragment_policy = policy;
+        self.reassembly = Reassembly::default();
+        self.reassembly_timer = None;
+    }
+
+    /// Sends `buf` to `target` through the relay, returning the number of bytes of `buf`
+    /// which were sent.
+    pub fn poll_send_to(&mut self, buf: &[u8], target: &TargetAddr<'_>) -> Poll<usize, Error> {
+        self.poll_control()?;
+        self.send_buf.clear();
+        write_header(&mut self.send_buf, target);
+        let header_len = self.send_buf.len();
+        self.send_buf.extend_from_slice(buf);
+        let n = try_ready!(self.socket.poll_send(&self.send_buf));
+        Ok(Async::Ready(n.saturating_sub(header_len)))
+    }
+
+    /// Receives a datagram from the relay into `buf`, returning the number of bytes read and
+    /// the address it was sent from.
+    ///
+    /// The data which doesn't fit into `buf` is discarded.
+    pub fn poll_recv_from(&mut self, buf: &mut [u8]) -> Poll<(usize, TargetAddr<'static>), Error> {
+        let (data, addr) = try_ready!(self.poll_recv());
+        let n = data.len().min(buf.len());
+        buf[..n].copy_from_slice(&data[..n]);
+        Ok(Async::Ready((n, addr)))
+    }
+
+    fn poll_recv(&mut self) -> Poll<(Bytes, TargetAddr<'static>), Error> {
+        self.poll_control()?;
+        loop {
+            if let Some(timer) = &mut self.reassembly_timer {
+                let elapsed = timer.poll().map_err(io::Error::other)?.is_ready();
+                if elapsed {
+                    self.reassembly = Reassembly::default();
+                    self.reassembly_timer = None;
+                }
+            }
+            let n = try_ready!(self.socket.poll_recv(&mut self.recv_buf));
+            let datagram = &self.recv_buf[..n];
+            let (frag, addr, header_len) = read_header(datagram)?;
+            let data = &datagram[header_len..];
+            if frag == 0x00 {
+                return Ok(Async::Ready((Bytes::from(data), addr)));
+            }
+            match self.fragment_policy {
+                FragmentPolicy::Reject => {
+                    Err(Error::InvalidDatagram("fragmented datagrams are rejected"))?
+                }
+                FragmentPolicy::Drop => {}
+                FragmentPolicy::Reassemble(timeout) => {
+                    if frag & 0x7f == 1 {
+                        self.reassembly_timer = Some(Delay::new(Instant::now() + timeout));
+                    }
+                    if let Some((data, addr)) = self.reassembly.push(frag, addr, data) {
+                        self.reassembly_timer = None;
+                        return Ok(Async::Ready((data.into(), addr)));
+                    }
+                }
+            }
+        }
+    }
+
+    fn poll_flush_pending(&mut self) -> Poll<(), Error> {
+        self.poll_control()?;
+        if let Some(datagram) = &self.pending {
+            try_ready!(self.socket.poll_send(datagram));
+            self.send_buf = self.pending.take().unwrap();
+        }
+        Ok(Async::Ready(()))
+    }
+
+    /// Checks that the proxy server hasn't closed the TCP connection of the association,
+    /// discarding anything it sends on it.
+    fn poll_control(&mut self) -> Result<()> {
+        let mut buf = [0; 64];
+        while !self.closed {
+            match self.stream.poll_read(&mut buf) {
+                Ok(Async::Ready(0)) => self.closed = true,
+                Ok(Async::Ready(_)) => {}
+                Ok(Async::NotReady) => return Ok(()),
+                Err(e) => {
+                    self.closed = true;
+                    Err(e)?
+                }
+            }
+        }
+        Err(Error::AssociationClosed)
+    }
+}
+
+impl Stream for Socks5Datagram {
+    type Item = (Bytes, TargetAddr<'static>);
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Error> {
+        let (data, addr) = try_ready!(self.poll_recv());
+        Ok(Async::Ready(Some((data, addr))))
+    }
+}
+
+impl Sink for Socks5Datagram {
+    type SinkItem = (Bytes, TargetAddr<'static>);
+    type SinkError = Error;
+
+    fn start_send(&mut self, item: Self::SinkItem) -> StartSend<Self::SinkItem, Error> {
+        if self.poll_flush_pending()?.is_not_ready() {
+            return Ok(AsyncSink::NotReady(item));
+        }
+        let (data, target) = item;
+        let mut datagram = mem::take(&mut self.send_buf);
+        datagram.clear();
+        write_header(&mut datagram, &target);
+        datagram.extend_from_slice(&data);
+        self.pending = Some(datagram);
+        Ok(AsyncSink::Ready)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), Error> {
+        self.poll_flush_pending()
+    }
+}
+
+/// A `Future` which resolves to a `Socks5Datagram`.
+pub struct AssociateFuture<'a, S>
+where
+    S: Stream<Item = SocketAddr, Error = Error>,
+{
+    inner: ConnectFuture<'a, 'static, S>,
+    local_addr: Option<SocketAddr>,
+    socket: Option<UdpSocket>,
+}
+
+impl<'a, S> AssociateFuture<'a, S>
+where
+    S: Stream<Item = SocketAddr, Error = Error>,
+{
+    /// Sets the local address the UDP socket is bound to. It is sent to the proxy server as
+    /// the address the datagrams will come from.
+    ///
+    /// By default, the socket is bound to the unspecified address of the family of the relay,
+    /// with a port assigned by the OS. An IPv6 socket can be used with an IPv4 relay if it
+    /// isn't restricted to IPv6, but an IPv4 socket can't be used with an IPv6 relay.
+    pub fn local_addr(mut self, local_addr: SocketAddr) -> Self {
+        self.local_addr = Some(local_addr);
+        self
+    }
+}
+
+impl<'a, S> Future for AssociateFuture<'a, S>
+where
+    S: Stream<Item = SocketAddr, Error = Error>,
+{
+    type Item = Socks5Datagram;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Socks5Datagram, Error> {
+        if let Some(local_addr) = self.local_addr.take() {
+            let socket = UdpSocket::bind(&local_addr)?;
+            self.inner.set_target(TargetAddr::Ip(socket.local_addr()?));
+            self.socket = Some(socket);
+        }
+        let stream = try_ready!(self.inner.poll());
+        let reply_addr = match *stream.target_addr() {
+            TargetAddr::Ip(addr) => addr,
+            TargetAddr::Domain(..) => Err(Error::InvalidTargetAddress(
+                "relay address should be an IP address",
+            ))?,
+        };
+        let relay_addr = resolve_relay_addr(reply_addr, stream.proxy_addr());
+        let socket = match self.socket.take() {
+            Some(socket) => socket,
+            None => {
+                let local_addr = match relay_addr {
+                    SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+                    SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+                };
+                UdpSocket::bind(&local_addr)?
+            }
+        };
+        let relay_addr = match (socket.local_addr()?, relay_addr) {
+            (SocketAddr::V6(_), SocketAddr::V4(addr)) => {
+                SocketAddr::new(addr.ip().to_ipv6_mapped().into(), addr.port())
+            }
+            (SocketAddr::V4(_), SocketAddr::V6(_)) => Err(io::Error::new(
+                io::ErrorKind::AddrNotAvailable,
+                "an IPv4 socket can't reach an IPv6 relay",
+            ))?,
+            (_, relay_addr) => relay_addr,
+        };
+        socket.connect(&relay_addr)?;
+        Ok(Async::Ready(Socks5Datagram {
+            socket,
+            stream,
+            relay_addr,
+            recv_buf: vec![0; MAX_DATAGRAM_SIZE].into_boxed_slice(),
+            send_buf: Vec::with_capacity(MAX_HEADER_SIZE),
+            pending: None,
+            fragment_policy: FragmentPolicy::default(),
+            reassembly: Reassembly::default(),
+            reassembly_timer: None,
+            closed: false,
+        }))
+    }
+}
+
+/// Returns the address of the relay described by the ASSOCIATE reply.
+fn resolve_relay_addr(reply_addr: SocketAddr, proxy_addr: SocketAddr) -> SocketAddr {
+    let ip = match reply_addr.ip() {
+        // The relay is on the proxy server itself.
+        ip if ip.is_unspecified() => proxy_addr.ip(),
+        IpAddr::V6(ip) => match ip.segments() {
+            // An IPv4-mapped address
+            [0, 0, 0, 0, 0, 0xffff, hi, lo] => {
+                IpAddr::V4(Ipv4Addr::from(u32::from(hi) << 16 | u32::from(lo)))
+            }
+            _ => IpAddr::V6(ip),
+        },
+        ip => ip,
+    };
+    SocketAddr::new(ip, reply_addr.port())
+}
+
+/// The reassembly queue of a fragmented datagram.
+#[derive(Debug, Default)]
+struct Reassembly {
+    addr: Option<TargetAddr<'static>>,
+    position: u8,
+    data: Vec<u8>,
+}
+
+impl Reassembly {
+    /// Adds a fragment, returning the datagram when it is the last one.
+    fn push(
+        &mut self,
+        frag: u8,
+        addr: TargetAddr<'static>,
+        data: &[u8],
+    ) -> Option<(Vec<u8>, TargetAddr<'static>)> {
+        let position = frag & 0x7f;
+        if position == 1 {
+            *self = Reassembly::default();
+            self.addr = Some(addr);
+        } else if position != self.position + 1 || self.addr.as_ref() != Some(&addr) {
+            *self = Reassembly::default();
+            return None;
+        }
+        self.position = position;
+        self.data.extend_from_slice(data);
+        // The high-order bit marks the end of the fragment sequence.
+        if frag & 0x80 == 0 {
+            return None;
+        }
+        let reassembly = mem::take(self);
+        Some((reassembly.data, reassembly.addr.unwrap()))
+    }
+}
+
+/// Writes the header of a UDP request, as defined in RFC 1928, to `buf`.
+fn write_header(buf: &mut Vec<u8>, target: &TargetAddr<'_>) {
+    // RSV and FRAG
+    buf.extend_from_slice(&[0x00, 0x00, 0x00]);
+    codec::write_addr(target, |bytes| buf.extend_from_slice(bytes));
+}
+
+/// Reads the header of a UDP reply, returning the fragment number and the address in it and
+/// the length of the header.
+fn read_header(buf: &[u8]) -> Result<(u8, TargetAddr<'static>, usize)> {
+    let truncated = || Error::InvalidDatagram("truncated header");
+    if buf.len() < 4 {
+        Err(truncated())?
+    }
+    if buf[0] != 0x00 || buf[1] != 0x00 {
+        Err(Error::InvalidReservedByte)?
+    }
+    let len = 3 + codec::addr_len(&buf[3..]).ok_or_else(truncated)?;
+    let addr = codec::read_addr(buf.get(3..len).ok_or_else(truncated)?, Compliance::Strict)?;
+    Ok((buf[2], addr, len))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_headers() {
+        for target in &["10.0.0.1:53", "[::1]:53", "example.com:53"] {
+            let target: TargetAddr = target.parse().unwrap();
+            let mut buf = Vec::new();
+            write_header(&mut buf, &target);
+            buf.extend_from_slice(b"data");
+            let (frag, addr, len) = read_header(&buf).unwrap();
+            assert_eq!(frag, 0x00);
+            assert_eq!(addr, target);
+            assert_eq!(&buf[len..], b"data");
+        }
+    }
+
+    #[test]
+    fn rejects_truncated_headers() {
+        let mut buf = Vec::new();
+        write_header(&mut buf, &"example.com:53".parse().unwrap());
+        buf.truncate(buf.len() - 1);
+        assert!(read_header(&buf).is_err());
+    }
+
+    #[test]
+    fn reassembles_fragments() {
+        let addr: TargetAddr = "10.0.0.1:53".parse().unwrap();
+        let mut reassembly = Reassembly::default();
+        assert!(reassembly.push(1, addr.clone(), b"da").is_none());
+        assert!(reassembly.push(2, addr.clone(), b"t").is_none());
+        let (data, from) = reassembly.push(0x83, addr.clone(), b"a").unwrap();
+        assert_eq!(&data[..], b"data");
+        assert_eq!(from, addr);
+
+        // A missing fragment drops the datagram.
+        assert!(reassembly.push(1, addr.clone(), b"da").is_none());
+        assert!(reassembly.push(0x83, addr.clone(), b"a").is_none());
+        assert!(reassembly.push(0x82, addr.clone(), b"t").is_none());
+    }
+
+    #[test]
+    fn resolves_relay_addr() {
+        let proxy_addr = SocketAddr::from(([10, 0, 0, 1], 1080));
+        let relay_addr = |reply_addr: &str| {
+            resolve_relay_addr(reply_addr.parse().unwrap(), proxy_addr).to_string()
+        };
+        assert_eq!(relay_addr("0.0.0.0:4000"), "10.0.0.1:4000");
+        assert_eq!(relay_addr("[::]:4000"), "10.0.0.1:4000");
+        assert_eq!(relay_addr("[::ffff:10.0.0.2]:4000"), "10.0.0.2:4000");
+        assert_eq!(relay_addr("[::1]:4000"), "[::1]:4000");
+    }
+}
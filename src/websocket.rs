@@ -0,0 +1,168 @@
+//! A `Dialer` reaching the proxy server through a WebSocket, for the SOCKS services exposed
+//! behind one to traverse restrictive firewalls.
+//!
+//! The bytes of the handshake and of the tunnel are carried in binary messages, in both
+//! directions.
+
+use crate::dialer::Dialer;
+use bytes::Bytes;
+use futures::{Async, AsyncSink, Future, Poll, Sink, Stream};
+use std::{
+    cmp, fmt,
+    io::{self, Read, Write},
+};
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_tcp::TcpStream;
+use tokio_tungstenite::{
+    connect_async,
+    tungstenite::{Error as WsError, Message},
+    MaybeTlsStream, WebSocketStream,
+};
+use url::Url;
+
+/// Dials the proxy server with a WebSocket connection to a `ws` or `wss` URL.
+#[derive(Debug, Clone)]
+pub struct WebSocketDialer {
+    url: Url,
+}
+
+impl WebSocketDialer {
+    /// Creates a dialer connecting to `url`.
+    pub fn new(url: Url) -> WebSocketDialer {
+        WebSocketDialer { url }
+    }
+}
+
+impl Dialer for WebSocketDialer {
+    type Stream = WebSocket;
+    type Future = Box<dyn Future<Item = WebSocket, Error = io::Error> + Send>;
+
+    fn dial(&self) -> Self::Future {
+        debug_event!(url = %self.url, "connecting to the WebSocket");
+        let conn = connect_async(self.url.clone())
+            .map(|(inner, _)| WebSocket {
+                inner,
+                read: Bytes::new(),
+            })
+            .map_err(ws_error);
+        Box::new(conn)
+    }
+}
+
+/// A WebSocket connection to a proxy server, read and written as a stream of bytes.
+///
+/// Text messages are ignored, and a close message ends the stream.
+pub struct WebSocket {
+    inner: WebSocketStream<MaybeTlsStream<TcpStream>>,
+    read: Bytes,
+}
+
+impl WebSocket {
+    /// Gets a reference to the underlying WebSocket stream.
+    pub fn get_ref(&self) -> &WebSocketStream<MaybeTlsStream<TcpStream>> {
+        &self.inner
+    }
+}
+
+impl fmt::Debug for WebSocket {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("WebSocket")
+            .field("buffered", &self.read.len())
+            .finish()
+    }
+}
+
+fn ws_error(e: WsError) -> io::Error {
+    match e {
+        WsError::Io(e) => e,
+        e => io::Error::other(e),
+    }
+}
+
+impl Read for WebSocket {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.read.is_empty() {
+            match self.inner.poll().map_err(ws_error)? {
+                Async::Ready(Some(Message::Binary(data))) => self.read = Bytes::from(data),
+                Async::Ready(Some(Message::Close(_))) | Async::Ready(None) => return Ok(0),
+                // The pings are answered by the WebSocket stream.
+                Async::Ready(Some(_)) => {}
+                Async::NotReady => return Err(io::ErrorKind::WouldBlock.into()),
+            }
+        }
+        let n = cmp::min(buf.len(), self.read.len());
+        buf[..n].copy_from_slice(&self.read[..n]);
+        self.read.advance(n);
+        Ok(n)
+    }
+}
+
+impl Write for WebSocket {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.inner.start_send(Message::Binary(buf.to_vec())) {
+            Ok(AsyncSink::Ready) => {
+                // The message is flushed by the next reads otherwise.
+                self.inner.poll_complete().map_err(ws_error)?;
+                Ok(buf.len())
+            }
+            Ok(AsyncSink::NotReady(_)) => Err(io::ErrorKind::WouldBlock.into()),
+            Err(e) => Err(ws_error(e)),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.inner.poll_complete().map_err(ws_error)? {
+            Async::Ready(()) => Ok(()),
+            Async::NotReady => Err(io::ErrorKind::WouldBlock.into()),
+        }
+    }
+}
+
+impl AsyncRead for WebSocket {}
+
+impl AsyncWrite for WebSocket {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.inner.close().map_err(ws_error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::stream;
+    use tokio::runtime::current_thread::Runtime;
+    use tokio_io::io::{read_to_end, write_all};
+    use tokio_tcp::TcpListener;
+    use tokio_tungstenite::accept_async;
+
+    #[test]
+    fn carries_the_bytes_in_binary_messages() {
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        // The server answers `hello` with `world`, and closes the WebSocket.
+        let server = listener
+            .incoming()
+            .into_future()
+            .map_err(|(e, _)| WsError::Io(e))
+            .and_then(|(tcp, _)| accept_async(tcp.unwrap()))
+            .and_then(|ws| ws.into_future().map_err(|(e, _)| e))
+            .and_then(|(message, ws)| {
+                assert_eq!(message, Some(Message::Binary(b"hello".to_vec())));
+                let messages = vec![
+                    Message::Text("ignored".into()),
+                    Message::Binary(b"world".to_vec()),
+                ];
+                ws.send_all(stream::iter_ok::<_, WsError>(messages))
+            })
+            .map(|_| ())
+            .map_err(|e| panic!("{}", e));
+        let mut runtime = Runtime::new().unwrap();
+        runtime.spawn(server);
+        let client = WebSocketDialer::new(url.parse().unwrap())
+            .dial()
+            .and_then(|ws| write_all(ws, b"hello"))
+            .and_then(|(ws, _)| read_to_end(ws, Vec::new()));
+        let (_, response) = runtime.block_on(client).unwrap();
+        assert_eq!(&response[..], b"world");
+    }
+}
@@ -1,5 +1,7 @@
 use once_cell::sync::OnceCell;
-use std::net::{SocketAddr, TcpStream as StdTcpStream};
+use std::net::SocketAddr;
+#[cfg(feature = "bind")]
+use std::net::TcpStream as StdTcpStream;
 use std::sync::Mutex;
 use tokio::{
     io::{copy, read_exact, write_all},
@@ -7,10 +9,9 @@ use tokio::{
     prelude::*,
     runtime::Runtime,
 };
-use tokio_socks::{
-    tcp::{BindFuture, ConnectFuture},
-    Error,
-};
+#[cfg(feature = "bind")]
+use tokio_socks::tcp::BindFuture;
+use tokio_socks::{tcp::ConnectFuture, Error};
 
 type Result<T> = std::result::Result<T, Error>;
 
@@ -46,6 +47,7 @@ where
     Ok(())
 }
 
+#[cfg(feature = "bind")]
 pub fn test_bind<S>(bind: BindFuture<'static, 'static, S>) -> Result<()>
 where
     S: Stream<Item = SocketAddr, Error = Error> + Send + 'static,
@@ -1,10 +1,11 @@
 mod common;
 
-use common::{test_bind, test_connect, ECHO_SERVER_ADDR, PROXY_ADDR};
-use tokio_socks::{
-    tcp::{Socks5Listener, Socks5Stream},
-    Error,
-};
+#[cfg(feature = "bind")]
+use common::test_bind;
+use common::{test_connect, ECHO_SERVER_ADDR, PROXY_ADDR};
+#[cfg(feature = "bind")]
+use tokio_socks::tcp::Socks5Listener;
+use tokio_socks::{tcp::Socks5Stream, Error};
 
 type Result<T> = std::result::Result<T, Error>;
 
@@ -16,6 +17,7 @@ fn connect() -> Result<()> {
 }
 
 #[test]
+#[cfg(feature = "bind")]
 fn bind() -> Result<()> {
     let bind =
         Socks5Listener::bind_with_password(PROXY_ADDR, ECHO_SERVER_ADDR, "mylonglonglonglonglonglonglonglonglonglonglonglonglonglonglonglonglonglonglonglonglonglonglonglonglonglonglonglonglonglonglonglonglonglonglonglonglonglonglonglonglonglonglonglonglonglonglonglonglonglonglonglonglonglonglonglonglonglonglogin", "longlonglonglonglonglonglonglonglonglonglonglonglonglonglonglonglonglonglonglonglonglonglonglonglonglonglonglonglonglonglonglonglonglonglonglonglonglonglonglonglonglonglonglonglonglonglonglonglonglonglonglonglonglonglonglonglonglonglonglonglongpassword")?;
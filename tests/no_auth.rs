@@ -1,10 +1,18 @@
 mod common;
 
-use common::{test_bind, test_connect, ECHO_SERVER_ADDR, PROXY_ADDR};
-use tokio_socks::{
-    tcp::{Socks5Listener, Socks5Stream},
-    Error,
+#[cfg(feature = "bind")]
+use common::test_bind;
+use common::{runtime, test_connect, ECHO_SERVER_ADDR, MSG, PROXY_ADDR};
+use futures::try_ready;
+use mio::Ready;
+use std::io::{Read, Write};
+use tokio::{
+    io::{read_exact, read_to_end, write_all},
+    prelude::*,
 };
+#[cfg(feature = "bind")]
+use tokio_socks::tcp::Socks5Listener;
+use tokio_socks::{tcp::Socks5Stream, AuthConfig, Error};
 
 type Result<T> = std::result::Result<T, Error>;
 
@@ -15,7 +23,91 @@ fn connect() -> Result<()> {
 }
 
 #[test]
+#[cfg(feature = "bind")]
 fn bind() -> Result<()> {
     let bind = Socks5Listener::bind(PROXY_ADDR, ECHO_SERVER_ADDR)?;
     test_bind(bind)
 }
+
+#[test]
+fn connect_with_std() -> Result<()> {
+    let stream = std::net::TcpStream::connect(PROXY_ADDR)?;
+    let conn = Socks5Stream::connect_with_std(stream, ECHO_SERVER_ADDR, AuthConfig::none())?;
+    test_connect(conn)
+}
+
+#[test]
+fn into_std() -> Result<()> {
+    let conn = Socks5Stream::connect(PROXY_ADDR, ECHO_SERVER_ADDR)?;
+    let stream = runtime().lock().unwrap().block_on(conn)?;
+    let mut tcp = stream.into_std()?;
+    tcp.write_all(MSG)?;
+    let mut buf = [0; 5];
+    tcp.read_exact(&mut buf[..])?;
+    assert_eq!(&buf[..], MSG);
+    Ok(())
+}
+
+#[test]
+fn peek() -> Result<()> {
+    let conn = Socks5Stream::connect(PROXY_ADDR, ECHO_SERVER_ADDR)?;
+    let fut = conn
+        .and_then(|stream| write_all(stream, MSG).map_err(Into::into))
+        .and_then(|(stream, _)| {
+            let mut stream = Some(stream);
+            let mut buf = [0; 5];
+            future::poll_fn(move || {
+                let n = try_ready!(stream.as_mut().unwrap().poll_peek(&mut buf));
+                Ok(Async::Ready((stream.take().unwrap(), buf[..n].to_vec())))
+            })
+        })
+        .and_then(|(stream, peeked)| {
+            read_exact(stream, [0; 5])
+                .map(move |(_, read)| (peeked, read))
+                .map_err(Into::into)
+        });
+    let (peeked, read) = runtime().lock().unwrap().block_on(fut)?;
+    assert!(!peeked.is_empty());
+    assert_eq!(&peeked[..], &MSG[..peeked.len()]);
+    assert_eq!(&read[..], MSG);
+    Ok(())
+}
+
+#[test]
+fn shutdown_write() -> Result<()> {
+    let conn = Socks5Stream::connect(PROXY_ADDR, ECHO_SERVER_ADDR)?;
+    let fut = conn
+        .and_then(|stream| write_all(stream, MSG).map_err(Into::into))
+        .and_then(|(stream, _)| stream.shutdown_write().map(|()| stream).map_err(Into::into))
+        .and_then(|stream| read_to_end(stream, Vec::new()).map_err(Into::into))
+        .map(|(_, echoed)| echoed);
+    let echoed = runtime().lock().unwrap().block_on(fut)?;
+    assert_eq!(&echoed[..], MSG);
+    Ok(())
+}
+
+#[test]
+fn poll_ready() -> Result<()> {
+    let conn = Socks5Stream::connect(PROXY_ADDR, ECHO_SERVER_ADDR)?;
+    let fut = conn
+        .and_then(|stream| {
+            let mut stream = Some(stream);
+            future::poll_fn(move || {
+                try_ready!(stream.as_ref().unwrap().poll_write_ready());
+                Ok(Async::Ready(stream.take().unwrap()))
+            })
+        })
+        .and_then(|stream| write_all(stream, MSG).map_err(Into::into))
+        .and_then(|(stream, _)| {
+            let mut stream = Some(stream);
+            future::poll_fn(move || {
+                try_ready!(stream.as_ref().unwrap().poll_read_ready(Ready::readable()));
+                Ok(Async::Ready(stream.take().unwrap()))
+            })
+        })
+        .and_then(|stream| read_exact(stream, [0; 5]).map_err(Into::into))
+        .map(|(_, echoed)| echoed);
+    let echoed = runtime().lock().unwrap().block_on(fut)?;
+    assert_eq!(&echoed[..], MSG);
+    Ok(())
+}
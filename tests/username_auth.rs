@@ -1,10 +1,11 @@
 mod common;
 
-use common::{test_bind, test_connect, ECHO_SERVER_ADDR, PROXY_ADDR};
-use tokio_socks::{
-    tcp::{Socks5Listener, Socks5Stream},
-    Error,
-};
+#[cfg(feature = "bind")]
+use common::test_bind;
+use common::{runtime, test_connect, ECHO_SERVER_ADDR, PROXY_ADDR};
+#[cfg(feature = "bind")]
+use tokio_socks::tcp::Socks5Listener;
+use tokio_socks::{tcp::Socks5Stream, AuthConfig, AuthMethod, Error};
 
 type Result<T> = std::result::Result<T, Error>;
 
@@ -16,8 +17,25 @@ fn connect() -> Result<()> {
 }
 
 #[test]
+fn connect_with_auth() -> Result<()> {
+    let auth = AuthConfig::password("mylogin", "mypassword");
+    let conn = Socks5Stream::connect_with_auth(PROXY_ADDR, ECHO_SERVER_ADDR, auth)?;
+    test_connect(conn)
+}
+
+#[test]
+#[cfg(feature = "bind")]
 fn bind() -> Result<()> {
     let bind =
         Socks5Listener::bind_with_password(PROXY_ADDR, ECHO_SERVER_ADDR, "mylogin", "mypassword")?;
     test_bind(bind)
 }
+
+#[test]
+fn auth_method() -> Result<()> {
+    let conn =
+        Socks5Stream::connect_with_password(PROXY_ADDR, ECHO_SERVER_ADDR, "mylogin", "mypassword")?;
+    let tcp = runtime().lock().unwrap().block_on(conn)?;
+    assert_eq!(tcp.auth_method(), AuthMethod::Password);
+    Ok(())
+}